@@ -0,0 +1,68 @@
+//! Times the shell on multi-megabyte scripts, which should scale linearly with
+//! the size of the input.
+//!
+//! Run with `cargo bench`.
+
+use std::fs;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const MEGABYTE: usize = 1 << 20;
+
+/// Runs a script and returns how long it took, checking that it printed no
+/// errors unless `errors` says it is made of bad lines.
+fn run_script(script: &str, errors: bool) -> Duration {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("script.aish");
+    fs::write(&path, script).unwrap();
+
+    let start = Instant::now();
+    let output = Command::new(env!("CARGO_BIN_EXE_aish"))
+        .arg(&path)
+        .current_dir(dir.path())
+        // Keep the log file out of the real home directory
+        .env("HOME", dir.path())
+        .output()
+        .unwrap();
+    let elapsed = start.elapsed();
+
+    assert!(
+        errors || output.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    elapsed
+}
+
+fn bench(name: &str, errors: bool, make_script: impl Fn(usize) -> String) {
+    for megabytes in [1, 2, 4] {
+        let script = make_script(megabytes * MEGABYTE);
+        let elapsed = run_script(&script, errors);
+        println!("{name:<24} {megabytes} MB {elapsed:>12.2?}");
+    }
+}
+
+fn main() {
+    bench("single long word", false, |size| {
+        format!(": '{}'\n", "x".repeat(size))
+    });
+    bench("many words", false, |size| {
+        let word = "word 'single quoted' \"double $HOME\" a\\ b ";
+        format!(": {}\n", word.repeat(size / word.len()))
+    });
+    bench("many lines", false, |size| {
+        let line = ": line of a long script with $HOME and 'quotes'\n";
+        line.repeat(size / line.len())
+    });
+    // The shell has no here-documents, so pasted data is quoted instead
+    bench("pasted data", false, |size| {
+        let line = "id,name,value\t1,\"first\",$x\n";
+        format!(": '\n{}'\n", line.repeat(size / line.len()))
+    });
+    // After each error lexing starts again from the next line
+    bench("many bad lines", true, |size| {
+        let lines = ": ${bad substitution}\n: fine\n";
+        lines.repeat(size / lines.len())
+    });
+}
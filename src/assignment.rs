@@ -0,0 +1,208 @@
+//! Variable assignments, on their own as in `x=1` and `array=(a b)`, or
+//! before a command as in `LANG=C sort`.
+
+use crate::ast::{Assignment, AssignmentValue};
+use crate::command::piped_fds;
+use crate::compound::fork_stage;
+use crate::errors::RuntimeError;
+use crate::shell::Shell;
+use crate::token::Token;
+use crate::traits::{Runnable, ShellCommand, Stage};
+use crate::variables::{Value, Variable};
+
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::process::ChildStdout;
+
+/// Assignments with the command they come before, if any.
+#[derive(Clone)]
+pub struct Assign {
+    assignments: Vec<Assignment>,
+    command: Option<Box<dyn ShellCommand>>,
+    /// Whether the assignments only last while the command runs, as they do
+    /// when it has words rather than only redirections
+    temporary: bool,
+    /// Arrays given to `declare`, assigned once it has run
+    declarations: Vec<Assignment>,
+}
+
+impl Assign {
+    pub fn new(
+        assignments: Vec<Assignment>,
+        command: Option<Box<dyn ShellCommand>>,
+        temporary: bool,
+        declarations: Vec<Assignment>,
+    ) -> Assign {
+        Assign {
+            assignments,
+            command,
+            temporary,
+            declarations,
+        }
+    }
+
+    /// Runs `f` with the assignments made, undoing them afterwards if they
+    /// are temporary, and then makes the declarations if it succeeded.
+    fn run_with<T>(
+        &self,
+        shell: &mut Shell,
+        f: impl FnOnce(&mut Shell) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        if !self.temporary {
+            for assignment in &self.assignments {
+                assign(shell, assignment)?;
+            }
+            return f(shell);
+        }
+
+        // The command makes the assignments once it has expanded its words,
+        // which see the values from before them
+        let mut saved = Vec::<(&str, Option<Variable>)>::new();
+        for assignment in &self.assignments {
+            saved.push((&assignment.name, shell.variables.save(&assignment.name)));
+        }
+        shell.set_prefix_assignments(self.assignments.clone());
+        let result = f(shell);
+        shell.take_prefix_assignments();
+        for (name, variable) in saved.into_iter().rev() {
+            shell.variables.restore(name, variable);
+        }
+
+        let result = result?;
+        for declaration in &self.declarations {
+            assign(shell, declaration)?;
+        }
+        Ok(result)
+    }
+}
+
+impl fmt::Debug for Assign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Assign({:?}, {:?}, {:?})",
+            self.assignments, self.command, self.declarations
+        )
+    }
+}
+
+impl Runnable for Assign {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        self.run_with(shell, |shell| match &self.command {
+            Some(command) => command.run(shell),
+            None => Ok("".to_string()),
+        })
+    }
+}
+
+impl ShellCommand for Assign {
+    fn pipe(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+        stderr: bool,
+    ) -> Result<Stage, Box<dyn Error>> {
+        match &self.command {
+            Some(command) => self.run_with(shell, |shell| command.pipe(shell, stdin, stderr)),
+            None => {
+                let fds = piped_fds(shell, stdin);
+                fork_stage(shell, fds, stderr, |shell| self.run(shell))
+            }
+        }
+    }
+
+    fn run_last(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+    ) -> Result<String, Box<dyn Error>> {
+        match &self.command {
+            Some(command) => self.run_with(shell, |shell| command.run_last(shell, stdin)),
+            None => self.run(shell),
+        }
+    }
+}
+
+/// Makes an assignment. The values of scalars and subscripts are expanded
+/// without field splitting or pathname expansion, while the elements of an
+/// array are expanded like the words of a command.
+pub fn assign(shell: &mut Shell, assignment: &Assignment) -> Result<(), RuntimeError> {
+    let name = &assignment.name;
+    match (&assignment.index, &assignment.value) {
+        (Some(index), AssignmentValue::Scalar(value)) => {
+            let subscript = subscript(shell, name, index)?;
+            let mut value = value.resolve(shell)?;
+            shell.trace_assignment(
+                &format!("{}[{}]", name, subscript.to_string_lossy()),
+                assignment.append,
+                &[value.clone()],
+                false,
+            );
+            if assignment.append {
+                let mut current = shell
+                    .variables
+                    .element(name, &subscript)?
+                    .map(OsString::from)
+                    .unwrap_or_default();
+                current.push(value);
+                value = current;
+            }
+            shell.variables.set_element(name, &subscript, value)
+        }
+        (None, AssignmentValue::Scalar(value)) => {
+            let mut value = value.resolve(shell)?;
+            shell.trace_assignment(name, assignment.append, &[value.clone()], false);
+            if assignment.append {
+                let mut current = shell
+                    .variables
+                    .get(name)
+                    .map(OsString::from)
+                    .unwrap_or_default();
+                current.push(value);
+                value = current;
+            }
+            shell.variables.set(name, value);
+            Ok(())
+        }
+        (_, AssignmentValue::Array(elements)) => {
+            let mut values = Vec::new();
+            for (index, value) in elements {
+                match index {
+                    Some(index) => {
+                        let subscript = subscript(shell, name, index)?;
+                        values.push((Some(subscript), value.resolve(shell)?));
+                    }
+                    None => values.extend(value.expand(shell)?.into_iter().map(|v| (None, v))),
+                }
+            }
+            let traced: Vec<OsString> = values.iter().map(|(_, value)| value.clone()).collect();
+            shell.trace_assignment(name, assignment.append, &traced, true);
+            match assignment.append {
+                true => shell.variables.append_elements(name, values),
+                false => shell.variables.assign_elements(name, values),
+            }
+        }
+    }
+}
+
+/// Makes an assignment that comes before a command and only lasts while it
+/// runs. The variable is exported, so that the command sees it.
+pub fn assign_temporary(shell: &mut Shell, assignment: &Assignment) -> Result<(), RuntimeError> {
+    assign(shell, assignment)?;
+    if let Some(Value::String(value)) = shell.variables.value(&assignment.name) {
+        let value = value.clone();
+        shell.variables.export(&assignment.name, value);
+    }
+    Ok(())
+}
+
+/// Expands a subscript, which for an indexed array may name a variable that
+/// holds the index.
+fn subscript(shell: &Shell, name: &str, index: &Token) -> Result<OsString, RuntimeError> {
+    let subscript = index.resolve(shell)?;
+    match shell.variables.value(name) {
+        Some(Value::Associative(_)) => Ok(subscript),
+        _ => Ok(shell.index(&subscript)),
+    }
+}
@@ -0,0 +1,161 @@
+//! The syntax tree of a script.
+//!
+//! The compiler turns the tree into runnable commands separately from
+//! parsing, so the tree can also be used by tools such as `aish --dump-ast`,
+//! for which [`parse`](crate::parsing::parse) builds it for a whole script
+//! up front. Running a script is different: the whole script is lexed first,
+//! but [`ScriptParser`](crate::parsing::ScriptParser) parses and runs one
+//! complete command at a time, as other shells do, so that an alias defined
+//! by one command applies to the commands after it.
+
+use crate::redirect::Redirect;
+use crate::token::Token;
+use serde::{Deserialize, Serialize};
+
+/// A word of a command, made up of plain, quoted and variable parts.
+pub type Word = Token;
+
+/// And-or lists separated by `;`, `&` or newlines, run one after the other.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct List {
+    pub items: Vec<AndOr>,
+}
+
+/// Pipelines joined by `&&` and `||`, evaluated from left to right.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    /// Whether the list ends with `&`, so that it runs in the background
+    /// while the shell carries on
+    pub background: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+/// Commands connected by `|` or `|&`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    /// For each command but the last, whether it is followed by `|&`, which
+    /// pipes its stderr to the next command along with its stdout
+    pub pipe_stderr: Vec<bool>,
+    /// Whether the pipeline is preceded by `!`, which inverts its status
+    pub negated: bool,
+    /// How to report the time the pipeline took, if it is preceded by `time`
+    pub time: Option<TimeFormat>,
+}
+
+/// The report printed after a pipeline preceded by `time`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeFormat {
+    /// The format in `$TIMEFORMAT`, or the usual one if it is unset
+    Default,
+    /// The format POSIX gives for `time -p`
+    Posix,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    Simple(SimpleCommand),
+    Compound(CompoundCommand, Vec<Redirect>),
+    FunctionDefinition(FunctionDefinition),
+}
+
+/// Words and redirections, in any order, e.g. `ls -l > out`, after any
+/// assignments, e.g. `LANG=C sort`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimpleCommand {
+    /// Assignments before the first word, which only last while the command
+    /// runs if there is one
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+    /// Arrays given to `declare`, as in `declare -A m=([a]=1)`, which are
+    /// assigned once it has run. Their names are left in `words`.
+    pub declarations: Vec<Assignment>,
+}
+
+/// `name=value`, `name[subscript]=value`, `name=(a b c)` or the same with
+/// `+=`, which appends to the value instead.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Assignment {
+    pub name: String,
+    /// The subscript of the array element being assigned, if any
+    pub index: Option<Word>,
+    pub append: bool,
+    pub value: AssignmentValue,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AssignmentValue {
+    /// A word, which is expanded without field splitting or pathname
+    /// expansion
+    Scalar(Word),
+    /// The elements of an array, each with the subscript it was given, if
+    /// any, as in `([key]=value)`
+    Array(Vec<(Option<Word>, Word)>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CompoundCommand {
+    /// `{ list; }`
+    BraceGroup(List),
+    /// `( list )`
+    Subshell(List),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`
+    If {
+        branches: Vec<(List, List)>,
+        else_body: Option<List>,
+    },
+    /// `while list; do list; done`
+    While { condition: List, body: List },
+    /// `until list; do list; done`
+    Until { condition: List, body: List },
+    /// `for name [in word...]; do list; done`
+    For {
+        variable: String,
+        words: Option<Vec<Word>>,
+        body: List,
+    },
+    /// `[[ expression ]]`
+    Conditional(ConditionalExpression),
+}
+
+/// The expression of a `[[ ]]` command. Its words are expanded without
+/// field splitting or pathname expansion.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConditionalExpression {
+    /// A lone word, which is true when it is not empty
+    Word(Word),
+    /// An operator such as `-f` applied to one word
+    Unary(String, Word),
+    /// An operator such as `==` or `-lt` between two words
+    Binary(Word, String, Word),
+    Not(Box<ConditionalExpression>),
+    And(Box<ConditionalExpression>, Box<ConditionalExpression>),
+    Or(Box<ConditionalExpression>, Box<ConditionalExpression>),
+}
+
+/// `<(list)` or `>(list)`, a list run with its stdout or stdin connected to
+/// a pipe, which stands for the path of the other end of the pipe.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProcessSubstitution {
+    /// Whether the list reads what the command writes, as with `>(list)`,
+    /// rather than writing what it reads
+    pub output: bool,
+    pub list: List,
+    /// The substitution as it was written
+    pub text: String,
+}
+
+/// `name() compound-command`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub body: Box<Command>,
+}
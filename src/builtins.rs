@@ -1,83 +1,1918 @@
+use crate::command::{external_command, script_command, spawn_external, wait_child};
+use crate::conditional;
+use crate::directories::{abbreviate, is_stack_index, logical_path, stack_index};
 use crate::errors::RuntimeError;
+use crate::fields::{split_fields, DEFAULT_IFS};
+use crate::format;
+use crate::functions;
+use crate::io_context::IoContext;
+use crate::jobs;
+use crate::limits;
+use crate::options::{self, Options, ShellOption, SET_OPTIONS, SHOPT_OPTIONS};
+use crate::parsing::is_name;
+use crate::resolver::{self, Resolution};
+use crate::shell::Shell;
+use crate::token::lossy;
+use crate::variables::Value;
 
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::resource::{getrlimit, setrlimit};
+use nix::sys::signal::{self, Signal};
+use nix::sys::stat::{self, Mode};
+use nix::sys::termios::{
+    tcgetattr, tcsetattr, LocalFlags, SetArg, SpecialCharacterIndices, Termios,
+};
+use nix::unistd::{access, AccessFlags, Pid};
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::{AsFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-const BUILTINS: &[&str] = &["cd", "pwd", "exit", "echo", "export", "unset"];
+/// A command that runs inside the shell, given its arguments and the streams
+/// to read from and write to.
+pub type Builtin =
+    Rc<dyn Fn(&mut Shell, Vec<OsString>, &mut IoContext) -> Result<String, Box<dyn Error>>>;
+
+type BuiltinFn = fn(&mut Shell, Vec<OsString>, &mut IoContext) -> Result<String, Box<dyn Error>>;
+
+/// The builtins every shell starts with.
+pub const BUILTINS: &[(&str, BuiltinFn)] = &[
+    (":", colon),
+    ("cd", cd),
+    ("pwd", pwd),
+    ("pushd", pushd),
+    ("popd", popd),
+    ("dirs", dirs),
+    ("exit", exit),
+    ("exec", exec),
+    ("echo", echo),
+    ("printf", printf),
+    ("export", export),
+    ("unset", unset),
+    ("declare", declare),
+    ("alias", alias),
+    ("unalias", unalias),
+    ("source", source),
+    (".", source),
+    ("return", return_),
+    ("break", break_),
+    ("continue", continue_),
+    ("shift", shift),
+    ("type", type_),
+    ("which", which),
+    ("command", command),
+    ("builtin", builtin),
+    ("hash", hash),
+    ("history", history),
+    ("read", read),
+    ("test", test),
+    ("[", bracket),
+    ("wait", wait),
+    ("kill", kill),
+    ("trap", trap),
+    ("umask", umask),
+    ("ulimit", ulimit),
+    ("set", set),
+    ("shopt", shopt),
+];
 
 pub fn is_builtin(cmd: &str) -> bool {
-    BUILTINS.contains(&cmd)
+    BUILTINS.iter().any(|(name, _)| *name == cmd)
+}
+
+pub fn colon(
+    _shell: &mut Shell,
+    _args: Vec<OsString>,
+    _io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    Ok("".to_string())
+}
+
+/// Prints its arguments separated by spaces. `-n` leaves out the newline
+/// and `-e` expands backslash escapes, which `-E` turns off again.
+pub fn echo(
+    _shell: &mut Shell,
+    msg: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    // Only words made up entirely of known flags are options
+    let is_flags = |arg: &OsString| {
+        let arg = arg.as_bytes();
+        arg.len() > 1 && arg[0] == b'-' && arg[1..].iter().all(|c| b"neE".contains(c))
+    };
+    let flags = msg.iter().take_while(|arg| is_flags(arg)).count();
+    let (mut newline, mut escapes) = (true, false);
+    for &flag in msg[..flags].iter().flat_map(|arg| &arg.as_bytes()[1..]) {
+        match flag {
+            b'n' => newline = false,
+            b'e' => escapes = true,
+            _ => escapes = false,
+        }
+    }
+
+    let mut line = msg[flags..].join(" ".as_ref()).into_vec();
+    if escapes {
+        let (text, stop) = format::unescape(&line);
+        line = text;
+        newline &= !stop;
+    }
+    if newline {
+        line.push(b'\n');
+    }
+    io.stdout.write_all(&line)?;
+    Ok("".to_string())
+}
+
+/// Formats its arguments as `printf(3)` does, printing the result or with
+/// `-v NAME` assigning it to a variable.
+pub fn printf(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let mut args = args.into_iter().peekable();
+    let mut variable = None;
+    while let Some(arg) = args.next_if(|arg| arg.as_bytes().starts_with(b"-")) {
+        match arg.to_str() {
+            Some("--") => break,
+            Some("-v") => match args.next() {
+                Some(name) => variable = Some(name),
+                None => return Err("printf: -v: option requires an argument".into()),
+            },
+            _ => return Err(format!("printf: {}: invalid option", lossy(&arg)).into()),
+        }
+    }
+    let Some(format) = args.next() else {
+        return Err("printf: usage: printf [-v var] format [arguments]".into());
+    };
+
+    let args: Vec<OsString> = args.collect();
+    let formatted = format::printf(format.as_bytes(), &args);
+    for error in &formatted.errors {
+        writeln!(io.stderr, "printf: {}", error)?;
+    }
+    match variable {
+        Some(name) => shell
+            .variables
+            .set(name, OsString::from_vec(formatted.output)),
+        None => io.stdout.write_all(&formatted.output)?,
+    }
+    if !formatted.errors.is_empty() {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    Ok("".to_string())
+}
+
+/// Prints the working directory, as the logical path it was entered by, or
+/// with `-P` with symbolic links resolved.
+pub fn pwd(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, _) = split_flags(args);
+    let path = match physical(&flags, "pwd")? {
+        true => shell.cwd().canonicalize()?,
+        false => shell.cwd().to_path_buf(),
+    };
+    io.stdout.write_all(path.as_os_str().as_bytes())?;
+    writeln!(io.stdout)?;
+    Ok("".to_string())
+}
+
+/// Stops the shell with the given status, or that of the last command run.
+pub fn exit(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let code = match &args[..] {
+        [] => shell.last_status(),
+        [arg] => match lossy(arg).parse::<i64>() {
+            // Only the low byte of the status reaches the parent process
+            Ok(code) => (code & 0xff) as i32,
+            Err(_) => {
+                writeln!(io.stderr, "exit: {}: numeric argument required", lossy(arg))?;
+                2
+            }
+        },
+        _ => return Err("exit: too many arguments".into()),
+    };
+    Err(RuntimeError::Exit(code).into())
+}
+
+/// Replaces the shell with a command. Without one, the redirections of
+/// `exec` stay in place for the shell itself, so `exec 3>file` opens a file
+/// for the commands after it. A command that can't be run stops a shell
+/// that isn't interactive, with status 127 if it wasn't found and 126
+/// otherwise.
+pub fn exec(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (_, args) = split_flags(args);
+    if args.is_empty() {
+        shell.keep_fds();
+        return Ok("".to_string());
+    }
+    let name = lossy(&args[0]);
+    let fds = shell.fds();
+    let code = match external_command(shell, &args, &fds) {
+        Ok(mut command) => {
+            std::io::stdout().flush()?;
+            let mut error = fds.exec(&mut command);
+            if error.raw_os_error() == Some(Errno::ENOEXEC as i32) {
+                error = fds.exec(&mut script_command(shell, &args, &fds)?);
+            }
+            let reason = match error.raw_os_error() {
+                Some(errno) => Errno::from_raw(errno).desc().to_string(),
+                None => error.to_string(),
+            };
+            writeln!(io.stderr, "exec: {}: cannot execute: {}", name, reason)?;
+            match error.kind() {
+                std::io::ErrorKind::NotFound => 127,
+                _ => 126,
+            }
+        }
+        Err(e) if matches!(e.downcast_ref(), Some(RuntimeError::CommandNotFound(_))) => {
+            writeln!(io.stderr, "exec: {}: not found", name)?;
+            127
+        }
+        Err(e) => return Err(e),
+    };
+    match shell.options.interactive {
+        true => Err(RuntimeError::ExitStatus(code).into()),
+        false => Err(RuntimeError::Exit(code).into()),
+    }
+}
+
+/// Leaves the function or sourced file being run, with the given status.
+pub fn return_(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    _io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    if !shell.functions.in_call() {
+        return Err("return: can only return from a function or sourced file".into());
+    }
+    let code = match args.first() {
+        Some(arg) => lossy(arg)
+            .parse()
+            .map_err(|_| format!("return: {}: numeric argument required", lossy(arg)))?,
+        None => 0,
+    };
+    Err(RuntimeError::Return(code).into())
+}
+
+/// Leaves the loop it is run in, or with `break N` the `N` innermost loops.
+pub fn break_(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    loop_control(shell, "break", args, io, RuntimeError::Break)
+}
+
+/// Starts the next round of the loop it is run in, or with `continue N` of
+/// the `N`th loop out.
+pub fn continue_(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    loop_control(shell, "continue", args, io, RuntimeError::Continue)
 }
 
-pub fn builtin(cmd: String, args: Vec<String>) -> Result<String, Box<dyn Error>> {
-    match cmd.as_str() {
-        "cd" => cd(args),
-        "pwd" => pwd(),
-        "exit" => exit(),
-        "echo" => echo(args),
-        "export" => export(args),
-        "unset" => unset(args),
-        _ => Err(format!("{}: command not found", cmd).into()),
+/// Raises `error` for `break` or `continue` with the number of loops given,
+/// one by default and at most as many as the command is inside. Outside of
+/// a loop there is nothing to do.
+fn loop_control(
+    shell: &mut Shell,
+    name: &str,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+    error: fn(usize) -> RuntimeError,
+) -> Result<String, Box<dyn Error>> {
+    let count = match &args[..] {
+        [] => 1,
+        [arg] => match lossy(arg).parse::<i64>() {
+            Ok(count) if count > 0 => count as usize,
+            Ok(_) => {
+                writeln!(
+                    io.stderr,
+                    "{}: {}: loop count out of range",
+                    name,
+                    lossy(arg)
+                )?;
+                return Err(RuntimeError::ExitStatus(1).into());
+            }
+            Err(_) => {
+                writeln!(
+                    io.stderr,
+                    "{}: {}: numeric argument required",
+                    name,
+                    lossy(arg)
+                )?;
+                return Err(RuntimeError::ExitStatus(1).into());
+            }
+        },
+        _ => return Err(format!("{}: too many arguments", name).into()),
+    };
+    if shell.loop_depth() == 0 {
+        writeln!(
+            io.stderr,
+            "{}: only meaningful in a `for', `while', or `until' loop",
+            name
+        )?;
+        return Ok("".to_string());
     }
+    Err(error(count.min(shell.loop_depth())).into())
 }
 
-pub fn echo(msg: Vec<String>) -> Result<String, Box<dyn Error>> {
-    println!("{}", msg.join(" "));
+/// Drops the first positional parameter, or the first `N` with `shift N`.
+/// Fails, changing nothing, when there are fewer than `N`.
+pub fn shift(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let count = match args.first() {
+        None => 1,
+        Some(arg) => match lossy(arg).parse::<i64>() {
+            Ok(count) if count >= 0 => count as usize,
+            Ok(_) => {
+                writeln!(io.stderr, "shift: {}: shift count out of range", lossy(arg))?;
+                return Err(RuntimeError::ExitStatus(1).into());
+            }
+            Err(_) => {
+                writeln!(
+                    io.stderr,
+                    "shift: {}: numeric argument required",
+                    lossy(arg)
+                )?;
+                return Err(RuntimeError::ExitStatus(1).into());
+            }
+        },
+    };
+    let positional = shell.functions.positional_args();
+    if count > positional.len() {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    shell.functions.set_positional(positional[count..].to_vec());
     Ok("".to_string())
 }
 
-pub fn pwd() -> Result<String, Box<dyn Error>> {
-    let path = std::env::current_dir()?;
-    println!("{}", path.display());
+/// Changes the working directory, to `$HOME` by default or to `$OLDPWD`
+/// with `-`. Relative paths are looked for in the directories of `$CDPATH`
+/// first. `..` goes back up through symbolic links unless `-P` is given.
+pub fn cd(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_flags(args);
+    let physical = physical(&flags, "cd")?;
+    let (dir, mut print) = match &args[..] {
+        [] => match shell.variables.get("HOME") {
+            Some(home) => (home.to_os_string(), false),
+            None => return Err("cd: HOME not set".into()),
+        },
+        [dir] if dir == "-" => match shell.variables.get("OLDPWD") {
+            Some(old) => (old.to_os_string(), true),
+            None => return Err("cd: OLDPWD not set".into()),
+        },
+        [dir] => (dir.clone(), false),
+        _ => return Err("cd: too many arguments".into()),
+    };
+
+    let (dir, from_cdpath) = find_cd_target(shell, dir);
+    print |= from_cdpath;
+    change_dir(shell, &dir, physical)?;
+    if print {
+        io.stdout.write_all(shell.cwd().as_os_str().as_bytes())?;
+        writeln!(io.stdout)?;
+    }
     Ok("".to_string())
 }
 
-pub fn exit() -> Result<String, Box<dyn Error>> {
-    std::process::exit(0);
+/// Whether `-P` comes after any `-L` in the flags of `cd` or `pwd`.
+fn physical(flags: &[char], name: &str) -> Result<bool, Box<dyn Error>> {
+    let mut physical = false;
+    for flag in flags {
+        match flag {
+            'L' => physical = false,
+            'P' => physical = true,
+            flag => return Err(format!("{}: -{}: invalid option", name, flag).into()),
+        }
+    }
+    Ok(physical)
+}
+
+/// Looks for a relative directory in each directory of `$CDPATH`, returning
+/// where it was found and whether a directory other than `.` was used, in
+/// which case `cd` prints where it went.
+fn find_cd_target(shell: &Shell, dir: OsString) -> (OsString, bool) {
+    let relative = Path::new(&dir)
+        .components()
+        .next()
+        .is_some_and(|first| matches!(first, Component::Normal(_)));
+    let cdpath = shell.variables.get("CDPATH").filter(|_| relative);
+    for base in cdpath.into_iter().flat_map(std::env::split_paths) {
+        let candidate = base.join(&dir);
+        if logical_path(shell.cwd(), &candidate).is_dir() {
+            let printed = !base.as_os_str().is_empty() && base != Path::new(".");
+            return (candidate.into_os_string(), printed);
+        }
+    }
+    (dir, false)
+}
+
+/// Moves to `dir`, setting `$OLDPWD` to where the shell was.
+fn change_dir(shell: &mut Shell, dir: &OsStr, physical: bool) -> Result<(), Box<dyn Error>> {
+    let logical = logical_path(shell.cwd(), Path::new(dir));
+    let error = |e: Errno| format!("cd: {}: {}", lossy(dir), e.desc());
+    let meta = logical
+        .metadata()
+        .map_err(|e| error(Errno::from_raw(e.raw_os_error().unwrap_or(0))))?;
+    if !meta.is_dir() {
+        return Err(error(Errno::ENOTDIR).into());
+    }
+    access(&logical, AccessFlags::X_OK).map_err(error)?;
+
+    let path = match physical {
+        true => logical.canonicalize()?,
+        false => logical,
+    };
+    let old = shell.cwd().to_path_buf();
+    shell.set_cwd(path);
+    shell.variables.set("OLDPWD", old);
+    Ok(())
+}
+
+/// Changes to a directory and saves the one the shell was in on the
+/// directory stack, or with no arguments swaps the top two entries. `+N` and
+/// `-N` rotate the stack to bring that entry to the top, and `-n` adds a
+/// directory without changing to it.
+pub fn pushd(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_stack_args(args);
+    let no_change = flags.contains(&'n');
+    let cwd = shell.cwd().to_path_buf();
+    match &args[..] {
+        [] => {
+            let mut stack = shell.dir_stack.clone();
+            let Some(dir) = stack.remove(1) else {
+                return Err("pushd: no other directory".into());
+            };
+            change_dir(shell, dir.as_os_str(), false)?;
+            stack.push(cwd);
+            shell.dir_stack = stack;
+        }
+        [arg] if is_stack_index(&lossy(arg)) => {
+            let len = shell.dir_stack.entries(&cwd).len();
+            let Some(n) = stack_index(&lossy(arg), len) else {
+                return Err(
+                    format!("pushd: {}: directory stack index out of range", lossy(arg)).into(),
+                );
+            };
+            let mut stack = shell.dir_stack.clone();
+            if let Some(top) = stack.rotate(&cwd, n) {
+                change_dir(shell, top.as_os_str(), false)?;
+                shell.dir_stack = stack;
+            }
+        }
+        [dir] if no_change => shell.dir_stack.push(logical_path(&cwd, Path::new(dir))),
+        [dir] => {
+            let (dir, _) = find_cd_target(shell, dir.clone());
+            change_dir(shell, &dir, false)?;
+            shell.dir_stack.push(cwd);
+        }
+        _ => return Err("pushd: too many arguments".into()),
+    }
+    print_dirs(shell, io, &[])?;
+    Ok("".to_string())
 }
 
-pub fn cd(args: Vec<String>) -> Result<String, Box<dyn Error>> {
-    let home = std::env::var("HOME").unwrap();
-    let path = args.first().map_or(home.as_str(), |s| s);
-    let path = path.to_string();
+/// Removes the top entry of the directory stack and changes to the one
+/// below it, or with `+N` or `-N` removes that entry instead. `-n` removes
+/// the entry below the top without changing directory.
+pub fn popd(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_stack_args(args);
+    let len = shell.dir_stack.entries(shell.cwd()).len();
+    if len == 1 {
+        return Err("popd: directory stack empty".into());
+    }
+    let n = match &args[..] {
+        [] => 0,
+        [arg] => stack_index(&lossy(arg), len)
+            .ok_or_else(|| format!("popd: {}: directory stack index out of range", lossy(arg)))?,
+        _ => return Err("popd: too many arguments".into()),
+    };
 
-    // Check if path exists
-    if !std::path::Path::new(&path).exists() {
-        debug!("cd: no such file or directory: {}", path);
-        return Err(Box::new(RuntimeError::CommandFailed(
-            "cd: no such directory".into(),
-        )));
+    match n {
+        0 if flags.contains(&'n') => {
+            shell.dir_stack.remove(1);
+        }
+        0 => {
+            let mut stack = shell.dir_stack.clone();
+            if let Some(dir) = stack.remove(1) {
+                change_dir(shell, dir.as_os_str(), false)?;
+                shell.dir_stack = stack;
+            }
+        }
+        n => {
+            shell.dir_stack.remove(n);
+        }
     }
+    print_dirs(shell, io, &[])?;
+    Ok("".to_string())
+}
 
-    std::env::set_current_dir(path)?;
+/// Prints the directory stack, starting with the working directory, with
+/// `~` for the home directory unless `-l` is given. `-p` prints an entry
+/// per line and `-v` numbers them, `+N` and `-N` print a single entry and
+/// `-c` empties the stack.
+pub fn dirs(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_stack_args(args);
+    if flags.contains(&'c') {
+        shell.dir_stack.clear();
+        return Ok("".to_string());
+    }
+    match &args[..] {
+        [] => print_dirs(shell, io, &flags)?,
+        [arg] => {
+            let entries = shell.dir_stack.entries(shell.cwd());
+            let Some(n) = stack_index(&lossy(arg), entries.len()) else {
+                return Err(
+                    format!("dirs: {}: directory stack index out of range", lossy(arg)).into(),
+                );
+            };
+            let home = shell.variables.get("HOME").map(Path::new);
+            let entry = match flags.contains(&'l') {
+                true => entries[n].to_path_buf(),
+                false => abbreviate(entries[n], home),
+            };
+            io.stdout.write_all(entry.as_os_str().as_bytes())?;
+            writeln!(io.stdout)?;
+        }
+        _ => return Err("dirs: too many arguments".into()),
+    }
     Ok("".to_string())
 }
 
-pub fn export(args: Vec<String>) -> Result<String, Box<dyn Error>> {
+fn print_dirs(shell: &Shell, io: &mut IoContext, flags: &[char]) -> Result<(), Box<dyn Error>> {
+    let home = shell.variables.get("HOME").map(Path::new);
+    let entries = shell.dir_stack.entries(shell.cwd());
+    let mut output = Vec::new();
+    for (i, entry) in entries.into_iter().enumerate() {
+        let entry = match flags.contains(&'l') {
+            true => entry.to_path_buf(),
+            false => abbreviate(entry, home),
+        };
+        if flags.contains(&'v') {
+            output.extend_from_slice(format!("{:2}  ", i).as_bytes());
+        } else if i > 0 {
+            output.push(if flags.contains(&'p') { b'\n' } else { b' ' });
+        }
+        output.extend_from_slice(entry.as_os_str().as_bytes());
+        if flags.contains(&'v') {
+            output.push(b'\n');
+        }
+    }
+    if !flags.contains(&'v') {
+        output.push(b'\n');
+    }
+    io.stdout.write_all(&output)?;
+    Ok(())
+}
+
+/// Splits the flags of `pushd`, `popd` and `dirs` from their arguments,
+/// where `-N` is an entry of the stack rather than a flag.
+fn split_stack_args(args: Vec<OsString>) -> (Vec<char>, Vec<OsString>) {
+    let (flags, args): (Vec<_>, Vec<_>) = args.into_iter().partition(|arg| {
+        let arg = lossy(arg);
+        arg.len() > 1 && arg.starts_with('-') && !is_stack_index(&arg)
+    });
+    let flags = flags
+        .iter()
+        .flat_map(|arg| lossy(arg).chars().skip(1).collect::<Vec<_>>());
+    (flags.collect(), args)
+}
+
+pub fn export(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
     if args.is_empty() {
-        for (key, value) in std::env::vars() {
-            println!("{}=\"{}\"", key, value);
+        for (key, value) in shell.variables.exported() {
+            io.stdout.write_all(key.as_bytes())?;
+            io.stdout.write_all(b"=\"")?;
+            io.stdout.write_all(value.as_bytes())?;
+            io.stdout.write_all(b"\"\n")?;
         }
         Ok("".to_string())
     } else if args.len() > 1 {
-        return Err("export: too many arguments".into());
+        Err("export: too many arguments".into())
+    } else {
+        let arg = args[0].as_bytes();
+        let split = arg
+            .iter()
+            .position(|b| *b == b'=')
+            .ok_or("export: expected NAME=VALUE")?;
+        let key = OsString::from_vec(arg[..split].to_vec());
+        let value = &arg[split + 1..];
+
+        shell
+            .variables
+            .export(key, OsString::from_vec(value.to_vec()));
+        Ok("".to_string())
+    }
+}
+
+/// Unsets variables, or single elements of arrays given as `name[subscript]`.
+pub fn unset(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    _io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    for arg in args {
+        match split_subscript(&arg) {
+            Some((name, subscript)) => {
+                let subscript = match shell.variables.value(&name) {
+                    Some(Value::Associative(_)) => subscript,
+                    _ => shell.index(&subscript),
+                };
+                shell.variables.unset_element(name, &subscript)?;
+            }
+            None => shell.variables.unset(arg),
+        }
+    }
+    Ok("".to_string())
+}
+
+/// Splits `name[subscript]` into the name and the subscript.
+fn split_subscript(arg: &OsStr) -> Option<(String, OsString)> {
+    let arg = arg.as_bytes();
+    let open = arg.iter().position(|c| *c == b'[')?;
+    let subscript = arg[open + 1..].strip_suffix(b"]")?;
+    let name = std::str::from_utf8(&arg[..open]).ok()?;
+    Some((name.to_string(), OsString::from_vec(subscript.to_vec())))
+}
+
+/// Declares variables given as `name` or `name=value`. `-a` makes them
+/// indexed arrays, `-A` associative arrays and `-x` exports them. With `-p`,
+/// or without names, prints them as the `declare` commands that would make
+/// them again.
+pub fn declare(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_flags(args);
+    if let Some(flag) = flags.iter().find(|flag| !"aApx".contains(**flag)) {
+        writeln!(io.stderr, "declare: -{}: invalid option", flag)?;
+        writeln!(
+            io.stderr,
+            "declare: usage: declare [-aAx] [-p] [name[=value] ...]"
+        )?;
+        return Err(RuntimeError::ExitStatus(2).into());
+    }
+
+    let mut failed = false;
+    if flags.contains(&'p') || args.is_empty() {
+        let names: Vec<OsString> = match args.is_empty() {
+            true => shell
+                .variables
+                .all()
+                .into_iter()
+                .map(|(name, _)| name.to_os_string())
+                .collect(),
+            false => args,
+        };
+        for name in names {
+            match shell.variables.value(&name) {
+                Some(value) => {
+                    let kind = match value {
+                        Value::Indexed(_) => "-a",
+                        Value::Associative(_) => "-A",
+                        Value::String(_) if shell.variables.is_exported(&name) => "-x",
+                        Value::String(_) => "--",
+                    };
+                    let mut line = format!("declare {} ", kind).into_bytes();
+                    line.extend(name.as_bytes());
+                    line.push(b'=');
+                    line.extend(format_value(value, format::double_quote));
+                    line.push(b'\n');
+                    io.stdout.write_all(&line)?;
+                }
+                None => {
+                    writeln!(io.stderr, "declare: {}: not found", lossy(&name))?;
+                    failed = true;
+                }
+            }
+        }
+        return match failed {
+            true => Err(RuntimeError::ExitStatus(1).into()),
+            false => Ok("".to_string()),
+        };
+    }
+
+    for arg in args {
+        let (name, value) = match arg.as_bytes().iter().position(|c| *c == b'=') {
+            Some(i) => (
+                lossy(OsStr::from_bytes(&arg.as_bytes()[..i])),
+                Some(OsString::from_vec(arg.as_bytes()[i + 1..].to_vec())),
+            ),
+            None => (lossy(&arg), None),
+        };
+        let element = split_subscript(OsStr::new(&name));
+        let variable = element
+            .as_ref()
+            .map_or(name.clone(), |(name, _)| name.clone());
+        let variable = variable.as_str();
+        if !is_name(variable) {
+            writeln!(
+                io.stderr,
+                "declare: `{}': not a valid identifier",
+                lossy(&arg)
+            )?;
+            failed = true;
+            continue;
+        }
+
+        if flags.contains(&'A') {
+            shell.variables.declare_associative(variable);
+        } else if flags.contains(&'a') {
+            shell.variables.declare_indexed(variable);
+        }
+        match (value, element) {
+            (Some(value), Some((name, subscript))) => {
+                let subscript = match shell.variables.value(&name) {
+                    Some(Value::Associative(_)) => subscript,
+                    _ => shell.index(&subscript),
+                };
+                shell.variables.set_element(name, &subscript, value)?;
+            }
+            (Some(value), None) => shell.variables.set(variable, value),
+            (None, _) if shell.variables.value(variable).is_none() => {
+                shell.variables.set(variable, "")
+            }
+            (None, _) => {}
+        }
+        if flags.contains(&'x') {
+            if let Some(value) = shell.variables.get(variable).map(OsStr::to_os_string) {
+                shell.variables.export(variable, value);
+            }
+        }
+    }
+    match failed {
+        true => Err(RuntimeError::ExitStatus(1).into()),
+        false => Ok("".to_string()),
+    }
+}
+
+/// Defines aliases given as `name=value`, prints the aliases given by name,
+/// or prints every alias when given no arguments.
+pub fn alias(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    if args.is_empty() {
+        for (name, value) in &shell.aliases {
+            print_alias(io, name, value)?;
+        }
+        return Ok("".to_string());
+    }
+
+    let mut failed = false;
+    for arg in args {
+        let arg = arg.to_string_lossy();
+        match arg.split_once('=') {
+            Some(("", _)) => {
+                writeln!(io.stderr, "alias: {}: invalid alias name", arg)?;
+                failed = true;
+            }
+            Some((name, value)) => {
+                shell.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match shell.aliases.get(arg.as_ref()) {
+                Some(value) => print_alias(io, &arg, value)?,
+                None => {
+                    writeln!(io.stderr, "alias: {}: not found", arg)?;
+                    failed = true;
+                }
+            },
+        }
+    }
+    if failed {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    Ok("".to_string())
+}
+
+/// Prints an alias in a form that defines it again when run.
+fn print_alias(io: &mut IoContext, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        io.stdout,
+        "alias {}='{}'",
+        name,
+        value.replace('\'', "'\\''")
+    )?;
+    Ok(())
+}
+
+/// Removes the aliases given by name, or every alias with `-a`.
+pub fn unalias(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    if args.is_empty() {
+        return Err("unalias: usage: unalias [-a] name [name ...]".into());
+    }
+    if args[0] == "-a" {
+        shell.aliases.clear();
+        return Ok("".to_string());
+    }
+
+    let mut failed = false;
+    for arg in args {
+        let name = arg.to_string_lossy();
+        if shell.aliases.remove(name.as_ref()).is_none() {
+            writeln!(io.stderr, "unalias: {}: not found", name)?;
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    Ok("".to_string())
+}
+
+/// Runs a file in the current shell, with any arguments after it as the
+/// positional parameters while it runs.
+pub fn source(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    _io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let mut args = args.into_iter();
+    let name = args.next().ok_or("source: filename argument required")?;
+    let path = find_sourced(shell, &name)
+        .ok_or_else(|| format!("source: {}: file not found", lossy(&name)))?;
+    let input =
+        std::fs::read(&path).map_err(|e| format!("source: {}: {}", lossy(&name), e.kind()))?;
+
+    let args: Vec<OsString> = args.collect();
+    let args = if args.is_empty() {
+        shell.functions.positional_args()
     } else {
-        let (key, value) = args.first().unwrap().split_once("=").unwrap();
+        args
+    };
+    let result = functions::call_with(shell, args, |shell| {
+        match shell.run_script(&input, &lossy(&name))? {
+            0 => Ok("".to_string()),
+            status => Err(RuntimeError::ExitStatus(status).into()),
+        }
+    });
+
+    // An `exit` in the file stops the commands around the `source` too
+    if let Some(code) = shell.exit_status() {
+        return Err(RuntimeError::Exit(code).into());
+    }
+    result
+}
 
-        if value.eq("~") || value.starts_with("~/") {
-            let home = std::env::var("HOME").unwrap();
-            let value = value.replace("~", home.as_str());
-            std::env::set_var(key, value);
-        } else {
-            std::env::set_var(key, value);
+/// The file that `source` runs: a name without a slash is searched for on
+/// `PATH` and then in the working directory, like bash does.
+fn find_sourced(shell: &Shell, name: &OsStr) -> Option<PathBuf> {
+    if !name.as_bytes().contains(&b'/') {
+        let path = shell.variables.get("PATH").unwrap_or_default();
+        let found = std::env::split_paths(path)
+            .map(|dir| shell.path(dir).join(name))
+            .find(|file| file.is_file());
+        if found.is_some() {
+            return found;
         }
+    }
+    // Anything that can be read will do here, such as the pipe of `<(list)`
+    Some(shell.path(name)).filter(|file| file.exists() && !file.is_dir())
+}
 
-        Ok("".to_string())
+/// Describes what each name runs as: `-a` lists everything the name could
+/// refer to, `-t` prints only the kind and `-p` only the path of a program.
+pub fn type_(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, names) = split_flags(args);
+    let all = flags.contains(&'a');
+    let mut failed = false;
+    for name in names {
+        let found = match all {
+            true => resolver::resolve_all(shell, &name),
+            false => resolver::resolve(shell, &name).into_iter().collect(),
+        };
+        if found.is_empty() {
+            if !flags.contains(&'t') && !flags.contains(&'p') {
+                writeln!(io.stderr, "type: {}: not found", lossy(&name))?;
+            }
+            failed = true;
+        }
+        for resolution in found {
+            if flags.contains(&'t') {
+                writeln!(io.stdout, "{}", resolution.kind())?;
+            } else if flags.contains(&'p') {
+                if let Resolution::File(file) = resolution {
+                    writeln!(io.stdout, "{}", file.display())?;
+                }
+            } else {
+                writeln!(io.stdout, "{}", describe(&name, &resolution))?;
+            }
+        }
+    }
+    if failed {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    Ok("".to_string())
+}
+
+/// Prints the path of the program each name runs, or every program on
+/// `PATH` with that name with `-a`.
+pub fn which(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, names) = split_flags(args);
+    let mut failed = false;
+    for name in names {
+        let mut files = resolver::resolve_all(shell, &name)
+            .into_iter()
+            .filter_map(|resolution| match resolution {
+                Resolution::File(file) => Some(file),
+                _ => None,
+            })
+            .peekable();
+        failed |= files.peek().is_none();
+        for file in files.take(if flags.contains(&'a') { usize::MAX } else { 1 }) {
+            writeln!(io.stdout, "{}", file.display())?;
+        }
+    }
+    if failed {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    Ok("".to_string())
+}
+
+/// Runs a builtin or program, skipping any function of the same name. With
+/// `-v` prints how each name would run instead, or with `-V` describes it as
+/// `type` does.
+pub fn command(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, argv) = split_flags(args);
+    if flags.contains(&'v') || flags.contains(&'V') {
+        let mut failed = false;
+        for name in argv {
+            match resolver::resolve(shell, &name) {
+                Some(resolution) if flags.contains(&'V') => {
+                    writeln!(io.stdout, "{}", describe(&name, &resolution))?
+                }
+                Some(Resolution::Alias(value)) => print_alias(io, &lossy(&name), &value)?,
+                Some(Resolution::File(file)) => writeln!(io.stdout, "{}", file.display())?,
+                Some(_) => writeln!(io.stdout, "{}", lossy(&name))?,
+                None => failed = true,
+            }
+        }
+        if failed {
+            return Err(RuntimeError::ExitStatus(1).into());
+        }
+        return Ok("".to_string());
+    }
+
+    let Some(name) = argv.first() else {
+        return Ok("".to_string());
+    };
+    if let Some(builtin) = name.to_str().and_then(|name| shell.builtin(name)) {
+        return builtin(shell, argv[1..].to_vec(), io);
+    }
+    let fds = shell.fds();
+    let child = spawn_external(shell, &argv, &fds)?;
+    drop(fds);
+    wait_child(shell, child)
+}
+
+/// Runs a builtin, even when a function of the same name has replaced it.
+pub fn builtin(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let Some(name) = args.first() else {
+        return Ok("".to_string());
+    };
+    let builtin = name
+        .to_str()
+        .and_then(|name| shell.builtin(name))
+        .ok_or_else(|| format!("builtin: {}: not a shell builtin", lossy(name)))?;
+    builtin(shell, args[1..].to_vec(), io)
+}
+
+/// Lists the programs found on `PATH` so far, finds the given ones ahead of
+/// time, or with `-r` forgets them all.
+pub fn hash(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, names) = split_flags(args);
+    let (path, cwd) = resolver::search_path(shell);
+    shell.path_cache.set_path(&path);
+    if flags.contains(&'r') {
+        shell.path_cache.clear();
+    }
+    if names.is_empty() && flags.is_empty() {
+        if shell.path_cache.entries().next().is_none() {
+            writeln!(io.stdout, "hash: hash table empty")?;
+            return Ok("".to_string());
+        }
+        writeln!(io.stdout, "hits\tcommand")?;
+        for (_, file, hits) in shell.path_cache.entries() {
+            writeln!(io.stdout, "{:4}\t{}", hits, file.display())?;
+        }
+    }
+
+    let mut failed = false;
+    for name in names {
+        if shell.path_cache.remember(&name, &path, &cwd).is_none() {
+            writeln!(io.stderr, "hash: {}: not found", lossy(&name))?;
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    Ok("".to_string())
+}
+
+/// Lists the lines entered at the prompt, the last `N` of them, or those
+/// containing some text. `-c` clears the history and `-d N` deletes line
+/// `N`, or the `N`th line from the end when negative.
+pub fn history(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let args: Vec<String> = args.iter().map(lossy).collect();
+    let entries = shell.history.entries();
+    let listed: Vec<(usize, &String)> = match args.iter().map(String::as_str).collect::<Vec<_>>()[..]
+    {
+        [] => entries.iter().enumerate().collect(),
+        ["-c"] => {
+            shell.history.clear();
+            return Ok("".to_string());
+        }
+        ["-d", position] => {
+            let number = match position.parse::<i64>() {
+                Ok(n) if n < 0 => (entries.len() as i64 + 1 + n).max(0) as usize,
+                Ok(n) => n as usize,
+                Err(_) => 0,
+            };
+            if !shell.history.delete(number) {
+                return Err(format!("history: {}: history position out of range", position).into());
+            }
+            return Ok("".to_string());
+        }
+        [count] if count.parse::<usize>().is_ok() => {
+            let count = count.parse::<usize>()?;
+            let skip = entries.len().saturating_sub(count);
+            entries.iter().enumerate().skip(skip).collect()
+        }
+        [text] if !text.starts_with('-') => entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.contains(text))
+            .collect(),
+        _ => return Err("history: usage: history [-c] [-d offset] [n | text]".into()),
+    };
+    for (index, entry) in listed {
+        writeln!(io.stdout, "{:5}  {}", index + 1, entry)?;
+    }
+    Ok("".to_string())
+}
+
+/// Reads a line and splits it into fields at the characters in `IFS`, giving
+/// each name one field and the last name the rest of the line, or the whole
+/// line to `REPLY` when there are no names.
+///
+/// `-r` keeps backslashes, `-p` prompts on a terminal, `-s` turns off echo,
+/// `-t` gives up after a number of seconds, `-n` stops after a number of
+/// characters and `-N` reads exactly that many. `-d` ends the line at another
+/// character, `-a` reads the fields into an array and `-u` reads from
+/// another descriptor.
+pub fn read(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let options = ReadOptions::parse(args)?;
+    let fd = shell
+        .fds()
+        .dup(options.fd)?
+        .ok_or_else(|| format!("read: {}: invalid file descriptor", options.fd))?;
+    let input = File::from(fd);
+
+    let terminal = input.is_terminal();
+    if let (Some(prompt), true) = (&options.prompt, terminal) {
+        write!(io.stderr, "{}", prompt)?;
+        io.stderr.flush()?;
+    }
+    let _mode = match terminal && (options.silent || options.chars.is_some()) {
+        true => Some(TerminalMode::set(
+            &input,
+            options.silent,
+            options.chars.is_some(),
+        )?),
+        false => None,
+    };
+
+    let line = read_line(&input, &options)?;
+    let ifs = shell
+        .variables
+        .get("IFS")
+        .map_or(DEFAULT_IFS.to_vec(), |ifs| ifs.as_bytes().to_vec());
+    if let Some(array) = options.array {
+        let fields = split_fields(&line.bytes, &line.quoted, &ifs, None);
+        shell
+            .variables
+            .set_array(array, fields.into_iter().map(OsString::from_vec).collect());
+    } else if options.names.is_empty() {
+        shell.variables.set("REPLY", OsString::from_vec(line.bytes));
+    } else {
+        let mut fields =
+            split_fields(&line.bytes, &line.quoted, &ifs, Some(options.names.len())).into_iter();
+        for name in options.names {
+            let field = fields.next().unwrap_or_default();
+            shell.variables.set(name, OsString::from_vec(field));
+        }
+    }
+
+    match line.end {
+        ReadEnd::Done => Ok("".to_string()),
+        ReadEnd::Eof => Err(RuntimeError::ExitStatus(1).into()),
+        // The status of a command killed by SIGALRM, as bash uses
+        ReadEnd::TimedOut => Err(RuntimeError::ExitStatus(142).into()),
+    }
+}
+
+#[derive(Default)]
+struct ReadOptions {
+    raw: bool,
+    silent: bool,
+    prompt: Option<String>,
+    timeout: Option<Duration>,
+    chars: Option<usize>,
+    /// Whether exactly `chars` characters are read, ignoring the delimiter
+    exact: bool,
+    delimiter: u8,
+    array: Option<OsString>,
+    fd: RawFd,
+    names: Vec<OsString>,
+}
+
+impl ReadOptions {
+    fn parse(args: Vec<OsString>) -> Result<ReadOptions, Box<dyn Error>> {
+        let mut options = ReadOptions {
+            delimiter: b'\n',
+            ..Default::default()
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flags = match arg.as_bytes() {
+                b"--" => break,
+                [b'-', flags @ ..] if !flags.is_empty() => flags.to_vec(),
+                _ => {
+                    options.names.push(arg);
+                    break;
+                }
+            };
+            for (i, &flag) in flags.iter().enumerate() {
+                match flag {
+                    b'r' => options.raw = true,
+                    b's' => options.silent = true,
+                    _ if b"ptnNdau".contains(&flag) => {
+                        // The value is the rest of the argument or the next one
+                        let value = match &flags[i + 1..] {
+                            [] => args.next().ok_or_else(|| {
+                                format!("read: -{}: option requires an argument", flag as char)
+                            })?,
+                            rest => OsString::from_vec(rest.to_vec()),
+                        };
+                        options.set(flag, value)?;
+                        break;
+                    }
+                    _ => return Err(format!("read: -{}: invalid option", flag as char).into()),
+                }
+            }
+        }
+        options.names.extend(args);
+        Ok(options)
+    }
+
+    fn set(&mut self, flag: u8, value: OsString) -> Result<(), Box<dyn Error>> {
+        let text = lossy(&value);
+        let invalid = || format!("read: {}: invalid argument to -{}", text, flag as char);
+        match flag {
+            b'p' => self.prompt = Some(text.clone()),
+            b't' => {
+                let seconds: f64 = text.parse().map_err(|_| invalid())?;
+                self.timeout = Some(Duration::try_from_secs_f64(seconds).map_err(|_| invalid())?);
+            }
+            b'n' | b'N' => {
+                self.chars = Some(text.parse().map_err(|_| invalid())?);
+                self.exact = flag == b'N';
+            }
+            b'd' => self.delimiter = value.as_bytes().first().copied().unwrap_or(0),
+            b'a' => self.array = Some(value),
+            _ => self.fd = text.parse().map_err(|_| invalid())?,
+        }
+        Ok(())
+    }
+}
+
+/// Why `read` stopped reading.
+enum ReadEnd {
+    Done,
+    Eof,
+    TimedOut,
+}
+
+/// What `read` read, along with which bytes were escaped by a backslash.
+struct Line {
+    bytes: Vec<u8>,
+    quoted: Vec<bool>,
+    end: ReadEnd,
+}
+
+/// Reads up to the delimiter one byte at a time, so that whatever comes after
+/// it is left for the next command to read.
+fn read_line(input: &File, options: &ReadOptions) -> Result<Line, Box<dyn Error>> {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut line = Vec::new();
+    let mut quoted = Vec::new();
+    let mut chars = 0;
+    let end = loop {
+        if options.chars.is_some_and(|limit| chars >= limit) {
+            break ReadEnd::Done;
+        }
+        let mut c = match read_byte(input, deadline)? {
+            Ok(c) => c,
+            Err(end) => break end,
+        };
+        if c == options.delimiter && !options.exact {
+            break ReadEnd::Done;
+        }
+        let mut escaped = false;
+        if c == b'\\' && !options.raw {
+            c = match read_byte(input, deadline)? {
+                // A backslash before a newline continues the line
+                Ok(b'\n') => continue,
+                Ok(c) => c,
+                Err(end) => break end,
+            };
+            escaped = true;
+        }
+
+        line.push(c);
+        quoted.push(escaped);
+        // The rest of a multi-byte character is read along with its first
+        // byte, so that `-n` counts characters
+        for _ in 1..utf8_len(c) {
+            if let Ok(next) = read_byte(input, deadline)? {
+                line.push(next);
+                quoted.push(escaped);
+            }
+        }
+        chars += 1;
+    };
+    Ok(Line {
+        bytes: line,
+        quoted,
+        end,
+    })
+}
+
+/// Reads one byte, unless the input ends or the deadline passes first.
+fn read_byte(
+    mut input: &File,
+    deadline: Option<Instant>,
+) -> Result<Result<u8, ReadEnd>, Box<dyn Error>> {
+    if let Some(deadline) = deadline {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(input.as_fd(), PollFlags::POLLIN)];
+        let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+        if poll(&mut fds, timeout)? == 0 {
+            return Ok(Err(ReadEnd::TimedOut));
+        }
+    }
+    let mut byte = [0];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(Err(ReadEnd::Eof)),
+            Ok(_) => return Ok(Ok(byte[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The length of a UTF-8 character from its first byte.
+fn utf8_len(first: u8) -> usize {
+    match first {
+        0xf0..=0xf7 => 4,
+        0xe0..=0xef => 3,
+        0xc0..=0xdf => 2,
+        _ => 1,
+    }
+}
+
+/// Changes how a terminal handles input while `read` runs, restoring it when
+/// dropped.
+struct TerminalMode<'a> {
+    input: &'a File,
+    saved: Termios,
+}
+
+impl<'a> TerminalMode<'a> {
+    /// Turns off echo when `silent`, and line editing when `by_char`, so that
+    /// each character is read as soon as it is typed.
+    fn set(input: &'a File, silent: bool, by_char: bool) -> Result<Self, Box<dyn Error>> {
+        let saved = tcgetattr(input)?;
+        let mut termios = saved.clone();
+        if silent {
+            termios.local_flags.remove(LocalFlags::ECHO);
+        }
+        if by_char {
+            termios.local_flags.remove(LocalFlags::ICANON);
+            termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+            termios.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        }
+        tcsetattr(input, SetArg::TCSANOW, &termios)?;
+        Ok(TerminalMode { input, saved })
+    }
+}
+
+impl Drop for TerminalMode<'_> {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.input, SetArg::TCSANOW, &self.saved);
+    }
+}
+
+/// Waits for background jobs to finish, either all of them or those given by
+/// process ID or job spec, and returns the status of the last one given.
+pub fn wait(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (_, args) = split_flags(args);
+    if args.is_empty() {
+        for pid in shell.jobs.pids() {
+            shell.jobs.wait(pid);
+        }
+        return Ok("".to_string());
+    }
+
+    let mut status = 0;
+    for arg in args.iter().map(lossy) {
+        let pid = match job_pid(shell, &arg) {
+            Ok(pid) => pid,
+            Err(e) => {
+                writeln!(io.stderr, "wait: {}", e)?;
+                status = 127;
+                continue;
+            }
+        };
+        status = match shell.jobs.wait(pid) {
+            Some(status) => status,
+            None => {
+                writeln!(io.stderr, "wait: pid {} is not a child of this shell", pid)?;
+                127
+            }
+        };
+    }
+    match status {
+        0 => Ok("".to_string()),
+        status => Err(RuntimeError::ExitStatus(status).into()),
+    }
+}
+
+/// Sends a signal, `TERM` unless another is given with `-s`, `-n` or as
+/// `-SIGNAL`, to processes given by process ID, or to every process of a
+/// job given by job spec. `-l` lists the signal names, or converts between
+/// the names and numbers given.
+pub fn kill(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    const USAGE: &str = "kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]";
+    let args: Vec<String> = args.iter().map(lossy).collect();
+    let signal_arg = |name: &str| {
+        jobs::signal_number(name)
+            .ok_or_else(|| format!("kill: {}: invalid signal specification", name))
+    };
+
+    let (signal, targets) = match args.first().map(String::as_str) {
+        Some("-l" | "-L") => return list_signals(&args[1..], io),
+        Some("-s" | "-n") => match args.get(1) {
+            Some(name) => (signal_arg(name)?, &args[2..]),
+            None => return Err(USAGE.into()),
+        },
+        Some("--") => (Signal::SIGTERM as i32, &args[1..]),
+        Some(arg) if arg.len() > 1 && arg.starts_with('-') => (signal_arg(&arg[1..])?, &args[1..]),
+        _ => (Signal::SIGTERM as i32, &args[..]),
+    };
+    let targets = match targets.first().map(String::as_str) {
+        Some("--") => &targets[1..],
+        _ => targets,
+    };
+    if targets.is_empty() {
+        return Err(USAGE.into());
+    }
+
+    // Signal 0 sends nothing, but still checks that the process exists
+    let signal = Signal::try_from(signal).ok();
+    let mut failed = false;
+    for target in targets {
+        let result = job_pid(shell, target).and_then(|pid| {
+            // A job is signalled as the process group it runs in
+            let sent = match target.starts_with('%') {
+                true => signal::killpg(pid, signal),
+                false => signal::kill(pid, signal),
+            };
+            sent.map_err(|e| format!("({}) - {}", pid, e.desc()))
+        });
+        if let Err(e) = result {
+            writeln!(io.stderr, "kill: {}", e)?;
+            failed = true;
+        }
     }
+    if failed {
+        return Err(RuntimeError::ExitStatus(1).into());
+    }
+    Ok("".to_string())
 }
 
-pub fn unset(args: Vec<String>) -> Result<String, Box<dyn Error>> {
+/// Lists every signal name for `kill -l`, or the names of the signals given
+/// by number, including as the status of a process they killed, and the
+/// numbers of those given by name.
+fn list_signals(args: &[String], io: &mut IoContext) -> Result<String, Box<dyn Error>> {
+    if args.is_empty() {
+        let names: Vec<&str> = Signal::iterator().map(jobs::signal_name).collect();
+        writeln!(io.stdout, "{}", names.join(" "))?;
+        return Ok("".to_string());
+    }
     for arg in args {
-        std::env::remove_var(arg);
+        match arg.parse::<i32>() {
+            Ok(n) => {
+                let n = if n > 128 { n - 128 } else { n };
+                let signal = Signal::try_from(n)
+                    .map_err(|_| format!("kill: {}: invalid signal specification", arg))?;
+                writeln!(io.stdout, "{}", jobs::signal_name(signal))?;
+            }
+            Err(_) => {
+                let n = jobs::signal_number(arg)
+                    .ok_or_else(|| format!("kill: {}: invalid signal specification", arg))?;
+                writeln!(io.stdout, "{}", n)?;
+            }
+        }
+    }
+    Ok("".to_string())
+}
+
+/// The process a `kill` or `wait` operand refers to, given as a process ID
+/// or as a job spec such as `%1`.
+fn job_pid(shell: &Shell, arg: &str) -> Result<Pid, String> {
+    if arg.starts_with('%') {
+        return shell
+            .jobs
+            .find(arg)
+            .ok_or_else(|| format!("{}: no such job", arg));
+    }
+    arg.parse()
+        .map(Pid::from_raw)
+        .map_err(|_| format!("{}: arguments must be process or job IDs", arg))
+}
+
+/// Sets commands to run as the shell exits with `trap 'commands' EXIT`,
+/// removes them with `trap - EXIT`, or prints them. `EXIT`, also written
+/// `0`, is the only condition that can be trapped.
+pub fn trap(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_flags(args);
+    let args: Vec<String> = args.iter().map(lossy).collect();
+    let (action, conditions) = match &args[..] {
+        [] => (None, &args[..]),
+        _ if flags.contains(&'p') => (None, &args[..]),
+        // A lone condition is reset, as with `-`
+        [_] => (Some("-"), &args[..]),
+        [action, conditions @ ..] => (Some(action.as_str()), conditions),
+    };
+    for condition in conditions {
+        if !matches!(condition.as_str(), "EXIT" | "0") {
+            return Err(format!("trap: {}: only EXIT can be trapped", condition).into());
+        }
+    }
+
+    match action {
+        None => {
+            if let Some(action) = &shell.exit_trap {
+                writeln!(
+                    io.stdout,
+                    "trap -- '{}' EXIT",
+                    action.replace('\'', "'\\''")
+                )?;
+            }
+        }
+        Some("-") => shell.exit_trap = None,
+        Some(action) => shell.exit_trap = Some(action.to_string()),
+    }
+    Ok("".to_string())
+}
+
+/// Prints the file mode mask of the shell process in octal, or with `-S`
+/// as the permissions it lets through, or sets it from an octal number or
+/// symbolic clauses such as `g-w`.
+pub fn umask(
+    _shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_flags(args);
+    // The mask can only be read by setting it, so it is put straight back
+    let mask = stat::umask(Mode::empty());
+    stat::umask(mask);
+    let mask = mask.bits();
+
+    match args.first() {
+        None if flags.contains(&'S') => writeln!(io.stdout, "{}", limits::symbolic_mask(mask))?,
+        None => writeln!(io.stdout, "{:04o}", mask)?,
+        Some(arg) => {
+            let mask =
+                limits::parse_mask(&lossy(arg), mask).map_err(|e| format!("umask: {}", e))?;
+            stat::umask(Mode::from_bits_truncate(mask));
+        }
+    }
+    Ok("".to_string())
+}
+
+/// Prints or sets the resource limits of the shell process, which the
+/// commands it starts inherit. `-a` prints them all, and `-S` or `-H` pick
+/// the soft or hard limit, both being set when neither is given.
+pub fn ulimit(
+    _shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, args) = split_flags(args);
+    let mut selected = Vec::new();
+    for flag in flags.iter().filter(|flag| !"aSH".contains(**flag)) {
+        selected.push(
+            limits::find_limit(*flag)
+                .ok_or_else(|| format!("ulimit: -{}: invalid option", flag))?,
+        );
+    }
+    let all = flags.contains(&'a');
+    if all {
+        selected = limits::LIMITS.iter().collect();
+    } else if selected.is_empty() {
+        selected.extend(limits::find_limit('f'));
+    }
+    let (soft, hard) = (flags.contains(&'S'), flags.contains(&'H'));
+
+    match args.first().map(lossy) {
+        Some(_) if all => Err("ulimit: too many arguments".into()),
+        Some(value) => {
+            for limit in selected {
+                let (current_soft, current_hard) = getrlimit(limit.resource)?;
+                let new = match value.as_str() {
+                    "soft" => current_soft,
+                    "hard" => current_hard,
+                    _ => limit
+                        .parse(&value)
+                        .ok_or_else(|| format!("ulimit: {}: invalid number", value))?,
+                };
+                let (new_soft, new_hard) = match (soft, hard) {
+                    (true, false) => (new, current_hard),
+                    (false, true) => (current_soft, new),
+                    _ => (new, new),
+                };
+                setrlimit(limit.resource, new_soft, new_hard).map_err(|e| {
+                    format!(
+                        "ulimit: {}: cannot modify limit: {}",
+                        limit.description,
+                        e.desc()
+                    )
+                })?;
+            }
+            Ok("".to_string())
+        }
+        None => {
+            for limit in &selected {
+                let (current_soft, current_hard) = getrlimit(limit.resource)?;
+                let value = if hard && !soft {
+                    current_hard
+                } else {
+                    current_soft
+                };
+                match selected.len() {
+                    1 => writeln!(io.stdout, "{}", limit.display(value))?,
+                    _ => writeln!(io.stdout, "{}", limit.describe(value))?,
+                }
+            }
+            Ok("".to_string())
+        }
+    }
+}
+
+/// Turns options on with `-e` or `-o errexit` and off with `+e` or
+/// `+o errexit`, and replaces the positional parameters with the arguments
+/// after the options. `set -o` lists the options, `set +o` prints the
+/// commands that restore them, and `set` alone prints every variable.
+pub fn set(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    if args.is_empty() {
+        for (name, value) in shell.variables.all() {
+            let mut line = name.as_bytes().to_vec();
+            line.push(b'=');
+            line.extend(format_value(value, format::single_quote));
+            line.push(b'\n');
+            io.stdout.write_all(&line)?;
+        }
+        return Ok("".to_string());
+    }
+
+    let mut args = args.into_iter().peekable();
+    let mut positional = None;
+    while let Some(arg) = args.next() {
+        let text = lossy(&arg);
+        let on = text.starts_with('-');
+        match text.as_str() {
+            "--" => {
+                positional = Some(Vec::new());
+                break;
+            }
+            "-" => break,
+            _ if text.len() > 1 && (on || text.starts_with('+')) => {
+                for letter in text.chars().skip(1) {
+                    // `o` takes the name of an option from the next argument,
+                    // as in `set -euo pipefail`
+                    let option = match letter {
+                        'o' => match args.next() {
+                            Some(name) => {
+                                let name = lossy(name);
+                                let Some(option) = options::find_option(SET_OPTIONS, &name) else {
+                                    writeln!(io.stderr, "set: {}: invalid option name", name)?;
+                                    return Err(RuntimeError::ExitStatus(2).into());
+                                };
+                                option
+                            }
+                            None => {
+                                let restore = (!on).then_some(("set -o", "set +o"));
+                                print_options(io, SET_OPTIONS, &shell.options, restore)?;
+                                continue;
+                            }
+                        },
+                        letter => match options::find_letter(letter) {
+                            Some(option) => option,
+                            None => {
+                                writeln!(
+                                    io.stderr,
+                                    "set: {}{}: invalid option",
+                                    &text[..1],
+                                    letter
+                                )?;
+                                return Err(RuntimeError::ExitStatus(2).into());
+                            }
+                        },
+                    };
+                    option.set(&mut shell.options, on);
+                }
+            }
+            _ => {
+                positional = Some(vec![arg]);
+                break;
+            }
+        }
+    }
+    if let Some(mut positional) = positional {
+        positional.extend(args);
+        shell.functions.set_positional(positional);
     }
     Ok("".to_string())
 }
+
+/// A value as `set` and `declare -p` show it, with a string quoted by
+/// `quote` and an array written as `([0]="a" [1]="b")`.
+fn format_value(value: &Value, quote: fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+    if let Value::String(value) = value {
+        return quote(value.as_bytes());
+    }
+    let mut text = b"(".to_vec();
+    for (i, (key, element)) in value.keys().iter().zip(value.elements()).enumerate() {
+        if i > 0 {
+            text.push(b' ');
+        }
+        text.push(b'[');
+        match format::single_quote(key.as_bytes()) == key.as_bytes() {
+            true => text.extend(key.as_bytes()),
+            false => text.extend(format::double_quote(key.as_bytes())),
+        }
+        text.extend(b"]=");
+        text.extend(format::double_quote(element.as_bytes()));
+    }
+    text.push(b')');
+    text
+}
+
+/// Turns shell options on with `-s` and off with `-u`, or prints them, all
+/// of them when none are named. With `-o` the options are those of `set -o`.
+/// Fails when printing an option that is off, and `-q` only fails.
+pub fn shopt(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    let (flags, names) = split_flags(args);
+    let (list, restore) = match flags.contains(&'o') {
+        true => (SET_OPTIONS, ("set -o", "set +o")),
+        false => (SHOPT_OPTIONS, ("shopt -s", "shopt -u")),
+    };
+    let restore = flags.contains(&'p').then_some(restore);
+    let mut selected = Vec::new();
+    for name in &names {
+        let name = lossy(name);
+        match options::find_option(list, &name) {
+            Some(option) => selected.push(option),
+            None => {
+                writeln!(io.stderr, "shopt: {}: invalid shell option name", name)?;
+                return Err(RuntimeError::ExitStatus(1).into());
+            }
+        }
+    }
+
+    if flags.contains(&'s') || flags.contains(&'u') {
+        let on = flags.contains(&'s');
+        if selected.is_empty() {
+            // Lists the options that are already set that way
+            let options = list
+                .iter()
+                .filter(|option| option.get(&shell.options) == on);
+            return print_options(io, options, &shell.options, restore);
+        }
+        for option in selected {
+            option.set(&mut shell.options, on);
+        }
+        return Ok("".to_string());
+    }
+
+    if selected.is_empty() {
+        // Listing every option succeeds whichever are off
+        if !flags.contains(&'q') {
+            print_options(io, list, &shell.options, restore)?;
+        }
+        return Ok("".to_string());
+    }
+    if !flags.contains(&'q') {
+        print_options(io, selected.iter().copied(), &shell.options, restore)?;
+    }
+    match selected.iter().all(|option| option.get(&shell.options)) {
+        true => Ok("".to_string()),
+        false => Err(RuntimeError::ExitStatus(1).into()),
+    }
+}
+
+/// Lists options with whether they are on, or with `restore` as the
+/// commands that turn each on or off again, such as `set -o` and `set +o`.
+fn print_options<'a>(
+    io: &mut IoContext,
+    options: impl IntoIterator<Item = &'a ShellOption>,
+    values: &Options,
+    restore: Option<(&str, &str)>,
+) -> Result<String, Box<dyn Error>> {
+    for option in options {
+        let on = option.get(values);
+        match restore {
+            Some((set, unset)) => writeln!(
+                io.stdout,
+                "{} {}",
+                if on { set } else { unset },
+                option.name
+            )?,
+            None => writeln!(
+                io.stdout,
+                "{:<15}\t{}",
+                option.name,
+                if on { "on" } else { "off" }
+            )?,
+        }
+    }
+    Ok("".to_string())
+}
+
+/// Evaluates a conditional expression, failing when it is false and with
+/// status 2 when it is malformed.
+pub fn test(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    evaluate_test(shell, "test", &args, io)
+}
+
+/// `[`, which is `test` with a closing `]` as its last argument.
+pub fn bracket(
+    shell: &mut Shell,
+    mut args: Vec<OsString>,
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    if args.pop().is_none_or(|last| last != "]") {
+        writeln!(io.stderr, "[: missing `]'")?;
+        return Err(RuntimeError::ExitStatus(2).into());
+    }
+    evaluate_test(shell, "[", &args, io)
+}
+
+fn evaluate_test(
+    shell: &Shell,
+    name: &str,
+    args: &[OsString],
+    io: &mut IoContext,
+) -> Result<String, Box<dyn Error>> {
+    match conditional::test(shell, args) {
+        Ok(true) => Ok("".to_string()),
+        Ok(false) => Err(RuntimeError::ExitStatus(1).into()),
+        Err(message) => {
+            writeln!(io.stderr, "{}: {}", name, message)?;
+            Err(RuntimeError::ExitStatus(2).into())
+        }
+    }
+}
+
+/// How `type` describes what a name runs as.
+fn describe(name: &OsStr, resolution: &Resolution) -> String {
+    let name = lossy(name);
+    match resolution {
+        Resolution::Alias(value) => format!("{} is aliased to `{}'", name, value),
+        Resolution::Keyword => format!("{} is a shell keyword", name),
+        Resolution::Function => format!("{} is a function", name),
+        Resolution::Builtin => format!("{} is a shell builtin", name),
+        Resolution::Llm => format!("{} is an LLM prompt", name),
+        Resolution::File(file) => format!("{} is {}", name, file.display()),
+    }
+}
+
+/// Splits single-letter flags such as `-a` or `-ap` from the arguments after
+/// them, stopping at `--` or the first argument that is not a flag.
+fn split_flags(args: Vec<OsString>) -> (Vec<char>, Vec<OsString>) {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next_if(|arg| arg.len() > 1 && arg.as_bytes()[0] == b'-') {
+        if arg == "--" {
+            break;
+        }
+        flags.extend(lossy(&arg).chars().skip(1));
+    }
+    (flags, args.collect())
+}
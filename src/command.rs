@@ -1,49 +1,69 @@
-use crate::builtins::builtin;
+use crate::assignment::{assign_temporary, Assign};
 use crate::builtins::is_builtin;
-use crate::errors::SyntaxError;
+use crate::compound::{fork_stage, Compound, FunctionCall};
+use crate::errors::{exit_code, report_error, unwinds, RuntimeError, SyntaxError};
+use crate::io_context::{cloexec_pipe, Capture, FdTable, IoContext, Stream};
 use crate::openai_client::OpenAIClient;
-use crate::redirect;
-use crate::token::{join_tokens, Token};
-use crate::traits::{Runnable, ShellCommand};
-
-use nix::unistd::{dup2, fork, pipe, ForkResult};
+use crate::redirect::{apply_redirects, Redirect};
+use crate::resolver::find_program;
+use crate::shell::Shell;
+use crate::substitution::substitute;
+use crate::token::{lossy, Token};
+use crate::traits::{Runnable, ShellCommand, Stage, StageStatus};
+
+use nix::errno::Errno;
+use nix::unistd::Pid;
 use std::error::Error;
+use std::ffi::OsString;
 use std::fmt;
-use std::io::Read;
-use std::os::fd::AsRawFd;
-use std::process::{ChildStdout, Command, Stdio};
+use std::io::{Read, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdout, Command};
+use std::time::Instant;
 use tokio::runtime::Runtime;
 
 pub enum CommandType {
     Builtin(BuiltinCommand),
     External(ExternalCommand),
     Llm(LlmCommand),
-    InputRedirect(redirect::InputRedirect),
-    OutputRedirect(redirect::OutputRedirect),
-    OutputRedirectAppend(redirect::OutputRedirectAppend),
+    Compound(Compound),
+    Assign(Assign),
 }
 
 impl CommandType {
-    pub fn create(tokens: Vec<Token>) -> Result<CommandType, SyntaxError> {
+    pub fn create(
+        tokens: Vec<Token>,
+        redirects: Vec<Redirect>,
+    ) -> Result<CommandType, SyntaxError> {
         if tokens.is_empty() {
-            return Err(SyntaxError::ExpectedToken("".to_string()));
+            if redirects.is_empty() {
+                return Err(SyntaxError::ExpectedToken("".to_string()));
+            }
+            // Only redirections (e.g. `> file`), which are performed by a no-op
+            debug!("Detected redirect only command: {:?}", redirects);
+            return Ok(CommandType::Builtin(BuiltinCommand::new(
+                vec![Token::Plain(OsString::from(":"))],
+                redirects,
+            )?));
         }
 
         match &tokens[..] {
-            [Token::DoubleQuoted(prompt)] => {
+            [prompt @ Token::DoubleQuoted(_)] => {
                 debug!("Detected LLM command with tokens: {:?}", tokens);
-                Ok(CommandType::Llm(LlmCommand::new(
-                    join_tokens(prompt.to_vec()),
-                    OpenAIClient::new(None)?,
-                )))
+                Ok(CommandType::Llm(LlmCommand::new(prompt.clone(), redirects)))
             }
-            [Token::Plain(cmd), ..] if is_builtin(cmd) => {
+            [Token::Plain(cmd), ..] if cmd.to_str().is_some_and(is_builtin) => {
                 debug!("Detected builtin command: {:?}", tokens);
-                Ok(CommandType::Builtin(BuiltinCommand::new(tokens)?))
+                Ok(CommandType::Builtin(BuiltinCommand::new(
+                    tokens, redirects,
+                )?))
             }
             _ => {
                 debug!("Detected external command: {:?}", tokens);
-                Ok(CommandType::External(ExternalCommand::new(tokens)?))
+                Ok(CommandType::External(ExternalCommand::new(
+                    tokens, redirects,
+                )?))
             }
         }
     }
@@ -53,9 +73,8 @@ impl CommandType {
             CommandType::Builtin(cmd) => Box::new(cmd),
             CommandType::External(cmd) => Box::new(cmd),
             CommandType::Llm(cmd) => Box::new(cmd),
-            CommandType::InputRedirect(cmd) => Box::new(cmd),
-            CommandType::OutputRedirect(cmd) => Box::new(cmd),
-            CommandType::OutputRedirectAppend(cmd) => Box::new(cmd),
+            CommandType::Compound(cmd) => Box::new(cmd),
+            CommandType::Assign(cmd) => Box::new(cmd),
         }
     }
 
@@ -64,9 +83,8 @@ impl CommandType {
             CommandType::Builtin(cmd) => Box::new(cmd),
             CommandType::External(cmd) => Box::new(cmd),
             CommandType::Llm(cmd) => Box::new(cmd),
-            CommandType::InputRedirect(cmd) => Box::new(cmd),
-            CommandType::OutputRedirect(cmd) => Box::new(cmd),
-            CommandType::OutputRedirectAppend(cmd) => Box::new(cmd),
+            CommandType::Compound(cmd) => Box::new(cmd),
+            CommandType::Assign(cmd) => Box::new(cmd),
         }
     }
 }
@@ -77,161 +95,387 @@ impl fmt::Debug for CommandType {
             CommandType::Builtin(cmd) => write!(f, "{:?}", cmd),
             CommandType::External(cmd) => write!(f, "{:?}", cmd),
             CommandType::Llm(cmd) => write!(f, "{:?}", cmd),
-            CommandType::InputRedirect(cmd) => write!(f, "{:?}", cmd),
-            CommandType::OutputRedirect(cmd) => write!(f, "{:?}", cmd),
-            CommandType::OutputRedirectAppend(cmd) => write!(f, "{:?}", cmd),
+            CommandType::Compound(cmd) => write!(f, "{:?}", cmd),
+            CommandType::Assign(cmd) => write!(f, "{:?}", cmd),
         }
     }
 }
 
+/// Expands the words of a command into its argv.
+pub fn expand_words(tokens: &[Token], shell: &Shell) -> Result<Vec<OsString>, RuntimeError> {
+    let mut words = Vec::new();
+    for token in tokens {
+        words.extend(token.expand(shell)?);
+    }
+    Ok(words)
+}
+
+/// File descriptors for a command reading from the previous pipeline stage.
+pub fn piped_fds(shell: &Shell, stdin: Option<ChildStdout>) -> FdTable {
+    let mut fds = shell.fds();
+    if let Some(stdin) = stdin {
+        fds.set(0, OwnedFd::from(stdin).into());
+    }
+    fds
+}
+
+/// File descriptors for a pipeline stage that runs inside the shell process.
+///
+/// Its stdout, and its stderr with `stderr`, is captured so the stage can
+/// finish before the next stage starts reading.
+pub fn captured_fds(
+    shell: &Shell,
+    stdin: Option<ChildStdout>,
+    stderr: bool,
+) -> Result<(FdTable, Capture), Box<dyn Error>> {
+    let mut fds = piped_fds(shell, stdin);
+    let (capture, write_end) = Capture::new()?;
+    set_stdout(&mut fds, write_end.into(), stderr);
+    Ok((fds, capture))
+}
+
+/// Points stdout in `fds` at the stream the next stage of a pipeline reads,
+/// and stderr too for a stage followed by `|&`. This happens before the
+/// command's own redirections, which can still send either elsewhere.
+pub fn set_stdout(fds: &mut FdTable, stream: Stream, stderr: bool) {
+    if stderr {
+        fds.set(2, stream.clone());
+    }
+    fds.set(1, stream);
+}
+
+/// The stage of a pipeline that ran in the shell process with its stdout
+/// captured, once it has finished with `result`.
+pub fn finished_stage(
+    shell: &Shell,
+    result: Result<String, Box<dyn Error>>,
+    capture: Capture,
+) -> Result<Stage, Box<dyn Error>> {
+    let status = match result {
+        Ok(_) => 0,
+        Err(e) if unwinds(e.as_ref()) => return Err(e),
+        Err(e) => {
+            report_error(shell, e.as_ref());
+            exit_code(e.as_ref())
+        }
+    };
+    Ok(Stage {
+        stdout: Some(capture.into_stdout()?),
+        status: StageStatus::Finished(status),
+    })
+}
+
+/// The process for an external command, with the program found on `PATH`.
+pub fn external_command(
+    shell: &mut Shell,
+    argv: &[OsString],
+    fds: &FdTable,
+) -> Result<Command, Box<dyn Error>> {
+    let program = find_program(shell, &argv[0])
+        .ok_or_else(|| RuntimeError::CommandNotFound(lossy(&argv[0])))?;
+    let mut command = Command::new(program);
+    command.arg0(&argv[0]).args(&argv[1..]);
+    set_up_command(shell, command, fds)
+}
+
+/// The process that runs the program of an external command as a script
+/// with `/bin/sh`, as other shells do for a file the system cannot run
+/// itself because it is not a binary and does not start with `#!`.
+pub fn script_command(
+    shell: &mut Shell,
+    argv: &[OsString],
+    fds: &FdTable,
+) -> Result<Command, Box<dyn Error>> {
+    let program = find_program(shell, &argv[0])
+        .ok_or_else(|| RuntimeError::CommandNotFound(lossy(&argv[0])))?;
+    let mut command = Command::new("/bin/sh");
+    command.arg(program).args(&argv[1..]);
+    set_up_command(shell, command, fds)
+}
+
+fn set_up_command(
+    shell: &Shell,
+    mut command: Command,
+    fds: &FdTable,
+) -> Result<Command, Box<dyn Error>> {
+    command
+        .current_dir(shell.cwd())
+        .env_clear()
+        .envs(shell.variables.exported());
+    fds.apply(&mut command)?;
+    Ok(command)
+}
+
+/// Starts an external command. A program that cannot be run fails with its
+/// name and the reason, such as that it does not exist.
+pub fn spawn_external(
+    shell: &mut Shell,
+    argv: &[OsString],
+    fds: &FdTable,
+) -> Result<Child, Box<dyn Error>> {
+    let error = match external_command(shell, argv, fds)?.spawn() {
+        Ok(child) => return Ok(child),
+        Err(error) => error,
+    };
+    let errno = match error.raw_os_error().map(Errno::from_raw) {
+        Some(Errno::ENOEXEC) => return Ok(script_command(shell, argv, fds)?.spawn()?),
+        // The system refuses to run a directory as it would any other file
+        // without permission to run it
+        Some(Errno::EACCES) if find_program(shell, &argv[0]).is_some_and(|p| p.is_dir()) => {
+            Errno::EISDIR
+        }
+        Some(errno) => errno,
+        None => return Err(error.into()),
+    };
+    Err(RuntimeError::CannotRun(lossy(&argv[0]), errno).into())
+}
+
+/// Expands the words of a simple command, and then makes the assignments
+/// that come before it, whose values the words do not see.
+fn expand_command(
+    shell: &mut Shell,
+    tokens: &[Token],
+    fds: &mut FdTable,
+) -> Result<Vec<OsString>, Box<dyn Error>> {
+    // Taken first, so that process substitutions do not make them
+    let assignments = shell.take_prefix_assignments();
+    let tokens = substitute(shell, tokens, fds)?;
+    let argv = expand_words(&tokens, shell)?;
+    for assignment in &assignments {
+        assign_temporary(shell, assignment)?;
+    }
+    shell.trace(&argv);
+    Ok(argv)
+}
+
+/// The function to run, if the name of a command refers to one. Functions are
+/// looked up when the command runs, as they are defined by running their
+/// definition.
+fn function_call(shell: &Shell, argv: &[OsString], redirects: &[Redirect]) -> Option<Compound> {
+    let body = shell.functions.lookup(&argv[0])?;
+    let call = FunctionCall::new(body, argv[1..].to_vec());
+    Some(Compound::new(Box::new(call), redirects.to_vec()))
+}
+
+/// The directory to change to when a command is the name of one, as typed at
+/// the prompt with `shopt -s autocd`.
+fn autocd_directory(shell: &mut Shell, argv: &[OsString]) -> Option<OsString> {
+    if !shell.options.autocd || !shell.options.interactive || argv.len() != 1 {
+        return None;
+    }
+    if find_program(shell, &argv[0]).is_some() || !shell.path(&argv[0]).is_dir() {
+        return None;
+    }
+    Some(argv[0].clone())
+}
+
+/// Waits for a program the shell started to finish, turning its exit status
+/// into its result.
+pub fn wait_child(shell: &mut Shell, child: Child) -> Result<String, Box<dyn Error>> {
+    match shell.wait_for(Pid::from_raw(child.id() as i32)) {
+        0 => Ok("".to_string()),
+        code => Err(RuntimeError::ExitStatus(code).into()),
+    }
+}
+
 #[derive(Clone)]
 pub struct BuiltinCommand {
     tokens: Vec<Token>,
+    redirects: Vec<Redirect>,
 }
 
 impl BuiltinCommand {
-    pub fn new(tokens: Vec<Token>) -> Result<BuiltinCommand, SyntaxError> {
+    pub fn new(
+        tokens: Vec<Token>,
+        redirects: Vec<Redirect>,
+    ) -> Result<BuiltinCommand, SyntaxError> {
         if tokens.is_empty() {
             return Err(SyntaxError::InternalError);
         }
-        Ok(BuiltinCommand { tokens })
+        Ok(BuiltinCommand { tokens, redirects })
     }
 
-    pub fn run_builtin(&self) -> Result<String, Box<dyn Error>> {
-        let output = builtin(self.cmd(), self.args())?;
-        Ok(output)
+    fn run_with(&self, shell: &mut Shell, mut fds: FdTable) -> Result<String, Box<dyn Error>> {
+        let argv = expand_command(shell, &self.tokens, &mut fds)?;
+        self.run_argv(shell, argv, fds)
+    }
+
+    /// Runs the builtin once its words have been expanded into `argv`.
+    fn run_argv(
+        &self,
+        shell: &mut Shell,
+        mut argv: Vec<OsString>,
+        mut fds: FdTable,
+    ) -> Result<String, Box<dyn Error>> {
+        // A function takes the place of the builtin it is named after
+        if let Some(function) = function_call(shell, &argv, &self.redirects) {
+            return function.run_with(shell, fds);
+        }
+
+        apply_redirects(shell, &self.redirects, &mut fds)?;
+        let mut io = fds.io_context()?;
+        let cmd = lossy(argv.remove(0));
+        let builtin = shell
+            .builtin(&cmd)
+            .ok_or(RuntimeError::CommandNotFound(cmd))?;
+        // Commands that a builtin runs itself, as `source` does, use the same
+        // streams as the builtin
+        shell.with_fds(fds, |shell| builtin(shell, argv, &mut io))
     }
 }
 
 impl fmt::Debug for BuiltinCommand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "BuiltinCommand({:?})", self.tokens)
+        write!(f, "BuiltinCommand({:?}, {:?})", self.tokens, self.redirects)
     }
 }
 
 impl Runnable for BuiltinCommand {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
         debug!("Running builtin: {:?}", self);
-        let output = self.run_builtin()?;
-        Ok(output)
+        self.run_with(shell, shell.fds())
     }
 }
 
 impl ShellCommand for BuiltinCommand {
-    fn cmd(&self) -> String {
-        self.tokens[0].resolve()
+    fn pipe(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+        stderr: bool,
+    ) -> Result<Stage, Box<dyn Error>> {
+        let fds = piped_fds(shell, stdin);
+        fork_stage(shell, fds, stderr, |shell| self.run(shell))
     }
 
-    fn args(&self) -> Vec<String> {
-        self.tokens[1..].iter().map(|s| s.resolve()).collect()
-    }
-
-    fn pipe(&self, _stdin: Option<ChildStdout>) -> Result<Option<ChildStdout>, Box<dyn Error>> {
-        let (pipe_out_r, pipe_out_w) = pipe()?;
-        let (pipe_err_r, pipe_err_w) = pipe()?;
-
-        match unsafe { fork() }? {
-            ForkResult::Parent { child: _ } => {
-                drop(pipe_out_w);
-                drop(pipe_err_w);
-                Ok(Some(ChildStdout::from(pipe_out_r)))
-            }
-            ForkResult::Child => {
-                drop(pipe_out_r);
-                drop(pipe_err_r);
-                dup2(pipe_out_w.as_raw_fd(), 1)?;
-                dup2(pipe_err_w.as_raw_fd(), 2)?;
-                self.run_builtin()?;
-                std::process::exit(0);
-            }
-        }
+    fn run_last(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+    ) -> Result<String, Box<dyn Error>> {
+        self.run_with(shell, piped_fds(shell, stdin))
     }
 }
 
 #[derive(Clone)]
 pub struct ExternalCommand {
     tokens: Vec<Token>,
+    redirects: Vec<Redirect>,
 }
 
 impl ExternalCommand {
-    pub fn new(tokens: Vec<Token>) -> Result<ExternalCommand, SyntaxError> {
+    pub fn new(
+        tokens: Vec<Token>,
+        redirects: Vec<Redirect>,
+    ) -> Result<ExternalCommand, SyntaxError> {
         if tokens.is_empty() {
             return Err(SyntaxError::InternalError);
         }
-        Ok(ExternalCommand { tokens })
+        Ok(ExternalCommand { tokens, redirects })
+    }
+
+    /// The builtin to run instead, if one was registered with the shell after
+    /// the command was compiled.
+    fn builtin(&self, shell: &Shell, argv: &[OsString]) -> Option<BuiltinCommand> {
+        shell.builtin(argv[0].to_str()?)?;
+        BuiltinCommand::new(self.tokens.clone(), self.redirects.clone()).ok()
+    }
+
+    fn run_with(&self, shell: &mut Shell, mut fds: FdTable) -> Result<String, Box<dyn Error>> {
+        let argv = expand_command(shell, &self.tokens, &mut fds)?;
+        if let Some(function) = function_call(shell, &argv, &self.redirects) {
+            return function.run_with(shell, fds);
+        }
+        if let Some(builtin) = self.builtin(shell, &argv) {
+            return builtin.run_argv(shell, argv, fds);
+        }
+        if let Some(directory) = autocd_directory(shell, &argv) {
+            let cd = vec![Token::Plain(OsString::from("cd"))];
+            let argv = vec![OsString::from("cd"), directory];
+            return BuiltinCommand::new(cd, self.redirects.clone())?.run_argv(shell, argv, fds);
+        }
+
+        apply_redirects(shell, &self.redirects, &mut fds)?;
+        let child = spawn_external(shell, &argv, &fds)?;
+        drop(fds);
+        wait_child(shell, child)
     }
 }
 
 impl fmt::Debug for ExternalCommand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ExternalCommand({:?})", self.tokens)
+        write!(
+            f,
+            "ExternalCommand({:?}, {:?})",
+            self.tokens, self.redirects
+        )
     }
 }
 
 impl Runnable for ExternalCommand {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
         debug!("Running external: {:?}", self);
-        let mut child = match Command::new(self.cmd()).args(self.args()).spawn() {
-            Ok(child) => child,
-            Err(e) => return Err(e.into()),
-        };
-        match child.wait() {
-            Ok(code) => {
-                if code.success() {
-                    Ok("".to_string())
-                } else {
-                    Err(format!("{}", code).into())
-                }
-            }
-            Err(e) => Err(e.into()),
-        }
+        self.run_with(shell, shell.fds())
     }
 }
 
 impl ShellCommand for ExternalCommand {
-    fn cmd(&self) -> String {
-        self.tokens[0].resolve()
-    }
+    fn pipe(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+        stderr: bool,
+    ) -> Result<Stage, Box<dyn Error>> {
+        let mut fds = piped_fds(shell, stdin);
+        let argv = expand_command(shell, &self.tokens, &mut fds)?;
+        if let Some(function) = function_call(shell, &argv, &self.redirects) {
+            return fork_stage(shell, fds, stderr, |shell| function.run(shell));
+        }
+        if let Some(builtin) = self.builtin(shell, &argv) {
+            return fork_stage(shell, fds, stderr, |shell| {
+                let fds = shell.fds();
+                builtin.run_argv(shell, argv, fds)
+            });
+        }
 
-    fn args(&self) -> Vec<String> {
-        self.tokens[1..].iter().map(|s| s.resolve()).collect()
+        let (read_end, write_end) = cloexec_pipe()?;
+        set_stdout(&mut fds, write_end.into(), stderr);
+        apply_redirects(shell, &self.redirects, &mut fds)?;
+
+        // The command and table are dropped here, closing the shell's copies
+        // of the write end so the reader sees EOF when the child exits
+        let child = spawn_external(shell, &argv, &fds)?;
+        Ok(Stage {
+            stdout: Some(ChildStdout::from(read_end)),
+            status: StageStatus::Running(child),
+        })
     }
 
-    fn pipe(&self, stdin: Option<ChildStdout>) -> Result<Option<ChildStdout>, Box<dyn Error>> {
-        let input = match stdin {
-            Some(input) => Stdio::from(input),
-            None => Stdio::inherit(),
-        };
-        // Spawn the command
-        let mut child = match Command::new(self.cmd())
-            .args(self.args())
-            .stdin(input)
-            .stdout(Stdio::piped())
-            .spawn()
-        {
-            Ok(child) => child,
-            Err(e) => return Err(e.into()),
-        };
-
-        Ok(child.stdout.take())
+    fn run_last(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+    ) -> Result<String, Box<dyn Error>> {
+        self.run_with(shell, piped_fds(shell, stdin))
     }
 }
 
 #[derive(Clone)]
 pub struct LlmCommand {
-    prompt: String,
-    openai_client: OpenAIClient,
+    prompt: Token,
+    redirects: Vec<Redirect>,
 }
 
 impl LlmCommand {
-    pub fn new(prompt: String, openai_client: OpenAIClient) -> Self {
-        LlmCommand {
-            prompt,
-            openai_client,
-        }
+    pub fn new(prompt: Token, redirects: Vec<Redirect>) -> Self {
+        LlmCommand { prompt, redirects }
     }
 
-    pub async fn generate_response(&self, input: Option<String>) -> Result<String, Box<dyn Error>> {
+    pub async fn generate_response(
+        client: &OpenAIClient,
+        prompt: String,
+        input: Option<String>,
+    ) -> Result<String, Box<dyn Error>> {
         // TODO: do something more sophisticated
         let context = if let Some(input) = input {
             format!(
@@ -239,15 +483,15 @@ impl LlmCommand {
                 Example one: input = result of a git diff, prompt = 'Write a commit message in one line', output = 'COMMAND: git commit -m \"<LLM generated response>\"'.\
                 Example two: input = '', prompt = 'hello', output = 'Hello, how are you?' \
                 Input: {} Prompt: {}",
-                input, self.prompt
+                input, prompt
             )
         } else {
-            self.prompt.clone()
+            prompt
         };
 
         debug!("Context: {}", context);
 
-        let output = match self.openai_client.generate_text(&context, 100).await {
+        let output = match client.generate_text(&context, 100).await {
             Ok(text) => text,
             Err(e) => {
                 return Err(Box::new(e));
@@ -256,43 +500,79 @@ impl LlmCommand {
         debug!("Generated response: {}", output);
         Ok(output)
     }
+
+    /// Returns the response when stdout is the terminal, offering it as the
+    /// next command if it is one, and writes it to stdout otherwise.
+    fn run_with(&self, shell: &mut Shell, mut fds: FdTable) -> Result<String, Box<dyn Error>> {
+        apply_redirects(shell, &self.redirects, &mut fds)?;
+        let client = shell.llm_client()?;
+        let assignments = shell.take_prefix_assignments();
+        let prompt = lossy(self.prompt.resolve(shell)?);
+        for assignment in &assignments {
+            assign_temporary(shell, assignment)?;
+        }
+        let mut io = fds.io_context()?;
+        let input = Self::read_input(&fds, &mut io)?;
+        let inherits_stdout = matches!(fds.get(1), Some(Stream::Inherit(1)));
+        drop(fds);
+
+        let runtime = Runtime::new()?;
+        let start = Instant::now();
+        let output = runtime.block_on(Self::generate_response(&client, prompt, input));
+        shell.add_llm_time(start.elapsed());
+        let output = output?;
+        if inherits_stdout {
+            if let Some(command) = output.strip_prefix("COMMAND: ") {
+                shell.suggest(command.to_string());
+                return Ok("".to_string());
+            }
+            return Ok(output);
+        }
+        writeln!(io.stdout, "{}", output)?;
+        Ok("".to_string())
+    }
+
+    /// Reads the input for the prompt if stdin does not point at the terminal.
+    fn read_input(fds: &FdTable, io: &mut IoContext) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(Stream::Inherit(0)) = fds.get(0) {
+            return Ok(None);
+        }
+        let mut input = Vec::new();
+        io.stdin.read_to_end(&mut input)?;
+        Ok(Some(String::from_utf8_lossy(&input).into_owned()))
+    }
 }
 
 impl fmt::Debug for LlmCommand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "LlmCommand({:?})", self.prompt)
+        write!(f, "LlmCommand({:?}, {:?})", self.prompt, self.redirects)
     }
 }
 
 impl Runnable for LlmCommand {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
         debug!("Running llm: {:?}", self);
-        let runtime = Runtime::new().unwrap();
-        let output = runtime.block_on(self.generate_response(None))?;
-        Ok(output)
+        self.run_with(shell, shell.fds())
     }
 }
 
 impl ShellCommand for LlmCommand {
-    fn cmd(&self) -> String {
-        "llm".to_string()
-    }
-
-    fn args(&self) -> Vec<String> {
-        vec![self.prompt.clone()]
+    fn pipe(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+        stderr: bool,
+    ) -> Result<Stage, Box<dyn Error>> {
+        let (fds, capture) = captured_fds(shell, stdin, stderr)?;
+        let result = self.run_with(shell, fds);
+        finished_stage(shell, result, capture)
     }
 
-    fn pipe(&self, stdin: Option<ChildStdout>) -> Result<Option<ChildStdout>, Box<dyn Error>> {
-        let mut input = String::new();
-        if let Some(mut stdin) = stdin {
-            stdin.read_to_string(&mut input)?;
-        }
-        let runtime = Runtime::new()?;
-        let response = runtime.block_on(self.generate_response(Some(input)))?;
-        let mut child = Command::new("echo")
-            .arg(response)
-            .stdout(Stdio::piped())
-            .spawn()?;
-        Ok(child.stdout.take())
+    fn run_last(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+    ) -> Result<String, Box<dyn Error>> {
+        self.run_with(shell, piped_fds(shell, stdin))
     }
 }
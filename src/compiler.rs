@@ -0,0 +1,149 @@
+//! Turns the syntax tree of a script into commands that can be run.
+
+use crate::assignment::Assign;
+use crate::ast::{self, AndOr, CompoundCommand, Connector, List};
+use crate::command::CommandType;
+use crate::compound::{
+    Background, Compound, Conditional, DefineFunction, For, If, Subshell, While,
+};
+use crate::errors::SyntaxError;
+use crate::pipeline::{Negated, Pipeline};
+use crate::sequence::{AndSequence, OrSequence, Sequence};
+use crate::timing::Timed;
+use crate::traits::Runnable;
+
+pub fn compile_list(list: &List) -> Result<Sequence, SyntaxError> {
+    let commands = list
+        .items
+        .iter()
+        .map(compile_and_or)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Sequence::init(commands))
+}
+
+pub fn compile_and_or(and_or: &AndOr) -> Result<Box<dyn Runnable>, SyntaxError> {
+    // Runs of the same connector are grouped together, and each group becomes
+    // the first command of the next, so `a && b || c` is `(a && b) || c`
+    let mut commands = vec![compile_pipeline(&and_or.first)?];
+    let mut connector = None;
+    for (next, pipeline) in &and_or.rest {
+        if let Some(current) = connector.filter(|current| current != next) {
+            commands = vec![join(current, commands)];
+        }
+        connector = Some(*next);
+        commands.push(compile_pipeline(pipeline)?);
+    }
+
+    let command = match connector {
+        Some(connector) => join(connector, commands),
+        None => commands.remove(0),
+    };
+    match and_or.background {
+        true => Ok(Box::new(Background::new(command))),
+        false => Ok(command),
+    }
+}
+
+fn join(connector: Connector, commands: Vec<Box<dyn Runnable>>) -> Box<dyn Runnable> {
+    match connector {
+        Connector::And => Box::new(AndSequence::init(commands)),
+        Connector::Or => Box::new(OrSequence::init(commands)),
+    }
+}
+
+fn compile_pipeline(pipeline: &ast::Pipeline) -> Result<Box<dyn Runnable>, SyntaxError> {
+    let command: Box<dyn Runnable> = match &pipeline.commands[..] {
+        [command] => compile_command(command)?.unpack_run(),
+        commands => {
+            let commands = commands
+                .iter()
+                .map(|command| Ok(compile_command(command)?.unpack_cmd()))
+                .collect::<Result<Vec<_>, SyntaxError>>()?;
+            Box::new(Pipeline::init(commands, pipeline.pipe_stderr.clone()))
+        }
+    };
+    let command: Box<dyn Runnable> = match pipeline.negated {
+        true => Box::new(Negated::new(command)),
+        false => command,
+    };
+    match pipeline.time {
+        Some(format) => Ok(Box::new(Timed::new(command, format))),
+        None => Ok(command),
+    }
+}
+
+fn compile_command(command: &ast::Command) -> Result<CommandType, SyntaxError> {
+    match command {
+        ast::Command::Simple(simple)
+            if simple.assignments.is_empty() && simple.declarations.is_empty() =>
+        {
+            CommandType::create(simple.words.clone(), simple.redirects.clone())
+        }
+        ast::Command::Simple(simple) => {
+            let command = match simple.words.is_empty() && simple.redirects.is_empty() {
+                true => None,
+                false => Some(
+                    CommandType::create(simple.words.clone(), simple.redirects.clone())?
+                        .unpack_cmd(),
+                ),
+            };
+            Ok(CommandType::Assign(Assign::new(
+                simple.assignments.clone(),
+                command,
+                !simple.words.is_empty(),
+                simple.declarations.clone(),
+            )))
+        }
+        ast::Command::Compound(compound, redirects) => Ok(CommandType::Compound(Compound::new(
+            compile_compound(compound)?,
+            redirects.clone(),
+        ))),
+        ast::Command::FunctionDefinition(definition) => {
+            let body = compile_command(&definition.body)?.unpack_run();
+            let define = DefineFunction::new(definition.name.clone(), body);
+            Ok(CommandType::Compound(Compound::new(
+                Box::new(define),
+                Vec::new(),
+            )))
+        }
+    }
+}
+
+fn compile_compound(compound: &CompoundCommand) -> Result<Box<dyn Runnable>, SyntaxError> {
+    let runnable: Box<dyn Runnable> = match compound {
+        CompoundCommand::BraceGroup(list) => Box::new(compile_list(list)?),
+        CompoundCommand::Subshell(list) => Box::new(Subshell::new(compile_list(list)?)),
+        CompoundCommand::If {
+            branches,
+            else_body,
+        } => {
+            let branches = branches
+                .iter()
+                .map(|(condition, body)| Ok((compile_list(condition)?, compile_list(body)?)))
+                .collect::<Result<Vec<_>, SyntaxError>>()?;
+            let else_body = else_body.as_ref().map(compile_list).transpose()?;
+            Box::new(If::new(branches, else_body))
+        }
+        CompoundCommand::While { condition, body } => Box::new(While::new(
+            compile_list(condition)?,
+            compile_list(body)?,
+            false,
+        )),
+        CompoundCommand::Until { condition, body } => Box::new(While::new(
+            compile_list(condition)?,
+            compile_list(body)?,
+            true,
+        )),
+        CompoundCommand::For {
+            variable,
+            words,
+            body,
+        } => Box::new(For::new(
+            variable.clone(),
+            words.clone(),
+            compile_list(body)?,
+        )),
+        CompoundCommand::Conditional(expression) => Box::new(Conditional::new(expression.clone())),
+    };
+    Ok(runnable)
+}
@@ -0,0 +1,484 @@
+use crate::ast::ConditionalExpression;
+use crate::command::{expand_words, piped_fds, set_stdout};
+use crate::conditional;
+use crate::errors::{exit_code, report_error, RuntimeError};
+use crate::functions;
+use crate::io_context::{cloexec_pipe, FdTable};
+use crate::redirect::{apply_redirects, Redirect};
+use crate::sequence::{print_output, run_and_report, Sequence};
+use crate::shell::Shell;
+use crate::token::Token;
+use crate::traits::{Runnable, ShellCommand, Stage, StageStatus};
+
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::{fork, setpgid, ForkResult, Pid};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Write;
+use std::process::ChildStdout;
+
+/// A compound command or function call, with the redirections that apply to
+/// every command run inside it.
+#[derive(Clone)]
+pub struct Compound {
+    body: Box<dyn Runnable>,
+    redirects: Vec<Redirect>,
+}
+
+impl Compound {
+    pub fn new(body: Box<dyn Runnable>, redirects: Vec<Redirect>) -> Compound {
+        Compound { body, redirects }
+    }
+
+    /// Runs the body with the shell's standard streams pointed at `fds`, so
+    /// that the redirections apply to every command run inside it.
+    pub fn run_with(&self, shell: &mut Shell, mut fds: FdTable) -> Result<String, Box<dyn Error>> {
+        apply_redirects(shell, &self.redirects, &mut fds)?;
+        shell.with_fds(fds, |shell| {
+            let output = self.body.run(shell)?;
+            print_output(shell, &output);
+            Ok("".to_string())
+        })
+    }
+}
+
+impl fmt::Debug for Compound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Compound({:?}, {:?})", self.body, self.redirects)
+    }
+}
+
+impl Runnable for Compound {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        debug!("Running compound: {:?}", self);
+        if self.redirects.is_empty() {
+            return self.body.run(shell);
+        }
+        let fds = shell.fds();
+        self.run_with(shell, fds)
+    }
+}
+
+impl ShellCommand for Compound {
+    fn pipe(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+        stderr: bool,
+    ) -> Result<Stage, Box<dyn Error>> {
+        let fds = piped_fds(shell, stdin);
+        fork_stage(shell, fds, stderr, |shell| self.run(shell))
+    }
+
+    fn run_last(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+    ) -> Result<String, Box<dyn Error>> {
+        if stdin.is_none() {
+            return self.run(shell);
+        }
+        let fds = piped_fds(shell, stdin);
+        self.run_with(shell, fds)
+    }
+}
+
+/// Defines a function when run.
+#[derive(Clone)]
+pub struct DefineFunction {
+    name: String,
+    body: Box<dyn Runnable>,
+}
+
+impl DefineFunction {
+    pub fn new(name: String, body: Box<dyn Runnable>) -> DefineFunction {
+        DefineFunction { name, body }
+    }
+}
+
+impl fmt::Debug for DefineFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DefineFunction({}, {:?})", self.name, self.body)
+    }
+}
+
+impl Runnable for DefineFunction {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        shell.functions.define(&self.name, self.body.clone());
+        Ok("".to_string())
+    }
+}
+
+/// A call to a function with its arguments.
+#[derive(Clone)]
+pub struct FunctionCall {
+    body: Box<dyn Runnable>,
+    args: Vec<OsString>,
+}
+
+impl FunctionCall {
+    pub fn new(body: Box<dyn Runnable>, args: Vec<OsString>) -> FunctionCall {
+        FunctionCall { body, args }
+    }
+}
+
+impl fmt::Debug for FunctionCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FunctionCall({:?}, {:?})", self.body, self.args)
+    }
+}
+
+impl Runnable for FunctionCall {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        match functions::call(shell, self.body.as_ref(), self.args.clone()) {
+            // The status of the call is tested as that of any other command,
+            // even if the body stopped at a failing command of an `&&` list
+            Err(e) => match e.downcast_ref::<RuntimeError>() {
+                Some(RuntimeError::ShortCircuit(code)) => {
+                    Err(RuntimeError::ExitStatus(*code).into())
+                }
+                _ => Err(e),
+            },
+            result => result,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct If {
+    branches: Vec<(Sequence, Sequence)>,
+    else_body: Option<Sequence>,
+}
+
+impl If {
+    pub fn new(branches: Vec<(Sequence, Sequence)>, else_body: Option<Sequence>) -> If {
+        If {
+            branches,
+            else_body,
+        }
+    }
+}
+
+impl fmt::Debug for If {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "If({:?}, {:?})", self.branches, self.else_body)
+    }
+}
+
+impl Runnable for If {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        for (condition, body) in &self.branches {
+            if shell.ignoring_errexit(|shell| run_and_report(shell, condition))? {
+                return body.run(shell);
+            }
+        }
+        match &self.else_body {
+            Some(body) => body.run(shell),
+            None => Ok("".to_string()),
+        }
+    }
+}
+
+/// A `while` loop, or an `until` loop when `until` is set.
+#[derive(Clone)]
+pub struct While {
+    condition: Sequence,
+    body: Sequence,
+    until: bool,
+}
+
+impl While {
+    pub fn new(condition: Sequence, body: Sequence, until: bool) -> While {
+        While {
+            condition,
+            body,
+            until,
+        }
+    }
+}
+
+impl fmt::Debug for While {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if self.until { "Until" } else { "While" };
+        write!(f, "{}({:?}, {:?})", name, self.condition, self.body)
+    }
+}
+
+impl Runnable for While {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        shell.in_loop(|shell| {
+            loop {
+                match shell.ignoring_errexit(|shell| loop_step(shell, &self.condition))? {
+                    Step::Break => break,
+                    Step::Continue => continue,
+                    Step::Ran(succeeded) if succeeded == self.until => break,
+                    Step::Ran(_) => {}
+                }
+                if let Step::Break = loop_step(shell, &self.body)? {
+                    break;
+                }
+            }
+            Ok("".to_string())
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct For {
+    variable: String,
+    words: Option<Vec<Token>>,
+    body: Sequence,
+}
+
+impl For {
+    pub fn new(variable: String, words: Option<Vec<Token>>, body: Sequence) -> For {
+        For {
+            variable,
+            words,
+            body,
+        }
+    }
+}
+
+impl fmt::Debug for For {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "For({}, {:?}, {:?})",
+            self.variable, self.words, self.body
+        )
+    }
+}
+
+impl Runnable for For {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        let values = match &self.words {
+            Some(words) => expand_words(words, shell)?,
+            None => shell.functions.positional_args(),
+        };
+        shell.in_loop(|shell| {
+            for value in values {
+                shell.variables.set(&self.variable, value);
+                if let Step::Break = loop_step(shell, &self.body)? {
+                    break;
+                }
+            }
+            Ok("".to_string())
+        })
+    }
+}
+
+/// What a loop does after running its condition or body.
+enum Step {
+    /// Carry on, as the part that ran succeeded or failed
+    Ran(bool),
+    /// Start the next round, after `continue`
+    Continue,
+    /// Stop, after `break`
+    Break,
+}
+
+/// Runs the condition or body of a loop. A `break` or `continue` for this
+/// loop is turned into the step it asks for, and one for a loop further out
+/// is passed on with a count one lower.
+fn loop_step(shell: &mut Shell, part: &Sequence) -> Result<Step, Box<dyn Error>> {
+    let e = match run_and_report(shell, part) {
+        Ok(succeeded) => return Ok(Step::Ran(succeeded)),
+        Err(e) => e,
+    };
+    match e.downcast_ref::<RuntimeError>() {
+        Some(RuntimeError::Break(1)) => Ok(Step::Break),
+        Some(RuntimeError::Continue(1)) => Ok(Step::Continue),
+        Some(RuntimeError::Break(n)) => Err(RuntimeError::Break(n - 1).into()),
+        Some(RuntimeError::Continue(n)) => Err(RuntimeError::Continue(n - 1).into()),
+        _ => Err(e),
+    }
+}
+
+/// A `[[ ]]` command, which fails when its expression is false and fails
+/// with status 2 when it cannot be evaluated.
+#[derive(Clone)]
+pub struct Conditional {
+    expression: ConditionalExpression,
+}
+
+impl Conditional {
+    pub fn new(expression: ConditionalExpression) -> Conditional {
+        Conditional { expression }
+    }
+}
+
+impl fmt::Debug for Conditional {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Conditional({:?})", self.expression)
+    }
+}
+
+impl Runnable for Conditional {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        match conditional::evaluate(shell, &self.expression) {
+            Ok(true) => Ok("".to_string()),
+            Ok(false) => Err(RuntimeError::ExitStatus(1).into()),
+            Err(message) => {
+                writeln!(shell.stderr(), "[[: {}", message)?;
+                Err(RuntimeError::ExitStatus(2).into())
+            }
+        }
+    }
+}
+
+/// A list run in a copy of the shell, so that it cannot change the state of
+/// the shell itself.
+#[derive(Clone)]
+pub struct Subshell {
+    body: Sequence,
+}
+
+impl Subshell {
+    pub fn new(body: Sequence) -> Subshell {
+        Subshell { body }
+    }
+}
+
+impl fmt::Debug for Subshell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Subshell({:?})", self.body)
+    }
+}
+
+impl Runnable for Subshell {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        match fork_shell()? {
+            ForkResult::Child => run_in_child(shell, |shell| self.body.run(shell)),
+            ForkResult::Parent { child } => match shell.wait_for(child) {
+                0 => Ok("".to_string()),
+                code => Err(RuntimeError::ExitStatus(code).into()),
+            },
+        }
+    }
+}
+
+/// Forks a copy of the shell process, for a command to run in.
+///
+/// The copy goes on to run whatever the command does, just as the shell
+/// would: it allocates, takes the locks of stdout and the logger, reads
+/// `/dev/fd` and runs builtins and functions. That is only sound because the
+/// shell process has a single thread when it forks, as only the thread that
+/// forked carries on in the copy, and a lock held by any other would stay
+/// held there for good. The shell keeps to this by never leaving a thread
+/// of its own running once a command returns: captured output goes to a
+/// file rather than to a thread reading a pipe, and the runtime behind the
+/// LLM is shut down, joining its threads, after each request. A program that
+/// embeds [`Shell`] has to keep to it too.
+pub fn fork_shell() -> Result<ForkResult, Box<dyn Error>> {
+    // Anything still buffered would otherwise be written by both processes
+    std::io::stdout().flush()?;
+    // SAFETY: the process is single-threaded, as set out above, so nothing
+    // the copy goes on to use can be left locked by another thread
+    Ok(unsafe { fork() }?)
+}
+
+/// Starts `run` as a stage of a pipeline, with the streams in `fds`. As in
+/// other shells it runs in a forked copy of the shell, so that its output
+/// reaches the next stage as it is written, a command that never finishes,
+/// such as a `while` loop, can be stopped by the next stage exiting, and a
+/// builtin such as `cd` changes nothing in the shell itself.
+pub fn fork_stage(
+    shell: &mut Shell,
+    mut fds: FdTable,
+    stderr: bool,
+    run: impl FnOnce(&mut Shell) -> Result<String, Box<dyn Error>>,
+) -> Result<Stage, Box<dyn Error>> {
+    let (read_end, write_end) = cloexec_pipe()?;
+    set_stdout(&mut fds, write_end.into(), stderr);
+    match fork_shell()? {
+        ForkResult::Child => run_in_child_with(shell, fds, run),
+        ForkResult::Parent { child } => Ok(Stage {
+            stdout: Some(ChildStdout::from(read_end)),
+            status: StageStatus::Forked(child),
+        }),
+    }
+}
+
+/// Runs `run` in a forked copy of the shell and exits the copy with its
+/// status.
+pub fn run_in_child(
+    shell: &mut Shell,
+    run: impl FnOnce(&mut Shell) -> Result<String, Box<dyn Error>>,
+) -> ! {
+    // A `break` in the copy cannot leave a loop of the shell that forked it
+    let code = match shell.outside_loops(run) {
+        Ok(output) => {
+            print_output(shell, &output);
+            0
+        }
+        Err(e) => {
+            report_error(shell, e.as_ref());
+            exit_code(e.as_ref())
+        }
+    };
+    let _ = std::io::stdout().flush();
+    std::process::exit(code);
+}
+
+/// Runs `run` in a forked copy of the shell with the streams in `fds`,
+/// closing every other descriptor it has, and exits the copy with its
+/// status. A write to a pipe with no reader ends the copy, as it would a
+/// program.
+pub fn run_in_child_with(
+    shell: &mut Shell,
+    fds: FdTable,
+    run: impl FnOnce(&mut Shell) -> Result<String, Box<dyn Error>>,
+) -> ! {
+    // SAFETY: the default action of a signal is always sound
+    let _ = unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) };
+    // The log file is closed with the other descriptors, and its number may
+    // be given to one in `fds`
+    log::set_max_level(log::LevelFilter::Off);
+    match fds.install() {
+        Ok(fds) => shell.with_fds(fds, |shell| run_in_child(shell, run)),
+        Err(e) => {
+            report_error(shell, e.as_ref());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// An and-or list ended with `&`, run in a copy of the shell that the shell
+/// does not wait for. It is recorded as a job, for `wait` and `kill`.
+#[derive(Clone)]
+pub struct Background {
+    body: Box<dyn Runnable>,
+}
+
+impl Background {
+    pub fn new(body: Box<dyn Runnable>) -> Background {
+        Background { body }
+    }
+}
+
+impl fmt::Debug for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Background({:?})", self.body)
+    }
+}
+
+impl Runnable for Background {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        // The job gets a process group of its own, which both processes set
+        // so that it exists before either goes on. `kill %1` signals the
+        // whole group, reaching the commands the job started as well.
+        match fork_shell()? {
+            ForkResult::Child => {
+                let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+                run_in_child(shell, |shell| self.body.run(shell))
+            }
+            ForkResult::Parent { child } => {
+                let _ = setpgid(child, child);
+                shell.jobs.add(child);
+                Ok("".to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! Conditional expressions, as evaluated by the `test` and `[` builtins and
+//! the `[[ ]]` command.
+
+use crate::ast::ConditionalExpression;
+use crate::glob;
+use crate::parsing::is_name;
+use crate::shell::Shell;
+use crate::token::{lossy, Token};
+
+use nix::libc;
+use nix::unistd::{access, getegid, geteuid, AccessFlags};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::Metadata;
+use std::io::IsTerminal;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+/// Operators that test a single word, mostly the file it names.
+const UNARY_OPERATORS: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-p", "-r", "-s", "-t", "-u", "-v",
+    "-w", "-x", "-z", "-G", "-L", "-N", "-O", "-S",
+];
+
+/// How many variables an arithmetic operand of `[[ ]]` may go through before
+/// it counts as 0.
+const MAX_INDIRECTION: usize = 100;
+
+/// Operators that compare two words.
+const BINARY_OPERATORS: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+pub fn is_unary_operator(word: &str) -> bool {
+    UNARY_OPERATORS.contains(&word)
+}
+
+/// Whether `word` compares two words in `[[ ]]`, where `=~` matches a
+/// regular expression as well.
+pub fn is_binary_operator(word: &str) -> bool {
+    word == "=~" || BINARY_OPERATORS.contains(&word)
+}
+
+/// Evaluates the arguments of `test`, following the POSIX rules for up to
+/// four arguments, which settle whether a word such as `!` or `=` is an
+/// operator or a string to test. Longer expressions are parsed with `!`
+/// binding tighter than `-a`, which binds tighter than `-o`.
+pub fn test(shell: &Shell, args: &[OsString]) -> Result<bool, String> {
+    let words: Vec<String> = args.iter().map(lossy).collect();
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    match words[..] {
+        [] => Ok(false),
+        [word] => Ok(!word.is_empty()),
+        ["!", _] => Ok(args[1].is_empty()),
+        [op, _] if is_unary_operator(op) => unary(shell, op, &args[1]),
+        [op, _] => Err(format!("{}: unary operator expected", op)),
+        [_, op, _] if BINARY_OPERATORS.contains(&op) => binary(shell, &args[0], op, &args[2]),
+        [_, "-a", _] => Ok(!args[0].is_empty() && !args[2].is_empty()),
+        [_, "-o", _] => Ok(!args[0].is_empty() || !args[2].is_empty()),
+        ["!", ..] if args.len() <= 4 => test(shell, &args[1..]).map(|result| !result),
+        ["(", _, ")"] => Ok(!args[1].is_empty()),
+        ["(", _, _, ")"] => test(shell, &args[1..3]),
+        [_, _, _] => Err(format!("{}: binary operator expected", words[1])),
+        _ => {
+            let mut parser = TestParser {
+                shell,
+                args,
+                words: &words,
+                next: 0,
+            };
+            let result = parser.or()?;
+            match words.get(parser.next) {
+                Some(word) => Err(format!("{}: unexpected argument", word)),
+                None => Ok(result),
+            }
+        }
+    }
+}
+
+/// Parses and evaluates a `test` expression with more than four arguments.
+struct TestParser<'a> {
+    shell: &'a Shell,
+    args: &'a [OsString],
+    words: &'a [&'a str],
+    next: usize,
+}
+
+impl<'a> TestParser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.words.get(self.next + offset).copied()
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.peek(0) == Some("-o") {
+            self.next += 1;
+            // Both sides are parsed even when the first settles the result
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.peek(0) == Some("-a") {
+            self.next += 1;
+            result &= self.not()?;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.peek(0) == Some("!") && self.peek(1).is_some() {
+            self.next += 1;
+            return self.not().map(|result| !result);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        let i = self.next;
+        match (self.peek(0), self.peek(1), self.peek(2)) {
+            (None, ..) => Err("argument expected".to_string()),
+            (Some(_), Some(op), Some(_)) if BINARY_OPERATORS.contains(&op) => {
+                self.next += 3;
+                binary(self.shell, &self.args[i], op, &self.args[i + 2])
+            }
+            (Some(op), Some(_), _) if is_unary_operator(op) => {
+                self.next += 2;
+                unary(self.shell, op, &self.args[i + 1])
+            }
+            (Some("("), Some(_), _) => {
+                self.next += 1;
+                let result = self.or()?;
+                match self.peek(0) {
+                    Some(")") => {
+                        self.next += 1;
+                        Ok(result)
+                    }
+                    _ => Err("`)' expected".to_string()),
+                }
+            }
+            (Some(_), ..) => {
+                self.next += 1;
+                Ok(!self.args[i].is_empty())
+            }
+        }
+    }
+}
+
+/// Evaluates a `[[ ]]` expression. A match with `=~` sets `BASH_REMATCH` to
+/// the matched text followed by what each group matched.
+pub fn evaluate(shell: &mut Shell, expression: &ConditionalExpression) -> Result<bool, String> {
+    let resolve = |word: &Token, shell: &Shell| word.resolve(shell).map_err(|e| e.to_string());
+    match expression {
+        ConditionalExpression::Word(word) => Ok(!resolve(word, shell)?.is_empty()),
+        ConditionalExpression::Unary(op, word) => unary(shell, op, &resolve(word, shell)?),
+        ConditionalExpression::Binary(left, op, right) => {
+            let text = resolve(left, shell)?;
+            let pattern = |shell: &Shell| right.pattern(shell).map_err(|e| e.to_string());
+            match op.as_str() {
+                "=" | "==" => Ok(glob::matches(&pattern(shell)?, &text)),
+                "!=" => Ok(!glob::matches(&pattern(shell)?, &text)),
+                "=~" => match Regex::new(&regex_pattern(right, shell)?)?.captures(&text) {
+                    Some(captures) => {
+                        shell.variables.set_array("BASH_REMATCH", captures);
+                        Ok(true)
+                    }
+                    None => {
+                        shell.variables.set_array("BASH_REMATCH", Vec::new());
+                        Ok(false)
+                    }
+                },
+                "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                    let left = arithmetic(shell, &text);
+                    let right = arithmetic(shell, &resolve(right, shell)?);
+                    binary(shell, &left, op, &right)
+                }
+                op => binary(shell, &text, op, &resolve(right, shell)?),
+            }
+        }
+        ConditionalExpression::Not(inner) => evaluate(shell, inner).map(|result| !result),
+        ConditionalExpression::And(left, right) => {
+            Ok(evaluate(shell, left)? && evaluate(shell, right)?)
+        }
+        ConditionalExpression::Or(left, right) => {
+            Ok(evaluate(shell, left)? || evaluate(shell, right)?)
+        }
+    }
+}
+
+/// Applies an operator such as `-f` or `-z` to `operand`.
+fn unary(shell: &Shell, op: &str, operand: &OsStr) -> Result<bool, String> {
+    let result = match op {
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-v" => shell.variables.value(operand).is_some(),
+        "-t" => {
+            let fd = integer(operand)?;
+            let fd = i32::try_from(fd).map_err(|_| format!("{}: bad file descriptor", fd))?;
+            shell
+                .fds()
+                .dup(fd)
+                .ok()
+                .flatten()
+                .is_some_and(|fd| fd.is_terminal())
+        }
+        "-h" | "-L" => metadata(shell, operand, false).is_some_and(|m| m.file_type().is_symlink()),
+        op => {
+            let Some(meta) = metadata(shell, operand, true) else {
+                return Ok(false);
+            };
+            let file_type = meta.file_type();
+            match op {
+                "-a" | "-e" => true,
+                "-b" => file_type.is_block_device(),
+                "-c" => file_type.is_char_device(),
+                "-d" => file_type.is_dir(),
+                "-f" => file_type.is_file(),
+                "-p" => file_type.is_fifo(),
+                "-S" => file_type.is_socket(),
+                "-s" => meta.len() > 0,
+                "-g" => meta.mode() & 0o2000 != 0,
+                "-u" => meta.mode() & 0o4000 != 0,
+                "-k" => meta.mode() & 0o1000 != 0,
+                "-O" => meta.uid() == geteuid().as_raw(),
+                "-G" => meta.gid() == getegid().as_raw(),
+                "-N" => meta.mtime() > meta.atime(),
+                "-r" => access(&shell.path(operand), AccessFlags::R_OK).is_ok(),
+                "-w" => access(&shell.path(operand), AccessFlags::W_OK).is_ok(),
+                "-x" => access(&shell.path(operand), AccessFlags::X_OK).is_ok(),
+                op => return Err(format!("{}: unary operator expected", op)),
+            }
+        }
+    };
+    Ok(result)
+}
+
+/// Compares `left` and `right` with an operator such as `!=` or `-lt`.
+fn binary(shell: &Shell, left: &OsStr, op: &str, right: &OsStr) -> Result<bool, String> {
+    let result = match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left.as_bytes() < right.as_bytes(),
+        ">" => left.as_bytes() > right.as_bytes(),
+        "-eq" => integer(left)? == integer(right)?,
+        "-ne" => integer(left)? != integer(right)?,
+        "-lt" => integer(left)? < integer(right)?,
+        "-le" => integer(left)? <= integer(right)?,
+        "-gt" => integer(left)? > integer(right)?,
+        "-ge" => integer(left)? >= integer(right)?,
+        "-nt" | "-ot" | "-ef" => {
+            let (left, right) = (metadata(shell, left, true), metadata(shell, right, true));
+            let modified = |meta: &Metadata| (meta.mtime(), meta.mtime_nsec());
+            match (op, left, right) {
+                ("-nt", Some(left), Some(right)) => modified(&left) > modified(&right),
+                ("-ot", Some(left), Some(right)) => modified(&left) < modified(&right),
+                ("-ef", Some(left), Some(right)) => {
+                    (left.dev(), left.ino()) == (right.dev(), right.ino())
+                }
+                // A file that exists is newer than one that does not
+                ("-nt", left, None) => left.is_some(),
+                ("-ot", None, right) => right.is_some(),
+                _ => false,
+            }
+        }
+        op => return Err(format!("{}: binary operator expected", op)),
+    };
+    Ok(result)
+}
+
+/// The metadata of the file at `path`, following a symbolic link when
+/// `follow` is set. An empty path names no file.
+fn metadata(shell: &Shell, path: &OsStr, follow: bool) -> Option<Metadata> {
+    if path.is_empty() {
+        return None;
+    }
+    let path = shell.path(path);
+    match follow {
+        true => path.metadata().ok(),
+        false => path.symlink_metadata().ok(),
+    }
+}
+
+fn integer(word: &OsStr) -> Result<i64, String> {
+    let text = lossy(word);
+    text.trim()
+        .parse()
+        .map_err(|_| format!("{}: integer expression expected", text))
+}
+
+/// An operand of `-eq` and the like in `[[ ]]`, which is evaluated as an
+/// arithmetic expression: a variable name stands for its value, and an unset
+/// variable or any other word that is not a number is 0.
+fn arithmetic(shell: &Shell, word: &OsStr) -> OsString {
+    let mut text = lossy(word);
+    // A variable's value may name another variable, but not without end
+    for _ in 0..MAX_INDIRECTION {
+        let word = text.trim();
+        if let Ok(n) = word.parse::<i64>() {
+            return OsString::from(n.to_string());
+        }
+        match shell.variables.get(word) {
+            Some(value) if is_name(word) => text = lossy(value),
+            _ => break,
+        }
+    }
+    OsString::from("0")
+}
+
+/// The right-hand side of `=~` as an extended regular expression, with its
+/// quoted parts matching literally.
+fn regex_pattern(word: &Token, shell: &Shell) -> Result<OsString, String> {
+    match word {
+        Token::Plain(s) => Ok(s.clone()),
+        Token::Variable(_) => word.resolve(shell).map_err(|e| e.to_string()),
+        Token::Group(parts) => parts.iter().map(|t| regex_pattern(t, shell)).collect(),
+        _ => {
+            let mut escaped = Vec::new();
+            for c in word.resolve(shell).map_err(|e| e.to_string())?.into_vec() {
+                if b"\\^$.|?*+()[]{}".contains(&c) {
+                    escaped.push(b'\\');
+                }
+                escaped.push(c);
+            }
+            Ok(OsString::from_vec(escaped))
+        }
+    }
+}
+
+/// A POSIX extended regular expression, compiled by the C library the way
+/// other shells match `=~`.
+struct Regex {
+    compiled: libc::regex_t,
+    groups: usize,
+}
+
+impl Regex {
+    fn new(pattern: &OsStr) -> Result<Regex, String> {
+        let invalid = || format!("{}: invalid regular expression", lossy(pattern));
+        let source = CString::new(pattern.as_bytes()).map_err(|_| invalid())?;
+        let mut compiled = MaybeUninit::<libc::regex_t>::uninit();
+        // SAFETY: `regcomp` initializes `compiled` when it succeeds, and
+        // `source` is a NUL-terminated string
+        let code =
+            unsafe { libc::regcomp(compiled.as_mut_ptr(), source.as_ptr(), libc::REG_EXTENDED) };
+        if code != 0 {
+            return Err(invalid());
+        }
+        Ok(Regex {
+            // SAFETY: compiling succeeded
+            compiled: unsafe { compiled.assume_init() },
+            groups: count_groups(pattern.as_bytes()),
+        })
+    }
+
+    /// The text the expression matched in `text`, followed by what each
+    /// group matched, which is empty for a group that took no part.
+    fn captures(&self, text: &OsStr) -> Option<Vec<OsString>> {
+        let subject = CString::new(text.as_bytes()).ok()?;
+        let empty = libc::regmatch_t {
+            rm_so: -1,
+            rm_eo: -1,
+        };
+        let mut matches = vec![empty; self.groups + 1];
+        // SAFETY: `matches` has room for the number of entries passed
+        let code = unsafe {
+            libc::regexec(
+                &self.compiled,
+                subject.as_ptr(),
+                matches.len(),
+                matches.as_mut_ptr(),
+                0,
+            )
+        };
+        if code != 0 {
+            return None;
+        }
+        let bytes = text.as_bytes();
+        let captures = matches
+            .iter()
+            .map(
+                |m| match (usize::try_from(m.rm_so), usize::try_from(m.rm_eo)) {
+                    (Ok(start), Ok(end)) => OsStr::from_bytes(&bytes[start..end]).to_os_string(),
+                    _ => OsString::new(),
+                },
+            )
+            .collect();
+        Some(captures)
+    }
+}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        // SAFETY: `compiled` was initialized by `regcomp` and is freed once
+        unsafe { libc::regfree(&mut self.compiled) };
+    }
+}
+
+/// How many groups an extended regular expression has, which is the number
+/// of `(` not escaped with a backslash or inside a bracket expression.
+fn count_groups(pattern: &[u8]) -> usize {
+    let mut groups = 0;
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'\\' => i += 1,
+            b'(' => groups += 1,
+            b'[' => {
+                // A `]` straight after the opening bracket is part of the set
+                i += 1;
+                if pattern.get(i) == Some(&b'^') {
+                    i += 1;
+                }
+                if pattern.get(i) == Some(&b']') {
+                    i += 1;
+                }
+                while i < pattern.len() && pattern[i] != b']' {
+                    i += 1;
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    groups
+}
@@ -0,0 +1,105 @@
+//! The directory stack of `pushd`, `popd` and `dirs`, and the logical paths
+//! `cd` moves between.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Directories saved by `pushd`. The working directory is not stored here,
+/// but is always the top of the stack as `dirs` prints it, entry 0.
+#[derive(Clone, Debug, Default)]
+pub struct DirStack {
+    /// Entries 1 and up
+    dirs: Vec<PathBuf>,
+}
+
+impl DirStack {
+    pub fn new() -> DirStack {
+        DirStack::default()
+    }
+
+    /// Every entry of the stack, starting with the working directory.
+    pub fn entries<'a>(&'a self, cwd: &'a Path) -> Vec<&'a Path> {
+        std::iter::once(cwd)
+            .chain(self.dirs.iter().map(PathBuf::as_path))
+            .collect()
+    }
+
+    /// Saves `dir` as entry 1, just below the working directory.
+    pub fn push(&mut self, dir: PathBuf) {
+        self.dirs.insert(0, dir);
+    }
+
+    /// Removes entry `n`, which must not be the working directory.
+    pub fn remove(&mut self, n: usize) -> Option<PathBuf> {
+        let index = n.checked_sub(1).filter(|index| *index < self.dirs.len())?;
+        Some(self.dirs.remove(index))
+    }
+
+    /// Rotates the stack so that entry `n` is at the top, returning it as the
+    /// directory to change to. The working directory moves down with the
+    /// entries above `n`.
+    pub fn rotate(&mut self, cwd: &Path, n: usize) -> Option<PathBuf> {
+        let mut entries: Vec<PathBuf> = self
+            .entries(cwd)
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect();
+        if n >= entries.len() {
+            return None;
+        }
+        entries.rotate_left(n);
+        let top = entries.remove(0);
+        self.dirs = entries;
+        Some(top)
+    }
+
+    pub fn clear(&mut self) {
+        self.dirs.clear();
+    }
+}
+
+/// The entry that `+N`, counting from the top of the stack, or `-N`,
+/// counting from the bottom, refers to in a stack of `len` entries.
+pub fn stack_index(arg: &str, len: usize) -> Option<usize> {
+    let (from_top, n) = match arg.as_bytes().first() {
+        Some(b'+') => (true, &arg[1..]),
+        Some(b'-') => (false, &arg[1..]),
+        _ => return None,
+    };
+    let n: usize = n.parse().ok()?;
+    match from_top {
+        true => Some(n).filter(|n| *n < len),
+        false => len.checked_sub(n + 1),
+    }
+}
+
+/// Whether `arg` is written as a stack entry, such as `+1` or `-0`.
+pub fn is_stack_index(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with(['+', '-']) && arg[1..].bytes().all(|c| c.is_ascii_digit())
+}
+
+/// Joins `dir` onto `cwd` and removes `.` and `..` from the result without
+/// looking at the file system, so that `..` leaves a symbolic link to a
+/// directory the way it was entered rather than going to the parent of
+/// where the link points.
+pub fn logical_path(cwd: &Path, dir: &Path) -> PathBuf {
+    let mut path = PathBuf::from("/");
+    for component in cwd.join(dir).components() {
+        match component {
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::Normal(name) => path.push(name),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+        }
+    }
+    path
+}
+
+/// The path as `dirs` prints it, with the home directory written as `~`.
+pub fn abbreviate(path: &Path, home: Option<&Path>) -> PathBuf {
+    match home.and_then(|home| path.strip_prefix(home).ok()) {
+        Some(rest) if rest.as_os_str().is_empty() => PathBuf::from("~"),
+        Some(rest) => Path::new("~").join(rest),
+        None => path.to_path_buf(),
+    }
+}
@@ -1,3 +1,8 @@
+use crate::parsing::Span;
+use crate::shell::Shell;
+use nix::errno::Errno;
+use std::error;
+use std::io::Write;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -8,16 +13,180 @@ pub enum SyntaxError {
     UnexpectedToken(String),
     #[error("Expected token: {0}")]
     ExpectedToken(String),
+    #[error("Unexpected end of input, expected {0}")]
+    UnexpectedEof(String),
     #[error("Internal Error")]
     InternalError,
     #[error("Invalid OPENAI_API_KEY: {0}")]
     InvalidOpenAIKey(String),
 }
 
+impl SyntaxError {
+    /// Whether the input is valid so far but ends too early, so that more
+    /// lines can complete it.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            SyntaxError::UnclosedQuote | SyntaxError::UnexpectedEof(_)
+        )
+    }
+}
+
+/// A syntax error together with the part of the input it was found in.
+#[derive(Error, Debug)]
+#[error("{error} at {}", span.start)]
+pub struct ParseError {
+    pub error: SyntaxError,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(error: SyntaxError, span: Span) -> ParseError {
+        ParseError { error, span }
+    }
+
+    /// Renders the error the way a compiler would: the message, the file name
+    /// with the line and column, and the offending line with a caret under
+    /// the span.
+    pub fn render(&self, source: &[u8], name: &str) -> String {
+        let start = self.span.start;
+        let offset = start.offset.min(source.len());
+        let line_start = source[..offset]
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = source[offset..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(source.len(), |i| offset + i);
+        let line = String::from_utf8_lossy(&source[line_start..line_end]);
+
+        // Tabs are kept so that the caret lines up with the line above it
+        let indent: String = line
+            .chars()
+            .take(start.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let end_column = if self.span.end.line == start.line {
+            self.span.end.column
+        } else {
+            line.chars().count() + 1
+        };
+        let carets = "^".repeat(end_column.saturating_sub(start.column).max(1));
+
+        let gutter = " ".repeat(start.line.to_string().len());
+        format!(
+            "error: {}\n{gutter}--> {name}:{start}\n{gutter} |\n{} | {line}\n{gutter} | {indent}{carets}",
+            self.error, start.line,
+        )
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum RuntimeError {
     #[error("Command failed: {0}")]
     CommandFailed(String),
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+    #[error("{0}: bad file descriptor")]
+    BadFileDescriptor(String),
+    #[error("{0}: command not found")]
+    CommandNotFound(String),
+    /// A program that was found, or named by a path, but could not be run
+    #[error("{0}: {}", .1.desc())]
+    CannotRun(String, Errno),
+    #[error("{0}: unbound variable")]
+    UnboundVariable(String),
+    #[error("no match: {0}")]
+    NoMatch(String),
+    #[error("{0}: bad array subscript")]
+    BadSubscript(String),
+    #[error("{0}: must use subscript when assigning associative array")]
+    MissingSubscript(String),
+    #[error("{0}: cannot overwrite existing file")]
+    Clobber(String),
+    #[error("exit status: {0}")]
+    ExitStatus(i32),
+    /// The status of an `&&` list that stopped at a failing command before
+    /// its last, or of a pipeline negated with `!` that succeeded, neither of
+    /// which `set -e` exits for
+    #[error("exit status: {0}")]
+    ShortCircuit(i32),
+    /// Raised by `exit` to stop the shell, skipping the commands after it
+    #[error("exit {0}")]
+    Exit(i32),
+    /// Raised by `return` to leave the function or sourced file being run
+    #[error("return {0}")]
+    Return(i32),
+    /// Raised by `break` to leave the given number of loops it is inside
+    #[error("break {0}")]
+    Break(usize),
+    /// Raised by `continue` to start the next round of the loop the given
+    /// number of loops out, leaving the loops inside it
+    #[error("continue {0}")]
+    Continue(usize),
+}
+
+/// The exit status of a command that failed with `e`.
+pub fn exit_code(e: &(dyn error::Error + 'static)) -> i32 {
+    match e.downcast_ref::<RuntimeError>() {
+        Some(
+            RuntimeError::ExitStatus(code)
+            | RuntimeError::ShortCircuit(code)
+            | RuntimeError::Exit(code)
+            | RuntimeError::Return(code),
+        ) => *code,
+        Some(RuntimeError::CommandNotFound(_) | RuntimeError::CannotRun(_, Errno::ENOENT)) => 127,
+        Some(RuntimeError::CannotRun(..)) => 126,
+        Some(RuntimeError::Break(_) | RuntimeError::Continue(_)) => 0,
+        _ => 1,
+    }
+}
+
+/// Whether the error skips the commands after the one that raised it, as
+/// `exit`, `return`, `break` and `continue` do, rather than only failing
+/// that command.
+pub fn unwinds(e: &(dyn error::Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<RuntimeError>(),
+        Some(
+            RuntimeError::Exit(_)
+                | RuntimeError::Return(_)
+                | RuntimeError::Break(_)
+                | RuntimeError::Continue(_)
+        )
+    )
+}
+
+/// Prints the error a command failed with.
+///
+/// A non-zero exit status alone is not reported, as the command has already
+/// had the chance to print why it failed.
+pub fn report_error(shell: &Shell, e: &(dyn error::Error + 'static)) {
+    if let Some(
+        RuntimeError::ExitStatus(_)
+        | RuntimeError::ShortCircuit(_)
+        | RuntimeError::Exit(_)
+        | RuntimeError::Return(_)
+        | RuntimeError::Break(_)
+        | RuntimeError::Continue(_),
+    ) = e.downcast_ref::<RuntimeError>()
+    {
+        return;
+    }
+    let _ = writeln!(shell.stderr(), "Error in command: {}", e);
+}
+
+/// Why a line typed at the prompt could not have its history references
+/// expanded, in which case it is not run.
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("{0}: event not found")]
+    EventNotFound(String),
+    #[error("{0}: bad word specifier")]
+    BadWordSpecifier(String),
+    #[error("{0}: substitution failed")]
+    SubstitutionFailed(String),
 }
 
 #[derive(Error, Debug)]
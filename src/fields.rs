@@ -0,0 +1,133 @@
+//! Splitting text into fields at the characters in `IFS`.
+
+/// The separators used when `IFS` is unset.
+pub const DEFAULT_IFS: &[u8] = b" \t\n";
+
+/// Splits `text` into fields the way POSIX splits the results of unquoted
+/// expansions. Runs of the blanks in `ifs` separate fields and are trimmed
+/// from both ends, while each other character in `ifs` ends a field, so
+/// `a::b` with an `IFS` of `:` has an empty field in the middle.
+///
+/// Bytes marked in `quoted` are never separators. With a `limit`, the last
+/// field is the rest of the text after the ones before it, which is how
+/// `read` fills in its last variable.
+pub fn split_fields(
+    text: &[u8],
+    quoted: &[bool],
+    ifs: &[u8],
+    limit: Option<usize>,
+) -> Vec<Vec<u8>> {
+    let is_separator = |i: usize| !quoted[i] && ifs.contains(&text[i]);
+    let is_blank = |i: usize| is_separator(i) && DEFAULT_IFS.contains(&text[i]);
+    let skip_blanks = |mut i: usize| {
+        while i < text.len() && is_blank(i) {
+            i += 1;
+        }
+        i
+    };
+
+    let mut fields = Vec::new();
+    let mut i = skip_blanks(0);
+    while i < text.len() {
+        if limit.is_some_and(|limit| fields.len() + 1 == limit) {
+            let mut end = text.len();
+            while end > i && is_blank(end - 1) {
+                end -= 1;
+            }
+            fields.push(text[i..end].to_vec());
+            break;
+        }
+
+        let start = i;
+        while i < text.len() && !is_separator(i) {
+            i += 1;
+        }
+        fields.push(text[start..i].to_vec());
+
+        // A separator is any run of blanks, with at most one other separator
+        // character in it
+        i = skip_blanks(i);
+        if i < text.len() && is_separator(i) {
+            i = skip_blanks(i + 1);
+        }
+    }
+    fields
+}
+
+/// A field of an expanded word, both as a glob pattern, with quoted
+/// characters escaped, and as the text it stands for once quotes are removed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Field {
+    pub pattern: Vec<u8>,
+    pub text: Vec<u8>,
+}
+
+/// Collects the fields of a word from its parts in order. Only the parts
+/// that come from unquoted expansions are split, so `a$x` with `x` set to
+/// `b c` is the two fields `ab` and `c`.
+pub struct FieldSplitter<'a> {
+    ifs: &'a [u8],
+    fields: Vec<Field>,
+    /// The field being added to, if one has been started since the last
+    /// separator
+    current: Option<Field>,
+    /// Whether the last field was ended by blanks, which a following
+    /// non-blank separator then belongs to rather than ending an empty field
+    after_blanks: bool,
+}
+
+impl<'a> FieldSplitter<'a> {
+    pub fn new(ifs: &'a [u8]) -> FieldSplitter<'a> {
+        FieldSplitter {
+            ifs,
+            fields: Vec::new(),
+            current: None,
+            after_blanks: false,
+        }
+    }
+
+    /// Adds a part that is not split. A quoted part starts a field even when
+    /// it is empty, so that `""` is an argument of its own.
+    pub fn push(&mut self, pattern: &[u8], text: &[u8], quoted: bool) {
+        if pattern.is_empty() && !quoted {
+            return;
+        }
+        let field = self.current.get_or_insert_with(Field::default);
+        field.pattern.extend_from_slice(pattern);
+        field.text.extend_from_slice(text);
+        self.after_blanks = false;
+    }
+
+    /// Adds the result of an unquoted expansion, splitting it at the
+    /// characters in `IFS`.
+    pub fn push_split(&mut self, text: &[u8]) {
+        for &c in text {
+            if !self.ifs.contains(&c) {
+                self.push(&[c], &[c], false);
+            } else if DEFAULT_IFS.contains(&c) {
+                if let Some(field) = self.current.take() {
+                    self.fields.push(field);
+                    self.after_blanks = true;
+                }
+            } else {
+                match self.current.take() {
+                    Some(field) => self.fields.push(field),
+                    None if !self.after_blanks => self.fields.push(Field::default()),
+                    None => {}
+                }
+                self.after_blanks = false;
+            }
+        }
+    }
+
+    /// Ends the field being added to, so that what comes next starts another.
+    pub fn end_field(&mut self) {
+        self.fields.extend(self.current.take());
+        self.after_blanks = false;
+    }
+
+    pub fn finish(mut self) -> Vec<Field> {
+        self.fields.extend(self.current.take());
+        self.fields
+    }
+}
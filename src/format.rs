@@ -0,0 +1,616 @@
+//! Formatting for the `printf` builtin, and the backslash escapes that
+//! `printf` and `echo -e` understand.
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+
+/// What `printf` wrote, along with the problems it found in the arguments,
+/// which do not stop it unless the format itself is malformed.
+#[derive(Debug, Default)]
+pub struct Formatted {
+    pub output: Vec<u8>,
+    pub errors: Vec<String>,
+}
+
+/// Whether to keep going after an escape or conversion.
+enum Flow {
+    Continue,
+    /// Stop printing, after `\c` or a malformed format
+    Stop,
+}
+
+/// Formats `args` according to `format`, reusing the format for as long as
+/// it consumes arguments and some are left. Missing arguments are treated as
+/// empty strings or zero.
+pub fn printf(format: &[u8], args: &[OsString]) -> Formatted {
+    let mut formatted = Formatted::default();
+    let mut args = Arguments { args, next: 0 };
+    loop {
+        let start = args.next;
+        if let Flow::Stop = formatted.format(format, &mut args) {
+            break;
+        }
+        if args.next == start || args.next >= args.args.len() {
+            break;
+        }
+    }
+    formatted
+}
+
+/// Expands the escapes `echo -e` and `%b` understand, such as `\n`, `\0NNN`
+/// and `\xHH`. The second value is whether `\c` asked for the rest of the
+/// output, including any newline, to be dropped.
+pub fn unescape(text: &[u8]) -> (Vec<u8>, bool) {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] != b'\\' || i + 1 == text.len() {
+            output.push(text[i]);
+            i += 1;
+            continue;
+        }
+        match text[i + 1] {
+            b'c' => return (output, true),
+            // Octal escapes need a leading zero here, unlike in a format
+            b'0' => {
+                let (value, len) = octal(&text[i + 2..]);
+                output.push(value);
+                i += 2 + len;
+            }
+            _ => i += escape(&text[i..], &mut output),
+        }
+    }
+    (output, false)
+}
+
+/// Expands the escapes of a `$'...'` string, which are those of `echo -e`
+/// along with `\'`, `\"`, `\?` and `\cX` for control characters. Octal
+/// escapes do not need a leading zero, and the string ends at a NUL byte as
+/// it would in C. Unicode escapes are always written as UTF-8, whatever the
+/// locale.
+pub fn unescape_ansi_c(text: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] != b'\\' || i + 1 == text.len() {
+            output.push(text[i]);
+            i += 1;
+            continue;
+        }
+        match text[i + 1] {
+            c @ (b'\'' | b'"' | b'?') => {
+                output.push(c);
+                i += 2;
+            }
+            b'0'..=b'7' => {
+                let (value, len) = octal(&text[i + 1..]);
+                output.push(value);
+                i += 1 + len;
+            }
+            b'c' if i + 2 < text.len() => {
+                output.push(text[i + 2].to_ascii_uppercase() ^ 0x40);
+                i += 3;
+            }
+            _ => i += escape(&text[i..], &mut output),
+        }
+    }
+    if let Some(nul) = output.iter().position(|c| *c == 0) {
+        output.truncate(nul);
+    }
+    output
+}
+
+/// Expands the escape at the start of `text`, which begins with a
+/// backslash, returning how many bytes it took up. An unknown escape is
+/// kept as it is.
+fn escape(text: &[u8], output: &mut Vec<u8>) -> usize {
+    let simple = match text[1] {
+        b'\\' => Some(b'\\'),
+        b'a' => Some(0x07),
+        b'b' => Some(0x08),
+        b'e' | b'E' => Some(0x1b),
+        b'f' => Some(0x0c),
+        b'n' => Some(b'\n'),
+        b'r' => Some(b'\r'),
+        b't' => Some(b'\t'),
+        b'v' => Some(0x0b),
+        _ => None,
+    };
+    if let Some(c) = simple {
+        output.push(c);
+        return 2;
+    }
+
+    let (max, radix) = match text[1] {
+        b'x' => (2, 16),
+        b'u' => (4, 16),
+        b'U' => (8, 16),
+        _ => (0, 16),
+    };
+    let digits = text[2..]
+        .iter()
+        .take(max)
+        .take_while(|c| (**c as char).is_digit(radix))
+        .count();
+    if digits == 0 {
+        output.extend_from_slice(&text[..2]);
+        return 2;
+    }
+    let value = u32::from_str_radix(&lossy_bytes(&text[2..2 + digits]), radix).unwrap_or(0);
+    match text[1] {
+        b'x' => output.push(value as u8),
+        _ => {
+            let c = char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER);
+            output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    2 + digits
+}
+
+/// The value of up to three octal digits at the start of `text`, and how
+/// many there were.
+fn octal(text: &[u8]) -> (u8, usize) {
+    let len = text
+        .iter()
+        .take(3)
+        .take_while(|c| (b'0'..=b'7').contains(c))
+        .count();
+    let value = text[..len]
+        .iter()
+        .fold(0u32, |value, c| value * 8 + (c - b'0') as u32);
+    (value as u8, len)
+}
+
+fn lossy_bytes(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// The arguments left for a format to consume.
+struct Arguments<'a> {
+    args: &'a [OsString],
+    next: usize,
+}
+
+impl Arguments<'_> {
+    fn string(&mut self) -> &[u8] {
+        let arg = self
+            .args
+            .get(self.next)
+            .map_or(&[][..], |arg| arg.as_bytes());
+        self.next += 1;
+        arg
+    }
+
+    /// The next argument as an integer, which may be written in hex with
+    /// `0x`, in octal with a leading `0`, or as a quote followed by a
+    /// character to use its code.
+    fn integer(&mut self, errors: &mut Vec<String>) -> i64 {
+        let arg = self.string();
+        match parse_integer(arg) {
+            Ok(value) => value,
+            Err((value, error)) => {
+                errors.push(format!("{}: {}", lossy_bytes(arg), error));
+                value
+            }
+        }
+    }
+
+    fn float(&mut self, errors: &mut Vec<String>) -> f64 {
+        let arg = self.string();
+        let text = lossy_bytes(arg);
+        if let Ok(value) = text.trim_start().parse() {
+            return value;
+        }
+        match parse_integer(arg) {
+            Ok(value) => value as f64,
+            Err((value, error)) => {
+                errors.push(format!("{}: {}", text, error));
+                value as f64
+            }
+        }
+    }
+}
+
+/// Parses an integer argument. When only the start of it is a number, or it
+/// doesn't fit in 64 bits, the error holds the value to use anyway (the
+/// number's start, or the limit it overflowed) and what was wrong with it.
+fn parse_integer(arg: &[u8]) -> Result<i64, (i64, &'static str)> {
+    let text = lossy_bytes(arg);
+    let text = text.trim_start();
+    if let Some(quoted) = text.strip_prefix(['\'', '"']) {
+        return Ok(quoted.chars().next().map_or(0, |c| c as i64));
+    }
+    if text.is_empty() {
+        return Ok(0);
+    }
+
+    let (negative, text) = match text.as_bytes()[0] {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let (radix, digits) = if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        (16, hex)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+    let len = digits.chars().take_while(|c| c.is_digit(radix)).count();
+    // Accumulate towards the sign so that i64::MIN can be written
+    let sign = if negative { -1 } else { 1 };
+    let value = digits[..len].chars().try_fold(0i64, |value, c| {
+        value
+            .checked_mul(radix as i64)?
+            .checked_add(sign * c.to_digit(radix).unwrap_or(0) as i64)
+    });
+    let Some(value) = value else {
+        let limit = if negative { i64::MIN } else { i64::MAX };
+        return Err((limit, "Numerical result out of range"));
+    };
+    match len == digits.len() && (len > 0 || radix == 8) {
+        true => Ok(value),
+        false => Err((value, "invalid number")),
+    }
+}
+
+/// A conversion such as `%-10s` or `%05.2f`.
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// What goes before the digits of a signed number, which the `+` and
+    /// space flags add to positive ones.
+    fn sign(&self, negative: bool) -> &'static str {
+        match (negative, self.plus, self.space) {
+            (true, ..) => "-",
+            (false, true, _) => "+",
+            (false, false, true) => " ",
+            _ => "",
+        }
+    }
+}
+
+impl Formatted {
+    /// Formats the whole of `format` once.
+    fn format(&mut self, format: &[u8], args: &mut Arguments) -> Flow {
+        let mut i = 0;
+        while i < format.len() {
+            match format[i] {
+                b'\\' if i + 1 < format.len() => {
+                    if (b'0'..=b'7').contains(&format[i + 1]) {
+                        let (value, len) = octal(&format[i + 1..]);
+                        self.output.push(value);
+                        i += 1 + len;
+                    } else {
+                        i += escape(&format[i..], &mut self.output);
+                    }
+                }
+                b'%' if format.get(i + 1) == Some(&b'%') => {
+                    self.output.push(b'%');
+                    i += 2;
+                }
+                b'%' => match self.conversion(&format[i + 1..], args) {
+                    (Flow::Continue, len) => i += 1 + len,
+                    (Flow::Stop, _) => return Flow::Stop,
+                },
+                c => {
+                    self.output.push(c);
+                    i += 1;
+                }
+            }
+        }
+        Flow::Continue
+    }
+
+    /// Formats the conversion at the start of `format`, just after its `%`,
+    /// returning how many bytes it took up.
+    fn conversion(&mut self, format: &[u8], args: &mut Arguments) -> (Flow, usize) {
+        let mut spec = Spec::default();
+        let mut i = 0;
+        while let Some(flag) = format.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        if format.get(i) == Some(&b'*') {
+            let width = args.integer(&mut self.errors);
+            spec.left |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+            i += 1;
+        } else {
+            let (width, len) = number(&format[i..]);
+            spec.width = width;
+            i += len;
+        }
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            if format.get(i) == Some(&b'*') {
+                let precision = args.integer(&mut self.errors);
+                spec.precision = usize::try_from(precision).ok();
+                i += 1;
+            } else {
+                let (precision, len) = number(&format[i..]);
+                spec.precision = Some(precision);
+                i += len;
+            }
+        }
+        // Length modifiers mean nothing when every integer is 64 bits
+        while format.get(i).is_some_and(|c| b"hlLjzt".contains(c)) {
+            i += 1;
+        }
+
+        let Some(&conversion) = format.get(i) else {
+            self.errors.push(format!(
+                "`%{}': missing format character",
+                lossy_bytes(format)
+            ));
+            return (Flow::Stop, i);
+        };
+        let text = match conversion {
+            b'd' | b'i' | b'u' | b'o' | b'x' | b'X' | b'f' | b'F' | b'e' | b'E' | b'g' | b'G' => {
+                self.numeric(conversion, &spec, args);
+                return (Flow::Continue, i + 1);
+            }
+            b's' => {
+                let arg = args.string();
+                let len = spec.precision.map_or(arg.len(), |p| p.min(arg.len()));
+                arg[..len].to_vec()
+            }
+            b'b' => {
+                let (text, stop) = unescape(args.string());
+                if stop {
+                    self.output.extend_from_slice(&pad(&spec, text));
+                    return (Flow::Stop, i + 1);
+                }
+                text
+            }
+            b'q' => quote(args.string()),
+            b'c' => {
+                let arg = args.string();
+                let len = std::str::from_utf8(arg)
+                    .ok()
+                    .and_then(|s| s.chars().next())
+                    .map_or(arg.len().min(1), char::len_utf8);
+                arg[..len].to_vec()
+            }
+            c => {
+                self.errors
+                    .push(format!("`{}': invalid format character", c as char));
+                return (Flow::Stop, i + 1);
+            }
+        };
+        self.output.extend_from_slice(&pad(&spec, text));
+        (Flow::Continue, i + 1)
+    }
+
+    /// Formats a number for a conversion such as `%d`, `%x` or `%f`.
+    fn numeric(&mut self, conversion: u8, spec: &Spec, args: &mut Arguments) {
+        match conversion {
+            b'd' | b'i' => {
+                let value = args.integer(&mut self.errors);
+                let sign = spec.sign(value < 0);
+                let digits = integer_digits(spec, value.unsigned_abs().to_string());
+                // A precision replaces the `0` flag for integers
+                let zero = spec.zero && spec.precision.is_none();
+                self.number(spec, sign, "", digits, zero);
+            }
+            b'u' | b'o' | b'x' | b'X' => {
+                let value = args.integer(&mut self.errors) as u64;
+                let (digits, prefix) = match conversion {
+                    b'u' => (value.to_string(), ""),
+                    b'o' => (format!("{:o}", value), "0"),
+                    b'x' => (format!("{:x}", value), "0x"),
+                    _ => (format!("{:X}", value), "0X"),
+                };
+                let prefix = match spec.alternate && value != 0 {
+                    true => prefix,
+                    false => "",
+                };
+                let zero = spec.zero && spec.precision.is_none();
+                self.number(spec, "", prefix, integer_digits(spec, digits), zero);
+            }
+            _ => {
+                let value = args.float(&mut self.errors);
+                let sign = spec.sign(value.is_sign_negative());
+                let digits = float(value.abs(), conversion, spec);
+                self.number(spec, sign, "", digits, spec.zero && value.is_finite());
+            }
+        }
+    }
+
+    /// Writes a number padded to the width of `spec`, with zeros between the
+    /// sign and the digits when `zero` is set.
+    fn number(&mut self, spec: &Spec, sign: &str, prefix: &str, digits: String, zero: bool) {
+        let len = sign.len() + prefix.len() + digits.len();
+        let zeros = match zero && !spec.left {
+            true => spec.width.saturating_sub(len),
+            false => 0,
+        };
+        let text = format!("{}{}{}{}", sign, prefix, "0".repeat(zeros), digits);
+        self.output.extend_from_slice(&pad(spec, text.into_bytes()));
+    }
+}
+
+/// Pads integer digits with zeros to the precision of `spec`, which is the
+/// least number of digits to write.
+fn integer_digits(spec: &Spec, digits: String) -> String {
+    match spec.precision {
+        Some(precision) if digits.len() < precision => {
+            format!("{}{}", "0".repeat(precision - digits.len()), digits)
+        }
+        _ => digits,
+    }
+}
+
+/// A width or precision written in the format, and how many digits it took.
+fn number(format: &[u8]) -> (usize, usize) {
+    let len = format.iter().take_while(|c| c.is_ascii_digit()).count();
+    let value = lossy_bytes(&format[..len]).parse().unwrap_or(0);
+    (value, len)
+}
+
+/// Pads `text` with spaces to the width of `spec`.
+fn pad(spec: &Spec, mut text: Vec<u8>) -> Vec<u8> {
+    if text.len() >= spec.width {
+        return text;
+    }
+    let padding = vec![b' '; spec.width - text.len()];
+    match spec.left {
+        true => text.extend(padding),
+        false => text.splice(0..0, padding).for_each(drop),
+    }
+    text
+}
+
+/// Formats a non-negative float for `%f`, `%e` or `%g`, without its sign.
+fn float(value: f64, conversion: u8, spec: &Spec) -> String {
+    let upper = conversion.is_ascii_uppercase();
+    if !value.is_finite() {
+        let text = if value.is_nan() { "nan" } else { "inf" };
+        return if upper {
+            text.to_uppercase()
+        } else {
+            text.to_string()
+        };
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let text = match conversion.to_ascii_lowercase() {
+        b'f' => format!("{:.*}", precision, value),
+        b'e' => exponent(value, precision),
+        _ => {
+            // `%g` uses the shorter of the two, with `precision` significant
+            // digits and without trailing zeros
+            let precision = precision.max(1);
+            let exp = match value {
+                0.0 => 0,
+                _ => exponent(value, precision - 1)
+                    .split_once('e')
+                    .and_then(|(_, exp)| exp.parse::<i32>().ok())
+                    .unwrap_or(0),
+            };
+            let text = if exp < -4 || exp >= precision as i32 {
+                exponent(value, precision - 1)
+            } else {
+                format!("{:.*}", (precision as i32 - 1 - exp) as usize, value)
+            };
+            match spec.alternate {
+                true => text,
+                false => trim_zeros(&text),
+            }
+        }
+    };
+    if upper {
+        text.to_uppercase()
+    } else {
+        text
+    }
+}
+
+/// Formats `value` as `d.ddde+XX`, with at least two digits of exponent as
+/// in C.
+fn exponent(value: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exp) = text.split_once('e').unwrap_or((&text, "0"));
+    let (sign, digits) = match exp.strip_prefix('-') {
+        Some(digits) => ('-', digits),
+        None => ('+', exp),
+    };
+    format!("{}e{}{:0>2}", mantissa, sign, digits)
+}
+
+/// Removes trailing zeros after the decimal point, and the point itself if
+/// nothing is left after it.
+fn trim_zeros(text: &str) -> String {
+    let (mantissa, exp) = match text.find('e') {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    let mantissa = match mantissa.contains('.') {
+        true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+        false => mantissa,
+    };
+    format!("{}{}", mantissa, exp)
+}
+
+/// Quotes `text` so that the shell reads it back as the same word, for
+/// `%q`. Text with control characters is written as `$'...'`.
+pub fn quote(text: &[u8]) -> Vec<u8> {
+    if text.is_empty() {
+        return b"''".to_vec();
+    }
+    if text.iter().any(|c| c.is_ascii_control()) {
+        let mut quoted = b"$'".to_vec();
+        for &c in text {
+            match c {
+                b'\n' => quoted.extend_from_slice(b"\\n"),
+                b'\t' => quoted.extend_from_slice(b"\\t"),
+                b'\r' => quoted.extend_from_slice(b"\\r"),
+                0x1b => quoted.extend_from_slice(b"\\E"),
+                b'\'' | b'\\' => quoted.extend_from_slice(&[b'\\', c]),
+                c if c.is_ascii_control() => {
+                    quoted.extend_from_slice(format!("\\{:03o}", c).as_bytes())
+                }
+                c => quoted.push(c),
+            }
+        }
+        quoted.push(b'\'');
+        return quoted;
+    }
+
+    let mut quoted = Vec::with_capacity(text.len());
+    for (i, &c) in text.iter().enumerate() {
+        if b" '\"\\|&;()<>!{}*[]?^$`,".contains(&c) || (i == 0 && b"~#".contains(&c)) {
+            quoted.push(b'\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+/// Quotes `text` in single quotes if the shell would not read it back as the
+/// same word otherwise, the way `set -x` and `set` show values.
+pub fn single_quote(text: &[u8]) -> Vec<u8> {
+    let plain = |c: &u8| c.is_ascii_alphanumeric() || b"_-+=./:,@%^".contains(c);
+    if !text.is_empty() && text.iter().all(plain) {
+        return text.to_vec();
+    }
+    let mut quoted = vec![b'\''];
+    for &c in text {
+        match c {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Quotes `text` in double quotes, escaping the characters that are special
+/// inside them, the way `declare -p` shows values.
+pub fn double_quote(text: &[u8]) -> Vec<u8> {
+    let mut quoted = vec![b'"'];
+    for &c in text {
+        if b"\"\\$`".contains(&c) {
+            quoted.push(b'\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push(b'"');
+    quoted
+}
@@ -0,0 +1,101 @@
+//! Shell functions and the positional parameters of the function being run.
+
+use crate::errors::RuntimeError;
+use crate::shell::Shell;
+use crate::traits::Runnable;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+
+#[derive(Clone, Debug, Default)]
+pub struct Functions {
+    definitions: HashMap<OsString, Box<dyn Runnable>>,
+    positional: Vec<Vec<OsString>>,
+    /// The positional parameters outside of any function, which only `set`
+    /// changes
+    script_args: Vec<OsString>,
+}
+
+impl Functions {
+    pub fn new() -> Functions {
+        Functions::default()
+    }
+
+    /// Defines or replaces a function.
+    pub fn define(&mut self, name: &str, body: Box<dyn Runnable>) {
+        debug!("Defining function: {}", name);
+        self.definitions.insert(OsString::from(name), body);
+    }
+
+    pub fn lookup(&self, name: &OsStr) -> Option<Box<dyn Runnable>> {
+        self.definitions.get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &OsStr) -> bool {
+        self.definitions.contains_key(name)
+    }
+
+    /// Whether a function or sourced file is being run, so that `return` has
+    /// something to return from.
+    pub fn in_call(&self) -> bool {
+        !self.positional.is_empty()
+    }
+
+    /// The positional parameters of the function being run.
+    pub fn positional_args(&self) -> Vec<OsString> {
+        self.positional.last().unwrap_or(&self.script_args).clone()
+    }
+
+    /// Replaces the positional parameters of the function being run, as
+    /// `set -- args` does.
+    pub fn set_positional(&mut self, args: Vec<OsString>) {
+        match self.positional.last_mut() {
+            Some(positional) => *positional = args,
+            None => self.script_args = args,
+        }
+    }
+
+    /// Resolves `$1`, `$2`, ..., `$#`, `$@` and `$*`, or `None` for any other name.
+    pub fn positional(&self, name: &str) -> Option<OsString> {
+        let args = self.positional.last().unwrap_or(&self.script_args);
+        match name {
+            "#" => Some(OsString::from(args.len().to_string())),
+            "@" | "*" => Some(args.join(OsStr::new(" "))),
+            _ => {
+                let index: usize = name.parse().ok()?;
+                if index == 0 {
+                    return None;
+                }
+                args.get(index - 1).cloned()
+            }
+        }
+    }
+}
+
+/// Runs a function body with `args` as its positional parameters.
+pub fn call(
+    shell: &mut Shell,
+    body: &dyn Runnable,
+    args: Vec<OsString>,
+) -> Result<String, Box<dyn Error>> {
+    call_with(shell, args, |shell| {
+        shell.outside_loops(|shell| body.run(shell))
+    })
+}
+
+/// Runs `f` the way a function body or sourced file is run, with `args` as
+/// the positional parameters, until it finishes or runs `return`.
+pub fn call_with(
+    shell: &mut Shell,
+    args: Vec<OsString>,
+    f: impl FnOnce(&mut Shell) -> Result<String, Box<dyn Error>>,
+) -> Result<String, Box<dyn Error>> {
+    shell.functions.positional.push(args);
+    let result = f(shell);
+    shell.functions.positional.pop();
+    match result.as_ref().err().and_then(|e| e.downcast_ref()) {
+        Some(RuntimeError::Return(0)) => Ok("".to_string()),
+        Some(RuntimeError::Return(code)) => Err(RuntimeError::ExitStatus(*code).into()),
+        _ => result,
+    }
+}
@@ -0,0 +1,254 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+const MAGIC: &[u8] = b"*?[";
+
+/// Bytes that are not valid UTF-8 are decoded to values above this, outside
+/// of the Unicode range, so that they can only ever match themselves.
+const INVALID_BASE: u32 = 0x110000;
+
+/// Escapes every glob metacharacter so the string only matches itself.
+pub fn escape(s: &OsStr) -> OsString {
+    OsString::from_vec(escape_bytes(s.as_bytes()))
+}
+
+pub fn escape_bytes(s: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(s.len());
+    for &b in s {
+        if MAGIC.contains(&b) || b == b'\\' || b == b']' {
+            escaped.push(b'\\');
+        }
+        escaped.push(b);
+    }
+    escaped
+}
+
+/// Removes the backslash escapes from a pattern.
+pub fn unescape(pattern: &OsStr) -> OsString {
+    let mut unescaped = Vec::with_capacity(pattern.len());
+    let mut bytes = pattern.as_bytes().iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'\\' {
+            if let Some(&next) = bytes.next() {
+                unescaped.push(next);
+            }
+        } else {
+            unescaped.push(b);
+        }
+    }
+    OsString::from_vec(unescaped)
+}
+
+/// Whether the pattern contains an unescaped `*`, `?` or `[`.
+pub fn has_magic(pattern: &OsStr) -> bool {
+    let mut bytes = pattern.as_bytes().iter();
+    while let Some(b) = bytes.next() {
+        if *b == b'\\' {
+            bytes.next();
+        } else if MAGIC.contains(b) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Decodes UTF-8 into chars, keeping invalid bytes as distinct values.
+fn decode(s: &OsStr) -> Vec<u32> {
+    let mut decoded = Vec::with_capacity(s.len());
+    for chunk in s.as_bytes().utf8_chunks() {
+        decoded.extend(chunk.valid().chars().map(|c| c as u32));
+        decoded.extend(chunk.invalid().iter().map(|b| INVALID_BASE + *b as u32));
+    }
+    decoded
+}
+
+/// Matches `name` against a shell pattern supporting `*`, `?`, `[...]` and
+/// backslash escapes.
+pub fn matches(pattern: &OsStr, name: &OsStr) -> bool {
+    matches_impl(&decode(pattern), &decode(name))
+}
+
+fn matches_impl(pattern: &[u32], name: &[u32]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position to resume from when the last `*` has to consume another char
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        let step = match pattern.get(p).and_then(|c| char::from_u32(*c)) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match match_class(&pattern[p..], name[n]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                // An unclosed bracket matches itself
+                None if name[n] == '[' as u32 => Some(1),
+                None => None,
+            },
+            Some('\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == name[n] {
+                    Some(2)
+                } else {
+                    None
+                }
+            }
+            _ if pattern.get(p) == Some(&name[n]) => Some(1),
+            _ => None,
+        };
+
+        match (step, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((star, from))) => {
+                p = star + 1;
+                n = from + 1;
+                backtrack = Some((star, from + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*' as u32)
+}
+
+/// Matches one char against the bracket expression at the start of `pattern`.
+///
+/// Returns whether it matched and the length of the bracket expression, or
+/// `None` if the bracket is never closed.
+fn match_class(pattern: &[u32], c: u32) -> Option<(bool, usize)> {
+    let is = |i: usize, expected: char| pattern.get(i) == Some(&(expected as u32));
+
+    let mut i = 1;
+    let negated = is(i, '!') || is(i, '^');
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        if is(i, ']') && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if is(i, '\\') {
+            i += 1;
+        }
+        let start = *pattern.get(i)?;
+        i += 1;
+
+        let mut end = start;
+        if is(i, '-') && !is(i + 1, ']') && pattern.get(i + 1).is_some() {
+            i += 1;
+            if is(i, '\\') {
+                i += 1;
+            }
+            end = *pattern.get(i)?;
+            i += 1;
+        }
+
+        if start <= c && c <= end {
+            matched = true;
+        }
+    }
+}
+
+/// Changes to how [`expand`] matches names.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobFlags {
+    /// Names starting with `.` match without a leading `.` in the pattern
+    pub dotglob: bool,
+    /// Letters match regardless of case
+    pub nocaseglob: bool,
+}
+
+/// Expands a pattern against the file system, with relative patterns
+/// matched in `cwd`.
+///
+/// Returns the matching paths in sorted order, or nothing if no path matches.
+pub fn expand(pattern: &OsStr, cwd: &Path, flags: GlobFlags) -> Vec<OsString> {
+    let pattern = pattern.as_bytes();
+    let (mut paths, rest) = match pattern.strip_prefix(b"/") {
+        Some(rest) => (vec![b"/".to_vec()], rest),
+        None => (vec![Vec::new()], pattern),
+    };
+
+    for component in rest.split(|b| *b == b'/') {
+        let component = OsStr::from_bytes(component);
+        let mut next = Vec::new();
+        for path in &paths {
+            if component.is_empty() {
+                // Repeated or trailing slashes
+                next.push(join(path, b""));
+            } else if !has_magic(component) {
+                let candidate = join(path, unescape(component).as_bytes());
+                if cwd
+                    .join(OsStr::from_bytes(&candidate))
+                    .symlink_metadata()
+                    .is_ok()
+                {
+                    next.push(candidate);
+                }
+            } else {
+                next.extend(
+                    read_dir_matches(&cwd.join(OsStr::from_bytes(path)), component, flags)
+                        .into_iter()
+                        .map(|name| join(path, name.as_bytes())),
+                );
+            }
+        }
+        paths = next;
+    }
+
+    paths.sort();
+    paths.into_iter().map(OsString::from_vec).collect()
+}
+
+fn read_dir_matches(dir: &Path, component: &OsStr, flags: GlobFlags) -> Vec<OsString> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    // Hidden files are only matched by a pattern with a leading literal dot,
+    // unless `dotglob` is on
+    let pattern = component.as_bytes();
+    let show_hidden = flags.dotglob || pattern.starts_with(b".") || pattern.starts_with(b"\\.");
+    let fold = |s: &OsStr| match flags.nocaseglob {
+        true => fold_case(decode(s)),
+        false => decode(s),
+    };
+    let pattern = fold(component);
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name())
+        .filter(|name| show_hidden || !name.as_bytes().starts_with(b"."))
+        .filter(|name| matches_impl(&pattern, &fold(name)))
+        .collect()
+}
+
+/// Lowercases decoded chars, so that names can be matched regardless of case.
+fn fold_case(chars: Vec<u32>) -> Vec<u32> {
+    chars
+        .into_iter()
+        .map(|c| match char::from_u32(c) {
+            Some(c) => c.to_lowercase().next().unwrap_or(c) as u32,
+            None => c,
+        })
+        .collect()
+}
+
+fn join(dir: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut path = dir.to_vec();
+    if !dir.is_empty() && !dir.ends_with(b"/") {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path
+}
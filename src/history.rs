@@ -0,0 +1,59 @@
+//! Lines entered at the prompt, numbered from 1, for the `history` builtin
+//! and history expansion.
+
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// Whether entries were removed since the line editor last copied them
+    edited: bool,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    pub fn add(&mut self, line: &str) {
+        if !line.trim().is_empty() {
+            self.entries.push(line.to_string());
+        }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// The entry with the given number.
+    pub fn get(&self, number: usize) -> Option<&str> {
+        let index = number.checked_sub(1)?;
+        self.entries.get(index).map(String::as_str)
+    }
+
+    pub fn last(&self) -> Option<&str> {
+        self.entries.last().map(String::as_str)
+    }
+
+    /// Removes the entry with the given number, returning whether there was
+    /// one. The entries after it are numbered one lower.
+    pub fn delete(&mut self, number: usize) -> bool {
+        match number.checked_sub(1) {
+            Some(index) if index < self.entries.len() => {
+                self.entries.remove(index);
+                self.edited = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.edited = true;
+    }
+
+    /// Whether entries were removed since the last call, so that the line
+    /// editor needs to copy them again.
+    pub fn take_edited(&mut self) -> bool {
+        std::mem::take(&mut self.edited)
+    }
+}
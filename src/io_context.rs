@@ -0,0 +1,279 @@
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::unistd::{close, dup2, pipe2};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{ChildStdout, Command, Stdio};
+use std::rc::Rc;
+
+/// The standard streams a builtin reads from and writes to.
+///
+/// Builtins run inside the shell process, so instead of printing to the
+/// process stdout they write into whatever handles they are given. This lets
+/// the same builtin write to the terminal, into a pipe or into a buffer.
+pub struct IoContext<'a> {
+    pub stdin: Box<dyn Read + 'a>,
+    pub stdout: Box<dyn Write + 'a>,
+    pub stderr: Box<dyn Write + 'a>,
+}
+
+impl<'a> IoContext<'a> {
+    pub fn new(
+        stdin: Box<dyn Read + 'a>,
+        stdout: Box<dyn Write + 'a>,
+        stderr: Box<dyn Write + 'a>,
+    ) -> IoContext<'a> {
+        IoContext {
+            stdin,
+            stdout,
+            stderr,
+        }
+    }
+
+    /// Streams connected to the shell's own stdin, stdout and stderr.
+    pub fn inherit() -> IoContext<'static> {
+        IoContext {
+            stdin: Box::new(std::io::stdin()),
+            stdout: Box::new(std::io::stdout()),
+            stderr: Box::new(std::io::stderr()),
+        }
+    }
+}
+
+/// Where a file descriptor of a command points to.
+#[derive(Clone, Debug)]
+pub enum Stream {
+    /// A file descriptor of the shell process itself
+    Inherit(RawFd),
+    /// A file or pipe opened by the shell
+    Fd(Rc<OwnedFd>),
+}
+
+impl Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Inherit(fd) => *fd,
+            Stream::Fd(fd) => fd.as_raw_fd(),
+        }
+    }
+
+    fn try_clone(&self) -> Result<OwnedFd, Box<dyn Error>> {
+        let fd = match self {
+            Stream::Inherit(fd) => unsafe { BorrowedFd::borrow_raw(*fd) }.try_clone_to_owned()?,
+            Stream::Fd(fd) => fd.try_clone()?,
+        };
+        Ok(fd)
+    }
+}
+
+impl From<OwnedFd> for Stream {
+    fn from(fd: OwnedFd) -> Self {
+        Stream::Fd(Rc::new(fd))
+    }
+}
+
+/// The file descriptors a command is started with.
+///
+/// Redirections are applied to the table in order, so later redirections of
+/// the same descriptor replace earlier ones.
+#[derive(Clone, Debug)]
+pub struct FdTable {
+    fds: BTreeMap<RawFd, Stream>,
+}
+
+impl FdTable {
+    /// A table with stdin, stdout and stderr inherited from the shell.
+    pub fn new() -> FdTable {
+        let mut fds = BTreeMap::new();
+        for fd in 0..3 {
+            fds.insert(fd, Stream::Inherit(fd));
+        }
+        FdTable { fds }
+    }
+
+    pub fn get(&self, fd: RawFd) -> Option<&Stream> {
+        self.fds.get(&fd)
+    }
+
+    pub fn set(&mut self, fd: RawFd, stream: Stream) -> &mut FdTable {
+        self.fds.insert(fd, stream);
+        self
+    }
+
+    pub fn close(&mut self, fd: RawFd) -> &mut FdTable {
+        self.fds.remove(&fd);
+        self
+    }
+
+    /// A copy of one of the descriptors in the table, for a builtin that reads
+    /// from it directly rather than through its [`IoContext`].
+    pub fn dup(&self, fd: RawFd) -> Result<Option<OwnedFd>, Box<dyn Error>> {
+        self.get(fd).map(Stream::try_clone).transpose()
+    }
+
+    /// Builds the streams handed to a builtin.
+    pub fn io_context(&self) -> Result<IoContext<'static>, Box<dyn Error>> {
+        let stdin: Box<dyn Read> = match self.get(0) {
+            Some(Stream::Inherit(0)) => Box::new(std::io::stdin()),
+            Some(stream) => Box::new(File::from(stream.try_clone()?)),
+            None => Box::new(std::io::empty()),
+        };
+        Ok(IoContext::new(stdin, self.writer(1)?, self.writer(2)?))
+    }
+
+    /// A writer for one of the descriptors in the table.
+    pub fn writer(&self, fd: RawFd) -> Result<Box<dyn Write>, Box<dyn Error>> {
+        let writer: Box<dyn Write> = match self.get(fd) {
+            Some(Stream::Inherit(1)) => Box::new(std::io::stdout()),
+            Some(Stream::Inherit(2)) => Box::new(std::io::stderr()),
+            Some(stream) => Box::new(File::from(stream.try_clone()?)),
+            None => Box::new(std::io::sink()),
+        };
+        Ok(writer)
+    }
+
+    /// Connects the descriptors of an external command to the table.
+    pub fn apply(&self, command: &mut Command) -> Result<(), Box<dyn Error>> {
+        command.stdin(self.stdio(0)?);
+        command.stdout(self.stdio(1)?);
+        command.stderr(self.stdio(2)?);
+
+        let mut extra = Vec::new();
+        for (fd, stream) in self.fds.range(3..) {
+            extra.push((stream.try_clone()?, *fd));
+        }
+        if !extra.is_empty() {
+            // SAFETY: dup2 is async-signal-safe and the closure allocates nothing
+            unsafe {
+                command.pre_exec(move || {
+                    for (src, dst) in &extra {
+                        dup2(src.as_raw_fd(), *dst)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the descriptors of the process itself those in the table and
+    /// closes every other, in a forked copy of the shell, so that it holds no
+    /// copies of pipes that other commands wait to see closed. Returns the
+    /// table the copy runs with, whose streams are all inherited.
+    pub fn install(self) -> Result<FdTable, Box<dyn Error>> {
+        // The streams are first copied above every descriptor in the table,
+        // so that none is replaced before it has been copied
+        let above = self.fds.keys().max().map_or(3, |fd| fd + 1);
+        let mut copies = Vec::new();
+        for (fd, stream) in &self.fds {
+            if !matches!(stream, Stream::Inherit(src) if src == fd) {
+                let copy = fcntl(stream.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(above))?;
+                // SAFETY: `fcntl` returned a new descriptor that nothing else owns
+                copies.push((unsafe { OwnedFd::from_raw_fd(copy) }, *fd));
+            }
+        }
+        for (copy, fd) in &copies {
+            dup2(copy.as_raw_fd(), *fd)?;
+        }
+        drop(copies);
+
+        let keep: Vec<RawFd> = self.fds.keys().copied().collect();
+        drop(self);
+        close_other_fds(&keep);
+        let fds = keep.iter().map(|fd| (*fd, Stream::Inherit(*fd))).collect();
+        Ok(FdTable { fds })
+    }
+
+    /// Replaces the process with `command`, which was given this table by
+    /// [`FdTable::apply`]. Running it moves the streams onto the process's
+    /// own descriptors first, so when it can't be run, those are put back
+    /// for the shell to carry on with.
+    pub fn exec(&self, command: &mut Command) -> std::io::Error {
+        let above = self.fds.keys().max().map_or(3, |fd| fd + 1).max(3);
+        let mut saved = Vec::new();
+        for fd in (0..3).chain(self.fds.range(3..).map(|(fd, _)| *fd)) {
+            let copy = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(above)).ok();
+            // SAFETY: `fcntl` returned a new descriptor that nothing else owns
+            saved.push((fd, copy.map(|copy| unsafe { OwnedFd::from_raw_fd(copy) })));
+        }
+        let error = command.exec();
+        for (fd, copy) in saved {
+            let _ = match copy {
+                Some(copy) => dup2(copy.as_raw_fd(), fd).map(drop),
+                None => close(fd),
+            };
+        }
+        error
+    }
+
+    fn stdio(&self, fd: RawFd) -> Result<Stdio, Box<dyn Error>> {
+        let stdio = match self.get(fd) {
+            Some(Stream::Inherit(src)) if *src == fd => Stdio::inherit(),
+            Some(stream) => Stdio::from(stream.try_clone()?),
+            None => Stdio::null(),
+        };
+        Ok(stdio)
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Collects everything written to a stream, in an unnamed temporary file.
+///
+/// Writing to a file never blocks, so a writer in the shell process can run
+/// to the end before anything reads what it wrote.
+pub struct Capture {
+    file: File,
+}
+
+impl Capture {
+    /// Returns the capture and the write end to hand to the producer.
+    pub fn new() -> Result<(Capture, OwnedFd), Box<dyn Error>> {
+        let file = tempfile::tempfile()?;
+        let write_end = OwnedFd::from(file.try_clone()?);
+        Ok((Capture { file }, write_end))
+    }
+
+    /// Returns the bytes written so far.
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let _ = self.file.rewind();
+        let _ = self.file.read_to_end(&mut buffer);
+        buffer
+    }
+
+    /// Turns the bytes written so far into a stream the next pipeline stage
+    /// can read.
+    pub fn into_stdout(mut self) -> Result<ChildStdout, Box<dyn Error>> {
+        self.file.rewind()?;
+        Ok(ChildStdout::from(OwnedFd::from(self.file)))
+    }
+}
+
+/// Closes every descriptor of the process but those in `keep`.
+///
+/// Nothing that owns one of the descriptors may be dropped afterwards.
+fn close_other_fds(keep: &[RawFd]) {
+    let fds: Vec<RawFd> = match std::fs::read_dir("/dev/fd") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    for fd in fds.into_iter().filter(|fd| !keep.contains(fd)) {
+        // The descriptor of the directory listing is already closed
+        let _ = close(fd);
+    }
+}
+
+/// Creates a pipe whose ends are not leaked into spawned commands.
+pub fn cloexec_pipe() -> Result<(OwnedFd, OwnedFd), Box<dyn Error>> {
+    Ok(pipe2(OFlag::O_CLOEXEC)?)
+}
@@ -0,0 +1,173 @@
+//! Commands started in the background with `&`, which `wait` and `kill`
+//! refer to by process ID or by job number.
+
+use crate::timing::CpuTime;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: usize,
+    pub pid: Pid,
+    /// How the job exited, once it has been reaped
+    status: Option<i32>,
+}
+
+/// The background jobs of a shell, in the order they were started.
+#[derive(Clone, Debug, Default)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+    /// The processes of process substitutions, which `wait` and `$!` know
+    /// about but which have no job number
+    substitutions: Vec<Job>,
+    /// The process started last, either a job or a substitution
+    last: Option<Pid>,
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        Jobs::default()
+    }
+
+    /// Records a job started as process `pid` and returns its job number,
+    /// one more than the highest number still in use.
+    pub fn add(&mut self, pid: Pid) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job {
+            id,
+            pid,
+            status: None,
+        });
+        self.last = Some(pid);
+        id
+    }
+
+    /// Records the process of a process substitution.
+    pub fn add_substitution(&mut self, pid: Pid) {
+        // Only the last substitution can still be waited for by `$!`
+        self.substitutions.retain(|job| job.status.is_none());
+        self.substitutions.push(Job {
+            id: 0,
+            pid,
+            status: None,
+        });
+        self.last = Some(pid);
+    }
+
+    /// The process ID of the job or process substitution started last.
+    pub fn last(&self) -> Option<Pid> {
+        self.last
+    }
+
+    /// The process IDs of the jobs and process substitutions, for `wait`.
+    pub fn pids(&self) -> Vec<Pid> {
+        let jobs = self.jobs.iter().chain(&self.substitutions);
+        jobs.map(|job| job.pid).collect()
+    }
+
+    /// The process ID of the job named by a job spec: `%N` for job number
+    /// `N`, `%%` or `%+` for the current job and `%-` for the one before it.
+    pub fn find(&self, spec: &str) -> Option<Pid> {
+        let job = match spec.strip_prefix('%')? {
+            "" | "%" | "+" => self.jobs.last(),
+            "-" => self.jobs.iter().rev().nth(1),
+            n => {
+                let id: usize = n.parse().ok()?;
+                self.jobs.iter().find(|job| job.id == id)
+            }
+        };
+        job.map(|job| job.pid)
+    }
+
+    /// Notes the status of every job that has finished, without waiting for
+    /// the others, so that they do not linger as zombies.
+    pub fn reap(&mut self) {
+        let jobs = self.jobs.iter_mut().chain(&mut self.substitutions);
+        for job in jobs.filter(|job| job.status.is_none()) {
+            if let Ok(status) = waitpid(job.pid, Some(WaitPidFlag::WNOHANG)) {
+                job.status = exit_status(status);
+            }
+        }
+    }
+
+    /// Waits for the job running as `pid` to finish and forgets it, returning
+    /// its exit status, or `None` if it is not a job of this shell.
+    pub fn wait(&mut self, pid: Pid) -> Option<i32> {
+        let job = match self.jobs.iter().position(|job| job.pid == pid) {
+            Some(index) => self.jobs.remove(index),
+            None => {
+                let index = self.substitutions.iter().position(|job| job.pid == pid)?;
+                self.substitutions.remove(index)
+            }
+        };
+        if let Some(status) = job.status {
+            return Some(status);
+        }
+        Some(wait_for(pid))
+    }
+}
+
+/// Waits for a child of the shell to finish, returning its exit status.
+pub fn wait_for(pid: Pid) -> i32 {
+    wait_with_usage(pid).0
+}
+
+/// Waits for a child of the shell to finish, returning its exit status and
+/// the CPU time used by it and by the processes it waited for.
+pub fn wait_with_usage(pid: Pid) -> (i32, CpuTime) {
+    loop {
+        match wait4(pid) {
+            Ok((status, usage)) => match exit_status(status) {
+                Some(status) => return (status, usage),
+                None => continue,
+            },
+            Err(Errno::EINTR) => continue,
+            Err(_) => return (127, CpuTime::default()),
+        }
+    }
+}
+
+/// `waitpid`, which also reports the resources the process used. nix has no
+/// wrapper for it.
+fn wait4(pid: Pid) -> nix::Result<(WaitStatus, CpuTime)> {
+    let mut status = 0;
+    // SAFETY: both pointers are to locals that outlive the call, and an
+    // all-zero `rusage` is a valid value of the plain C struct
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    let pid = Errno::result(unsafe { libc::wait4(pid.as_raw(), &mut status, 0, &mut usage) })?;
+    let status = WaitStatus::from_raw(Pid::from_raw(pid), status)?;
+    Ok((status, CpuTime::from(&usage)))
+}
+
+/// The status a finished process exited with, or `None` if it is still
+/// running.
+fn exit_status(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some(code),
+        WaitStatus::Signaled(_, signal, _) => Some(128 + signal as i32),
+        _ => None,
+    }
+}
+
+/// The number of the signal `name` refers to, given as a number or a name
+/// with or without `SIG`, in any case. Signal 0 only checks that a process
+/// exists.
+pub fn signal_number(name: &str) -> Option<i32> {
+    if let Ok(n) = name.parse::<i32>() {
+        return (n == 0 || Signal::try_from(n).is_ok()).then_some(n);
+    }
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    Signal::iterator()
+        .find(|signal| signal_name(*signal) == name)
+        .map(|signal| signal as i32)
+}
+
+/// The name of a signal without `SIG`, as `kill -l` lists it.
+pub fn signal_name(signal: Signal) -> &'static str {
+    &signal.as_str()[3..]
+}
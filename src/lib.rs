@@ -0,0 +1,41 @@
+//! A shell with an LLM at its core.
+//!
+//! The [`Shell`] type holds the state of a session and runs commands in it,
+//! so that the shell can be embedded in other programs as well as run from
+//! the `aish` binary.
+
+pub mod assignment;
+pub mod ast;
+pub mod builtins;
+pub mod command;
+pub mod compiler;
+pub mod compound;
+pub mod conditional;
+pub mod directories;
+pub mod errors;
+pub mod fields;
+pub mod format;
+pub mod functions;
+pub mod glob;
+pub mod history;
+pub mod io_context;
+pub mod jobs;
+pub mod limits;
+pub mod openai_client;
+pub mod options;
+pub mod parsing;
+pub mod pipeline;
+pub mod redirect;
+pub mod resolver;
+pub mod sequence;
+pub mod shell;
+pub mod substitution;
+pub mod timing;
+pub mod token;
+pub mod traits;
+pub mod variables;
+
+#[macro_use]
+extern crate log;
+
+pub use shell::{ExecResult, Shell};
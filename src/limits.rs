@@ -0,0 +1,160 @@
+//! The file mode mask and resource limits that `umask` and `ulimit` change.
+//! Unlike the rest of the shell's state they belong to the process, so every
+//! shell in it shares them.
+
+use nix::sys::resource::Resource::{self, *};
+use nix::sys::resource::{rlim_t, RLIM_INFINITY};
+
+/// A resource limit, with the flag `ulimit` selects it by.
+pub struct Limit {
+    pub flag: char,
+    pub description: &'static str,
+    /// The unit values are shown in, if any
+    pub unit: &'static str,
+    pub resource: Resource,
+    /// How many of the underlying units, bytes for sizes, make up one of `unit`
+    pub scale: rlim_t,
+}
+
+/// The limits `ulimit` knows about, in the order `ulimit -a` lists them.
+pub const LIMITS: &[Limit] = &[
+    limit('c', "core file size", "blocks", RLIMIT_CORE, 512),
+    limit('d', "data seg size", "kbytes", RLIMIT_DATA, 1024),
+    limit('f', "file size", "blocks", RLIMIT_FSIZE, 512),
+    limit('l', "max locked memory", "kbytes", RLIMIT_MEMLOCK, 1024),
+    limit('m', "max memory size", "kbytes", RLIMIT_RSS, 1024),
+    limit('n', "open files", "", RLIMIT_NOFILE, 1),
+    limit('s', "stack size", "kbytes", RLIMIT_STACK, 1024),
+    limit('t', "cpu time", "seconds", RLIMIT_CPU, 1),
+    limit('u', "max user processes", "", RLIMIT_NPROC, 1),
+    limit('v', "virtual memory", "kbytes", RLIMIT_AS, 1024),
+];
+
+const fn limit(
+    flag: char,
+    description: &'static str,
+    unit: &'static str,
+    resource: Resource,
+    scale: rlim_t,
+) -> Limit {
+    Limit {
+        flag,
+        description,
+        unit,
+        resource,
+        scale,
+    }
+}
+
+pub fn find_limit(flag: char) -> Option<&'static Limit> {
+    LIMITS.iter().find(|limit| limit.flag == flag)
+}
+
+impl Limit {
+    /// A limit in the units `ulimit` shows it in.
+    pub fn display(&self, value: rlim_t) -> String {
+        match value {
+            RLIM_INFINITY => "unlimited".to_string(),
+            value => (value / self.scale).to_string(),
+        }
+    }
+
+    /// The line `ulimit -a` prints for the limit, such as
+    /// `open files                          (-n) 1024`.
+    pub fn describe(&self, value: rlim_t) -> String {
+        let flag = match self.unit {
+            "" => format!("(-{}) ", self.flag),
+            unit => format!("({}, -{}) ", unit, self.flag),
+        };
+        format!(
+            "{:<20} {:>20}{}",
+            self.description,
+            flag,
+            self.display(value)
+        )
+    }
+
+    /// Reads a limit written in the units `ulimit` shows it in.
+    pub fn parse(&self, text: &str) -> Option<rlim_t> {
+        match text {
+            "unlimited" => Some(RLIM_INFINITY),
+            _ => text.parse::<rlim_t>().ok()?.checked_mul(self.scale),
+        }
+    }
+}
+
+/// The permissions that each of `u`, `g` and `o` stands for.
+const WHO: [(char, u32); 3] = [('u', 0o700), ('g', 0o070), ('o', 0o007)];
+
+/// A mask written the way `umask -S` prints it, as the permissions it lets
+/// through, such as `u=rwx,g=rx,o=rx`.
+pub fn symbolic_mask(mask: u32) -> String {
+    let allowed = !mask & 0o777;
+    let clauses: Vec<String> = WHO
+        .iter()
+        .map(|(who, bits)| {
+            let permissions: String = [('r', 0o444), ('w', 0o222), ('x', 0o111)]
+                .iter()
+                .filter(|(_, permission)| allowed & bits & permission != 0)
+                .map(|(c, _)| *c)
+                .collect();
+            format!("{}={}", who, permissions)
+        })
+        .collect();
+    clauses.join(",")
+}
+
+/// Reads a mask given to `umask`, either as an octal number or as symbolic
+/// clauses such as `g-w,o=` that change the current `mask`.
+pub fn parse_mask(text: &str, mask: u32) -> Result<u32, String> {
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        return match u32::from_str_radix(text, 8) {
+            Ok(mask) if mask <= 0o777 => Ok(mask),
+            _ => Err(format!("{}: octal number out of range", text)),
+        };
+    }
+
+    let mut allowed = !mask & 0o777;
+    for clause in text.split(',') {
+        let mut chars = clause.chars().peekable();
+        let mut who = 0;
+        while let Some(c) = chars.next_if(|c| "ugoa".contains(*c)) {
+            who |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                _ => 0o777,
+            };
+        }
+        if who == 0 {
+            who = 0o777;
+        }
+
+        let mut operators = 0;
+        while let Some(operator) = chars.next() {
+            if !"+-=".contains(operator) {
+                return Err(format!("`{}': invalid symbolic mode operator", operator));
+            }
+            operators += 1;
+            let mut permissions = 0;
+            while let Some(c) = chars.next_if(|c| !"+-=".contains(*c)) {
+                permissions |= match c {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    _ => return Err(format!("`{}': invalid symbolic mode character", c)),
+                };
+            }
+            let permissions = permissions & who;
+            match operator {
+                '+' => allowed |= permissions,
+                '-' => allowed &= !permissions,
+                _ => allowed = (allowed & !who) | permissions,
+            }
+        }
+        if operators == 0 {
+            return Err(format!("{}: invalid symbolic mode", text));
+        }
+    }
+    Ok(!allowed & 0o777)
+}
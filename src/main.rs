@@ -1,33 +1,28 @@
-pub mod builtins;
-pub mod command;
-pub mod errors;
-pub mod openai_client;
-pub mod parsing;
-pub mod pipeline;
-pub mod redirect;
-pub mod sequence;
-pub mod suggestions;
-pub mod token;
-pub mod traits;
+mod suggestions;
 
 #[macro_use]
 extern crate log;
 extern crate simplelog;
 
 use crate::suggestions::ShellHelper;
-use crate::traits::Runnable;
+use aish::parsing::{expand_history, parse};
+use aish::Shell;
 use home::home_dir;
-use parsing::parse;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
-use simplelog::{Config, LevelFilter, WriteLogger};
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, Editor};
+use simplelog::{LevelFilter, WriteLogger};
 use std::env;
-use std::fs::File;
+use std::ffi::OsString;
 use std::fs::OpenOptions;
-use std::io::{BufRead, BufReader};
+use std::io::{IsTerminal, LineWriter, Read, Write};
+use std::os::unix::ffi::OsStringExt;
 use std::path::PathBuf;
 use std::result::Result;
 
+/// How many lines of history are kept in `~/.aish_history`.
+const HISTORY_SIZE: usize = 1000;
+
 fn main() -> rustyline::Result<()> {
     // Setup logging
     let log_path = home_dir().unwrap().join(".aish_log");
@@ -35,65 +30,124 @@ fn main() -> rustyline::Result<()> {
         .create(true)
         .append(true)
         .open(log_path)?;
-    WriteLogger::init(LevelFilter::Debug, Config::default(), log_file).unwrap();
+    // Buffered per line, as debug output of large scripts is written in many small pieces
+    let log_file = LineWriter::new(log_file);
+    WriteLogger::init(LevelFilter::Debug, simplelog::Config::default(), log_file).unwrap();
     info!("Starting aish");
 
     // Get args
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<OsString> = env::args_os().collect();
+    let mut shell = Shell::new();
 
     // Run aishrc file if it exists
     let aishrc = aishrc_path()?;
     if aishrc.exists() {
-        match run_file_mode(&aishrc) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                return Ok(());
-            }
+        if let Err(e) = shell.run_file(&aishrc) {
+            eprintln!("Error: {}", e);
         }
     } else {
         warn!("No .aishrc file found");
     }
 
-    // Run in interactive mode if no args
-    match args.len() {
-        1 => match interactive_mode() {
-            Ok(_) => (),
-            Err(e) => eprintln!("Error: {}", e),
-        },
-        2 => match run_file_mode(&PathBuf::from(&args[1])) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Error: {}", e),
-        },
-        _ => eprintln!("Usage: aish [file]"),
+    // Run in interactive mode if no args, or read commands from a pipe
+    let result = match args.len() {
+        _ if shell.exit_status().is_some() => Ok(()),
+        1 if !std::io::stdin().is_terminal() => run_stdin_mode(&mut shell),
+        1 => interactive_mode(&mut shell),
+        2 if args[1] == "--dump-ast" => dump_ast(None),
+        3 if args[1] == "--dump-ast" => dump_ast(Some(&PathBuf::from(&args[2]))),
+        2 => shell
+            .run_file(&PathBuf::from(&args[1]))
+            .map(|_| ())
+            .map_err(|e| e.into()),
+        _ => {
+            eprintln!("Usage: aish [--dump-ast] [file]");
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
     }
 
+    // The shell exits with the status given to `exit`, or that of the last
+    // command, unless the `EXIT` trap calls `exit` itself
+    let status = shell.exit_status().unwrap_or(shell.last_status());
+    shell.run_exit_trap();
     info!("Exiting aish");
-    Ok(())
+    std::process::exit(shell.exit_status().unwrap_or(status));
 }
 
-fn interactive_mode() -> Result<(), Box<dyn std::error::Error>> {
+fn interactive_mode(shell: &mut Shell) -> Result<(), Box<dyn std::error::Error>> {
+    shell.options.interactive = true;
+    shell.options.histexpand = true;
+
     // Setup readline
-    let mut rl = Editor::<ShellHelper, rustyline::history::DefaultHistory>::new()?;
+    let config = Config::builder().max_history_size(HISTORY_SIZE)?.build();
+    let mut rl = Editor::<ShellHelper, DefaultHistory>::with_config(config)?;
     let history = home_dir().unwrap().join(".aish_history");
     let _ = rl.load_history(history.as_path());
+    for entry in rl.history().iter() {
+        shell.history.add(entry);
+    }
 
     let helper = ShellHelper {
         suggestion: String::new(),
     };
     rl.set_helper(Some(helper));
 
+    // A line with history references to edit before running, with `histverify`
+    let mut initial = String::new();
     loop {
-        let readline = rl.readline("> ");
+        let readline = rl.readline_with_initial("> ", (&std::mem::take(&mut initial), ""));
 
         match readline {
-            Ok(line) => {
+            Ok(mut line) => {
+                // Keep reading while the input is incomplete, e.g. an `if` without `fi`
+                while let Err(e) = parse(OsString::from(&line)) {
+                    if !e.error.is_incomplete() {
+                        break;
+                    }
+                    match rl.readline("... ") {
+                        Ok(more) => {
+                            line.push('\n');
+                            line.push_str(&more);
+                        }
+                        Err(_) => break,
+                    }
+                }
+
+                let expanded = match shell.options.histexpand {
+                    true => expand_history(&line, &shell.history),
+                    false => Ok(None),
+                };
+                let line = match expanded {
+                    Ok(Some(expanded)) if shell.options.hist_verify => {
+                        initial = expanded;
+                        continue;
+                    }
+                    Ok(Some(expanded)) => {
+                        println!("{}", expanded);
+                        expanded
+                    }
+                    Ok(None) => line,
+                    Err(e) => {
+                        eprintln!("aish: {}", e);
+                        continue;
+                    }
+                };
                 let _ = rl.add_history_entry(line.as_str());
+                shell.history.add(&line);
                 debug!("Added input to history");
-                let output = execute_commands(vec![line.to_string()]);
 
+                shell.run(line.as_bytes(), "<input>");
+                if shell.history.take_edited() {
+                    copy_history(shell, &mut rl)?;
+                }
                 if let Some(helper) = rl.helper_mut() {
-                    helper.suggestion = output.clone();
+                    helper.suggestion = shell.take_suggestion().unwrap_or_default();
+                }
+                if shell.exit_status().is_some() {
+                    break;
                 }
             }
             Err(ReadlineError::Interrupted) => break,
@@ -108,51 +162,49 @@ fn interactive_mode() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn run_file_mode(file_path: &PathBuf) -> Result<(), std::io::Error> {
-    let commands = read_file(file_path)?;
-    execute_commands(commands);
+/// Replaces the lines the editor offers with the shell's history, after the
+/// `history` builtin removed some of them.
+fn copy_history(
+    shell: &Shell,
+    rl: &mut Editor<ShellHelper, DefaultHistory>,
+) -> rustyline::Result<()> {
+    rl.clear_history()?;
+    for entry in shell.history.entries() {
+        rl.add_history_entry(entry.as_str())?;
+    }
     Ok(())
 }
 
-fn execute_commands(commands: Vec<String>) -> String {
-    let mut output = String::new();
-    for command in commands {
-        debug!("Executing command: {}", command);
-        let tokenized = match parse(command) {
-            Ok(tokenized) => tokenized,
-            Err(e) => {
-                eprintln!("Error in command: {}", e);
-                continue;
-            }
-        };
-        debug!("tokenized: {:?}", tokenized);
-        match tokenized.run() {
-            Ok(s) => {
-                if let Some(stripped) = s.strip_prefix("COMMAND: ") {
-                    output = stripped.to_string();
-                } else if !s.is_empty() {
-                    println!("{}", s);
-                    output.clear();
-                } else {
-                    output.clear();
-                }
-            }
-            Err(e) => eprintln!("Error in command: {}", e),
-        }
-    }
-    output
+fn run_stdin_mode(shell: &mut Shell) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+    shell.run(&input, "<stdin>");
+    Ok(())
 }
 
-fn read_file(file_path: &PathBuf) -> Result<Vec<String>, std::io::Error> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-    let commands: Vec<String> = reader
-        .lines()
-        .take_while(|r| r.is_ok())
-        .filter_map(|r| r.ok())
-        .collect();
-
-    Ok(commands)
+/// Prints the syntax tree of a script, or of stdin, as JSON.
+fn dump_ast(file_path: Option<&PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let (input, name) = match file_path {
+        Some(file_path) => (std::fs::read(file_path)?, file_path.display().to_string()),
+        None => {
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input)?;
+            (input, "<stdin>".to_string())
+        }
+    };
+    let script = match parse(OsString::from_vec(input.clone())) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("{}", e.render(&input, &name));
+            return Ok(());
+        }
+    };
+    writeln!(
+        std::io::stdout(),
+        "{}",
+        serde_json::to_string_pretty(&script)?
+    )?;
+    Ok(())
 }
 
 fn aishrc_path() -> Result<PathBuf, std::io::Error> {
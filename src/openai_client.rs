@@ -1,7 +1,6 @@
 use crate::errors::{OpenAIError, SyntaxError};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::env;
 
 #[derive(Serialize)]
 struct Message {
@@ -41,14 +40,11 @@ impl OpenAIClient {
     pub fn new(api_key: Option<String>) -> Result<Self, SyntaxError> {
         let key = match api_key {
             Some(key) => key,
-            None => match env::var("OPENAI_API_KEY") {
-                Ok(key) => key,
-                Err(_) => {
-                    return Err(SyntaxError::InvalidOpenAIKey(
-                        "OPENAI_API_KEY not set".to_string(),
-                    ));
-                }
-            },
+            None => {
+                return Err(SyntaxError::InvalidOpenAIKey(
+                    "OPENAI_API_KEY not set".to_string(),
+                ));
+            }
         };
 
         Ok(OpenAIClient {
@@ -0,0 +1,130 @@
+//! The options that `set` and `shopt` turn on and off.
+
+use crate::glob::GlobFlags;
+
+/// Settings that change how commands are run.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// `set -e`: exit when a command fails, unless its status is tested
+    pub errexit: bool,
+    /// `set -u`: expanding a variable that is not set is an error
+    pub nounset: bool,
+    /// `set -x`: print each command to stderr, after `$PS4`, before it runs
+    pub xtrace: bool,
+    /// `set -o pipefail`: a pipeline fails with the status of its last
+    /// failing command, rather than that of its last command
+    pub pipefail: bool,
+    /// `set -C`: `>` does not overwrite existing files, while `>|` still does
+    pub noclobber: bool,
+    /// `set -f`: no pathname expansion
+    pub noglob: bool,
+    /// `set -n`: read commands without running them, in scripts
+    pub noexec: bool,
+    /// `set -H`: expand history references at the prompt
+    pub histexpand: bool,
+    /// A pattern that matches nothing expands to no words at all
+    pub nullglob: bool,
+    /// A pattern that matches nothing is an error
+    pub failglob: bool,
+    /// Patterns match names starting with `.` without a leading `.`
+    pub dotglob: bool,
+    /// Patterns match regardless of case
+    pub nocaseglob: bool,
+    /// A command that names a directory changes to it, at the prompt
+    pub autocd: bool,
+    /// Whether a line with history references is shown for editing after
+    /// they are expanded, rather than run straight away
+    pub hist_verify: bool,
+    /// Whether commands are being read from the prompt
+    pub interactive: bool,
+}
+
+/// An option that can be turned on and off by name.
+pub struct ShellOption {
+    pub name: &'static str,
+    /// The letter of the option in `set -e` style flags and `$-`
+    pub letter: Option<char>,
+    field: fn(&mut Options) -> &mut bool,
+}
+
+/// The options of `set -o`, sorted by name.
+pub const SET_OPTIONS: &[ShellOption] = &[
+    option("errexit", Some('e'), |o| &mut o.errexit),
+    option("histexpand", Some('H'), |o| &mut o.histexpand),
+    option("noclobber", Some('C'), |o| &mut o.noclobber),
+    option("noexec", Some('n'), |o| &mut o.noexec),
+    option("noglob", Some('f'), |o| &mut o.noglob),
+    option("nounset", Some('u'), |o| &mut o.nounset),
+    option("pipefail", None, |o| &mut o.pipefail),
+    option("xtrace", Some('x'), |o| &mut o.xtrace),
+];
+
+/// The options of `shopt`, sorted by name.
+pub const SHOPT_OPTIONS: &[ShellOption] = &[
+    option("autocd", None, |o| &mut o.autocd),
+    option("dotglob", None, |o| &mut o.dotglob),
+    option("failglob", None, |o| &mut o.failglob),
+    option("histverify", None, |o| &mut o.hist_verify),
+    option("nocaseglob", None, |o| &mut o.nocaseglob),
+    option("nullglob", None, |o| &mut o.nullglob),
+];
+
+const fn option(
+    name: &'static str,
+    letter: Option<char>,
+    field: fn(&mut Options) -> &mut bool,
+) -> ShellOption {
+    ShellOption {
+        name,
+        letter,
+        field,
+    }
+}
+
+/// Finds an option of `set -o` or of `shopt` by name.
+pub fn find_option(options: &'static [ShellOption], name: &str) -> Option<&'static ShellOption> {
+    options.iter().find(|option| option.name == name)
+}
+
+/// Finds an option of `set` by its letter.
+pub fn find_letter(letter: char) -> Option<&'static ShellOption> {
+    SET_OPTIONS
+        .iter()
+        .find(|option| option.letter == Some(letter))
+}
+
+impl ShellOption {
+    pub fn get(&self, options: &Options) -> bool {
+        let mut options = *options;
+        *(self.field)(&mut options)
+    }
+
+    pub fn set(&self, options: &mut Options, on: bool) {
+        *(self.field)(options) = on;
+    }
+}
+
+impl Options {
+    /// The letters of the options that are on, as `$-` lists them.
+    pub fn flags(&self) -> String {
+        // Programs are always remembered in the `hash` table, as with `set -h`
+        let mut flags = String::from("h");
+        if self.interactive {
+            flags.push('i');
+        }
+        flags.extend(
+            SET_OPTIONS
+                .iter()
+                .filter(|option| option.get(self))
+                .filter_map(|option| option.letter),
+        );
+        flags
+    }
+
+    pub fn glob_flags(&self) -> GlobFlags {
+        GlobFlags {
+            dotglob: self.dotglob,
+            nocaseglob: self.nocaseglob,
+        }
+    }
+}
@@ -1,103 +1,168 @@
-use crate::errors::SyntaxError;
-use crate::parsing::scanner::Scanner;
-use crate::token::{tokenize, Token, TokenType, Tokens};
+use crate::ast::ProcessSubstitution;
+use crate::errors::{ParseError, SyntaxError};
+use crate::format;
+use crate::parsing::parse;
+use crate::parsing::scanner::{Scanner, SpannedTokens};
+use crate::parsing::span::{Position, Span};
+use crate::token::{tokenize, Parameter, ParameterForm, Token, TokenType, Tokens};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 
-pub fn lex_impl(scanner: &mut Scanner<String>) -> Result<Tokens, SyntaxError> {
+pub fn lex_impl(scanner: &mut Scanner<&[u8]>) -> Result<SpannedTokens, ParseError> {
     let mut buffer = TokenBuffer::new();
+    // Where the subscript of an assignment to an array element ends, when the
+    // word being lexed is one
+    let mut subscript_end = None;
     loop {
+        if buffer.is_empty() {
+            buffer.start = scanner.position();
+        }
         if scanner.peek().is_none() {
-            buffer.save(TokenType::Group);
+            buffer.save_at(TokenType::Group, scanner.position());
             debug!("Reached EOF");
             break;
         }
 
         match scanner.peek().unwrap() {
-            '<' | ';' | '|' => {
-                buffer.save(TokenType::Group);
-                debug!("Meta: {}", scanner.peek().unwrap());
-                buffer.push(scanner.next()).save(TokenType::Meta);
+            b';' | b'(' | b')' | b'\n' => {
+                buffer.save_at(TokenType::Group, scanner.position());
+                trace!("Meta: {:?}", scanner.peek().unwrap() as char);
+                buffer.push(scanner.next());
+                buffer.save_at(TokenType::Meta, scanner.position());
             }
-            '>' => {
-                buffer.save(TokenType::Group);
+            b'|' => {
+                buffer.save_at(TokenType::Group, scanner.position());
                 buffer.push(scanner.next());
-                if Some('>') == scanner.peek() {
-                    debug!("Meta: >>");
+                if matches!(scanner.peek(), Some(b'|') | Some(b'&')) {
                     buffer.push(scanner.next());
-                } else {
-                    debug!("Meta: >");
                 }
-                buffer.save(TokenType::Meta);
+                trace!("Meta: {:?}", buffer.token);
+                buffer.save_at(TokenType::Meta, scanner.position());
+            }
+            b'#' if buffer.is_empty() => {
+                trace!("Comment");
+                while scanner.peek().is_some_and(|c| c != b'\n') {
+                    scanner.next();
+                }
+            }
+            b'<' | b'>' if scanner.peek_next(1) == Some(b'(') => {
+                trace!("Process substitution");
+                let substitution = lex_process_substitution(scanner)?;
+                buffer.push_token(substitution);
+            }
+            b'<' | b'>' => {
+                buffer.save_at(TokenType::Group, scanner.position());
+                lex_redirect(scanner, &mut buffer);
+            }
+            c if c.is_ascii_digit() && buffer.is_empty() && is_io_number(scanner) => {
+                lex_redirect(scanner, &mut buffer);
             }
-            '&' => {
-                buffer.save(TokenType::Group);
+            b'&' => {
+                buffer.save_at(TokenType::Group, scanner.position());
                 let c = scanner.next();
-                if Some('&') == scanner.peek() {
-                    debug!("Meta: &&");
+                if Some(b'&') == scanner.peek() {
+                    trace!("Meta: &&");
                     buffer.push(c);
                     buffer.push(scanner.next());
-                    buffer.save(TokenType::Meta);
+                    buffer.save_at(TokenType::Meta, scanner.position());
                 } else {
-                    return Err(SyntaxError::UnexpectedToken("&".to_string()));
+                    trace!("Meta: &");
+                    buffer.push(c);
+                    buffer.save_at(TokenType::Meta, scanner.position());
                 }
             }
-            '$' => {
-                debug!("Variable");
+            b'$' if scanner.peek_next(1) == Some(b'\'') => {
+                trace!("ANSI-C quote");
+                let quoted = lex_ansi_c(scanner)?;
+                buffer.push_token(quoted);
+            }
+            b'$' => {
+                trace!("Variable");
                 let variable_token = lex_variable(scanner)?;
-                buffer.push_token(variable_token.first().unwrap().clone());
+                buffer.push_tokens(variable_token);
             }
-            '~' => {
-                debug!("Tilde");
-                let c = scanner.peek_next(1);
-                if c.is_none()
-                    || c.unwrap() == '/'
-                    || is_meta(c.unwrap())
-                    || is_whitespace(c.unwrap())
-                {
-                    scanner.next();
-                    buffer.push_token(Token::Tilde);
-                } else {
-                    scanner.next();
-                    buffer.push_token(Token::Plain('~'.to_string()));
-                }
+            b'~' if buffer.is_empty() => {
+                trace!("Tilde");
+                scanner.next();
+                match lex_tilde_prefix(scanner) {
+                    Some(prefix) => buffer.push_token(Token::Tilde(prefix)),
+                    None => buffer.push(b'~'),
+                };
             }
-            ' ' => {
-                debug!("Whitespace");
-                buffer.save(TokenType::Group);
+            c if is_whitespace(c) => {
+                trace!("Whitespace");
+                buffer.save_at(TokenType::Group, scanner.position());
                 scanner.next();
             }
             _ => {
+                if buffer.is_empty() {
+                    subscript_end = assignment_subscript_end(scanner);
+                }
                 let mut escaped = false;
                 let mut quote_type = QuoteType::None;
+                let mut quote_start = Position::start();
                 let mut sub_buffer = TokenBuffer::new();
 
                 loop {
-                    let c = scanner.peek();
-
-                    if c.is_none() {
+                    let Some(c) = scanner.peek() else {
                         sub_buffer.save(TokenType::Plain);
-                        debug!("EOF");
+                        trace!("EOF");
                         break;
-                    } else if is_escape(c.unwrap()) {
-                        debug!("Escape: {}", c.unwrap());
-                        if escaped || quote_type.single() {
+                    };
+
+                    if escaped && c == b'\n' {
+                        trace!("Line continuation");
+                        scanner.next();
+                        escaped = false;
+                    } else if escaped && !quote_type.quoted() {
+                        trace!("Escaped: {}", c as char);
+                        sub_buffer.push_escaped(scanner.next());
+                        escaped = false;
+                    } else if is_escape(c) {
+                        trace!("Escape: {}", c as char);
+                        // In double quotes a backslash only escapes the
+                        // characters that are special there, and is kept
+                        // before any other
+                        let literal = quote_type.double()
+                            && !matches!(
+                                scanner.peek_next(1),
+                                Some(b'$' | b'`' | b'"' | b'\\' | b'\n')
+                            );
+                        if escaped || quote_type.single() || literal {
                             sub_buffer.push(scanner.next());
                             escaped = false;
                         } else {
                             scanner.next();
                             escaped = true;
                         }
-                    } else if c.unwrap() == '$' {
+                    } else if c == b'$' {
                         if escaped || quote_type.single() {
                             sub_buffer.push(scanner.next());
                             escaped = false;
                             continue;
                         }
 
-                        debug!("Variable");
+                        if !quote_type.double() && scanner.peek_next(1) == Some(b'\'') {
+                            trace!("ANSI-C quote");
+                            let quoted = lex_ansi_c(scanner)?;
+                            sub_buffer.save(TokenType::Plain);
+                            sub_buffer.push_token(quoted);
+                            sub_buffer.save(TokenType::Group);
+                            continue;
+                        }
+
+                        trace!("Variable");
                         let variable_token = lex_variable(scanner)?;
-                        sub_buffer.push_token(variable_token.first().unwrap().clone());
-                    } else if is_meta(c.unwrap()) {
-                        debug!("Meta: {}", c.unwrap());
+                        if quote_type.double() {
+                            sub_buffer.push_tokens(variable_token);
+                        } else {
+                            // Keep the variable as its own part so it is resolved when run
+                            sub_buffer.save(TokenType::Plain);
+                            sub_buffer.push_tokens(variable_token);
+                            sub_buffer.save(TokenType::Group);
+                        }
+                    } else if is_meta(c) {
+                        trace!("Meta: {}", c as char);
                         if escaped || quote_type.quoted() {
                             sub_buffer.push(scanner.next());
                             escaped = false;
@@ -105,85 +170,417 @@ pub fn lex_impl(scanner: &mut Scanner<String>) -> Result<Tokens, SyntaxError> {
                             sub_buffer.save(TokenType::Plain);
                             break;
                         }
-                    } else if is_whitespace(c.unwrap()) {
-                        debug!("Whitespace");
-                        if escaped || quote_type.quoted() {
+                    } else if is_whitespace(c) {
+                        trace!("Whitespace");
+                        let in_subscript =
+                            subscript_end.is_some_and(|end| scanner.position().offset < end);
+                        if escaped || quote_type.quoted() || in_subscript {
                             sub_buffer.push(scanner.next());
                             escaped = false;
                         } else {
                             sub_buffer.save(TokenType::Plain);
                             break;
                         }
-                    } else if is_double_quote(c.unwrap()) {
-                        debug!("Double quote");
+                    } else if is_double_quote(c) {
+                        trace!("Double quote");
+                        let start = scanner.position();
                         let c = scanner.next();
                         if escaped || quote_type.single() {
                             sub_buffer.push(c);
                             escaped = false;
                         } else if quote_type.double() {
-                            sub_buffer.save(TokenType::DoubleQuoted);
+                            sub_buffer.save_quoted(TokenType::DoubleQuoted);
                             quote_type = QuoteType::None;
                             break;
                         } else {
                             sub_buffer.save(TokenType::Plain);
                             quote_type = QuoteType::Double;
+                            quote_start = start;
                             escaped = false;
                         }
-                    } else if is_single_quote(c.unwrap()) {
-                        debug!("Single quote");
+                    } else if is_single_quote(c) {
+                        trace!("Single quote");
+                        let start = scanner.position();
                         let c = scanner.next();
                         if escaped || quote_type.double() {
                             sub_buffer.push(c);
                             escaped = false;
                         } else if quote_type.single() {
-                            sub_buffer.save(TokenType::SingleQuoted);
+                            sub_buffer.save_quoted(TokenType::SingleQuoted);
                             quote_type = QuoteType::None;
                             break;
                         } else {
                             sub_buffer.save(TokenType::Plain);
                             quote_type = QuoteType::Single;
+                            quote_start = start;
                             escaped = false;
                         }
                     } else {
-                        debug!("Char: {}", c.unwrap());
+                        trace!("Char: {}", c as char);
                         sub_buffer.push(scanner.next());
                         escaped = false;
                     }
                 }
 
                 if quote_type.quoted() {
-                    return Err(SyntaxError::UnclosedQuote);
+                    return Err(ParseError::new(
+                        SyntaxError::UnclosedQuote,
+                        Span::new(quote_start, scanner.position()),
+                    ));
                 }
 
                 buffer.push_tokens(sub_buffer.tokens());
             }
         }
     }
-    Ok(buffer.tokens())
+    Ok(buffer.spanned_tokens())
 }
 
-fn lex_variable(scanner: &mut Scanner<String>) -> Result<Tokens, SyntaxError> {
+/// Lexes a redirection operator with an optional file descriptor prefix,
+/// e.g. `<`, `>>`, `>|`, `2>` or `2>&`.
+fn lex_redirect(scanner: &mut Scanner<&[u8]>, buffer: &mut TokenBuffer) {
+    while scanner.peek().is_some_and(|c| c.is_ascii_digit()) {
+        buffer.push(scanner.next());
+    }
+
+    let c = scanner.next();
+    buffer.push(c);
+    let second = scanner.peek();
+    if (c == b'>' && matches!(second, Some(b'>') | Some(b'|'))) || second == Some(b'&') {
+        buffer.push(scanner.next());
+    }
+    buffer.save_at(TokenType::Meta, scanner.position());
+    debug!("Meta: {:?}", buffer.tokens.last());
+}
+
+/// Where the subscript ends when the scanner is at the start of an assignment
+/// to an array element, such as `map[two words]=1`, so that blanks inside it
+/// do not end the word.
+fn assignment_subscript_end(scanner: &Scanner<&[u8]>) -> Option<usize> {
+    let mut i = 0;
+    while scanner
+        .peek_next(i)
+        .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+    {
+        i += 1;
+    }
+    if i == 0 || scanner.peek().is_some_and(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut depth = 0;
+    loop {
+        match scanner.peek_next(i)? {
+            b'[' => depth += 1,
+            b']' if depth > 0 => depth -= 1,
+            b'\n' => return None,
+            _ if depth == 0 => return None,
+            _ => {}
+        }
+        i += 1;
+        if depth == 0 {
+            break;
+        }
+    }
+    match (scanner.peek_next(i), scanner.peek_next(i + 1)) {
+        (Some(b'='), _) | (Some(b'+'), Some(b'=')) => Some(scanner.position().offset + i),
+        _ => None,
+    }
+}
+
+/// Whether the scanner is at a file descriptor number directly followed by
+/// a redirection operator, as in `2>err`.
+fn is_io_number(scanner: &Scanner<&[u8]>) -> bool {
+    let mut i = 0;
+    while scanner.peek_next(i).is_some_and(|c| c.is_ascii_digit()) {
+        i += 1;
+    }
+    matches!(scanner.peek_next(i), Some(b'<') | Some(b'>'))
+}
+
+fn lex_variable(scanner: &mut Scanner<&[u8]>) -> Result<Tokens, ParseError> {
+    let start = scanner.position();
+    let unexpected = |scanner: &Scanner<&[u8]>| {
+        ParseError::new(
+            SyntaxError::UnexpectedToken("$".to_string()),
+            Span::new(start, scanner.position()),
+        )
+    };
     match scanner.peek() {
-        Some('$') => scanner.next(),
-        _ => return Err(SyntaxError::UnexpectedToken("$".to_string())),
+        Some(b'$') => scanner.next(),
+        _ => return Err(unexpected(scanner)),
     };
 
-    let mut buffer = TokenBuffer::new();
+    if scanner.peek() == Some(b'{') {
+        return lex_braced(scanner, start).map(|token| vec![token]);
+    }
+
+    // A name runs for as long as it can, while the special parameters and
+    // positional parameters are a single character, so `$10` is `${1}0`
+    let mut name = Vec::new();
+    match scanner.peek() {
+        Some(c) if is_special_parameter(c) || c.is_ascii_digit() => name.push(scanner.next()),
+        Some(c) if c.is_ascii_alphabetic() || c == b'_' => {
+            while scanner
+                .peek()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+            {
+                name.push(scanner.next());
+            }
+        }
+        // A `$` that does not start a name stands for itself, as in `^a$`
+        _ => return Ok(vec![Token::Plain("$".into())]),
+    }
+
+    // Only the lexer knows which line `$LINENO` is written on
+    if name == b"LINENO" {
+        return Ok(vec![Token::Plain(start.line.to_string().into())]);
+    }
+    Ok(vec![Token::Variable(
+        String::from_utf8(name).expect("names are ASCII"),
+    )])
+}
+
+/// Lexes `<(list)` or `>(list)`, with the scanner at the `<` or `>`. The list
+/// runs up to the `)` that matches the `(`, and is parsed here so that its
+/// syntax errors are found with the rest of the command.
+fn lex_process_substitution(scanner: &mut Scanner<&[u8]>) -> Result<Token, ParseError> {
+    let start = scanner.position();
+    let output = scanner.next() == b'>';
+    scanner.next(); // Consume `(`
+
+    let mut text = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
     loop {
-        let c = scanner.peek();
-        if c.is_none() || is_break_point(c.unwrap()) {
+        let Some(c) = scanner.peek() else {
+            return Err(ParseError::new(
+                SyntaxError::UnexpectedEof(")".to_string()),
+                Span::new(start, scanner.position()),
+            ));
+        };
+        match (c, quote) {
+            (b')', None) if depth == 0 => {
+                scanner.next();
+                break;
+            }
+            (b'(', None) => depth += 1,
+            (b')', None) => depth -= 1,
+            (b'\\', q) if q != Some(b'\'') => {
+                text.push(scanner.next());
+                if scanner.peek().is_some() {
+                    text.push(scanner.next());
+                }
+                continue;
+            }
+            (b'"' | b'\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            _ => {}
+        }
+        text.push(scanner.next());
+    }
+
+    let list = parse(OsString::from_vec(text))
+        .map_err(|e| ParseError::new(e.error, Span::new(start, scanner.position())))?;
+    let text = String::from_utf8_lossy(scanner.text_since(start)).into_owned();
+    Ok(Token::ProcessSubstitution(Box::new(ProcessSubstitution {
+        output,
+        list,
+        text,
+    })))
+}
+
+/// Lexes the prefix after a `~` at the start of a word: the characters up to
+/// the first `/`, which name a user as in `~alice/src`, are `+` or `-`, or
+/// are empty. Returns `None` without consuming anything when one of them is
+/// quoted or expanded, in which case the `~` is kept as it is.
+fn lex_tilde_prefix(scanner: &mut Scanner<&[u8]>) -> Option<String> {
+    let mut length = 0;
+    while let Some(c) = scanner.peek_next(length) {
+        if c == b'/' || is_meta(c) || is_whitespace(c) {
             break;
-        } else {
-            buffer.push(scanner.next());
         }
+        if matches!(c, b'\'' | b'"' | b'\\' | b'$' | b'`') {
+            return None;
+        }
+        length += 1;
+    }
+    let prefix: Vec<u8> = (0..length).map(|_| scanner.next()).collect();
+    Some(String::from_utf8_lossy(&prefix).into_owned())
+}
+
+/// Lexes a `$'...'` string, with the scanner at the `$`. Its backslash
+/// escapes are expanded here, and the result is quoted like `'...'`.
+fn lex_ansi_c(scanner: &mut Scanner<&[u8]>) -> Result<Token, ParseError> {
+    let start = scanner.position();
+    scanner.next(); // Consume `$`
+    scanner.next(); // Consume `'`
+    let mut text = Vec::new();
+    loop {
+        match scanner.peek() {
+            None => {
+                return Err(ParseError::new(
+                    SyntaxError::UnclosedQuote,
+                    Span::new(start, scanner.position()),
+                ));
+            }
+            Some(b'\'') => {
+                scanner.next();
+                break;
+            }
+            // An escaped quote does not end the string
+            Some(b'\\') => {
+                text.push(scanner.next());
+                if scanner.peek().is_some() {
+                    text.push(scanner.next());
+                }
+            }
+            Some(_) => text.push(scanner.next()),
+        }
+    }
+    let text = OsString::from_vec(format::unescape_ansi_c(&text));
+    Ok(Token::SingleQuoted(vec![Token::Plain(text)]))
+}
+
+/// Lexes a parameter in braces, such as `${name}`, `${#name}`, `${array[1]}`
+/// or `${!array[@]}`, with the scanner at the `{`.
+fn lex_braced(scanner: &mut Scanner<&[u8]>, start: Position) -> Result<Token, ParseError> {
+    let error = |scanner: &Scanner<&[u8]>, error: SyntaxError| {
+        ParseError::new(error, Span::new(start, scanner.position()))
+    };
+    let bad_substitution = |scanner: &Scanner<&[u8]>| {
+        let text = String::from_utf8_lossy(scanner.text_since(start)).into_owned();
+        error(scanner, SyntaxError::UnexpectedToken(text))
+    };
+    scanner.next();
+
+    // `${#}` and `${!}` are the special parameters themselves
+    let form = match (scanner.peek(), scanner.peek_next(1)) {
+        (Some(b'#'), Some(c)) if c != b'}' => ParameterForm::Length,
+        (Some(b'!'), Some(c)) if c != b'}' => ParameterForm::Keys,
+        _ => ParameterForm::Value,
+    };
+    if form != ParameterForm::Value {
+        scanner.next();
+    }
+
+    let mut name = Vec::new();
+    match scanner.peek() {
+        Some(c) if is_special_parameter(c) => name.push(scanner.next()),
+        Some(c) if c.is_ascii_digit() => {
+            while scanner.peek().is_some_and(|c| c.is_ascii_digit()) {
+                name.push(scanner.next());
+            }
+        }
+        Some(c) if c.is_ascii_alphabetic() || c == b'_' => {
+            while scanner
+                .peek()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+            {
+                name.push(scanner.next());
+            }
+        }
+        None => return Err(error(scanner, SyntaxError::UnexpectedEof("}".to_string()))),
+        Some(_) => return Err(bad_substitution(scanner)),
+    }
+    let name = String::from_utf8(name).expect("names are ASCII");
+
+    let subscript = match scanner.peek() {
+        Some(b'[') => {
+            scanner.next();
+            match lex_subscript(scanner)? {
+                Some(subscript) => Some(Box::new(subscript)),
+                None if scanner.peek().is_none() => {
+                    return Err(error(scanner, SyntaxError::UnexpectedEof("}".to_string())));
+                }
+                None => return Err(bad_substitution(scanner)),
+            }
+        }
+        _ => None,
+    };
+
+    match scanner.peek() {
+        Some(b'}') => scanner.next(),
+        None => return Err(error(scanner, SyntaxError::UnexpectedEof("}".to_string()))),
+        Some(_) => return Err(bad_substitution(scanner)),
+    };
+
+    // Only a list of every element has keys to list
+    let every = matches!(subscript.as_deref(), Some(Token::Plain(s)) if s == "@" || s == "*");
+    if form == ParameterForm::Keys && !every {
+        return Err(bad_substitution(scanner));
+    }
+    if form == ParameterForm::Value && subscript.is_none() {
+        if name == "LINENO" {
+            return Ok(Token::Plain(start.line.to_string().into()));
+        }
+        return Ok(Token::Variable(name));
     }
+    Ok(Token::Parameter(Parameter {
+        name,
+        subscript,
+        form,
+    }))
+}
 
-    if buffer.is_empty() {
-        return Err(SyntaxError::UnexpectedToken("$".to_string()));
+/// Lexes the subscript of an array element up to the `]` that closes it,
+/// with the scanner just after the `[`. The subscript is expanded like a
+/// word, but blanks in it are kept. Returns `None`, with the scanner where
+/// it stopped, if the braces or the line end first.
+fn lex_subscript(scanner: &mut Scanner<&[u8]>) -> Result<Option<Token>, ParseError> {
+    let mut text = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    loop {
+        let Some(c) = scanner.peek() else {
+            return Ok(None);
+        };
+        match (c, quote) {
+            (b'}' | b'\n', None) => return Ok(None),
+            (b']', None) if depth == 0 => {
+                scanner.next();
+                break;
+            }
+            (b'[', None) => depth += 1,
+            (b']', None) => depth -= 1,
+            (b'\\', q) if q != Some(b'\'') => {
+                text.push(scanner.next());
+                if scanner.peek().is_some() {
+                    text.push(scanner.next());
+                }
+                continue;
+            }
+            (b'"' | b'\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            _ => {}
+        }
+        text.push(scanner.next());
     }
 
-    buffer.save(TokenType::Variable);
-    Ok(buffer.tokens())
+    let mut tokens = Vec::new();
+    let mut end = 0;
+    let mut inner = Scanner::new(&text[..]);
+    for (token, span) in lex_impl(&mut inner)? {
+        if span.start.offset > end {
+            tokens.push(Token::Plain(OsString::from_vec(
+                text[end..span.start.offset].to_vec(),
+            )));
+        }
+        end = span.end.offset;
+        tokens.push(token);
+    }
+    if end < text.len() {
+        tokens.push(Token::Plain(OsString::from_vec(text[end..].to_vec())));
+    }
+    Ok(Some(match tokens.len() {
+        0 => Token::Plain(OsString::new()),
+        1 => tokens.pop().unwrap(),
+        _ => Token::Group(tokens),
+    }))
+}
+
+/// Whether `c` names a special parameter, such as `$?` or `$$`.
+fn is_special_parameter(c: u8) -> bool {
+    b"$!-?#@*".contains(&c)
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -210,6 +607,10 @@ impl QuoteType {
 struct TokenBuffer {
     token: Vec<Token>,
     tokens: Vec<Token>,
+    /// The spans of `tokens`, for buffers saved with `save_at`.
+    spans: Vec<Span>,
+    /// Where the token being built started.
+    start: Position,
 }
 
 impl TokenBuffer {
@@ -217,11 +618,29 @@ impl TokenBuffer {
         TokenBuffer {
             token: Vec::<Token>::new(),
             tokens: Vec::<Token>::new(),
+            spans: Vec::<Span>::new(),
+            start: Position::start(),
+        }
+    }
+
+    /// Pushes a plain character, extending the current run of plain text so
+    /// that long words do not allocate a token per character.
+    fn push(&mut self, token: u8) -> &mut Self {
+        match self.token.last_mut() {
+            Some(Token::Plain(run)) => run.push(OsStr::from_bytes(&[token])),
+            _ => self
+                .token
+                .push(Token::Plain(OsString::from_vec(vec![token]))),
         }
+        self
     }
 
-    fn push(&mut self, token: char) -> &mut Self {
-        self.token.push(Token::Plain(token.to_string()));
+    /// Pushes a character escaped with a backslash outside of quotes. It is
+    /// kept quoted so that it does not take part in pathname expansion.
+    fn push_escaped(&mut self, token: u8) -> &mut Self {
+        self.save(TokenType::Plain);
+        self.push(token);
+        self.save(TokenType::SingleQuoted);
         self
     }
 
@@ -240,18 +659,37 @@ impl TokenBuffer {
             return false;
         }
         // One token groups are not necessary
-        if self.token.len() == 1 && token_type == TokenType::Group {
-            self.tokens.push(self.token.clone().pop().unwrap());
+        let mut token = std::mem::take(&mut self.token);
+        if token.len() == 1 && token_type == TokenType::Group {
+            self.tokens.push(token.pop().unwrap());
         } else {
-            let new_token = tokenize(self.token.clone(), token_type);
-            self.tokens.push(new_token);
+            self.tokens.push(tokenize(token, token_type));
         }
-        self.token.clear();
         true
     }
 
-    fn tokens(&self) -> Tokens {
-        self.tokens.clone()
+    /// Saves a quoted string, which is kept even when it is empty so that
+    /// `""` is still an argument.
+    fn save_quoted(&mut self, token_type: TokenType) {
+        let token = std::mem::take(&mut self.token);
+        self.tokens.push(tokenize(token, token_type));
+    }
+
+    /// Saves the token being built as ending at `end`, and starts the next
+    /// one there.
+    fn save_at(&mut self, token_type: TokenType, end: Position) {
+        if self.save(token_type) {
+            self.spans.push(Span::new(self.start, end));
+        }
+        self.start = end;
+    }
+
+    fn tokens(self) -> Tokens {
+        self.tokens
+    }
+
+    fn spanned_tokens(self) -> SpannedTokens {
+        self.tokens.into_iter().zip(self.spans).collect()
     }
 
     fn is_empty(&self) -> bool {
@@ -259,26 +697,24 @@ impl TokenBuffer {
     }
 }
 
-fn is_break_point(c: char) -> bool {
-    is_meta(c) || is_whitespace(c) || c == '$' || is_single_quote(c) || is_double_quote(c)
-}
-
-fn is_meta(c: char) -> bool {
-    ['&', '<', '>', ';', '|'].contains(&c)
+fn is_meta(c: u8) -> bool {
+    [b'&', b'<', b'>', b';', b'|', b'(', b')', b'\n'].contains(&c)
 }
 
-fn is_whitespace(c: char) -> bool {
-    c == ' '
+/// Whether a byte separates words. Newlines do too, but end the command as
+/// well, so they are lexed as meta characters.
+fn is_whitespace(c: u8) -> bool {
+    c == b' ' || c == b'\t'
 }
 
-fn is_escape(c: char) -> bool {
-    c == '\\'
+fn is_escape(c: u8) -> bool {
+    c == b'\\'
 }
 
-fn is_single_quote(c: char) -> bool {
-    c == '\''
+fn is_single_quote(c: u8) -> bool {
+    c == b'\''
 }
 
-fn is_double_quote(c: char) -> bool {
-    c == '"'
+fn is_double_quote(c: u8) -> bool {
+    c == b'"'
 }
@@ -2,28 +2,137 @@ mod lexer;
 mod parser;
 mod process;
 mod scanner;
+mod span;
 
-use std::error::Error;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
 
+use crate::ast::{AndOr, List};
+use crate::errors::ParseError;
 use crate::parsing::lexer::lex_impl;
-use crate::parsing::parser::parse_impl;
+use crate::parsing::parser::{parse_impl, parse_item, skip_line};
 use crate::parsing::process::process;
-use crate::parsing::scanner::Scanner;
-use crate::sequence::Sequence;
+use crate::parsing::scanner::{Scanner, Tokens};
 
-pub fn parse(input: String) -> Result<Sequence, Box<dyn Error>> {
-    debug!("User input: {}", input);
+pub use crate::parsing::parser::is_name;
+pub use crate::parsing::process::expand_history;
+pub use crate::parsing::span::{Position, Span};
+
+pub fn parse(input: OsString) -> Result<List, ParseError> {
+    debug!("User input: {:?}", input);
 
     let input = process(input);
-    debug!("Processed input: {}", input);
+    debug!("Processed input: {:?}", input);
 
-    let mut scanner = Scanner::new(input);
+    let input = input.into_vec();
+    let mut scanner = Scanner::new(&input[..]);
     let tokens = lex_impl(&mut scanner)?;
     debug!("Lexed tokens: {:?}", tokens);
 
-    let mut scanner = Scanner::new(tokens);
-    let commands = parse_impl(&mut scanner)?;
-    debug!("Parsed commands: {:?}", commands);
+    let mut scanner = Scanner::new(Tokens::new(tokens, Aliases::new()));
+    let list = parse_impl(&mut scanner)?;
+    debug!("Parsed list: {:?}", list);
+
+    Ok(list)
+}
+
+/// Aliases by name, with the text that replaces them.
+pub type Aliases = BTreeMap<String, String>;
+
+/// Parses a script one command at a time, so that each command can be run
+/// before the next is parsed and aliases it defines apply to the rest of the
+/// script. After a syntax error, parsing carries on from the next line, so
+/// that one bad line does not stop the rest of the script from running,
+/// unless the error runs to the end of the input as an unclosed quote does.
+pub struct ScriptParser {
+    input: Vec<u8>,
+    tokens: Scanner<Tokens>,
+    /// An error from lexing the input after the current tokens, and the line
+    /// to carry on lexing from after it
+    lex_error: Option<(ParseError, Option<Position>)>,
+}
+
+impl ScriptParser {
+    pub fn new(input: OsString) -> ScriptParser {
+        debug!("User input: {:?}", input);
+        let mut parser = ScriptParser {
+            input: process(input).into_vec(),
+            tokens: Scanner::new(Tokens::new(Vec::new(), Aliases::new())),
+            lex_error: None,
+        };
+        parser.lex_from(Position::start());
+        parser
+    }
+
+    /// Parses the next command with the aliases defined so far, or returns
+    /// `None` at the end of the script.
+    pub fn next(&mut self, aliases: &Aliases) -> Option<Result<AndOr, ParseError>> {
+        self.tokens.set_aliases(aliases);
+        match parse_item(&mut self.tokens) {
+            Ok(Some(and_or)) => Some(Ok(and_or)),
+            Ok(None) => {
+                let (error, next) = self.lex_error.take()?;
+                if let Some(next) = next {
+                    self.lex_from(next);
+                }
+                Some(Err(error))
+            }
+            Err(error) => {
+                debug!("Parsing error: {:?}", error);
+                skip_line(&mut self.tokens);
+                Some(Err(error))
+            }
+        }
+    }
+
+    fn lex_from(&mut self, start: Position) {
+        let aliases = Aliases::new();
+        let mut scanner = Scanner::starting_at(&self.input[..], start);
+        let error = match lex_impl(&mut scanner) {
+            Ok(tokens) => {
+                debug!("Lexed tokens: {:?}", tokens);
+                self.tokens = Scanner::new(Tokens::new(tokens, aliases));
+                return;
+            }
+            Err(error) => error,
+        };
+        debug!("Lexing error: {:?}", error);
+
+        // Everything on the lines before the error is complete, so it is
+        // lexed again on its own and parsed before the error is reported
+        let line_start = line_start(&self.input, error.span.start);
+        let mut scanner = Scanner::starting_at(&self.input[..line_start.offset], start);
+        let tokens = lex_impl(&mut scanner).unwrap_or_default();
+        self.tokens = Scanner::new(Tokens::new(tokens, aliases));
+
+        // Lexing carries on after the line the error ends on, so none of the
+        // text inside an unclosed quote is run. An error that reaches the end
+        // of the input leaves nothing to carry on with.
+        let next = next_line(&self.input, error.span.end);
+        self.lex_error = Some((error, next));
+    }
+}
+
+/// The start of the line that `position` is on.
+fn line_start(input: &[u8], position: Position) -> Position {
+    let offset = input[..position.offset]
+        .iter()
+        .rposition(|&c| c == b'\n')
+        .map_or(0, |i| i + 1);
+    Position {
+        offset,
+        line: position.line,
+        column: 1,
+    }
+}
 
-    Ok(commands)
+/// The start of the line after the one that `position` is on, if there is one.
+fn next_line(input: &[u8], position: Position) -> Option<Position> {
+    let newline = input[position.offset..].iter().position(|&c| c == b'\n')?;
+    Some(Position {
+        offset: position.offset + newline + 1,
+        line: position.line + 1,
+        column: 1,
+    })
 }
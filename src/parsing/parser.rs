@@ -1,157 +1,871 @@
-use crate::command::CommandType;
-use crate::errors::SyntaxError;
-use crate::parsing::scanner::Scanner;
-use crate::pipeline::Pipeline;
-use crate::redirect::{InputRedirect, OutputRedirect, OutputRedirectAppend};
-use crate::sequence::{AndSequence, Sequence};
-use crate::token::{Token, Tokens};
-
-pub fn parse_impl(tokens: &mut Scanner<Tokens>) -> Result<Sequence, SyntaxError> {
-    // State variables
-    let mut in_and_sequence = false;
-    let mut in_pipeline = false;
-
-    // Final commands
-    let mut final_commands = Sequence::new();
-    let mut and_sequence = AndSequence::new();
-    let mut pipeline = Pipeline::new();
+use crate::ast::{
+    AndOr, Assignment, AssignmentValue, Command, CompoundCommand, ConditionalExpression, Connector,
+    FunctionDefinition, List, Pipeline, SimpleCommand, TimeFormat, Word,
+};
+use crate::conditional::{is_binary_operator, is_unary_operator};
+use crate::errors::{ParseError, SyntaxError};
+use crate::parsing::lexer::lex_impl;
+use crate::parsing::scanner::{Scanner, Tokens};
+use crate::redirect::{is_redirect, Redirect};
+use crate::token::{lossy, Token};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 
+/// Words that end a list when they appear where a command could start.
+const TERMINATORS: &[&str] = &["then", "elif", "else", "fi", "do", "done", "}"];
+
+pub fn parse_impl(tokens: &mut Scanner<Tokens>) -> Result<List, ParseError> {
+    let mut list = List::default();
+    while let Some(and_or) = parse_item(tokens)? {
+        list.items.push(and_or);
+    }
+    Ok(list)
+}
+
+/// Parses the next command of a script, along with the `;` or newline after
+/// it, or returns `None` at the end of the input.
+pub fn parse_item(tokens: &mut Scanner<Tokens>) -> Result<Option<AndOr>, ParseError> {
+    skip_newlines(tokens);
+    if tokens.peek().is_none() {
+        return Ok(None);
+    }
+
+    let mut and_or = parse_and_or(tokens)?;
+    debug!("Parsed and-or list: {:?}", and_or);
+    match tokens.peek() {
+        Some(Token::Meta(m)) if m == ";" || m == "\n" || m == "&" => {
+            and_or.background = m == "&";
+            tokens.next(); // Consume token
+        }
+        None => (),
+        Some(_) => return Err(unexpected(tokens, "end of command")),
+    }
+    Ok(Some(and_or))
+}
+
+/// Skips the rest of the line after a syntax error, so that parsing can
+/// carry on with the next one.
+pub fn skip_line(tokens: &mut Scanner<Tokens>) {
+    while let Some(token) = tokens.peek() {
+        tokens.next();
+        if token == Token::Meta("\n".to_string()) {
+            break;
+        }
+    }
+}
+
+/// Parses and-or lists separated by `;`, `&` or newlines, up to the end of the
+/// input or a word that closes the enclosing compound command.
+fn parse_list(tokens: &mut Scanner<Tokens>) -> Result<List, ParseError> {
+    let mut list = List::default();
     loop {
-        debug!("Parsing tokens");
+        skip_newlines(tokens);
+        if at_list_end(tokens) {
+            debug!("End of list");
+            break;
+        }
 
-        if tokens.peek().is_none() {
-            debug!("End of tokens");
+        let mut and_or = parse_and_or(tokens)?;
+        debug!("Parsed and-or list: {:?}", and_or);
+        let separator = match tokens.peek() {
+            Some(Token::Meta(m)) if m == ";" || m == "\n" || m == "&" => {
+                tokens.next(); // Consume token
+                Some(m)
+            }
+            _ => None,
+        };
+        and_or.background = separator.as_deref() == Some("&");
+        list.items.push(and_or);
+        if separator.is_none() {
             break;
         }
+    }
+    Ok(list)
+}
 
-        let command = parse_cmd_impl(tokens)?;
-        debug!("Parsed command: {:?}", command);
+/// Parses a list that must contain at least one command, such as the body
+/// of a loop.
+fn parse_compound_list(tokens: &mut Scanner<Tokens>, expected: &str) -> Result<List, ParseError> {
+    let list = parse_list(tokens)?;
+    if list.items.is_empty() {
+        return Err(unexpected(tokens, expected));
+    }
+    Ok(list)
+}
 
-        match tokens.peek().unwrap() {
-            Token::Meta(m) if m == ";" => {
+fn parse_and_or(tokens: &mut Scanner<Tokens>) -> Result<AndOr, ParseError> {
+    let first = parse_pipeline(tokens)?;
+    let mut rest = Vec::new();
+    loop {
+        let connector = match tokens.peek() {
+            Some(Token::Meta(m)) if m == "&&" => Connector::And,
+            Some(Token::Meta(m)) if m == "||" => Connector::Or,
+            _ => break,
+        };
+        tokens.next(); // Consume token
+        skip_newlines(tokens);
+        rest.push((connector, parse_pipeline(tokens)?));
+    }
+    Ok(AndOr {
+        first,
+        rest,
+        background: false,
+    })
+}
+
+fn parse_pipeline(tokens: &mut Scanner<Tokens>) -> Result<Pipeline, ParseError> {
+    // `!` may come before or after `time`, and negates the pipeline either way
+    let mut negated = parse_bang(tokens);
+    let time = parse_time(tokens);
+    negated ^= parse_bang(tokens);
+    // `time` or `!` on its own applies to an empty pipeline
+    if (time.is_some() || negated) && ends_pipeline(tokens) {
+        return Ok(Pipeline {
+            commands: Vec::new(),
+            pipe_stderr: Vec::new(),
+            negated,
+            time,
+        });
+    }
+
+    let mut commands = vec![parse_command(tokens)?];
+    let mut pipe_stderr = Vec::new();
+    while let Some(Token::Meta(m)) = tokens.peek() {
+        if m != "|" && m != "|&" {
+            break;
+        }
+        pipe_stderr.push(m == "|&");
+        tokens.next(); // Consume token
+        skip_newlines(tokens);
+        commands.push(parse_command(tokens)?);
+    }
+    Ok(Pipeline {
+        commands,
+        pipe_stderr,
+        negated,
+        time,
+    })
+}
+
+/// Parses any number of `!` reserved words in front of a pipeline, returning
+/// whether there were an odd number of them.
+fn parse_bang(tokens: &mut Scanner<Tokens>) -> bool {
+    let mut negated = false;
+    while matches!(tokens.peek(), Some(Token::Plain(w)) if w == "!") {
+        tokens.next(); // Consume token
+        negated = !negated;
+    }
+    negated
+}
+
+/// Parses the `time` reserved word in front of a pipeline, with `-p` for
+/// the POSIX format of the report.
+fn parse_time(tokens: &mut Scanner<Tokens>) -> Option<TimeFormat> {
+    if !matches!(tokens.peek(), Some(Token::Plain(w)) if w == "time") {
+        return None;
+    }
+    tokens.next(); // Consume token
+    match tokens.peek() {
+        Some(Token::Plain(w)) if w == "-p" => {
+            tokens.next(); // Consume token
+            Some(TimeFormat::Posix)
+        }
+        _ => Some(TimeFormat::Default),
+    }
+}
+
+/// Whether the next token ends the pipeline before any command, such as the
+/// `;` or newline after a lone `time` or `!`.
+fn ends_pipeline(tokens: &Scanner<Tokens>) -> bool {
+    match tokens.peek() {
+        Some(Token::Meta(m)) => m != "(",
+        _ => at_list_end(tokens),
+    }
+}
+
+fn parse_command(tokens: &mut Scanner<Tokens>) -> Result<Command, ParseError> {
+    let mut expanded = Vec::new();
+    let after_blank = expand_alias(tokens, &mut expanded)?;
+    let compound = match tokens.peek() {
+        Some(Token::Meta(m)) if m == "(" => {
+            tokens.next(); // Consume token
+            let list = parse_compound_list(tokens, ")")?;
+            expect_meta(tokens, ")")?;
+            CompoundCommand::Subshell(list)
+        }
+        Some(Token::Plain(word)) => match word.to_str() {
+            Some("{") => {
                 tokens.next(); // Consume token
-                if in_pipeline && in_and_sequence {
-                    in_pipeline = false;
-                    in_and_sequence = false;
-                    pipeline.add(command.unpack_cmd());
-                    and_sequence.add(Box::new(pipeline.transfer()));
-                    final_commands.add(Box::new(and_sequence.transfer()));
-                } else if in_pipeline {
-                    in_pipeline = false;
-                    pipeline.add(command.unpack_cmd());
-                    final_commands.add(Box::new(pipeline.transfer()));
-                } else if in_and_sequence {
-                    in_and_sequence = false;
-                    and_sequence.add(command.unpack_run());
-                    final_commands.add(Box::new(and_sequence.transfer()));
-                } else {
-                    final_commands.add(command.unpack_run());
-                }
+                let list = parse_compound_list(tokens, "}")?;
+                expect_word(tokens, "}")?;
+                CompoundCommand::BraceGroup(list)
+            }
+            Some("if") => parse_if(tokens)?,
+            Some("while") | Some("until") => parse_while(tokens)?,
+            Some("for") => parse_for(tokens)?,
+            Some("[[") => parse_conditional(tokens)?,
+            Some(w) if TERMINATORS.contains(&w) => {
+                return Err(unexpected(tokens, "command"));
             }
-            Token::Meta(m) if m == "&&" => {
+            Some(_) if is_function_definition(tokens) => {
+                return parse_function_definition(tokens);
+            }
+            _ => return parse_simple_command(tokens, expanded, after_blank),
+        },
+        _ => return parse_simple_command(tokens, expanded, after_blank),
+    };
+
+    debug!("Parsed compound command: {:?}", compound);
+    Ok(Command::Compound(compound, parse_redirects(tokens)?))
+}
+
+fn parse_if(tokens: &mut Scanner<Tokens>) -> Result<CompoundCommand, ParseError> {
+    tokens.next(); // Consume `if`
+    let mut branches = Vec::new();
+    let mut else_body = None;
+    loop {
+        let condition = parse_compound_list(tokens, "then")?;
+        expect_word(tokens, "then")?;
+        let body = parse_compound_list(tokens, "fi")?;
+        branches.push((condition, body));
+
+        match tokens.peek() {
+            Some(Token::Plain(w)) if w == "elif" => {
                 tokens.next(); // Consume token
-                in_and_sequence = true;
-                if in_pipeline {
-                    in_pipeline = false;
-                    pipeline.add(command.unpack_cmd());
-                    and_sequence.add(Box::new(pipeline.transfer()));
-                } else {
-                    and_sequence.add(command.unpack_run());
-                }
             }
-            Token::Meta(m) if m == "|" => {
+            Some(Token::Plain(w)) if w == "else" => {
                 tokens.next(); // Consume token
-                in_pipeline = true;
-                pipeline.add(command.unpack_cmd());
+                else_body = Some(parse_compound_list(tokens, "fi")?);
+                expect_word(tokens, "fi")?;
+                break;
             }
             _ => {
-                let token = tokens.next().resolve();
-                return Err(SyntaxError::UnexpectedToken(token));
+                expect_word(tokens, "fi")?;
+                break;
             }
         }
     }
+    Ok(CompoundCommand::If {
+        branches,
+        else_body,
+    })
+}
 
-    Ok(final_commands)
+fn parse_while(tokens: &mut Scanner<Tokens>) -> Result<CompoundCommand, ParseError> {
+    let keyword = tokens.next();
+    let condition = parse_compound_list(tokens, "do")?;
+    let body = parse_do_group(tokens)?;
+    if keyword == Token::Plain("until".into()) {
+        Ok(CompoundCommand::Until { condition, body })
+    } else {
+        Ok(CompoundCommand::While { condition, body })
+    }
 }
 
-fn parse_cmd_impl(tokens: &mut Scanner<Tokens>) -> Result<CommandType, SyntaxError> {
-    let mut command_tokens = Vec::<Token>::new();
+fn parse_for(tokens: &mut Scanner<Tokens>) -> Result<CompoundCommand, ParseError> {
+    tokens.next(); // Consume `for`
+    let variable = match tokens.peek() {
+        Some(Token::Plain(name)) if name.to_str().is_some_and(is_name) => {
+            tokens.next();
+            lossy(name)
+        }
+        _ => return Err(unexpected(tokens, "variable name after for")),
+    };
 
-    loop {
-        if tokens.peek().is_none() {
-            debug!("End of tokens");
+    // Without `in`, the loop goes over the positional parameters
+    skip_newlines(tokens);
+    let mut words = None;
+    if let Some(Token::Plain(w)) = tokens.peek() {
+        if w == "in" {
+            tokens.next(); // Consume token
+            let mut list = Vec::new();
+            while let Some(token) = tokens.peek() {
+                if let Token::Meta(_) = token {
+                    break;
+                }
+                list.push(tokens.next());
+            }
+            words = Some(list);
+        }
+    }
+    if let Some(Token::Meta(m)) = tokens.peek() {
+        if m == ";" || m == "\n" {
+            tokens.next(); // Consume token
+        }
+    }
+
+    let body = parse_do_group(tokens)?;
+    Ok(CompoundCommand::For {
+        variable,
+        words,
+        body,
+    })
+}
+
+/// Parses `[[ expression ]]`. The words are kept whole here, so that they
+/// are expanded without field splitting or pathname expansion.
+fn parse_conditional(tokens: &mut Scanner<Tokens>) -> Result<CompoundCommand, ParseError> {
+    tokens.next(); // Consume `[[`
+    let expression = parse_conditional_or(tokens)?;
+    skip_newlines(tokens);
+    expect_word(tokens, "]]")?;
+    Ok(CompoundCommand::Conditional(expression))
+}
+
+fn parse_conditional_or(tokens: &mut Scanner<Tokens>) -> Result<ConditionalExpression, ParseError> {
+    let mut expression = parse_conditional_and(tokens)?;
+    while let Some(Token::Meta(m)) = tokens.peek() {
+        if m != "||" {
             break;
         }
+        tokens.next(); // Consume token
+        let right = parse_conditional_and(tokens)?;
+        expression = ConditionalExpression::Or(Box::new(expression), Box::new(right));
+    }
+    Ok(expression)
+}
 
-        match tokens.peek().unwrap() {
-            Token::Meta(c) => {
-                debug!("Tokens: {:?}", command_tokens);
-                debug!("Break point ({})", c);
-                break;
+fn parse_conditional_and(
+    tokens: &mut Scanner<Tokens>,
+) -> Result<ConditionalExpression, ParseError> {
+    let mut expression = parse_conditional_not(tokens)?;
+    while let Some(Token::Meta(m)) = tokens.peek() {
+        if m != "&&" {
+            break;
+        }
+        tokens.next(); // Consume token
+        let right = parse_conditional_not(tokens)?;
+        expression = ConditionalExpression::And(Box::new(expression), Box::new(right));
+    }
+    Ok(expression)
+}
+
+fn parse_conditional_not(
+    tokens: &mut Scanner<Tokens>,
+) -> Result<ConditionalExpression, ParseError> {
+    skip_newlines(tokens);
+    match tokens.peek() {
+        Some(Token::Plain(w)) if w == "!" => {
+            tokens.next(); // Consume token
+            let inner = parse_conditional_not(tokens)?;
+            Ok(ConditionalExpression::Not(Box::new(inner)))
+        }
+        Some(Token::Meta(m)) if m == "(" => {
+            tokens.next(); // Consume token
+            let inner = parse_conditional_or(tokens)?;
+            skip_newlines(tokens);
+            expect_meta(tokens, ")")?;
+            Ok(inner)
+        }
+        _ => parse_conditional_primary(tokens),
+    }
+}
+
+fn parse_conditional_primary(
+    tokens: &mut Scanner<Tokens>,
+) -> Result<ConditionalExpression, ParseError> {
+    let first = conditional_word(tokens, "conditional expression")?;
+    let unary = match &first {
+        Token::Plain(op) => op.to_str().filter(|op| is_unary_operator(op)),
+        _ => None,
+    };
+    if let Some(op) = unary {
+        let operand = conditional_word(tokens, &format!("argument to {}", op))?;
+        return Ok(ConditionalExpression::Unary(op.to_string(), operand));
+    }
+
+    let op = match tokens.peek() {
+        Some(Token::Meta(m)) if m == "<" || m == ">" => m,
+        Some(Token::Plain(w)) if w.to_str().is_some_and(is_binary_operator) => lossy(w),
+        _ => return Ok(ConditionalExpression::Word(first)),
+    };
+    tokens.next(); // Consume operator
+    let expected = format!("argument to {}", op);
+    let second = match op.as_str() {
+        "=~" => regex_word(tokens, &expected)?,
+        _ => conditional_word(tokens, &expected)?,
+    };
+    Ok(ConditionalExpression::Binary(first, op, second))
+}
+
+/// The next word of a `[[ ]]` expression, which cannot be an operator such
+/// as `&&` or the closing `]]`.
+fn conditional_word(tokens: &mut Scanner<Tokens>, expected: &str) -> Result<Word, ParseError> {
+    match tokens.peek() {
+        Some(Token::Meta(_)) | None => Err(unexpected(tokens, expected)),
+        Some(Token::Plain(w)) if w == "]]" => Err(unexpected(tokens, expected)),
+        Some(_) => Ok(tokens.next()),
+    }
+}
+
+/// The regular expression after `=~`, in which `(`, `)` and `|` are part of
+/// the expression rather than operators, up to the next blank.
+fn regex_word(tokens: &mut Scanner<Tokens>, expected: &str) -> Result<Word, ParseError> {
+    let mut parts = Vec::new();
+    loop {
+        match tokens.peek() {
+            Some(Token::Meta(m)) if ["(", ")", "|", "||"].contains(&m.as_str()) => {
+                tokens.next();
+                parts.push(Token::Plain(m.into()));
             }
-            _ => {
-                command_tokens.push(tokens.next());
+            Some(Token::Plain(w)) if w == "]]" && parts.is_empty() => {
+                return Err(unexpected(tokens, expected));
+            }
+            Some(Token::Meta(_)) | None if parts.is_empty() => {
+                return Err(unexpected(tokens, expected));
             }
+            Some(Token::Meta(_)) | None => break,
+            Some(_) => parts.push(tokens.next()),
+        }
+        if !tokens.follows_directly() {
+            break;
         }
     }
+    match parts.len() {
+        1 => Ok(parts.remove(0)),
+        _ => Ok(Token::Group(parts)),
+    }
+}
 
-    let mut command = CommandType::create(command_tokens)?;
+/// Parses `do list done`.
+fn parse_do_group(tokens: &mut Scanner<Tokens>) -> Result<List, ParseError> {
+    skip_newlines(tokens);
+    expect_word(tokens, "do")?;
+    let body = parse_compound_list(tokens, "done")?;
+    expect_word(tokens, "done")?;
+    Ok(body)
+}
+
+/// Whether the tokens start with `name()`.
+fn is_function_definition(tokens: &Scanner<Tokens>) -> bool {
+    matches!(
+        (tokens.peek(), tokens.peek_next(1), tokens.peek_next(2)),
+        (Some(Token::Plain(name)), Some(Token::Meta(open)), Some(Token::Meta(close)))
+            if name.to_str().is_some_and(is_name) && open == "(" && close == ")"
+    )
+}
+
+fn parse_function_definition(tokens: &mut Scanner<Tokens>) -> Result<Command, ParseError> {
+    let name = lossy(tokens.next().literal());
+    tokens.next(); // Consume `(`
+    tokens.next(); // Consume `)`
+    skip_newlines(tokens);
+
+    let span = tokens.span();
+    let body = parse_command(tokens)?;
+    if !matches!(body, Command::Compound(..)) {
+        let error = SyntaxError::ExpectedToken(format!("compound command as body of {}", name));
+        return Err(ParseError::new(error, span));
+    }
+    debug!("Parsed function definition: {}", name);
+    Ok(Command::FunctionDefinition(FunctionDefinition {
+        name,
+        body: Box::new(body),
+    }))
+}
 
+/// Replaces the next word with the text of the alias it names, again and
+/// again while the text starts with another alias. An alias is expanded at
+/// most once per command, so that `alias ls='ls -F'` does not recurse.
+///
+/// When an alias ends in a blank, the word after it is also checked for an
+/// alias, so this returns how many tokens are left at that word.
+fn expand_alias(
+    tokens: &mut Scanner<Tokens>,
+    expanded: &mut Vec<String>,
+) -> Result<Option<usize>, ParseError> {
+    let mut after_blank = None;
+    while let Some(Token::Plain(word)) = tokens.peek() {
+        let Some(name) = word
+            .to_str()
+            .filter(|name| !expanded.iter().any(|e| e == name))
+        else {
+            break;
+        };
+        let Some(value) = tokens.alias(name).map(str::to_string) else {
+            break;
+        };
+        debug!("Expanding alias {} to {:?}", name, value);
+        expanded.push(name.to_string());
+
+        // The tokens of the alias all point at the word they replace
+        let span = tokens.span();
+        tokens.next();
+        if value.ends_with([' ', '\t']) {
+            after_blank = Some(tokens.remaining());
+        }
+        let alias_tokens = lex_impl(&mut Scanner::new(value.as_bytes()))
+            .map_err(|e| ParseError::new(e.error, span))?;
+        tokens.push_front(alias_tokens.into_iter().map(|(t, _)| (t, span)).collect());
+    }
+    Ok(after_blank)
+}
+
+fn parse_simple_command(
+    tokens: &mut Scanner<Tokens>,
+    mut expanded: Vec<String>,
+    mut after_blank: Option<usize>,
+) -> Result<Command, ParseError> {
+    let mut assignments = Vec::<Assignment>::new();
+    let mut words = Vec::<Word>::new();
+    let mut redirects = Vec::<Redirect>::new();
+    let mut declarations = Vec::<Assignment>::new();
+
+    // Redirections may appear anywhere between the words of a command
     loop {
         if tokens.peek().is_none() {
+            debug!("End of tokens");
             break;
         }
 
         match tokens.peek().unwrap() {
-            Token::Meta(m) if m == ";" => {
-                debug!("End of command (;)");
-                break;
+            Token::Meta(m) if is_redirect(&m) => {
+                redirects.push(parse_redirect(tokens)?);
             }
-            Token::Meta(m) if m == "&&" => {
-                debug!("End of command (&&)");
+            Token::Meta(c) => {
+                debug!("Tokens: {:?}", words);
+                debug!("Break point ({:?})", c);
                 break;
             }
-            Token::Meta(m) if m == "|" => {
-                debug!("End of command (|)");
-                break;
+            _ if after_blank == Some(tokens.remaining()) => {
+                after_blank = expand_alias(tokens, &mut expanded)?;
             }
-            Token::Meta(m) if m == "<" => {
-                debug!("Input redirect");
-                tokens.next();
-                let file_name = tokens.next().resolve();
-                debug!("Input redirect file name: {}", file_name);
-                command = CommandType::InputRedirect(InputRedirect::new(
-                    vec![command.unpack_cmd()],
-                    file_name,
-                )?);
-            }
-            Token::Meta(m) if m == ">" => {
-                debug!("Output redirect");
+            _ => {
+                let word = mark_tildes(tokens.next());
+                let declaration = matches!(words.first(), Some(Token::Plain(w)) if w == "declare");
+                match parse_assignment(tokens, &word)? {
+                    Some(assignment) if words.is_empty() => assignments.push(assignment),
+                    Some(
+                        assignment @ Assignment {
+                            value: AssignmentValue::Array(_),
+                            ..
+                        },
+                    ) if declaration => {
+                        words.push(Token::Plain(OsString::from(&assignment.name)));
+                        declarations.push(assignment);
+                    }
+                    _ => words.push(word),
+                }
+            }
+        }
+    }
+
+    if assignments.is_empty() && words.is_empty() && redirects.is_empty() {
+        return Err(unexpected(tokens, "command"));
+    }
+    Ok(Command::Simple(SimpleCommand {
+        assignments,
+        words,
+        redirects,
+        declarations,
+    }))
+}
+
+/// Parses an assignment word, with the elements that follow it in
+/// parentheses when it assigns an array, as in `array=(a b c)`. Returns
+/// `None` for words that are not assignments.
+fn parse_assignment(
+    tokens: &mut Scanner<Tokens>,
+    word: &Word,
+) -> Result<Option<Assignment>, ParseError> {
+    let Some((name, index, append, value)) = split_assignment(word) else {
+        return Ok(None);
+    };
+    let value = match tokens.peek() {
+        Some(Token::Meta(m)) if m == "(" && index.is_none() && is_empty(&value) => {
+            if !tokens.follows_directly() {
+                return Err(unexpected(tokens, "command"));
+            }
+            tokens.next(); // Consume `(`
+            AssignmentValue::Array(parse_array_elements(tokens)?)
+        }
+        _ => AssignmentValue::Scalar(value),
+    };
+    Ok(Some(Assignment {
+        name,
+        index,
+        append,
+        value,
+    }))
+}
+
+/// Parses the elements of an array up to the closing `)`. They may be spread
+/// over several lines.
+fn parse_array_elements(
+    tokens: &mut Scanner<Tokens>,
+) -> Result<Vec<(Option<Word>, Word)>, ParseError> {
+    let mut elements = Vec::new();
+    loop {
+        skip_newlines(tokens);
+        match tokens.peek() {
+            Some(Token::Meta(m)) if m == ")" => {
                 tokens.next();
-                let file_name = tokens.next().resolve();
-                debug!("Output redirect file name: {}", file_name);
-                command = CommandType::OutputRedirect(OutputRedirect::new(
-                    vec![command.unpack_cmd()],
-                    file_name,
-                )?);
-            }
-            Token::Meta(m) if m == ">>" => {
-                debug!("Output redirect append");
+                return Ok(elements);
+            }
+            Some(Token::Meta(_)) | None => return Err(unexpected(tokens, ")")),
+            Some(word) => {
                 tokens.next();
-                let file_name = tokens.next().resolve();
-                debug!("Output redirect append file name: {}", file_name);
-                command = CommandType::OutputRedirectAppend(OutputRedirectAppend::new(
-                    vec![command.unpack_cmd()],
-                    file_name,
-                )?);
+                // An element may give its own subscript, as in `[key]=value`
+                let parts = word_parts(&word);
+                let element = match parts.first() {
+                    Some(Token::Plain(head)) if head.as_bytes().starts_with(b"[") => {
+                        split_subscript(parts).and_then(|(index, rest)| {
+                            match split_operator(rest)? {
+                                (false, value) => Some((Some(index), value)),
+                                (true, _) => None,
+                            }
+                        })
+                    }
+                    _ => None,
+                };
+                elements.push(element.unwrap_or((None, word)));
             }
-            _ => {
-                let token = tokens.next().resolve();
-                debug!("Unexpected token: {}", token);
-                return Err(SyntaxError::UnexpectedToken(token));
+        }
+    }
+}
+
+/// Splits a word such as `name=value` or `name[i]+=value` into the name, the
+/// subscript, whether it appends and the value, or returns `None` if it is
+/// not an assignment.
+fn split_assignment(word: &Word) -> Option<(String, Option<Word>, bool, Word)> {
+    let mut parts = word_parts(word);
+    let Some(Token::Plain(head)) = parts.first() else {
+        return None;
+    };
+    let head = head.as_bytes().to_vec();
+    let length = head
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+        .count();
+    let name = std::str::from_utf8(&head[..length])
+        .ok()
+        .filter(|name| is_name(name))?
+        .to_string();
+    parts[0] = Token::Plain(OsString::from_vec(head[length..].to_vec()));
+
+    let index = match head.get(length) {
+        Some(b'[') => {
+            let (index, rest) = split_subscript(parts)?;
+            parts = word_parts(&rest);
+            Some(index)
+        }
+        _ => None,
+    };
+    let (append, value) = split_operator(join_parts(parts))?;
+    Some((name, index, append, value))
+}
+
+/// Marks the tilde prefixes in a word that has the form of an assignment,
+/// where they may follow the `=` or any `:` in the value, as in
+/// `PATH=~/bin:~alice/bin`. This applies to arguments such as those of
+/// `export` as well. Other words are returned as they are.
+fn mark_tildes(word: Word) -> Word {
+    if split_assignment(&word).is_none() {
+        return word;
+    }
+    let parts = word_parts(&word);
+    let count = parts.len();
+    let mut marked = Vec::new();
+    let mut in_value = false;
+    for (i, part) in parts.into_iter().enumerate() {
+        let Token::Plain(text) = &part else {
+            marked.push(part);
+            continue;
+        };
+        let text = text.as_bytes();
+        let mut plain = Vec::new();
+        let mut j = 0;
+        while j < text.len() {
+            let c = text[j];
+            plain.push(c);
+            j += 1;
+            let boundary = match c {
+                b'=' if !in_value => {
+                    in_value = true;
+                    true
+                }
+                b':' => in_value,
+                _ => false,
+            };
+            if !boundary || text.get(j) != Some(&b'~') {
+                continue;
             }
+            let end = j
+                + 1
+                + text[j + 1..]
+                    .iter()
+                    .take_while(|c| **c != b'/' && **c != b':')
+                    .count();
+            // A prefix that runs into a quoted or expanded part is kept
+            if end == text.len() && i + 1 < count {
+                continue;
+            }
+            marked.push(Token::Plain(OsString::from_vec(std::mem::take(&mut plain))));
+            marked.push(Token::Tilde(lossy(OsStr::from_bytes(&text[j + 1..end]))));
+            j = end;
+        }
+        marked.push(Token::Plain(OsString::from_vec(plain)));
+    }
+    join_parts(marked)
+}
+
+/// Splits a word starting with `[subscript]` into the subscript and the
+/// rest of the word.
+fn split_subscript(mut parts: Vec<Token>) -> Option<(Word, Word)> {
+    let Some(Token::Plain(head)) = parts.first() else {
+        return None;
+    };
+    parts[0] = Token::Plain(OsString::from_vec(head.as_bytes()[1..].to_vec()));
+
+    let mut depth = 0;
+    for (i, part) in parts.iter().enumerate() {
+        // Quoted and expanded parts of the subscript cannot close it
+        let Token::Plain(text) = part else {
+            continue;
+        };
+        let text = text.as_bytes();
+        for (j, c) in text.iter().enumerate() {
+            match c {
+                b'[' => depth += 1,
+                b']' if depth > 0 => depth -= 1,
+                b']' => {
+                    let mut subscript = parts[..i].to_vec();
+                    subscript.push(Token::Plain(OsString::from_vec(text[..j].to_vec())));
+                    let mut rest = vec![Token::Plain(OsString::from_vec(text[j + 1..].to_vec()))];
+                    rest.extend_from_slice(&parts[i + 1..]);
+                    return Some((join_parts(subscript), join_parts(rest)));
+                }
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// Splits a word starting with `=` or `+=` into whether it was `+=` and the
+/// rest of the word.
+fn split_operator(word: Word) -> Option<(bool, Word)> {
+    let mut parts = word_parts(&word);
+    let Some(Token::Plain(head)) = parts.first() else {
+        return None;
+    };
+    let head = head.as_bytes();
+    let (append, length) = match head {
+        [b'+', b'=', ..] => (true, 2),
+        [b'=', ..] => (false, 1),
+        _ => return None,
+    };
+    parts[0] = Token::Plain(OsString::from_vec(head[length..].to_vec()));
+    Some((append, join_parts(parts)))
+}
+
+/// The parts a word is made of.
+fn word_parts(word: &Word) -> Vec<Token> {
+    match word {
+        Token::Group(parts) => parts.clone(),
+        word => vec![word.clone()],
+    }
+}
+
+/// Puts parts back together into a word, leaving out empty plain parts.
+fn join_parts(parts: Vec<Token>) -> Word {
+    let mut parts: Vec<Token> = parts.into_iter().filter(|part| !is_empty(part)).collect();
+    match parts.len() {
+        0 => Token::Plain(OsString::new()),
+        1 => parts.remove(0),
+        _ => Token::Group(parts),
+    }
+}
+
+fn is_empty(word: &Word) -> bool {
+    matches!(word, Token::Plain(text) if text.is_empty())
+}
+
+fn parse_redirects(tokens: &mut Scanner<Tokens>) -> Result<Vec<Redirect>, ParseError> {
+    let mut redirects = Vec::new();
+    while let Some(Token::Meta(m)) = tokens.peek() {
+        if !is_redirect(&m) {
+            break;
         }
+        redirects.push(parse_redirect(tokens)?);
     }
+    Ok(redirects)
+}
+
+fn parse_redirect(tokens: &mut Scanner<Tokens>) -> Result<Redirect, ParseError> {
+    let span = tokens.span();
+    let operator = lossy(tokens.next().literal());
+    debug!("Redirect ({})", operator);
+    let target = match tokens.peek() {
+        Some(Token::Meta(_)) | None => {
+            let error = SyntaxError::ExpectedToken(format!("file after {}", operator));
+            return Err(ParseError::new(error, span));
+        }
+        Some(_) => tokens.next(),
+    };
+    debug!("Redirect target: {:?}", target);
+    Redirect::new(&operator, target).map_err(|error| ParseError::new(error, span))
+}
+
+fn skip_newlines(tokens: &mut Scanner<Tokens>) {
+    while let Some(Token::Meta(m)) = tokens.peek() {
+        if m != "\n" {
+            break;
+        }
+        tokens.next();
+    }
+}
+
+fn at_list_end(tokens: &Scanner<Tokens>) -> bool {
+    match tokens.peek() {
+        None => true,
+        Some(Token::Meta(m)) => m == ")",
+        Some(Token::Plain(w)) => w.to_str().is_some_and(|w| TERMINATORS.contains(&w)),
+        Some(_) => false,
+    }
+}
+
+fn expect_word(tokens: &mut Scanner<Tokens>, expected: &str) -> Result<(), ParseError> {
+    match tokens.peek() {
+        Some(Token::Plain(w)) if w == expected => {
+            tokens.next();
+            Ok(())
+        }
+        _ => Err(unexpected(tokens, expected)),
+    }
+}
+
+fn expect_meta(tokens: &mut Scanner<Tokens>, expected: &str) -> Result<(), ParseError> {
+    match tokens.peek() {
+        Some(Token::Meta(m)) if m == expected => {
+            tokens.next();
+            Ok(())
+        }
+        _ => Err(unexpected(tokens, expected)),
+    }
+}
+
+/// The error for finding something other than `expected` at the next token.
+fn unexpected(tokens: &Scanner<Tokens>, expected: &str) -> ParseError {
+    let error = match tokens.peek() {
+        Some(token) => SyntaxError::UnexpectedToken(describe(&token)),
+        None => SyntaxError::UnexpectedEof(expected.to_string()),
+    };
+    ParseError::new(error, tokens.span())
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Meta(m) if m == "\n" => "newline".to_string(),
+        token => lossy(token.literal()),
+    }
+}
 
-    Ok(command)
+/// Whether a word can be used as a variable or function name.
+pub fn is_name(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
@@ -1,16 +1,264 @@
-pub fn process(input: String) -> String {
-    // Remove leading and trailing whitespace
-    let mut input = input.trim().to_string();
+use crate::errors::HistoryError;
+use crate::history::History;
 
-    // Remove trailing newline
-    if input.ends_with('\n') {
-        input.pop();
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+
+pub fn process(input: OsString) -> OsString {
+    // Remove trailing whitespace. Leading whitespace is kept so that
+    // positions in error messages match the input.
+    OsString::from_vec(input.into_vec().trim_ascii_end().to_vec())
+}
+
+/// Expands history references in a line typed at the prompt:
+///
+/// - `!!` for the last line, `!n` for line `n` and `!-n` for the `n`th line
+///   back
+/// - `!prefix` and `!?text?` for the last line that starts with or contains
+///   some text
+/// - `^old^new` at the start of the line for the last line with `old`
+///   replaced by `new`
+///
+/// A reference can pick out some of the words of the line with `:n`, `:n-m`,
+/// `:^`, `:$` or `:*`, and `!^`, `!$` and `!*` are short for `!!:^`, `!!:$`
+/// and `!!:*`. Nothing is expanded in single quotes, after a backslash or in
+/// parameters such as `$!`.
+///
+/// Returns `None` if the line has no references.
+pub fn expand_history(line: &str, history: &History) -> Result<Option<String>, HistoryError> {
+    if let Some(rest) = line.strip_prefix('^') {
+        return quick_substitution(rest, history).map(Some);
+    }
+
+    let chars: Vec<char> = line.chars().collect();
+    let mut expanded = String::new();
+    let mut changed = false;
+    let mut in_single = false;
+    let mut in_double = false;
+    // Inside `$'...'`, where a backslash can escape a quote
+    let mut in_ansi_c = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if (!in_single || in_ansi_c) && i + 1 < chars.len() => {
+                expanded.push(c);
+                expanded.push(chars[i + 1]);
+                i += 2;
+                continue;
+            }
+            '$' if !in_single && !in_double && chars.get(i + 1) == Some(&'\'') => {
+                expanded.push_str("$'");
+                in_single = true;
+                in_ansi_c = true;
+                i += 2;
+                continue;
+            }
+            '\'' if !in_double => {
+                in_single = !in_single;
+                in_ansi_c = false;
+            }
+            '"' if !in_single => in_double = !in_double,
+            '!' if !in_single
+                && !follows_dollar(&chars[..i])
+                && starts_reference(chars.get(i + 1), in_double) =>
+            {
+                let (text, end) = reference(&chars, i + 1, history)?;
+                expanded.push_str(&text);
+                changed = true;
+                i = end;
+                continue;
+            }
+            _ => (),
+        }
+        expanded.push(c);
+        i += 1;
+    }
+    Ok(changed.then_some(expanded))
+}
+
+/// Whether a `!` is part of a parameter, as in `$!` and `${!array[@]}`,
+/// given the characters before it.
+fn follows_dollar(before: &[char]) -> bool {
+    matches!(before, [.., '$'] | [.., '$', '{'])
+}
+
+/// Whether the character after a `!` makes it a history reference. A `!`
+/// before a blank, `=` or `(`, or at the end of the line, is left alone.
+fn starts_reference(next: Option<&char>, in_double: bool) -> bool {
+    match next {
+        None | Some(' ' | '\t' | '\n' | '=' | '(') => false,
+        Some('"') => !in_double,
+        Some(_) => true,
+    }
+}
+
+/// Expands the reference whose event starts at `start`, just after the `!`,
+/// returning its text and where the reference ends.
+fn reference(
+    chars: &[char],
+    start: usize,
+    history: &History,
+) -> Result<(String, usize), HistoryError> {
+    let mut end = start;
+    let mut designator = None;
+    let event = match chars[start] {
+        '!' => {
+            end += 1;
+            history.last()
+        }
+        // `!$` and the like refer to the last line
+        c @ ('$' | '^' | '*') => {
+            end += 1;
+            designator = Some(c.to_string());
+            history.last()
+        }
+        '?' => {
+            end += 1;
+            let text_start = end;
+            while end < chars.len() && chars[end] != '?' && chars[end] != '\n' {
+                end += 1;
+            }
+            let text: String = chars[text_start..end].iter().collect();
+            if end < chars.len() && chars[end] == '?' {
+                end += 1;
+            }
+            history
+                .entries()
+                .iter()
+                .rev()
+                .find(|e| e.contains(&text))
+                .map(String::as_str)
+        }
+        c if c == '-' || c.is_ascii_digit() => {
+            end += 1;
+            while end < chars.len() && chars[end].is_ascii_digit() {
+                end += 1;
+            }
+            let number: String = chars[start..end].iter().collect();
+            match number.parse::<i64>() {
+                Ok(n) if n < 0 => history
+                    .entries()
+                    .len()
+                    .checked_sub(n.unsigned_abs() as usize - 1)
+                    .and_then(|number| history.get(number)),
+                Ok(n) => history.get(n as usize),
+                Err(_) => None,
+            }
+        }
+        _ => {
+            while end < chars.len() && !ends_word(chars[end]) {
+                end += 1;
+            }
+            let prefix: String = chars[start..end].iter().collect();
+            history
+                .entries()
+                .iter()
+                .rev()
+                .find(|e| e.starts_with(&prefix))
+                .map(String::as_str)
+        }
+    };
+    let reference: String = chars[start - 1..end].iter().collect();
+    let event = event.ok_or(HistoryError::EventNotFound(reference))?;
+
+    if designator.is_none() && chars.get(end) == Some(&':') {
+        let designator_start = end + 1;
+        end = designator_start;
+        while end < chars.len() && (chars[end].is_ascii_digit() || "^$*-".contains(chars[end])) {
+            end += 1;
+        }
+        designator = Some(chars[designator_start..end].iter().collect());
+    }
+    let text = match designator {
+        Some(designator) => select_words(event, &designator).ok_or_else(|| {
+            HistoryError::BadWordSpecifier(chars[start - 1..end].iter().collect())
+        })?,
+        None => event.to_string(),
+    };
+    Ok((text, end))
+}
+
+/// Whether the character ends the prefix in a reference such as `!ec`.
+fn ends_word(c: char) -> bool {
+    c.is_whitespace() || ":;&|()<>\"'`".contains(c)
+}
+
+/// The words of a line picked out by a word designator, where word 0 is the
+/// command name.
+fn select_words(line: &str, designator: &str) -> Option<String> {
+    let words = split_words(line);
+    let last = words.len().checked_sub(1)?;
+    let (first, end) = match designator {
+        "^" => (1, 1),
+        "$" => (last, last),
+        // All of the arguments, which is nothing rather than an error when
+        // there are none
+        "*" if last == 0 => return Some(String::new()),
+        "*" => (1, last),
+        _ => {
+            let (first, end) = match designator.split_once('-') {
+                Some((first, end)) => (first, end),
+                None => match designator.strip_suffix('*') {
+                    Some(first) => (first, "$"),
+                    None => (designator, designator),
+                },
+            };
+            let first = if first.is_empty() {
+                0
+            } else {
+                first.parse().ok()?
+            };
+            let end = if end == "$" { last } else { end.parse().ok()? };
+            (first, end)
+        }
+    };
+    if first > end || end > last {
+        return None;
     }
+    Some(words[first..=end].join(" "))
+}
 
-    // Add semicolon to trigger final command
-    if !input.ends_with(';') {
-        input.push(';');
+/// Splits a line into words at blanks that are not quoted or escaped.
+fn split_words(line: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote != Some('\'') {
+            escaped = true;
+        } else if Some(c) == quote {
+            quote = None;
+        } else if quote.is_none() && (c == '\'' || c == '"') {
+            quote = Some(c);
+        } else if quote.is_none() && c.is_whitespace() {
+            if let Some(start) = start.take() {
+                words.push(&line[start..i]);
+            }
+            continue;
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        words.push(&line[start..]);
     }
+    words
+}
+
+/// Expands `^old^new^rest`, given what follows the first `^`.
+fn quick_substitution(rest: &str, history: &History) -> Result<String, HistoryError> {
+    let mut parts = rest.splitn(3, '^');
+    let old = parts.next().unwrap_or_default();
+    let new = parts.next().unwrap_or_default();
+    let tail = parts.next().unwrap_or_default();
 
-    input
+    let failed = || HistoryError::SubstitutionFailed(format!("^{}", rest));
+    let last = history.last().ok_or_else(failed)?;
+    if old.is_empty() || !last.contains(old) {
+        return Err(failed());
+    }
+    Ok(format!("{}{}", last.replacen(old, new, 1), tail))
 }
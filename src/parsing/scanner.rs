@@ -1,38 +1,149 @@
-use crate::token::{Token, Tokens};
+use crate::parsing::span::{Position, Span};
+use crate::parsing::Aliases;
+use crate::token::Token;
+
+use std::collections::VecDeque;
 
 pub struct Scanner<T> {
     input: T,
     index: usize,
+    position: Position,
 }
 
 impl<T> Scanner<T> {
     pub fn new(input: T) -> Scanner<T> {
-        Scanner { input, index: 0 }
+        Scanner::starting_at(input, Position::start())
+    }
+
+    /// A scanner that resumes part way through the input, at `position`.
+    pub fn starting_at(input: T, position: Position) -> Scanner<T> {
+        Scanner {
+            input,
+            index: position.offset,
+            position,
+        }
     }
 }
 
-impl Scanner<String> {
-    pub fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.index)
+/// Scans raw bytes in constant time per step, keeping track of the line and
+/// column as it goes.
+impl Scanner<&[u8]> {
+    pub fn peek(&self) -> Option<u8> {
+        self.input.get(self.index).copied()
     }
 
-    pub fn peek_next(&self, index: usize) -> Option<char> {
-        self.input.chars().nth(self.index + index)
+    pub fn peek_next(&self, index: usize) -> Option<u8> {
+        self.input.get(self.index + index).copied()
     }
 
-    pub fn next(&mut self) -> char {
+    pub fn next(&mut self) -> u8 {
+        let c = self.input[self.index];
         self.index += 1;
-        self.input.chars().nth(self.index - 1).unwrap()
+
+        self.position.offset = self.index;
+        if c == b'\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else if !is_continuation_byte(c) {
+            self.position.column += 1;
+        }
+        c
+    }
+
+    /// The position of the next byte to be scanned.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// The bytes scanned since `start`.
+    pub fn text_since(&self, start: Position) -> &[u8] {
+        &self.input[start.offset..self.index]
+    }
+}
+
+/// Whether the byte continues a multi-byte UTF-8 character.
+fn is_continuation_byte(c: u8) -> bool {
+    c & 0b1100_0000 == 0b1000_0000
+}
+
+/// Tokens paired with the part of the input they were lexed from.
+pub type SpannedTokens = Vec<(Token, Span)>;
+
+/// Tokens waiting to be parsed, along with the aliases that may replace the
+/// first word of a command.
+pub struct Tokens {
+    /// The tokens not yet consumed, so that the text of an alias can be
+    /// pushed onto the front in constant time per token
+    queue: VecDeque<(Token, Span)>,
+    aliases: Aliases,
+}
+
+impl Tokens {
+    pub fn new(tokens: SpannedTokens, aliases: Aliases) -> Tokens {
+        Tokens {
+            queue: tokens.into(),
+            aliases,
+        }
     }
 }
 
 impl Scanner<Tokens> {
     pub fn peek(&self) -> Option<Token> {
-        self.input.get(self.index).cloned()
+        self.input.queue.front().map(|(token, _)| token.clone())
+    }
+
+    pub fn peek_next(&self, index: usize) -> Option<Token> {
+        self.input.queue.get(index).map(|(token, _)| token.clone())
     }
 
     pub fn next(&mut self) -> Token {
-        self.index += 1;
-        self.input.get(self.index - 1).unwrap().clone()
+        let (token, span) = self.input.queue.pop_front().unwrap();
+        if !matches!(&token, Token::Meta(m) if m == "\n") {
+            self.position = span.end;
+        }
+        token
+    }
+
+    /// The span of the next token, or an empty span just after the last word
+    /// once the input is used up.
+    pub fn span(&self) -> Span {
+        match self.input.queue.front() {
+            Some((_, span)) => *span,
+            None => Span::at(self.position),
+        }
+    }
+
+    /// Whether the next token starts right where the last one ended, with no
+    /// blank between them, as the parts of a regular expression such as
+    /// `^(a|b)$` do.
+    pub fn follows_directly(&self) -> bool {
+        self.input
+            .queue
+            .front()
+            .is_some_and(|(_, span)| span.start == self.position)
+    }
+
+    /// How many tokens are left, which only goes up when an alias is expanded.
+    pub fn remaining(&self) -> usize {
+        self.input.queue.len()
+    }
+
+    pub fn alias(&self, name: &str) -> Option<&str> {
+        self.input.aliases.get(name).map(String::as_str)
+    }
+
+    /// Puts `tokens` in front of the ones not yet consumed.
+    pub fn push_front(&mut self, tokens: SpannedTokens) {
+        for token in tokens.into_iter().rev() {
+            self.input.queue.push_front(token);
+        }
+    }
+
+    /// Replaces the alias table, for the commands of a script that come
+    /// after an `alias` or `unalias`.
+    pub fn set_aliases(&mut self, aliases: &Aliases) {
+        if self.input.aliases != *aliases {
+            self.input.aliases = aliases.clone();
+        }
     }
 }
@@ -1,27 +1,33 @@
-use crate::traits::{Runnable, ShellCommand};
+use crate::errors::{exit_code, report_error, unwinds, RuntimeError};
+use crate::sequence::print_output;
+use crate::shell::Shell;
+use crate::traits::{Runnable, ShellCommand, Stage, StageStatus};
+use nix::unistd::Pid;
 use std::error::Error;
 use std::fmt;
-use std::fs::File;
-use std::io::{BufReader, Read, Write};
 use std::ops::Index;
-use std::os::fd::FromRawFd;
-use std::os::fd::IntoRawFd;
 use std::process::ChildStdout;
 
 #[derive(Clone)]
 pub struct Pipeline {
     commands: Vec<Box<dyn ShellCommand>>,
+    /// Whether each command but the last pipes its stderr to the next one
+    pipe_stderr: Vec<bool>,
 }
 
 impl Pipeline {
     pub fn new() -> Pipeline {
         Pipeline {
             commands: Vec::new(),
+            pipe_stderr: Vec::new(),
         }
     }
 
-    pub fn init(commands: Vec<Box<dyn ShellCommand>>) -> Pipeline {
-        Pipeline { commands }
+    pub fn init(commands: Vec<Box<dyn ShellCommand>>, pipe_stderr: Vec<bool>) -> Pipeline {
+        Pipeline {
+            commands,
+            pipe_stderr,
+        }
     }
 
     pub fn add(&mut self, command: Box<dyn ShellCommand>) -> &mut Pipeline {
@@ -30,13 +36,14 @@ impl Pipeline {
     }
 
     pub fn transfer(&mut self) -> Pipeline {
-        let commands = self.commands.clone();
+        let pipeline = self.clone();
         self.clear();
-        Pipeline { commands }
+        pipeline
     }
 
     pub fn clear(&mut self) -> &mut Pipeline {
         self.commands.clear();
+        self.pipe_stderr.clear();
         self
     }
 }
@@ -61,38 +68,83 @@ impl fmt::Debug for Pipeline {
 }
 
 impl Runnable for Pipeline {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        let Some((last, stages)) = self.commands.split_last() else {
+            return Ok("".to_string());
+        };
+
+        let mut statuses = Vec::with_capacity(stages.len());
         let mut prev_stdout: Option<ChildStdout> = None;
-        for (i, command) in self.commands.iter().enumerate() {
-            let cmd_stdout = command.pipe(prev_stdout.take())?;
-            if i == self.commands.len() - 1 {
-                if let Some(stdout) = cmd_stdout {
-                    let mut buff = Vec::new();
-                    let mut reader =
-                        BufReader::new(unsafe { File::from_raw_fd(stdout.into_raw_fd()) });
-                    reader.read_to_end(&mut buff)?;
-
-                    match String::from_utf8(buff.clone()) {
-                        Ok(s) => {
-                            let trimmed = s.trim_end_matches('\n').to_string();
-                            if !trimmed.is_empty() {
-                                return Ok(trimmed);
-                            }
-                            return Ok("".to_string());
-                        }
-                        Err(_) => {
-                            if !buff.is_empty() {
-                                std::io::stdout().write_all(&buff)?;
-                                return Ok(String::from_utf8_lossy(&buff).to_string());
-                            }
-                        }
+        for (i, command) in stages.iter().enumerate() {
+            let stderr = self.pipe_stderr.get(i).copied().unwrap_or(false);
+            let stage = match command.pipe(shell, prev_stdout.take(), stderr) {
+                Ok(stage) => stage,
+                Err(e) if unwinds(e.as_ref()) => return Err(e),
+                // A stage that cannot start fails alone, and the next stage
+                // reads nothing
+                Err(e) => {
+                    report_error(shell, e.as_ref());
+                    Stage {
+                        stdout: None,
+                        status: StageStatus::Finished(exit_code(e.as_ref())),
                     }
                 }
-            } else {
-                prev_stdout = cmd_stdout;
+            };
+            prev_stdout = stage.stdout;
+            statuses.push(stage.status);
+        }
+        let result = last.run_last(shell, prev_stdout);
+
+        let mut failed = None;
+        for status in statuses {
+            let code = match status {
+                StageStatus::Running(child) => shell.wait_for(Pid::from_raw(child.id() as i32)),
+                StageStatus::Forked(pid) => shell.wait_for(pid),
+                StageStatus::Finished(code) => code,
+            };
+            if code != 0 {
+                failed = Some(code);
+            }
+        }
+        // The status of a pipeline is the status of its last command, or
+        // with `set -o pipefail` that of the last command to fail
+        match failed {
+            Some(code) if shell.options.pipefail && result.is_ok() => {
+                Err(RuntimeError::ExitStatus(code).into())
+            }
+            _ => result,
+        }
+    }
+}
+
+/// A pipeline preceded by `!`, which succeeds when the pipeline fails and
+/// fails with status 1 when it succeeds.
+#[derive(Clone, Debug)]
+pub struct Negated {
+    body: Box<dyn Runnable>,
+}
+
+impl Negated {
+    pub fn new(body: Box<dyn Runnable>) -> Negated {
+        Negated { body }
+    }
+}
+
+impl Runnable for Negated {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        // The status of the pipeline is tested, so `set -e` does not exit
+        // when it fails, nor when the negation does
+        match shell.ignoring_errexit(|shell| self.body.run(shell)) {
+            Ok(output) => {
+                print_output(shell, &output);
+                Err(RuntimeError::ShortCircuit(1).into())
+            }
+            Err(e) if unwinds(e.as_ref()) => Err(e),
+            Err(e) => {
+                report_error(shell, e.as_ref());
+                Ok("".to_string())
             }
         }
-        Ok("".to_string())
     }
 }
 
@@ -1,237 +1,151 @@
-use crate::errors::SyntaxError;
-use crate::traits::{Runnable, ShellCommand};
+use crate::errors::{RuntimeError, SyntaxError};
+use crate::io_context::FdTable;
+use crate::shell::Shell;
+use crate::substitution::substitute_token;
+use crate::token::{lossy, Token};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::ffi::OsString;
 use std::fmt;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Read;
-use std::os::fd::IntoRawFd;
-use std::os::unix::io::FromRawFd;
-use std::process::ChildStdout;
-use std::process::Command;
-use std::process::Stdio;
+use std::fs::{File, OpenOptions};
+use std::os::fd::{OwnedFd, RawFd};
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum RedirectType {
     Output,
+    /// `>|`, which overwrites a file even with `set -o noclobber`
+    OutputClobber,
     OutputAppend,
     Input,
-    None,
-}
-
-pub enum Redirect {
-    Output(OutputRedirect),
-    OutputAppend(OutputRedirectAppend),
-    Input(InputRedirect),
-    None,
-}
-
-#[derive(Clone)]
-pub struct OutputRedirect {
-    commands: Vec<Box<dyn ShellCommand>>,
-    output_file: String,
-}
-
-impl OutputRedirect {
-    pub fn new(
-        commands: Vec<Box<dyn ShellCommand>>,
-        output_file: String,
-    ) -> Result<Self, SyntaxError> {
-        if commands.len() != 1 {
-            return Err(SyntaxError::InternalError);
-        }
-        Ok(Self {
-            commands,
-            output_file,
-        })
-    }
-
-    fn open_file(&self) -> Result<File, Box<dyn Error>> {
-        let file = File::create(&self.output_file)?;
-        Ok(file)
-    }
-}
-
-impl fmt::Debug for OutputRedirect {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "OutputRedirect({:?}, {:?})",
-            self.output_file, self.commands
-        )
-    }
-}
-
-impl Runnable for OutputRedirect {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
-        let mut file = self.open_file()?;
-        let stdout = self.commands[0].pipe(None)?;
-        if let Some(mut stdout) = stdout {
-            std::io::copy(&mut stdout, &mut file)?;
-        }
-        Ok("".to_string())
-    }
-}
-
-impl ShellCommand for OutputRedirect {
-    fn cmd(&self) -> String {
-        self.commands[0].cmd()
-    }
-
-    fn args(&self) -> Vec<String> {
-        self.commands[0].args()
-    }
-
-    fn pipe(&self, stdin: Option<ChildStdout>) -> Result<Option<ChildStdout>, Box<dyn Error>> {
-        let mut file = self.open_file()?;
-        let stdout = self.commands[0].pipe(stdin)?;
-        if let Some(mut stdout) = stdout {
-            std::io::copy(&mut stdout, &mut file)?;
+    DuplicateOutput,
+    DuplicateInput,
+}
+
+impl RedirectType {
+    fn parse(operator: &str) -> Option<RedirectType> {
+        match operator {
+            ">" => Some(RedirectType::Output),
+            ">|" => Some(RedirectType::OutputClobber),
+            ">>" => Some(RedirectType::OutputAppend),
+            "<" => Some(RedirectType::Input),
+            ">&" => Some(RedirectType::DuplicateOutput),
+            "<&" => Some(RedirectType::DuplicateInput),
+            _ => None,
         }
-        Ok(None)
     }
-}
-
-#[derive(Clone)]
-pub struct OutputRedirectAppend {
-    commands: Vec<Box<dyn ShellCommand>>,
-    output_file: String,
-}
 
-impl OutputRedirectAppend {
-    pub fn new(
-        commands: Vec<Box<dyn ShellCommand>>,
-        output_file: String,
-    ) -> Result<Self, SyntaxError> {
-        if commands.len() != 1 {
-            return Err(SyntaxError::InternalError);
+    fn default_fd(&self) -> RawFd {
+        match self {
+            RedirectType::Input | RedirectType::DuplicateInput => 0,
+            _ => 1,
         }
-        Ok(Self {
-            commands,
-            output_file,
-        })
-    }
-
-    fn open_file(&self) -> Result<File, Box<dyn Error>> {
-        let file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&self.output_file)?;
-        Ok(file)
-    }
-}
-
-impl fmt::Debug for OutputRedirectAppend {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "OutputRedirectAppend({:?}, {:?})",
-            self.output_file, self.commands
-        )
     }
 }
 
-impl Runnable for OutputRedirectAppend {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
-        let mut file = self.open_file()?;
-        let stdout = self.commands[0].pipe(None)?;
-        if let Some(mut stdout) = stdout {
-            std::io::copy(&mut stdout, &mut file)?;
-        }
-        Ok("".to_string())
-    }
+/// Splits an operator such as `2>>` into its file descriptor and operator.
+fn split_operator(operator: &str) -> (&str, &str) {
+    let digits = operator
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(operator.len());
+    operator.split_at(digits)
 }
 
-impl ShellCommand for OutputRedirectAppend {
-    fn cmd(&self) -> String {
-        self.commands[0].cmd()
-    }
-
-    fn args(&self) -> Vec<String> {
-        self.commands[0].args()
-    }
-
-    fn pipe(&self, stdin: Option<ChildStdout>) -> Result<Option<ChildStdout>, Box<dyn Error>> {
-        let mut file = self.open_file()?;
-        let stdout = self.commands[0].pipe(stdin)?;
-        if let Some(mut stdout) = stdout {
-            std::io::copy(&mut stdout, &mut file)?;
-        }
-        Ok(None)
-    }
+/// Whether a meta token is a redirection operator.
+pub fn is_redirect(operator: &str) -> bool {
+    RedirectType::parse(split_operator(operator).1).is_some()
 }
 
-#[derive(Clone)]
-pub struct InputRedirect {
-    commands: Vec<Box<dyn ShellCommand>>,
-    input_file: String,
+/// A single redirection of a simple command, such as `2>> log.txt`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Redirect {
+    fd: RawFd,
+    redirect_type: RedirectType,
+    target: Token,
 }
 
-impl InputRedirect {
-    pub fn new(
-        commands: Vec<Box<dyn ShellCommand>>,
-        input_file: String,
-    ) -> Result<Self, SyntaxError> {
-        if commands.len() != 1 {
-            return Err(SyntaxError::InternalError);
-        }
-        Ok(Self {
-            commands,
-            input_file,
+impl Redirect {
+    pub fn new(operator: &str, target: Token) -> Result<Redirect, SyntaxError> {
+        let (fd, op) = split_operator(operator);
+        let redirect_type = RedirectType::parse(op)
+            .ok_or_else(|| SyntaxError::UnexpectedToken(operator.to_string()))?;
+        let fd = match fd {
+            "" => redirect_type.default_fd(),
+            fd => fd
+                .parse()
+                .map_err(|_| SyntaxError::UnexpectedToken(operator.to_string()))?,
+        };
+        Ok(Redirect {
+            fd,
+            redirect_type,
+            target,
         })
     }
-}
 
-impl fmt::Debug for InputRedirect {
+    /// Expands the target word, which must produce exactly one field, after
+    /// starting any process substitution in it.
+    fn target(&self, shell: &mut Shell, fds: &mut FdTable) -> Result<OsString, Box<dyn Error>> {
+        let target = substitute_token(shell, &self.target, fds)?;
+        let mut fields = target.expand(shell)?;
+        if fields.len() != 1 {
+            let pattern = lossy(target.pattern(shell)?);
+            return Err(RuntimeError::AmbiguousRedirect(pattern).into());
+        }
+        Ok(fields.remove(0))
+    }
+
+    /// Points the redirected file descriptor of `fds` at the target.
+    pub fn apply(&self, shell: &mut Shell, fds: &mut FdTable) -> Result<(), Box<dyn Error>> {
+        let target = self.target(shell, fds)?;
+        debug!("Applying redirect {:?} to {:?}", self, target);
+
+        let path = shell.path(&target);
+        let file = match self.redirect_type {
+            RedirectType::Input => File::open(path),
+            // Only regular files are protected, so `> /dev/null` still works
+            RedirectType::Output if shell.options.noclobber && path.is_file() => {
+                return Err(RuntimeError::Clobber(lossy(&target)).into());
+            }
+            RedirectType::Output | RedirectType::OutputClobber => File::create(path),
+            RedirectType::OutputAppend => OpenOptions::new().append(true).create(true).open(path),
+            RedirectType::DuplicateOutput | RedirectType::DuplicateInput => {
+                if target == "-" {
+                    fds.close(self.fd);
+                    return Ok(());
+                }
+                let stream = target
+                    .to_str()
+                    .and_then(|src| src.parse::<RawFd>().ok())
+                    .and_then(|src| fds.get(src).cloned())
+                    .ok_or_else(|| RuntimeError::BadFileDescriptor(lossy(&target)))?;
+                fds.set(self.fd, stream);
+                return Ok(());
+            }
+        };
+
+        let file = file.map_err(|e| format!("{}: {}", lossy(&target), e))?;
+        fds.set(self.fd, OwnedFd::from(file).into());
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Redirect {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "InputRedirect({:?}, {:?})",
-            self.input_file, self.commands
+            "Redirect({}, {:?}, {:?})",
+            self.fd, self.redirect_type, self.target
         )
     }
 }
 
-impl Runnable for InputRedirect {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
-        let file = File::open(&self.input_file)?;
-        let file_fd = file.into_raw_fd();
-
-        let mut child = Command::new("cat")
-            .stdin(unsafe { Stdio::from_raw_fd(file_fd) })
-            .stdout(Stdio::piped())
-            .spawn()?;
-        let input = child.stdout.take().unwrap();
-
-        let stdout = self.commands[0].pipe(Some(input))?;
-        let mut output = String::new();
-        if let Some(mut stdout) = stdout {
-            stdout.read_to_string(&mut output)?;
-        }
-        Ok(output.trim_end().to_string())
-    }
-}
-
-impl ShellCommand for InputRedirect {
-    fn cmd(&self) -> String {
-        self.commands[0].cmd()
-    }
-
-    fn args(&self) -> Vec<String> {
-        self.commands[0].args()
-    }
-
-    fn pipe(&self, _stdin: Option<ChildStdout>) -> Result<Option<ChildStdout>, Box<dyn Error>> {
-        let file = File::open(&self.input_file)?;
-        let file_fd = file.into_raw_fd();
-
-        let mut child = Command::new("cat")
-            .stdin(unsafe { Stdio::from_raw_fd(file_fd) })
-            .stdout(Stdio::piped())
-            .spawn()?;
-        let input = child.stdout.take().unwrap();
-
-        let stdout = self.commands[0].pipe(Some(input))?;
-        Ok(stdout)
+/// Applies redirections from left to right.
+pub fn apply_redirects(
+    shell: &mut Shell,
+    redirects: &[Redirect],
+    fds: &mut FdTable,
+) -> Result<(), Box<dyn Error>> {
+    for redirect in redirects {
+        redirect.apply(shell, fds)?;
     }
+    Ok(())
 }
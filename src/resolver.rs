@@ -0,0 +1,210 @@
+//! Works out what the first word of a command refers to, in the same order
+//! the shell looks when it runs the command.
+
+use crate::shell::Shell;
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Words that start or end a compound command.
+const KEYWORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "while", "until", "for", "in", "do", "done", "{", "}",
+    "[[", "]]", "time",
+];
+
+/// What a command name refers to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    Alias(String),
+    Keyword,
+    Function,
+    Builtin,
+    /// A double-quoted command, which is sent to the LLM
+    Llm,
+    File(PathBuf),
+}
+
+impl Resolution {
+    /// The single word `type -t` prints for the resolution.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Resolution::Alias(_) => "alias",
+            Resolution::Keyword => "keyword",
+            Resolution::Function => "function",
+            Resolution::Builtin => "builtin",
+            Resolution::Llm => "llm",
+            Resolution::File(_) => "file",
+        }
+    }
+}
+
+/// Where programs were found on `PATH`, so that running the same command
+/// again does not search every directory. The cache is emptied whenever
+/// `PATH` changes.
+#[derive(Clone, Debug, Default)]
+pub struct PathCache {
+    /// The value of `PATH` the entries were found with
+    path: OsString,
+    /// Each program with its path and how many times it has been looked up
+    entries: BTreeMap<OsString, (PathBuf, usize)>,
+}
+
+impl PathCache {
+    pub fn new() -> PathCache {
+        PathCache::default()
+    }
+
+    /// Finds a program on `path`, using where it was found before if it is
+    /// still there.
+    pub fn find(&mut self, name: &OsStr, path: &OsStr, cwd: &Path) -> Option<PathBuf> {
+        self.set_path(path);
+        match self.entries.get(name) {
+            // A program that has since been removed is searched for again
+            Some((file, _)) if is_executable(file) => Some(file.clone()),
+            _ => self.remember(name, path, cwd),
+        }
+    }
+
+    /// Finds a program to run, counting a hit for it.
+    pub fn lookup(&mut self, name: &OsStr, path: &OsStr, cwd: &Path) -> Option<PathBuf> {
+        let file = self.find(name, path, cwd)?;
+        if let Some((_, hits)) = self.entries.get_mut(name) {
+            *hits += 1;
+        }
+        Some(file)
+    }
+
+    /// Searches for a program and caches where it was found, as `hash NAME`
+    /// does.
+    pub fn remember(&mut self, name: &OsStr, path: &OsStr, cwd: &Path) -> Option<PathBuf> {
+        self.set_path(path);
+        self.entries.remove(name);
+        let (dir, file) = search(name, path, cwd).next()?;
+        // A relative directory such as `.` gives a different program after
+        // `cd`, so what is found there is not cached
+        if dir.is_absolute() {
+            self.entries.insert(name.to_os_string(), (file.clone(), 0));
+        }
+        Some(file)
+    }
+
+    /// The cached programs, with their paths and hit counts, by name.
+    pub fn entries(&self) -> impl Iterator<Item = (&OsStr, &Path, usize)> {
+        self.entries
+            .iter()
+            .map(|(name, (file, hits))| (name.as_os_str(), file.as_path(), *hits))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Empties the cache if `PATH` is no longer what the entries were found
+    /// with.
+    pub fn set_path(&mut self, path: &OsStr) {
+        if self.path != path {
+            self.entries.clear();
+            self.path = path.to_os_string();
+        }
+    }
+}
+
+/// What `name` runs as when it is the first word of a command.
+pub fn resolve(shell: &mut Shell, name: &OsStr) -> Option<Resolution> {
+    resolve_in_shell(shell, name)
+        .into_iter()
+        .next()
+        .or_else(|| {
+            let (path, cwd) = search_path(shell);
+            let file = match name.as_bytes().contains(&b'/') {
+                true => Some(shell.path(name)).filter(|file| is_executable(file)),
+                false => shell.path_cache.find(name, &path, &cwd),
+            };
+            file.map(Resolution::File)
+        })
+}
+
+/// Everything `name` could refer to, starting with the one that runs, as
+/// `type -a` prints them.
+pub fn resolve_all(shell: &Shell, name: &OsStr) -> Vec<Resolution> {
+    let mut found = resolve_in_shell(shell, name);
+    if found.contains(&Resolution::Llm) {
+        return found;
+    }
+    if name.as_bytes().contains(&b'/') {
+        let file = shell.path(name);
+        found.extend(is_executable(&file).then_some(Resolution::File(file)));
+    } else {
+        let (path, cwd) = search_path(shell);
+        found.extend(search(name, &path, &cwd).map(|(_, file)| Resolution::File(file)));
+    }
+    found
+}
+
+/// The program an external command runs. A name with a slash is a path,
+/// which is left for running it to report if it is missing, while any other
+/// name is searched for on `PATH`.
+pub fn find_program(shell: &mut Shell, name: &OsStr) -> Option<PathBuf> {
+    if name.as_bytes().contains(&b'/') {
+        return Some(shell.path(name));
+    }
+    let (path, cwd) = search_path(shell);
+    shell.path_cache.lookup(name, &path, &cwd)
+}
+
+/// The value of `PATH`, and the directory relative entries in it are
+/// relative to.
+pub fn search_path(shell: &Shell) -> (OsString, PathBuf) {
+    let path = shell.variables.get("PATH").unwrap_or_default();
+    (path.to_os_string(), shell.cwd().to_path_buf())
+}
+
+/// The aliases, keywords, functions and builtins called `name`.
+fn resolve_in_shell(shell: &Shell, name: &OsStr) -> Vec<Resolution> {
+    if is_prompt(name) {
+        return vec![Resolution::Llm];
+    }
+    let mut found = Vec::new();
+    if let Some(value) = name.to_str().and_then(|name| shell.aliases.get(name)) {
+        found.push(Resolution::Alias(value.clone()));
+    }
+    if name.to_str().is_some_and(|name| KEYWORDS.contains(&name)) {
+        found.push(Resolution::Keyword);
+    }
+    if shell.functions.contains(name) {
+        found.push(Resolution::Function);
+    }
+    if name.to_str().and_then(|name| shell.builtin(name)).is_some() {
+        found.push(Resolution::Builtin);
+    }
+    found
+}
+
+/// Whether the name is written as a prompt, such as `"translate to French"`.
+fn is_prompt(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    name.len() >= 2 && name.starts_with(b"\"") && name.ends_with(b"\"")
+}
+
+/// The executables called `name` in each directory of `path`, along with
+/// the directory they were found in.
+fn search<'a>(
+    name: &'a OsStr,
+    path: &'a OsStr,
+    cwd: &'a Path,
+) -> impl Iterator<Item = (PathBuf, PathBuf)> + 'a {
+    std::env::split_paths(path)
+        .map(|dir| (cwd.join(&dir), dir))
+        .filter_map(move |(full, dir)| {
+            let file = full.join(name);
+            is_executable(&file).then_some((dir, file))
+        })
+}
+
+fn is_executable(file: &Path) -> bool {
+    file.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
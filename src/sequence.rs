@@ -1,8 +1,41 @@
+use crate::errors::{exit_code, report_error, unwinds, RuntimeError};
+use crate::shell::Shell;
 use crate::traits::Runnable;
 use std::error::Error;
 use std::fmt;
+use std::io::Write;
 use std::ops::Index;
 
+/// Prints output that a command returned instead of writing it itself.
+pub fn print_output(shell: &Shell, output: &str) {
+    if !output.is_empty() {
+        let _ = writeln!(shell.stdout(), "{}", output);
+    }
+}
+
+/// Runs a command whose result is not passed on, printing its output or
+/// reporting its error. Returns whether the command succeeded, or the error
+/// if it skips the commands after it, as a failure does under `set -e`.
+pub fn run_and_report(shell: &mut Shell, command: &dyn Runnable) -> Result<bool, Box<dyn Error>> {
+    match command.run(shell) {
+        Ok(output) => {
+            print_output(shell, &output);
+            shell.set_last_status(0);
+            Ok(true)
+        }
+        Err(e) if unwinds(e.as_ref()) => Err(e),
+        Err(e) => {
+            report_error(shell, e.as_ref());
+            let code = exit_code(e.as_ref());
+            shell.set_last_status(code);
+            if shell.exits_on(e.as_ref()) {
+                return Err(RuntimeError::Exit(code).into());
+            }
+            Ok(false)
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Sequence {
     commands: Vec<Box<dyn Runnable>>,
@@ -43,17 +76,15 @@ impl Default for Sequence {
 }
 
 impl Runnable for Sequence {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
-        let mut prev_output: Option<String> = None;
-        for command in &self.commands {
-            match command.run() {
-                Ok(output) => {
-                    prev_output = Some(output);
-                }
-                Err(e) => return Err(e),
-            }
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        let Some((last, rest)) = self.commands.split_last() else {
+            return Ok("".to_string());
+        };
+        // A failing command does not stop the ones after it
+        for command in rest {
+            run_and_report(shell, command.as_ref())?;
         }
-        Ok(prev_output.unwrap_or_default())
+        last.run(shell)
     }
 }
 
@@ -118,17 +149,26 @@ impl Default for AndSequence {
 }
 
 impl Runnable for AndSequence {
-    fn run(&self) -> Result<String, Box<dyn Error>> {
-        let mut prev_output: Option<String> = None;
-        for command in &self.commands {
-            match command.run() {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        let Some((last, rest)) = self.commands.split_last() else {
+            return Ok("".to_string());
+        };
+        // Only the last command can make `set -e` exit, as the failure of
+        // any other stops the list instead
+        for command in rest {
+            match shell.ignoring_errexit(|shell| command.run(shell)) {
                 Ok(output) => {
-                    prev_output = Some(output);
+                    print_output(shell, &output);
+                    shell.set_last_status(0);
+                }
+                Err(e) if unwinds(e.as_ref()) => return Err(e),
+                Err(e) => {
+                    report_error(shell, e.as_ref());
+                    return Err(RuntimeError::ShortCircuit(exit_code(e.as_ref())).into());
                 }
-                Err(e) => return Err(e),
             }
         }
-        Ok(prev_output.unwrap_or_default())
+        last.run(shell)
     }
 }
 
@@ -152,3 +192,83 @@ impl fmt::Debug for AndSequence {
         write!(f, ")")
     }
 }
+
+#[derive(Clone)]
+pub struct OrSequence {
+    commands: Vec<Box<dyn Runnable>>,
+}
+
+impl OrSequence {
+    pub fn new() -> OrSequence {
+        OrSequence {
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn init(commands: Vec<Box<dyn Runnable>>) -> OrSequence {
+        OrSequence { commands }
+    }
+
+    pub fn add(&mut self, command: Box<dyn Runnable>) -> &mut OrSequence {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn transfer(&mut self) -> OrSequence {
+        let commands = self.commands.clone();
+        self.clear();
+        OrSequence { commands }
+    }
+
+    pub fn clear(&mut self) -> &mut OrSequence {
+        self.commands.clear();
+        self
+    }
+}
+
+impl Default for OrSequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Runnable for OrSequence {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        let Some((last, rest)) = self.commands.split_last() else {
+            return Ok("".to_string());
+        };
+        // Stops at the first command that succeeds
+        for command in rest {
+            match shell.ignoring_errexit(|shell| command.run(shell)) {
+                Ok(output) => return Ok(output),
+                Err(e) if unwinds(e.as_ref()) => return Err(e),
+                Err(e) => {
+                    report_error(shell, e.as_ref());
+                    shell.set_last_status(exit_code(e.as_ref()));
+                }
+            }
+        }
+        last.run(shell)
+    }
+}
+
+impl Index<usize> for OrSequence {
+    type Output = Box<dyn Runnable>;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.commands[index]
+    }
+}
+
+impl fmt::Debug for OrSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OrSequence(")?;
+        for (i, command) in self.commands.iter().enumerate() {
+            write!(f, "{:?}", command)?;
+            if i < self.commands.len() - 1 {
+                write!(f, ", ")?;
+            }
+        }
+        write!(f, ")")
+    }
+}
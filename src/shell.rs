@@ -0,0 +1,614 @@
+//! The state of a shell session and the entry points for running commands in it.
+
+use crate::ast::{AndOr, Assignment};
+use crate::builtins::{self, Builtin};
+use crate::compiler::compile_and_or;
+use crate::directories::{logical_path, DirStack};
+use crate::errors::{exit_code, report_error, RuntimeError, SyntaxError};
+use crate::format::single_quote;
+use crate::functions::Functions;
+use crate::history::History;
+use crate::io_context::{Capture, FdTable, IoContext};
+use crate::jobs::{self, Jobs};
+use crate::openai_client::OpenAIClient;
+use crate::options::Options;
+use crate::parsing::{is_name, Aliases, ScriptParser};
+use crate::resolver::PathCache;
+use crate::sequence::print_output;
+use crate::timing::CpuTime;
+use crate::token::lossy;
+use crate::variables::{Value, Variables};
+
+use nix::unistd::{getppid, getuid, Pid, User};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// What a command printed and how it exited, as returned by [`Shell::eval`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExecResult {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: i32,
+}
+
+/// A shell session.
+///
+/// Everything a command can change lives here rather than in the shell
+/// process, so that several shells can be used side by side, each with
+/// their own variables and working directory.
+///
+/// Subshells, pipeline stages and background jobs run in forked copies of
+/// the process, which carry on running the shell's code. A program that
+/// embeds the shell must not have other threads running while it runs
+/// commands, as a lock one of them held when the process forked, such as
+/// that of stdout, would never be released in the copy.
+pub struct Shell {
+    pub variables: Variables,
+    pub aliases: Aliases,
+    pub functions: Functions,
+    pub options: Options,
+    pub history: History,
+    pub path_cache: PathCache,
+    pub dir_stack: DirStack,
+    pub jobs: Jobs,
+    /// The commands `trap` set to run as the shell exits
+    pub exit_trap: Option<String>,
+    /// The logical working directory, which keeps the symbolic links it was
+    /// entered through
+    cwd: PathBuf,
+    builtins: HashMap<String, Builtin>,
+    llm_client: Option<OpenAIClient>,
+    /// The standard streams of the shell, which redirections of compound
+    /// commands point elsewhere while they run
+    fds: FdTable,
+    /// The streams `exec` set up, which outlast the command that ran it
+    kept_fds: Option<FdTable>,
+    suggestion: Option<String>,
+    /// The status of the last command run, for `exit` without a status
+    last_status: i32,
+    /// How many commands whose status is tested, such as the condition of an
+    /// `if`, are being run, during which `set -e` does not exit
+    errexit_ignored: usize,
+    /// How many loops the command being run is inside, for `break` and
+    /// `continue`. A function body starts outside of any.
+    loop_depth: usize,
+    /// How long the shell has spent waiting for the LLM, for `time`
+    llm_time: Duration,
+    /// The CPU time of the children the shell has waited for, for `time`
+    child_cpu_time: CpuTime,
+    /// The process ID of the shell, which `$$` keeps in subshells
+    pid: u32,
+    /// When the shell started, for `$SECONDS`
+    started: Instant,
+    /// The state of the generator behind `$RANDOM`
+    random: Cell<u32>,
+    exit_status: Option<i32>,
+    /// The assignments before the simple command about to run, which it
+    /// makes once its words have been expanded
+    prefix_assignments: Vec<Assignment>,
+}
+
+impl Shell {
+    /// A shell started from the environment and working directory of the
+    /// process.
+    pub fn new() -> Shell {
+        let variables = Variables::from_env();
+        let cwd = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+        // An inherited `$PWD` is kept when it names the same directory, as it
+        // may go through symbolic links that the physical path does not
+        let cwd = match variables.get("PWD").map(Path::new) {
+            Some(pwd) if pwd.is_absolute() && same_directory(pwd, &cwd) => {
+                logical_path(pwd, Path::new(""))
+            }
+            _ => cwd,
+        };
+        let mut shell = Shell {
+            variables,
+            aliases: Aliases::new(),
+            functions: Functions::new(),
+            options: Options::default(),
+            history: History::new(),
+            path_cache: PathCache::new(),
+            dir_stack: DirStack::new(),
+            jobs: Jobs::new(),
+            exit_trap: None,
+            cwd: PathBuf::new(),
+            builtins: HashMap::new(),
+            llm_client: None,
+            fds: FdTable::new(),
+            kept_fds: None,
+            suggestion: None,
+            last_status: 0,
+            errexit_ignored: 0,
+            loop_depth: 0,
+            llm_time: Duration::ZERO,
+            child_cpu_time: CpuTime::default(),
+            pid: std::process::id(),
+            started: Instant::now(),
+            random: Cell::new(random_seed()),
+            exit_status: None,
+            prefix_assignments: Vec::new(),
+        };
+        let level = shell
+            .variables
+            .get("SHLVL")
+            .and_then(|level| lossy(level).parse::<i64>().ok())
+            .unwrap_or(0);
+        shell.variables.export("SHLVL", (level + 1).to_string());
+        shell.variables.set("PPID", getppid().to_string());
+        for (name, builtin) in builtins::BUILTINS {
+            shell.builtins.insert(name.to_string(), Rc::new(builtin));
+        }
+        shell.set_cwd(cwd);
+        shell
+    }
+
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// Changes the working directory, keeping `$PWD` in step with it.
+    pub fn set_cwd(&mut self, cwd: PathBuf) {
+        self.variables.set("PWD", &cwd);
+        self.cwd = cwd;
+    }
+
+    /// The value of a parameter: a positional parameter, a special parameter
+    /// such as `$?`, or a variable.
+    pub fn parameter(&self, name: &str) -> Option<OsString> {
+        if let Some(value) = self.functions.positional(name) {
+            return Some(value);
+        }
+        let value = match name {
+            "?" => self.last_status.to_string(),
+            "$" => self.pid.to_string(),
+            "!" => self.jobs.last()?.to_string(),
+            "-" => self.options.flags(),
+            "RANDOM" => self.next_random().to_string(),
+            "SECONDS" => self.started.elapsed().as_secs().to_string(),
+            _ => return self.variables.get(name).map(OsStr::to_os_string),
+        };
+        Some(OsString::from(value))
+    }
+
+    /// The elements of an array in order, the positional parameters for `@`
+    /// and `*`, and nothing for a variable that is not set.
+    pub fn elements(&self, name: &str) -> Vec<OsString> {
+        match name {
+            "@" | "*" => self.functions.positional_args(),
+            _ => match self.variables.value(name) {
+                Some(value) => value
+                    .elements()
+                    .into_iter()
+                    .map(OsStr::to_os_string)
+                    .collect(),
+                None => self.parameter(name).into_iter().collect(),
+            },
+        }
+    }
+
+    /// The indices or keys of the elements of an array.
+    pub fn keys(&self, name: &str) -> Vec<OsString> {
+        match name {
+            "@" | "*" => (1..=self.functions.positional_args().len())
+                .map(|i| i.to_string().into())
+                .collect(),
+            _ => match self.variables.value(name) {
+                Some(value) => value.keys(),
+                None => self
+                    .parameter(name)
+                    .map(|_| "0".into())
+                    .into_iter()
+                    .collect(),
+            },
+        }
+    }
+
+    /// The element of an array that `subscript` names. The subscript of an
+    /// indexed array may also be the name of a variable holding the index,
+    /// as in `${array[i]}`.
+    pub fn element(&self, name: &str, subscript: &OsStr) -> Result<Option<OsString>, RuntimeError> {
+        let subscript = match self.variables.value(name) {
+            Some(Value::Associative(_)) => subscript.to_os_string(),
+            _ => self.index(subscript),
+        };
+        match self.variables.value(name) {
+            Some(_) => Ok(self
+                .variables
+                .element(name, &subscript)?
+                .map(OsStr::to_os_string)),
+            // Special parameters are strings, with only an element 0
+            None => Ok(self
+                .parameter(name)
+                .filter(|_| lossy(&subscript).trim() == "0")),
+        }
+    }
+
+    /// An index given as a subscript, with a variable name replaced by its
+    /// value.
+    pub fn index(&self, subscript: &OsStr) -> OsString {
+        let text = lossy(subscript);
+        match is_name(text.trim()) {
+            true => self.parameter(text.trim()).unwrap_or_else(|| "0".into()),
+            false => subscript.to_os_string(),
+        }
+    }
+
+    /// The directory a tilde prefix stands for: the home directory for `~`,
+    /// `$PWD` for `~+`, `$OLDPWD` for `~-` and the home directory of a user
+    /// from the password database for `~user`.
+    pub fn tilde(&self, prefix: &str) -> Option<OsString> {
+        let home = |user: Option<User>| user.map(|user| user.dir.into_os_string());
+        match prefix {
+            "" => match self.variables.get("HOME") {
+                Some(home) => Some(home.to_os_string()),
+                None => home(User::from_uid(getuid()).ok().flatten()),
+            },
+            "+" => self.variables.get("PWD").map(OsStr::to_os_string),
+            "-" => self.variables.get("OLDPWD").map(OsStr::to_os_string),
+            name => home(User::from_name(name).ok().flatten()),
+        }
+    }
+
+    /// The next number from 0 to 32767 for `$RANDOM`, from the same
+    /// generator as other shells use.
+    fn next_random(&self) -> u32 {
+        let seed = (16807 * self.random.get() as u64 % 2147483647) as u32;
+        self.random.set(seed);
+        seed & 32767
+    }
+
+    /// Resolves a path against the working directory of the shell.
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.cwd.join(path)
+    }
+
+    /// Adds a command that runs inside the shell, replacing any builtin of the
+    /// same name.
+    pub fn register_builtin<F>(&mut self, name: &str, builtin: F)
+    where
+        F: Fn(&mut Shell, Vec<OsString>, &mut IoContext) -> Result<String, Box<dyn Error>>
+            + 'static,
+    {
+        self.builtins.insert(name.to_string(), Rc::new(builtin));
+    }
+
+    pub fn builtin(&self, name: &str) -> Option<Builtin> {
+        self.builtins.get(name).cloned()
+    }
+
+    /// Uses `client` for prompts instead of a client for `$OPENAI_API_KEY`.
+    pub fn set_llm_client(&mut self, client: OpenAIClient) {
+        self.llm_client = Some(client);
+    }
+
+    pub fn llm_client(&self) -> Result<OpenAIClient, SyntaxError> {
+        match &self.llm_client {
+            Some(client) => Ok(client.clone()),
+            None => OpenAIClient::new(self.variables.get("OPENAI_API_KEY").map(lossy)),
+        }
+    }
+
+    /// A copy of the shell's standard streams, for a command to start from.
+    pub fn fds(&self) -> FdTable {
+        self.fds.clone()
+    }
+
+    /// Runs `f` with the shell's standard streams pointed at `fds`.
+    pub fn with_fds<T>(&mut self, fds: FdTable, f: impl FnOnce(&mut Shell) -> T) -> T {
+        let saved = std::mem::replace(&mut self.fds, fds);
+        let result = f(self);
+        self.fds = self.kept_fds.take().unwrap_or(saved);
+        result
+    }
+
+    /// Keeps the streams of the command being run for the shell itself once
+    /// it finishes, as `exec` with only redirections does.
+    pub fn keep_fds(&mut self) {
+        self.kept_fds = Some(self.fds.clone());
+    }
+
+    pub fn stdout(&self) -> Box<dyn Write> {
+        self.fds
+            .writer(1)
+            .unwrap_or_else(|_| Box::new(std::io::sink()))
+    }
+
+    pub fn stderr(&self) -> Box<dyn Write> {
+        self.fds
+            .writer(2)
+            .unwrap_or_else(|_| Box::new(std::io::sink()))
+    }
+
+    /// Offers a command for the user to run next, such as one written by the
+    /// LLM.
+    pub fn suggest(&mut self, command: String) {
+        self.suggestion = Some(command);
+    }
+
+    pub fn take_suggestion(&mut self) -> Option<String> {
+        self.suggestion.take()
+    }
+
+    /// Hands assignments that come before a command to it.
+    pub fn set_prefix_assignments(&mut self, assignments: Vec<Assignment>) {
+        self.prefix_assignments = assignments;
+    }
+
+    /// The assignments that come before the command about to run, which
+    /// are only made once.
+    pub fn take_prefix_assignments(&mut self) -> Vec<Assignment> {
+        std::mem::take(&mut self.prefix_assignments)
+    }
+
+    /// The status the shell was asked to exit with by `exit`, if it was.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// The status of the last command run.
+    pub fn last_status(&self) -> i32 {
+        self.last_status
+    }
+
+    pub fn set_last_status(&mut self, status: i32) {
+        self.last_status = status;
+    }
+
+    /// Runs `f` as a command whose status is tested, so that its failure
+    /// does not exit the shell under `set -e`.
+    pub fn ignoring_errexit<T>(&mut self, f: impl FnOnce(&mut Shell) -> T) -> T {
+        self.errexit_ignored += 1;
+        let result = f(self);
+        self.errexit_ignored -= 1;
+        result
+    }
+
+    /// Runs `f`, the body of a loop, as a command inside one more loop.
+    pub fn in_loop<T>(&mut self, f: impl FnOnce(&mut Shell) -> T) -> T {
+        self.loop_depth += 1;
+        let result = f(self);
+        self.loop_depth -= 1;
+        result
+    }
+
+    /// Runs `f`, such as the body of a function, outside of every loop, so
+    /// that `break` cannot leave a loop the function was called from.
+    pub fn outside_loops<T>(&mut self, f: impl FnOnce(&mut Shell) -> T) -> T {
+        let depth = std::mem::take(&mut self.loop_depth);
+        let result = f(self);
+        self.loop_depth = depth;
+        result
+    }
+
+    /// How many loops the command being run is inside.
+    pub fn loop_depth(&self) -> usize {
+        self.loop_depth
+    }
+
+    /// Whether a command failing with `e` exits the shell: under `set -e`
+    /// unless its status is tested, and for an unset variable under `set -u`
+    /// unless at the prompt.
+    pub fn exits_on(&self, e: &(dyn Error + 'static)) -> bool {
+        match e.downcast_ref::<RuntimeError>() {
+            Some(RuntimeError::ShortCircuit(_)) => false,
+            Some(RuntimeError::UnboundVariable(_)) if !self.options.interactive => true,
+            _ => self.options.errexit && self.errexit_ignored == 0,
+        }
+    }
+
+    /// Prints a command about to run under `set -x`, after `$PS4`.
+    pub fn trace(&self, argv: &[OsString]) {
+        if !self.options.xtrace {
+            return;
+        }
+        let mut line = Vec::new();
+        for (i, word) in argv.iter().enumerate() {
+            if i > 0 {
+                line.push(b' ');
+            }
+            line.extend(single_quote(word.as_bytes()));
+        }
+        self.write_trace(&line);
+    }
+
+    /// Prints an assignment to stderr with `set -x`, as `name=value` or
+    /// `name=(a b)` with the values quoted as [`Shell::trace`] quotes words.
+    pub fn trace_assignment(&self, name: &str, append: bool, values: &[OsString], array: bool) {
+        if !self.options.xtrace {
+            return;
+        }
+        let mut line = name.as_bytes().to_vec();
+        line.extend_from_slice(if append { b"+=" } else { b"=" });
+        let values: Vec<Vec<u8>> = values.iter().map(|v| single_quote(v.as_bytes())).collect();
+        match array {
+            true => {
+                line.push(b'(');
+                line.extend(values.join(&b' '));
+                line.push(b')');
+            }
+            false => line.extend(values.concat()),
+        }
+        self.write_trace(&line);
+    }
+
+    /// Writes a line of `set -x` output after `$PS4`.
+    fn write_trace(&self, text: &[u8]) {
+        let mut line = self
+            .variables
+            .get("PS4")
+            .map_or_else(|| b"+ ".to_vec(), |ps4| ps4.as_bytes().to_vec());
+        line.extend_from_slice(text);
+        line.push(b'\n');
+        let _ = self.stderr().write_all(&line);
+    }
+
+    /// The total time spent waiting for responses from the LLM.
+    pub fn llm_time(&self) -> Duration {
+        self.llm_time
+    }
+
+    pub fn add_llm_time(&mut self, time: Duration) {
+        self.llm_time += time;
+    }
+
+    /// The total CPU time of the children the shell has waited for.
+    pub fn child_cpu_time(&self) -> CpuTime {
+        self.child_cpu_time
+    }
+
+    /// Waits for a child of the shell to finish, returning its exit status,
+    /// and counts the CPU time it used.
+    pub fn wait_for(&mut self, pid: Pid) -> i32 {
+        let (status, time) = jobs::wait_with_usage(pid);
+        self.child_cpu_time += time;
+        status
+    }
+
+    /// Runs the commands of the `EXIT` trap, once, as the shell exits. An
+    /// `exit` in them replaces the status the shell exits with.
+    pub fn run_exit_trap(&mut self) {
+        if let Some(action) = self.exit_trap.take() {
+            self.exit_status = None;
+            self.run(action.as_bytes(), "<trap>");
+        }
+    }
+
+    /// Runs a script and returns what it printed along with the status of its
+    /// last command. An `exit` only stops the script it is in, so each call
+    /// starts afresh after one.
+    pub fn eval(&mut self, input: &str) -> ExecResult {
+        self.exit_status = None;
+        self.eval_captured(input).unwrap_or_else(|e| ExecResult {
+            stdout: Vec::new(),
+            stderr: e.to_string().into_bytes(),
+            status: 1,
+        })
+    }
+
+    fn eval_captured(&mut self, input: &str) -> Result<ExecResult, Box<dyn Error>> {
+        let (stdout, stdout_fd) = Capture::new()?;
+        let (stderr, stderr_fd) = Capture::new()?;
+        let mut fds = self.fds();
+        fds.set(1, stdout_fd.into()).set(2, stderr_fd.into());
+
+        let status = self.with_fds(fds, |shell| shell.run(input.as_bytes(), "<eval>"));
+        Ok(ExecResult {
+            stdout: stdout.finish(),
+            stderr: stderr.finish(),
+            status,
+        })
+    }
+
+    /// Runs a script read from `name`, writing to the shell's own stdout and
+    /// stderr, and returns the status of its last command.
+    ///
+    /// Syntax errors are reported and the script carries on after them.
+    pub fn run(&mut self, input: &[u8], name: &str) -> i32 {
+        self.run_script(input, name)
+            .unwrap_or_else(|e| exit_code(e.as_ref()))
+    }
+
+    /// Like [`Shell::run`], but a `return` in the script stops it and is
+    /// passed on, for `source` to handle.
+    pub fn run_script(&mut self, input: &[u8], name: &str) -> Result<i32, Box<dyn Error>> {
+        let mut status = 0;
+        let mut parser = ScriptParser::new(OsString::from_vec(input.to_vec()));
+        while let Some(item) = parser.next(&self.aliases) {
+            status = match item {
+                Ok(item) => self.run_item(&item)?,
+                Err(e) => {
+                    let _ = writeln!(self.stderr(), "{}", e.render(input, name));
+                    2
+                }
+            };
+            if self.exit_status.is_some() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    /// Runs the script in a file, such as `.aishrc`.
+    pub fn run_file(&mut self, path: &Path) -> Result<i32, std::io::Error> {
+        // Read as raw bytes, since scripts may contain file names that are not
+        // valid UTF-8
+        let input = std::fs::read(self.path(path))?;
+        Ok(self.run(&input, &path.display().to_string()))
+    }
+
+    fn run_item(&mut self, item: &AndOr) -> Result<i32, Box<dyn Error>> {
+        let status = self.run_and_or(item)?;
+        self.last_status = status;
+        Ok(status)
+    }
+
+    fn run_and_or(&mut self, item: &AndOr) -> Result<i32, Box<dyn Error>> {
+        debug!("Executing command: {:?}", item);
+        self.jobs.reap();
+        let command = match compile_and_or(item) {
+            Ok(command) => command,
+            Err(e) => {
+                report_error(self, &e);
+                return Ok(2);
+            }
+        };
+        debug!("Compiled: {:?}", command);
+        // `set -n` checks a script for syntax errors without running it
+        if self.options.noexec && !self.options.interactive {
+            return Ok(0);
+        }
+        match command.run(self) {
+            Ok(output) => {
+                print_output(self, &output);
+                Ok(0)
+            }
+            Err(e) => match e.downcast_ref::<RuntimeError>() {
+                Some(RuntimeError::Return(_)) => Err(e),
+                Some(RuntimeError::Exit(code)) => {
+                    self.exit_status = Some(*code);
+                    Ok(*code)
+                }
+                _ => {
+                    report_error(self, e.as_ref());
+                    let code = exit_code(e.as_ref());
+                    if self.exits_on(e.as_ref()) {
+                        self.exit_status = Some(code);
+                    }
+                    Ok(code)
+                }
+            },
+        }
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A seed for `$RANDOM` that differs between runs. It must not be 0, which
+/// the generator would never leave.
+fn random_seed() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+    (nanos ^ std::process::id()) % 2147483646 + 1
+}
+
+/// Whether two paths lead to the same directory.
+fn same_directory(a: &Path, b: &Path) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    }
+}
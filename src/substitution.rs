@@ -0,0 +1,100 @@
+//! Process substitution, where `<(list)` and `>(list)` run a list connected
+//! to a pipe and stand for the path of the shell's end of it, so that
+//! `diff <(sort a) <(sort b)` compares the output of two commands.
+
+use crate::ast::ProcessSubstitution;
+use crate::compiler::compile_list;
+use crate::compound::{fork_shell, run_in_child_with};
+use crate::io_context::{cloexec_pipe, FdTable};
+use crate::shell::Shell;
+use crate::token::Token;
+use crate::traits::Runnable;
+
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::unistd::ForkResult;
+use std::borrow::Cow;
+use std::error::Error;
+use std::ffi::OsString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// The lowest descriptor the shell's ends of the pipes are moved to, as
+/// in other shells, so that they stay clear of those that redirections use.
+const FIRST_FD: i32 = 63;
+
+/// Starts the process substitutions among `tokens` and returns the tokens
+/// with each one replaced by the `/dev/fd/N` path of its pipe. The shell's
+/// ends of the pipes are added to `fds`, so that the command the tokens
+/// belong to inherits them and they are closed once it is done.
+pub fn substitute<'a>(
+    shell: &mut Shell,
+    tokens: &'a [Token],
+    fds: &mut FdTable,
+) -> Result<Cow<'a, [Token]>, Box<dyn Error>> {
+    if !tokens.iter().any(has_substitution) {
+        return Ok(Cow::Borrowed(tokens));
+    }
+    let tokens = tokens
+        .iter()
+        .map(|token| substitute_token(shell, token, fds))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Cow::Owned(tokens))
+}
+
+/// Like [`substitute`], for a single word such as the target of a
+/// redirection.
+pub fn substitute_token(
+    shell: &mut Shell,
+    token: &Token,
+    fds: &mut FdTable,
+) -> Result<Token, Box<dyn Error>> {
+    match token {
+        Token::ProcessSubstitution(substitution) => start(shell, substitution, fds),
+        Token::Group(parts) => Ok(Token::Group(substitute(shell, parts, fds)?.into_owned())),
+        token => Ok(token.clone()),
+    }
+}
+
+fn has_substitution(token: &Token) -> bool {
+    match token {
+        Token::ProcessSubstitution(_) => true,
+        Token::Group(parts) => parts.iter().any(has_substitution),
+        _ => false,
+    }
+}
+
+/// Runs the list of a substitution in a forked copy of the shell, with its
+/// stdout writing into a new pipe, or its stdin reading from it for
+/// `>(list)`. The process is recorded with the jobs, so that `wait` can wait
+/// for it, rather than waited for when the command is done.
+fn start(
+    shell: &mut Shell,
+    substitution: &ProcessSubstitution,
+    fds: &mut FdTable,
+) -> Result<Token, Box<dyn Error>> {
+    let list = compile_list(&substitution.list)?;
+    let (read_end, write_end) = cloexec_pipe()?;
+    let (pipe_end, list_end, list_fd) = match substitution.output {
+        true => (write_end, read_end, 0),
+        false => (read_end, write_end, 1),
+    };
+    let fd = fcntl(pipe_end.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(FIRST_FD))?;
+    // SAFETY: `fcntl` returned a new descriptor that nothing else owns
+    let shell_end = unsafe { OwnedFd::from_raw_fd(fd) };
+    drop(pipe_end);
+
+    match fork_shell()? {
+        ForkResult::Child => {
+            drop(shell_end);
+            let mut list_fds = shell.fds();
+            list_fds.set(list_fd, list_end.into());
+            run_in_child_with(shell, list_fds, |shell| list.run(shell))
+        }
+        ForkResult::Parent { child } => {
+            drop(list_end);
+            shell.jobs.add_substitution(child);
+            fds.set(fd, shell_end.into());
+            let path = OsString::from(format!("/dev/fd/{}", fd));
+            Ok(Token::SingleQuoted(vec![Token::Plain(path)]))
+        }
+    }
+}
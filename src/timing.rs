@@ -0,0 +1,192 @@
+//! The `time` reserved word, which reports how long a pipeline took.
+
+use crate::ast::TimeFormat;
+use crate::sequence::print_output;
+use crate::shell::Shell;
+use crate::token::lossy;
+use crate::traits::Runnable;
+
+use nix::libc;
+use nix::sys::resource::{getrusage, UsageWho};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+/// The report used when `$TIMEFORMAT` is unset.
+const DEFAULT_FORMAT: &str = "\nreal\t%3lR\nuser\t%3lU\nsys\t%3lS";
+
+/// The line added to the usual report when the pipeline waited on the LLM.
+const LLM_FORMAT: &str = "\nllm\t%3lL";
+
+const POSIX_FORMAT: &str = "real %2R\nuser %2U\nsys %2S";
+
+/// How long a pipeline took.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Times {
+    pub real: Duration,
+    /// CPU time in user mode, of the shell and every process it waited for
+    pub user: Duration,
+    /// CPU time in the kernel, of the shell and every process it waited for
+    pub sys: Duration,
+    /// Time spent waiting for responses from the LLM
+    pub llm: Duration,
+}
+
+/// CPU time used by processes the shell waited for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuTime {
+    pub user: Duration,
+    pub sys: Duration,
+}
+
+impl From<&libc::rusage> for CpuTime {
+    fn from(usage: &libc::rusage) -> CpuTime {
+        let duration = |time: libc::timeval| duration(TimeVal::from(time));
+        CpuTime {
+            user: duration(usage.ru_utime),
+            sys: duration(usage.ru_stime),
+        }
+    }
+}
+
+impl AddAssign for CpuTime {
+    fn add_assign(&mut self, other: CpuTime) {
+        self.user += other.user;
+        self.sys += other.sys;
+    }
+}
+
+/// A pipeline preceded by `time`, which prints how long it took to the
+/// shell's stderr once it finishes.
+#[derive(Clone)]
+pub struct Timed {
+    body: Box<dyn Runnable>,
+    format: TimeFormat,
+}
+
+impl Timed {
+    pub fn new(body: Box<dyn Runnable>, format: TimeFormat) -> Timed {
+        Timed { body, format }
+    }
+}
+
+impl fmt::Debug for Timed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed({:?}, {:?})", self.format, self.body)
+    }
+}
+
+impl Runnable for Timed {
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>> {
+        let start = Instant::now();
+        let before = shell_cpu_time()?;
+        let children = shell.child_cpu_time();
+        let llm = shell.llm_time();
+
+        // The output comes before the report, as it would if the pipeline
+        // had written it itself
+        let result = self.body.run(shell).map(|output| {
+            print_output(shell, &output);
+            "".to_string()
+        });
+
+        let after = shell_cpu_time()?;
+        let waited = shell.child_cpu_time();
+        let times = Times {
+            real: start.elapsed(),
+            user: after.user.saturating_sub(before.user)
+                + waited.user.saturating_sub(children.user),
+            sys: after.sys.saturating_sub(before.sys) + waited.sys.saturating_sub(children.sys),
+            llm: shell.llm_time().saturating_sub(llm),
+        };
+        let format = match (self.format, shell.variables.get("TIMEFORMAT")) {
+            (TimeFormat::Posix, _) => POSIX_FORMAT.to_string(),
+            (TimeFormat::Default, Some(format)) => lossy(format),
+            (TimeFormat::Default, None) if times.llm.is_zero() => DEFAULT_FORMAT.to_string(),
+            (TimeFormat::Default, None) => format!("{DEFAULT_FORMAT}{LLM_FORMAT}"),
+        };
+        // An empty format turns the report off
+        if !format.is_empty() {
+            writeln!(shell.stderr(), "{}", format_times(&format, &times))?;
+        }
+        result
+    }
+}
+
+/// The CPU time used so far by the shell itself. The time of the processes
+/// it waits for is added up as each is reaped, so that only those of the
+/// pipeline count, not background jobs that happen to be reaped meanwhile.
+fn shell_cpu_time() -> Result<CpuTime, Box<dyn Error>> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF)?;
+    Ok(CpuTime {
+        user: duration(usage.user_time()),
+        sys: duration(usage.system_time()),
+    })
+}
+
+fn duration(time: TimeVal) -> Duration {
+    Duration::from_micros(time.num_microseconds().max(0) as u64)
+}
+
+/// Fills in a `$TIMEFORMAT` style format. `%R`, `%U` and `%S` are the real,
+/// user and system time in seconds, `%L` is the time spent waiting for the
+/// LLM and `%P` is the CPU usage as a percentage of the real time. A digit
+/// after the `%` gives the number of decimal places, three by default, and
+/// an `l` writes the time as minutes and seconds, such as `0m1.500s`.
+pub fn format_times(format: &str, times: &Times) -> String {
+    let mut report = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            report.push(c);
+            continue;
+        }
+        let precision = chars
+            .next_if(char::is_ascii_digit)
+            .map_or(3, |digit| digit.to_digit(10).unwrap().min(3) as usize);
+        let long = chars.next_if_eq(&'l').is_some();
+        let time = match chars.next() {
+            Some('%') => {
+                report.push('%');
+                continue;
+            }
+            Some('R') => times.real,
+            Some('U') => times.user,
+            Some('S') => times.sys,
+            Some('L') => times.llm,
+            Some('P') => {
+                let cpu = (times.user + times.sys).as_secs_f64();
+                let real = times.real.as_secs_f64();
+                let percent = if real > 0.0 { cpu / real * 100.0 } else { 0.0 };
+                report.push_str(&format!("{:.2}", percent));
+                continue;
+            }
+            // Anything else is kept as it was written
+            Some(other) => {
+                report.push('%');
+                report.push(other);
+                continue;
+            }
+            None => {
+                report.push('%');
+                break;
+            }
+        };
+        report.push_str(&format_seconds(time, precision, long));
+    }
+    report
+}
+
+fn format_seconds(time: Duration, precision: usize, long: bool) -> String {
+    let seconds = time.as_secs_f64();
+    match long {
+        true => {
+            let minutes = (seconds / 60.0).floor();
+            format!("{}m{:.*}s", minutes, precision, seconds - minutes * 60.0)
+        }
+        false => format!("{:.*}", precision, seconds),
+    }
+}
@@ -1,4 +1,12 @@
+use crate::ast::ProcessSubstitution;
+use crate::errors::RuntimeError;
+use crate::fields::{FieldSplitter, DEFAULT_IFS};
+use crate::glob;
+use crate::shell::Shell;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TokenType {
@@ -11,30 +19,271 @@ pub enum TokenType {
     Group,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Token {
     Meta(String),
-    Plain(String),
+    Plain(#[serde(with = "os_string")] OsString),
     Variable(String),
-    Tilde,
+    Parameter(Parameter),
+    ProcessSubstitution(Box<ProcessSubstitution>),
+    /// A `~` with the prefix after it, such as `alice` for `~alice`
+    Tilde(String),
     Group(Vec<Token>),
     DoubleQuoted(Vec<Token>),
     SingleQuoted(Vec<Token>),
 }
 pub type Tokens = Vec<Token>;
 
+/// A parameter in braces that does more than name it, such as
+/// `${array[1]}`, `${#name}` or `${!array[@]}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Parameter {
+    pub name: String,
+    /// The subscript of an array element, or `@` or `*` for every element
+    pub subscript: Option<Box<Token>>,
+    pub form: ParameterForm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParameterForm {
+    /// `${name[i]}`, the value itself
+    Value,
+    /// `${#name}`, the length of the value, or `${#name[@]}`, the number of
+    /// elements
+    Length,
+    /// `${!name[@]}`, the indices or keys of the elements
+    Keys,
+}
+
+impl Parameter {
+    /// `@` or `*` when the parameter stands for every element of an array.
+    fn every(&self) -> Option<&str> {
+        match self.subscript.as_deref() {
+            Some(Token::Plain(s)) if s == "@" || s == "*" => s.to_str(),
+            _ => None,
+        }
+    }
+
+    /// The values the parameter expands to, one for each element when it
+    /// stands for every element.
+    fn values(&self, shell: &Shell) -> Result<Vec<OsString>, RuntimeError> {
+        let count = |n: usize| OsString::from(n.to_string());
+        Ok(match (self.form, self.every()) {
+            (ParameterForm::Keys, _) => shell.keys(&self.name),
+            (ParameterForm::Length, Some(_)) => vec![count(shell.elements(&self.name).len())],
+            (ParameterForm::Length, None) => vec![count(lossy(self.value(shell)?).chars().count())],
+            (ParameterForm::Value, Some(_)) => shell.elements(&self.name),
+            (ParameterForm::Value, None) => vec![self.value(shell)?],
+        })
+    }
+
+    /// The value of the parameter or of the element it names.
+    fn value(&self, shell: &Shell) -> Result<OsString, RuntimeError> {
+        let Some(subscript) = &self.subscript else {
+            return Token::Variable(self.name.clone()).resolve(shell);
+        };
+        let subscript = subscript.resolve(shell)?;
+        match shell.element(&self.name, &subscript)? {
+            Some(value) => Ok(value),
+            None if shell.options.nounset => Err(RuntimeError::UnboundVariable(format!(
+                "{}[{}]",
+                self.name,
+                lossy(subscript)
+            ))),
+            None => Ok(OsString::new()),
+        }
+    }
+
+    /// The parameter as it was written.
+    fn literal(&self) -> String {
+        let prefix = match self.form {
+            ParameterForm::Value => "",
+            ParameterForm::Length => "#",
+            ParameterForm::Keys => "!",
+        };
+        match &self.subscript {
+            Some(subscript) => format!(
+                "${{{}{}[{}]}}",
+                prefix,
+                self.name,
+                lossy(subscript.literal())
+            ),
+            None => format!("${{{}{}}}", prefix, self.name),
+        }
+    }
+}
+
 impl Token {
-    pub fn resolve(&self) -> String {
+    /// The text of the token with its variables filled in. With `set -u`, a
+    /// variable that is not set is an error.
+    pub fn resolve(&self, shell: &Shell) -> Result<OsString, RuntimeError> {
+        Ok(match self {
+            Token::Meta(s) => OsString::from(s),
+            Token::Plain(s) => s.clone(),
+            Token::Variable(s) => match shell.parameter(s) {
+                Some(value) => value,
+                None if shell.options.nounset && !matches!(s.as_str(), "@" | "*") => {
+                    return Err(RuntimeError::UnboundVariable(s.clone()));
+                }
+                None => OsString::new(),
+            },
+            Token::Parameter(parameter) => {
+                let separator = match parameter.every() {
+                    Some("*") => star_separator(shell),
+                    _ => b" ".to_vec(),
+                };
+                parameter.values(shell)?.join(OsStr::from_bytes(&separator))
+            }
+            // Substitutions are started before a command's words are
+            // expanded, so one left here is somewhere they are not supported
+            Token::ProcessSubstitution(substitution) => OsString::from(&substitution.text),
+            Token::DoubleQuoted(s) => join_tokens(s, shell)?,
+            Token::SingleQuoted(s) => join_tokens(s, shell)?,
+            // A prefix that names no directory is kept as it was written
+            Token::Tilde(prefix) => shell
+                .tilde(prefix)
+                .unwrap_or_else(|| OsString::from(format!("~{}", prefix))),
+            Token::Group(s) => join_tokens(s, shell)?,
+        })
+    }
+
+    /// The text of the token as it was written, for tokens that are never
+    /// expanded such as operators and names.
+    pub fn literal(&self) -> OsString {
         match self {
-            Token::Meta(s) => s.clone(),
+            Token::Meta(s) => OsString::from(s),
             Token::Plain(s) => s.clone(),
-            Token::Variable(s) => std::env::var(s.clone()).unwrap_or("".to_string()),
-            Token::DoubleQuoted(s) => join_tokens(s.to_vec()),
-            Token::SingleQuoted(s) => join_tokens(s.to_vec()),
-            Token::Tilde => std::env::var("HOME").unwrap_or("".to_string()),
-            Token::Group(s) => join_tokens(s.to_vec()),
+            Token::Variable(s) => OsString::from(format!("${}", s)),
+            Token::Parameter(parameter) => OsString::from(parameter.literal()),
+            Token::ProcessSubstitution(substitution) => OsString::from(&substitution.text),
+            Token::Tilde(prefix) => OsString::from(format!("~{}", prefix)),
+            Token::DoubleQuoted(s) | Token::SingleQuoted(s) | Token::Group(s) => {
+                s.iter().map(|t| t.literal()).collect()
+            }
         }
     }
+
+    /// The token as a glob pattern, with quoted characters escaped so that only
+    /// unquoted metacharacters take effect.
+    pub fn pattern(&self, shell: &Shell) -> Result<OsString, RuntimeError> {
+        match self {
+            Token::Plain(s) => Ok(s.clone()),
+            Token::Variable(_) | Token::Parameter(_) => self.resolve(shell),
+            Token::Group(s) => s.iter().map(|t| t.pattern(shell)).collect(),
+            _ => Ok(glob::escape(&self.resolve(shell)?)),
+        }
+    }
+
+    /// Expands the token into the fields it produces: the results of
+    /// unquoted expansions are split at the characters in `IFS`, each field
+    /// goes through pathname expansion as the glob options of the shell say,
+    /// and the quotes are removed from the fields that do not.
+    pub fn expand(&self, shell: &Shell) -> Result<Vec<OsString>, RuntimeError> {
+        let ifs = shell
+            .variables
+            .get("IFS")
+            .map_or(DEFAULT_IFS, OsStr::as_bytes);
+        let mut splitter = FieldSplitter::new(ifs);
+        self.split(shell, &mut splitter)?;
+
+        let mut words = Vec::new();
+        for field in splitter.finish() {
+            let pattern = OsString::from_vec(field.pattern);
+            if !shell.options.noglob && glob::has_magic(&pattern) {
+                let matches = glob::expand(&pattern, shell.cwd(), shell.options.glob_flags());
+                if !matches.is_empty() {
+                    words.extend(matches);
+                    continue;
+                }
+                if shell.options.failglob {
+                    return Err(RuntimeError::NoMatch(lossy(&pattern)));
+                }
+                if shell.options.nullglob {
+                    continue;
+                }
+            }
+            words.push(OsString::from_vec(field.text));
+        }
+        Ok(words)
+    }
+
+    /// The values of a parameter that stands for a list of words, such as
+    /// `$@` or `${array[@]}`, with `@` or `*` for how it was written, or
+    /// `None` for any other token.
+    fn list(&self, shell: &Shell) -> Result<Option<(Vec<OsString>, &str)>, RuntimeError> {
+        Ok(match self {
+            Token::Variable(name) if name == "@" || name == "*" => {
+                Some((shell.functions.positional_args(), name))
+            }
+            Token::Parameter(parameter) if parameter.form != ParameterForm::Length => {
+                match parameter.every() {
+                    Some(every) => Some((parameter.values(shell)?, every)),
+                    None => None,
+                }
+            }
+            _ => None,
+        })
+    }
+
+    /// Adds the parts of the token to the fields of the word it is in.
+    fn split(&self, shell: &Shell, splitter: &mut FieldSplitter) -> Result<(), RuntimeError> {
+        if let Some((values, _)) = self.list(shell)? {
+            // Each value is split on its own, and they are never joined
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    splitter.end_field();
+                }
+                splitter.push_split(value.as_bytes());
+            }
+            return Ok(());
+        }
+        match self {
+            Token::Variable(_) | Token::Parameter(_) => {
+                splitter.push_split(self.resolve(shell)?.as_bytes())
+            }
+            Token::DoubleQuoted(parts) if !parts.is_empty() => {
+                for part in parts {
+                    match part.list(shell)? {
+                        // `"$@"` is a word for each value, and no word at all
+                        // when there are none
+                        Some((values, "@")) => {
+                            for (i, value) in values.iter().enumerate() {
+                                if i > 0 {
+                                    splitter.end_field();
+                                }
+                                let value = value.as_bytes();
+                                splitter.push(&glob::escape_bytes(value), value, true);
+                            }
+                        }
+                        // `"$*"` is one word, with the values joined by the
+                        // first character of `IFS`
+                        Some((values, _)) => {
+                            let value = values.join(OsStr::from_bytes(&star_separator(shell)));
+                            let value = value.as_bytes();
+                            splitter.push(&glob::escape_bytes(value), value, true);
+                        }
+                        None => {
+                            let value = part.resolve(shell)?;
+                            let value = value.as_bytes();
+                            splitter.push(&glob::escape_bytes(value), value, true);
+                        }
+                    }
+                }
+            }
+            Token::Group(parts) => {
+                for part in parts {
+                    part.split(shell, splitter)?;
+                }
+            }
+            Token::Plain(s) => splitter.push(s.as_bytes(), s.as_bytes(), false),
+            Token::Meta(s) => splitter.push(s.as_bytes(), s.as_bytes(), false),
+            _ => {
+                let text = self.resolve(shell)?;
+                splitter.push(glob::escape(&text).as_bytes(), text.as_bytes(), true);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Display for Token {
@@ -45,24 +294,89 @@ impl fmt::Display for Token {
             Token::DoubleQuoted(s) => write!(f, "{:?}", s),
             Token::SingleQuoted(s) => write!(f, "{:?}", s),
             Token::Variable(s) => write!(f, "${:?}", s),
-            Token::Tilde => write!(f, "~"),
+            Token::Parameter(parameter) => write!(f, "{}", parameter.literal()),
+            Token::ProcessSubstitution(substitution) => write!(f, "{}", substitution.text),
+            Token::Tilde(prefix) => write!(f, "~{}", prefix),
             Token::Group(s) => write!(f, "{:?}", s),
         }
     }
 }
 
 pub fn tokenize(value: Vec<Token>, token_type: TokenType) -> Token {
+    let literal = || value.iter().map(|t| t.literal()).collect::<OsString>();
     match token_type {
-        TokenType::Meta => Token::Meta(join_tokens(value)),
-        TokenType::Plain => Token::Plain(join_tokens(value)),
+        TokenType::Meta => Token::Meta(lossy(literal())),
+        TokenType::Plain => Token::Plain(literal()),
         TokenType::DoubleQuoted => Token::DoubleQuoted(value),
         TokenType::SingleQuoted => Token::SingleQuoted(value),
-        TokenType::Variable => Token::Variable(join_tokens(value)),
-        TokenType::Tilde => Token::Tilde,
+        TokenType::Variable => Token::Variable(lossy(literal())),
+        TokenType::Tilde => Token::Tilde(lossy(literal())),
         TokenType::Group => Token::Group(value),
     }
 }
 
-pub fn join_tokens(tokens: Vec<Token>) -> String {
-    tokens.iter().map(|t| t.resolve()).collect::<String>()
+/// What `$*` and `${array[*]}` join values with: the first character of
+/// `IFS`, a space when it is unset, or nothing when it is empty.
+fn star_separator(shell: &Shell) -> Vec<u8> {
+    match shell.variables.get("IFS") {
+        Some(ifs) => ifs.as_bytes().iter().take(1).copied().collect(),
+        None => b" ".to_vec(),
+    }
+}
+
+pub fn join_tokens(tokens: &[Token], shell: &Shell) -> Result<OsString, RuntimeError> {
+    tokens.iter().map(|t| t.resolve(shell)).collect()
+}
+
+/// Converts to a `String` for places that need text, such as LLM prompts and
+/// error messages.
+pub fn lossy(s: impl AsRef<OsStr>) -> String {
+    s.as_ref().to_string_lossy().into_owned()
+}
+
+/// Serialises an `OsString` as a string when it is valid UTF-8 and as bytes
+/// otherwise, so that serialised scripts stay readable.
+mod os_string {
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::ffi::OsString;
+    use std::fmt;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    pub fn serialize<S: Serializer>(s: &OsString, serializer: S) -> Result<S::Ok, S::Error> {
+        match s.to_str() {
+            Some(s) => serializer.serialize_str(s),
+            None => serializer.serialize_bytes(s.as_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OsString, D::Error> {
+        deserializer.deserialize_any(OsStringVisitor)
+    }
+
+    struct OsStringVisitor;
+
+    impl<'de> Visitor<'de> for OsStringVisitor {
+        type Value = OsString;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a string or a sequence of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<OsString, E> {
+            Ok(OsString::from(s))
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<OsString, E> {
+            Ok(OsString::from_vec(bytes.to_vec()))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OsString, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(b) = seq.next_element::<u8>()? {
+                bytes.push(b);
+            }
+            Ok(OsString::from_vec(bytes))
+        }
+    }
 }
@@ -2,4 +2,4 @@ mod runnable;
 mod shell_command;
 
 pub use runnable::Runnable;
-pub use shell_command::ShellCommand;
+pub use shell_command::{ShellCommand, Stage, StageStatus};
@@ -1,8 +1,9 @@
+use crate::shell::Shell;
 use dyn_clone::DynClone;
 use std::error::Error;
 use std::fmt::Debug;
 
 pub trait Runnable: DynClone + Debug {
-    fn run(&self) -> Result<String, Box<dyn Error>>;
+    fn run(&self, shell: &mut Shell) -> Result<String, Box<dyn Error>>;
 }
 dyn_clone::clone_trait_object!(Runnable);
@@ -1,11 +1,44 @@
+use crate::shell::Shell;
 use crate::traits::Runnable;
 use dyn_clone::DynClone;
+use nix::unistd::Pid;
 use std::error::Error;
-use std::process::ChildStdout;
+use std::process::{Child, ChildStdout};
+
+/// A command started as a stage of a pipeline, with its stdout for the next
+/// stage to read.
+pub struct Stage {
+    pub stdout: Option<ChildStdout>,
+    pub status: StageStatus,
+}
+
+pub enum StageStatus {
+    /// A process that the pipeline waits for once its last command finishes
+    Running(Child),
+    /// A forked copy of the shell running a compound command or function
+    Forked(Pid),
+    /// A command that ran in the shell process, with its exit status
+    Finished(i32),
+}
 
 pub trait ShellCommand: Runnable + DynClone {
-    fn cmd(&self) -> String;
-    fn args(&self) -> Vec<String>;
-    fn pipe(&self, stdin: Option<ChildStdout>) -> Result<Option<ChildStdout>, Box<dyn Error>>;
+    /// Starts the command as a stage of a pipeline. Its failure is reported
+    /// and kept as the status of the stage rather than stopping the pipeline.
+    /// With `stderr`, as for a stage followed by `|&`, its stderr is sent to
+    /// the next stage too.
+    fn pipe(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+        stderr: bool,
+    ) -> Result<Stage, Box<dyn Error>>;
+
+    /// Runs the command as the last stage of a pipeline, reading from `stdin`
+    /// and writing to the shell's stdout, and waits for it to finish.
+    fn run_last(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<ChildStdout>,
+    ) -> Result<String, Box<dyn Error>>;
 }
 dyn_clone::clone_trait_object!(ShellCommand);
@@ -0,0 +1,350 @@
+//! Shell variables and which of them are exported to commands.
+
+use crate::errors::RuntimeError;
+use crate::token::lossy;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+
+/// The value of a variable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    String(OsString),
+    /// An indexed array, whose elements need not be numbered contiguously
+    Indexed(BTreeMap<usize, OsString>),
+    /// An array of strings indexed by strings, made with `declare -A`
+    Associative(BTreeMap<OsString, OsString>),
+}
+
+impl Value {
+    /// The value used where a single string is expected, which for an array
+    /// is its element 0, as `$array` is `${array[0]}`.
+    fn as_string(&self) -> Option<&OsStr> {
+        match self {
+            Value::String(value) => Some(value),
+            Value::Indexed(elements) => elements.get(&0).map(OsString::as_os_str),
+            Value::Associative(elements) => elements.get(OsStr::new("0")).map(OsString::as_os_str),
+        }
+    }
+
+    /// The elements in order, as `${array[@]}` lists them. A string is an
+    /// array of one element.
+    pub fn elements(&self) -> Vec<&OsStr> {
+        match self {
+            Value::String(value) => vec![value],
+            Value::Indexed(elements) => elements.values().map(OsString::as_os_str).collect(),
+            Value::Associative(elements) => elements.values().map(OsString::as_os_str).collect(),
+        }
+    }
+
+    /// The indices or keys of the elements, as `${!array[@]}` lists them.
+    pub fn keys(&self) -> Vec<OsString> {
+        match self {
+            Value::String(_) => vec![OsString::from("0")],
+            Value::Indexed(elements) => elements.keys().map(|i| i.to_string().into()).collect(),
+            Value::Associative(elements) => elements.keys().cloned().collect(),
+        }
+    }
+}
+
+/// The element of an indexed array that a subscript refers to. Negative
+/// subscripts count back from the end, so `-1` is the last element.
+fn index(
+    name: &OsStr,
+    elements: &BTreeMap<usize, OsString>,
+    subscript: &OsStr,
+) -> Result<usize, RuntimeError> {
+    let bad = || RuntimeError::BadSubscript(format!("{}[{}]", lossy(name), lossy(subscript)));
+    let n: i64 = lossy(subscript).trim().parse().map_err(|_| bad())?;
+    let end = elements
+        .keys()
+        .next_back()
+        .map_or(0, |last| *last as i64 + 1);
+    usize::try_from(if n < 0 { end + n } else { n }).map_err(|_| bad())
+}
+
+/// The elements of an indexed array with a single element, which is how
+/// subscripts of a string are looked up.
+fn string_elements() -> BTreeMap<usize, OsString> {
+    BTreeMap::from([(0, OsString::new())])
+}
+
+/// A variable with its value and whether it is exported, as saved by
+/// [`Variables::save`].
+#[derive(Clone, Debug)]
+pub struct Variable {
+    value: Value,
+    exported: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Variables {
+    values: HashMap<OsString, Variable>,
+}
+
+impl Variables {
+    pub fn new() -> Variables {
+        Variables::default()
+    }
+
+    /// Variables for the environment of the shell process, all exported.
+    pub fn from_env() -> Variables {
+        let mut variables = Variables::new();
+        for (name, value) in std::env::vars_os() {
+            variables.export(name, value);
+        }
+        variables
+    }
+
+    pub fn get(&self, name: impl AsRef<OsStr>) -> Option<&OsStr> {
+        self.values.get(name.as_ref())?.value.as_string()
+    }
+
+    pub fn value(&self, name: impl AsRef<OsStr>) -> Option<&Value> {
+        self.values
+            .get(name.as_ref())
+            .map(|variable| &variable.value)
+    }
+
+    /// Sets a variable, which stays exported if it already was. Setting an
+    /// array sets its element 0.
+    pub fn set(&mut self, name: impl Into<OsString>, value: impl Into<OsString>) {
+        let (name, value) = (name.into(), value.into());
+        match self.values.get_mut(&name) {
+            Some(Variable {
+                value: Value::Indexed(elements),
+                ..
+            }) => {
+                elements.insert(0, value);
+            }
+            Some(Variable {
+                value: Value::Associative(elements),
+                ..
+            }) => {
+                elements.insert(OsString::from("0"), value);
+            }
+            Some(variable) => variable.value = Value::String(value),
+            None => {
+                let variable = Variable {
+                    value: Value::String(value),
+                    exported: false,
+                };
+                self.values.insert(name, variable);
+            }
+        }
+    }
+
+    /// The element of an array named by a subscript, an index for indexed
+    /// arrays and a key for associative ones.
+    pub fn element(
+        &self,
+        name: impl AsRef<OsStr>,
+        subscript: &OsStr,
+    ) -> Result<Option<&OsStr>, RuntimeError> {
+        let name = name.as_ref();
+        let Some(variable) = self.values.get(name) else {
+            return Ok(None);
+        };
+        Ok(match &variable.value {
+            Value::String(value) => {
+                (index(name, &string_elements(), subscript)? == 0).then_some(value.as_os_str())
+            }
+            Value::Indexed(elements) => elements
+                .get(&index(name, elements, subscript)?)
+                .map(OsString::as_os_str),
+            Value::Associative(elements) => elements.get(subscript).map(OsString::as_os_str),
+        })
+    }
+
+    /// Sets an element of an array, making the variable an indexed array
+    /// if it is not an array yet.
+    pub fn set_element(
+        &mut self,
+        name: impl Into<OsString>,
+        subscript: &OsStr,
+        value: impl Into<OsString>,
+    ) -> Result<(), RuntimeError> {
+        let name = name.into();
+        let variable = self.values.entry(name.clone()).or_insert(Variable {
+            value: Value::Indexed(BTreeMap::new()),
+            exported: false,
+        });
+        if let Value::String(value) = &variable.value {
+            variable.value = Value::Indexed(BTreeMap::from([(0, value.clone())]));
+        }
+        match &mut variable.value {
+            Value::Indexed(elements) => {
+                let index = index(&name, elements, subscript)?;
+                elements.insert(index, value.into());
+            }
+            Value::Associative(elements) => {
+                elements.insert(subscript.to_os_string(), value.into());
+            }
+            Value::String(_) => unreachable!("strings were made arrays above"),
+        }
+        Ok(())
+    }
+
+    pub fn unset_element(
+        &mut self,
+        name: impl AsRef<OsStr>,
+        subscript: &OsStr,
+    ) -> Result<(), RuntimeError> {
+        let name = name.as_ref();
+        let Some(variable) = self.values.get_mut(name) else {
+            return Ok(());
+        };
+        match &mut variable.value {
+            Value::String(_) => {
+                if index(name, &string_elements(), subscript)? == 0 {
+                    self.values.remove(name);
+                }
+            }
+            Value::Indexed(elements) => {
+                let index = index(name, elements, subscript)?;
+                elements.remove(&index);
+            }
+            Value::Associative(elements) => {
+                elements.remove(subscript);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds elements to the end of an array, as `array+=(a b)` does. An
+    /// element may give its own index or key, as in `([3]=c)`, and the ones
+    /// after it carry on from there.
+    pub fn append_elements(
+        &mut self,
+        name: impl Into<OsString>,
+        elements: Vec<(Option<OsString>, OsString)>,
+    ) -> Result<(), RuntimeError> {
+        let name = name.into();
+        for (subscript, value) in elements {
+            let subscript = match (subscript, self.value(&name)) {
+                (Some(subscript), _) => subscript,
+                (None, Some(Value::Associative(_))) => {
+                    return Err(RuntimeError::MissingSubscript(lossy(&name)));
+                }
+                (None, Some(Value::Indexed(elements))) => {
+                    let end = elements.keys().next_back().map_or(0, |last| last + 1);
+                    OsString::from(end.to_string())
+                }
+                (None, Some(Value::String(_))) => OsString::from("1"),
+                (None, None) => OsString::from("0"),
+            };
+            self.set_element(name.clone(), &subscript, value)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the elements of an array, as `array=(a b)` does. An
+    /// associative array stays associative.
+    pub fn assign_elements(
+        &mut self,
+        name: impl Into<OsString>,
+        elements: Vec<(Option<OsString>, OsString)>,
+    ) -> Result<(), RuntimeError> {
+        let name = name.into();
+        match self.values.get_mut(&name) {
+            Some(Variable {
+                value: value @ Value::Associative(_),
+                ..
+            }) => *value = Value::Associative(BTreeMap::new()),
+            Some(variable) => variable.value = Value::Indexed(BTreeMap::new()),
+            None => self.set_array(name.clone(), Vec::new()),
+        }
+        self.append_elements(name, elements)
+    }
+
+    /// Makes a variable an associative array, as `declare -A` does. An
+    /// existing array is left as it is.
+    pub fn declare_associative(&mut self, name: impl Into<OsString>) {
+        let variable = self.values.entry(name.into()).or_insert(Variable {
+            value: Value::Associative(BTreeMap::new()),
+            exported: false,
+        });
+        if let Value::String(_) = variable.value {
+            variable.value = Value::Associative(BTreeMap::new());
+        }
+    }
+
+    /// Makes a variable an indexed array, as `declare -a` does, keeping a
+    /// string value as its element 0.
+    pub fn declare_indexed(&mut self, name: impl Into<OsString>) {
+        let variable = self.values.entry(name.into()).or_insert(Variable {
+            value: Value::Indexed(BTreeMap::new()),
+            exported: false,
+        });
+        if let Value::String(value) = &variable.value {
+            variable.value = Value::Indexed(BTreeMap::from([(0, value.clone())]));
+        }
+    }
+
+    /// Replaces a variable with an array of `elements`, numbered from 0.
+    pub fn set_array(&mut self, name: impl Into<OsString>, elements: Vec<OsString>) {
+        let value = Value::Indexed(elements.into_iter().enumerate().collect());
+        let name = name.into();
+        let exported = self.values.get(&name).is_some_and(|v| v.exported);
+        self.values.insert(name, Variable { value, exported });
+    }
+
+    /// Sets a variable and passes it on to the commands the shell runs.
+    pub fn export(&mut self, name: impl Into<OsString>, value: impl Into<OsString>) {
+        let variable = Variable {
+            value: Value::String(value.into()),
+            exported: true,
+        };
+        self.values.insert(name.into(), variable);
+    }
+
+    pub fn unset(&mut self, name: impl AsRef<OsStr>) {
+        self.values.remove(name.as_ref());
+    }
+
+    pub fn is_exported(&self, name: impl AsRef<OsStr>) -> bool {
+        self.values
+            .get(name.as_ref())
+            .is_some_and(|variable| variable.exported)
+    }
+
+    /// The variable as it is now, for [`Variables::restore`] to put back
+    /// once a temporary assignment such as `NAME=value command` is over.
+    pub fn save(&self, name: impl AsRef<OsStr>) -> Option<Variable> {
+        self.values.get(name.as_ref()).cloned()
+    }
+
+    pub fn restore(&mut self, name: impl Into<OsString>, saved: Option<Variable>) {
+        let name = name.into();
+        match saved {
+            Some(variable) => self.values.insert(name, variable),
+            None => self.values.remove(&name),
+        };
+    }
+
+    /// Every variable with its value, sorted by name.
+    pub fn all(&self) -> Vec<(&OsStr, &Value)> {
+        let mut all: Vec<_> = self
+            .values
+            .iter()
+            .map(|(name, variable)| (name.as_os_str(), &variable.value))
+            .collect();
+        all.sort_by_key(|(name, _)| *name);
+        all
+    }
+
+    /// The exported variables, sorted by name, which make up the environment
+    /// of commands. Arrays cannot be exported, so they are left out.
+    pub fn exported(&self) -> Vec<(&OsStr, &OsStr)> {
+        let mut exported: Vec<_> = self
+            .values
+            .iter()
+            .filter(|(_, variable)| variable.exported)
+            .filter_map(|(name, variable)| match &variable.value {
+                Value::String(value) => Some((name.as_os_str(), value.as_os_str())),
+                Value::Indexed(_) | Value::Associative(_) => None,
+            })
+            .collect();
+        exported.sort();
+        exported
+    }
+}
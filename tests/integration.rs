@@ -1,3 +1,4 @@
+use aish::Shell;
 use std::env;
 use std::io::Write;
 use std::path::PathBuf;
@@ -14,6 +15,11 @@ fn canonicalize_path(path: &str) -> String {
 }
 
 fn run_shell_command(input: &str) -> (String, String, String) {
+    let (stdout, stderr, root) = run_shell_command_bytes(input.as_bytes());
+    (String::from_utf8_lossy(&stdout).to_string(), stderr, root)
+}
+
+fn run_shell_command_bytes(input: &[u8]) -> (Vec<u8>, String, String) {
     let temp_dir = TempDir::new().expect("Failed to create temp dir");
 
     let aish_path = env!("CARGO_BIN_EXE_aish");
@@ -26,20 +32,16 @@ fn run_shell_command(input: &str) -> (String, String, String) {
         .spawn()
         .expect("Failed to spawn child process");
 
-    let input = input.to_string() + "\n";
+    let mut input = input.to_vec();
+    input.push(b'\n');
     let mut stdin = child.stdin.take().expect("Failed to open stdin");
-    stdin
-        .write_all(input.as_bytes())
-        .expect("Failed to write to stdin");
+    stdin.write_all(&input).expect("Failed to write to stdin");
     drop(stdin);
 
     let output = child.wait_with_output().expect("Failed to read stdout");
 
     (
-        String::from_utf8_lossy(&output.stdout)
-            .to_string()
-            .trim()
-            .to_string(),
+        output.stdout.trim_ascii().to_vec(),
         String::from_utf8_lossy(&output.stderr)
             .to_string()
             .trim()
@@ -161,6 +163,29 @@ fn test_tilde_variable_value_literal() {
     assert_eq!(stderr, "");
 }
 
+#[test]
+fn test_tilde_prefixes() {
+    let (stdout, stderr, root) = run_shell_command(
+        "HOME=/home/me\n\
+         echo ~root ~root/bin ~nosuchuser/x \"~\"/a ~\"\"\n\
+         cd /; cd - > /dev/null; echo ~+ ~-\n\
+         PATH=~/bin:~root/sbin:/usr/bin; echo $PATH\n\
+         x=a:~:~+:\"~\"; echo $x\n\
+         echo --prefix=~/local prefix=~/local a~ $HOME~",
+    );
+    assert_eq!(
+        stdout,
+        format!(
+            "/root /root/bin ~nosuchuser/x ~/a ~\n\
+             {root} /\n\
+             /home/me/bin:/root/sbin:/usr/bin\n\
+             a:/home/me:{root}:~\n\
+             --prefix=~/local prefix=/home/me/local a~ /home/me~"
+        )
+    );
+    assert_eq!(stderr, "");
+}
+
 #[test]
 fn test_pipeline() {
     let (stdout, stderr, _) = run_shell_command("echo Hello | wc -c");
@@ -215,6 +240,51 @@ fn test_non_utf8_output() {
     assert_eq!(stderr, "");
 }
 
+#[test]
+fn test_latin1_file_name() {
+    let (stdout, stderr, _) = run_shell_command_bytes(b"touch caf\xe9.txt && ls");
+    assert_eq!(stdout, b"caf\xe9.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_latin1_file_name_pipeline() {
+    let (stdout, stderr, _) = run_shell_command_bytes(b"touch caf\xe9.txt && ls | cat");
+    assert_eq!(stdout, b"caf\xe9.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_latin1_glob() {
+    let (stdout, stderr, _) = run_shell_command_bytes(b"touch caf\xe9.txt && echo caf?.txt");
+    assert_eq!(stdout, b"caf\xe9.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_latin1_redirection() {
+    let (stdout, stderr, _) =
+        run_shell_command_bytes(b"echo Hello > caf\xe9.txt && cat caf\xe9.txt");
+    assert_eq!(stdout, b"Hello");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_latin1_variable() {
+    let (stdout, stderr, _) = run_shell_command_bytes(b"export FOO=caf\xe9 && echo $FOO");
+    assert_eq!(stdout, b"caf\xe9");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_latin1_cd() {
+    let (stdout, stderr, root) = run_shell_command_bytes(b"mkdir caf\xe9 && cd caf\xe9 && pwd");
+    let mut expected = root.into_bytes();
+    expected.extend_from_slice(b"/caf\xe9");
+    assert_eq!(stdout, expected);
+    assert_eq!(stderr, "");
+}
+
 #[test]
 fn test_export_command() {
     let (stdout, stderr, _) = run_shell_command("export FOO=BAR && echo $FOO");
@@ -256,3 +326,1646 @@ fn test_export_command_single_quotes() {
     assert_eq!(stdout, "$FOO");
     assert_eq!(stderr, "");
 }
+
+#[test]
+fn test_builtin_pipeline() {
+    let (stdout, stderr, _) = run_shell_command("echo foo | cat");
+    assert_eq!(stdout, "foo");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_builtin_pipeline_middle() {
+    let (stdout, stderr, _) = run_shell_command("echo foo | cat | wc -c");
+    assert_eq!(stdout, "4");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_builtin_output_redirection() {
+    let (stdout, stderr, _) = run_shell_command("pwd > test.txt && cat test.txt");
+    assert!(!stdout.is_empty());
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_builtin_pipeline_keeps_state() {
+    let (stdout, stderr, root) = run_shell_command("cd /tmp | cat; pwd");
+    assert_eq!(stdout, root);
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_assignment_pipeline_keeps_state() {
+    let (stdout, stderr, _) = run_shell_command("x=1 | cat; echo \"[$x]\"");
+    assert_eq!(stdout, "[]");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_exit_in_pipeline_stage() {
+    let (stdout, stderr, _) = run_shell_command("exit 3 | cat; echo still running");
+    assert_eq!(stdout, "still running");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_before_words() {
+    let (stdout, stderr, _) = run_shell_command("> test.txt echo Hello && cat test.txt");
+    assert_eq!(stdout, "Hello");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_between_words() {
+    let (stdout, stderr, _) = run_shell_command("echo Hello > test.txt World && cat test.txt");
+    assert_eq!(stdout, "Hello World");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_last_wins() {
+    let (stdout, stderr, _) =
+        run_shell_command("echo Hello > first.txt > second.txt && cat first.txt second.txt");
+    assert_eq!(stdout, "Hello");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_input_and_output() {
+    let (stdout, stderr, _) = run_shell_command(
+        "echo Hello > in.txt && sed 's/H/h/g' < in.txt > out.txt 2> err.txt && cat out.txt err.txt",
+    );
+    assert_eq!(stdout, "hello");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_stderr() {
+    let (stdout, _, _) = run_shell_command("ls nonexistent 2> err.txt\ncat err.txt");
+    assert!(stdout.contains("nonexistent"));
+}
+
+#[test]
+fn test_redirection_duplicate() {
+    let (stdout, stderr, _) = run_shell_command("ls nonexistent 2>&1 | wc -l");
+    assert_eq!(stdout, "1");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_only() {
+    let (stdout, stderr, _) = run_shell_command("> test.txt && ls");
+    assert_eq!(stdout, "test.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_variable_target() {
+    let (stdout, stderr, _) =
+        run_shell_command("export FILE=test.txt && echo Hello > $FILE && cat test.txt");
+    assert_eq!(stdout, "Hello");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_redirection_ambiguous() {
+    let (stdout, stderr, _) = run_shell_command("touch a.txt b.txt && echo Hello > *.txt");
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("ambiguous redirect"));
+}
+
+#[test]
+fn test_glob() {
+    let (stdout, stderr, _) = run_shell_command("touch b.txt a.txt c.rs && echo *.txt");
+    assert_eq!(stdout, "a.txt b.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_glob_quoted() {
+    let (stdout, stderr, _) =
+        run_shell_command("touch a.txt && echo \\*.txt '*.txt' \"*.txt\" [ab].txt");
+    assert_eq!(stdout, "*.txt *.txt *.txt a.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_unclosed_quote_position() {
+    let (stdout, stderr, _) = run_shell_command("echo ok; echo été 'unclosed");
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        "error: Unclosed quote\n --> <stdin>:1:19\n  |\n1 | echo ok; echo été 'unclosed\n  |                   ^^^^^^^^^"
+    );
+}
+
+#[test]
+fn test_multiline_script() {
+    let (stdout, stderr, _) = run_shell_command(
+        "# Comments and blank lines are skipped\n\necho first\necho second \\\n  continued",
+    );
+    assert_eq!(stdout, "first\nsecond continued");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_sequence_keeps_pipeline_output() {
+    let (stdout, stderr, _) = run_shell_command("echo a | cat; echo b");
+    assert_eq!(stdout, "a\nb");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_sequence_continues_after_error() {
+    let (stdout, stderr, _) = run_shell_command("cd /nonexistent; echo after");
+    assert_eq!(stdout, "after");
+    assert!(!stderr.is_empty());
+}
+
+#[test]
+fn test_or_sequence() {
+    let (stdout, stderr, _) = run_shell_command("false || echo fallback; true || echo skipped");
+    assert_eq!(stdout, "fallback");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_and_or_sequence() {
+    let (stdout, stderr, _) = run_shell_command("true && false || echo recovered");
+    assert_eq!(stdout, "recovered");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_pipeline_status() {
+    let (stdout, stderr, _) = run_shell_command("echo abc | grep -q xyz || echo missing");
+    assert_eq!(stdout, "missing");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_compound_stage_streams() {
+    let (stdout, stderr, _) = run_shell_command(
+        "while :; do echo y; done | head -1\n\
+         yes() { while :; do echo \"$1\"; done; }; yes n | head -2\n\
+         printf 'a\\nb\\n' | while read -r l; do echo \"<$l>\"; done | cat\n\
+         x=1; { x=2; } | cat; echo $x",
+    );
+    assert_eq!(stdout, "y\nn\nn\n<a>\n<b>\n1");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_if_elif_else() {
+    let (stdout, stderr, _) = run_shell_command(
+        "if false; then echo one\nelif true; then\n  echo two\nelse\n  echo three\nfi",
+    );
+    assert_eq!(stdout, "two");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_while_loop() {
+    let (stdout, stderr, _) =
+        run_shell_command("touch flag\nwhile test -e flag; do\n  echo once\n  rm flag\ndone");
+    assert_eq!(stdout, "once");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_until_loop() {
+    let (stdout, stderr, _) = run_shell_command("until true; do echo never; done; echo done");
+    assert_eq!(stdout, "done");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_for_loop() {
+    let (stdout, stderr, _) = run_shell_command("for x in 1 \"2 3\"; do echo item $x; done");
+    assert_eq!(stdout, "item 1\nitem 2 3");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_for_loop_glob() {
+    let (stdout, stderr, _) =
+        run_shell_command("touch b.txt a.txt; for f in *.txt; do echo $f; done");
+    assert_eq!(stdout, "a.txt\nb.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_break() {
+    let (stdout, stderr, _) = run_shell_command("while true; do echo once; break; done; echo $?");
+    assert_eq!(stdout, "once\n0");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_continue() {
+    let (stdout, stderr, _) =
+        run_shell_command("for x in 1 2 3; do [ $x = 2 ] && continue; echo $x; done");
+    assert_eq!(stdout, "1\n3");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_break_and_continue_outer_loop() {
+    let (stdout, stderr, _) = run_shell_command(
+        "for x in 1 2 3; do\n\
+         for y in a b; do [ $y = b ] && continue 2; [ $x = 3 ] && break 2; echo $x$y; done\n\
+         done",
+    );
+    assert_eq!(stdout, "1a\n2a");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_break_in_while_condition() {
+    let (stdout, stderr, _) = run_shell_command("while break; do echo never; done; echo done");
+    assert_eq!(stdout, "done");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_break_outside_loop() {
+    let (stdout, stderr, _) =
+        run_shell_command("break; echo $?; f() { break; }; for x in 1 2; do f; echo $x; done");
+    assert_eq!(stdout, "0\n1\n2");
+    assert_eq!(
+        stderr,
+        "break: only meaningful in a `for', `while', or `until' loop\n\
+         break: only meaningful in a `for', `while', or `until' loop\n\
+         break: only meaningful in a `for', `while', or `until' loop"
+    );
+}
+
+#[test]
+fn test_break_in_subshell() {
+    let (stdout, _, _) = run_shell_command("for x in 1 2; do (break) 2> /dev/null; echo $x; done");
+    assert_eq!(stdout, "1\n2");
+}
+
+#[test]
+fn test_shift() {
+    let (stdout, stderr, _) =
+        run_shell_command("f() { shift; echo \"$# $@\"; shift 2; echo \"$# $@\"; }; f a b c d");
+    assert_eq!(stdout, "3 b c d\n1 d");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shift_too_far() {
+    let (stdout, stderr, _) = run_shell_command("set -- a b; shift 3; echo $? $@");
+    assert_eq!(stdout, "1 a b");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_function() {
+    let (stdout, stderr, _) =
+        run_shell_command("greet() {\n  echo hello $1 $#\n}\ngreet world again");
+    assert_eq!(stdout, "hello world 2");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_function_redirection() {
+    let (stdout, stderr, _) = run_shell_command("f() { echo inside; }; f > out.txt; cat out.txt");
+    assert_eq!(stdout, "inside");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_brace_group_redirection() {
+    let (stdout, stderr, _) =
+        run_shell_command("{ echo one; ls missing; } > out.txt 2>&1; wc -l < out.txt");
+    assert_eq!(stdout, "2");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_compound_in_pipeline() {
+    let (stdout, stderr, _) =
+        run_shell_command("for x in c a b; do echo $x; done | sort | { cat; echo end; }");
+    assert_eq!(stdout, "a\nb\nc\nend");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_subshell() {
+    let (stdout, stderr, _) = run_shell_command("cd /tmp; (cd /; pwd); pwd");
+    assert_eq!(stdout, "/\n/tmp");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_unterminated_compound_command() {
+    let (stdout, stderr, _) = run_shell_command("if true; then echo yes");
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        "error: Unexpected end of input, expected fi\n --> <stdin>:1:23\n  |\n1 | if true; then echo yes\n  |                       ^"
+    );
+}
+
+#[test]
+fn test_syntax_error_continues_script() {
+    let (stdout, stderr, _) = run_shell_command("echo one\nif true; then\n  cat <\nfi\necho two");
+    assert_eq!(stdout, "one\ntwo");
+    assert_eq!(
+        stderr,
+        "error: Expected token: file after <\n --> <stdin>:3:7\n  |\n3 |   cat <\n  |       ^\n\
+         error: Unexpected token: fi\n --> <stdin>:4:1\n  |\n4 | fi\n  | ^^"
+    );
+}
+
+#[test]
+fn test_unclosed_quote_stops_script() {
+    let (stdout, stderr, _) = run_shell_command("echo one\necho 'unterminated\necho DANGER");
+    assert_eq!(stdout, "one");
+    assert!(stderr.contains("2:6"), "{}", stderr);
+}
+
+#[test]
+fn test_dump_ast() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aish"))
+        .arg("--dump-ast")
+        .arg("/dev/stdin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b"echo hi | cat > out\n")?;
+            drop(stdin);
+            child.wait_with_output()
+        })
+        .expect("Failed to run aish");
+
+    let ast: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let commands = &ast["items"][0]["first"]["commands"];
+    assert_eq!(commands[0]["Simple"]["words"][0]["Plain"], "echo");
+    assert_eq!(
+        commands[1]["Simple"]["redirects"][0]["target"]["Plain"],
+        "out"
+    );
+}
+
+#[test]
+fn test_shell_eval_after_exit() {
+    let mut shell = Shell::new();
+    let result = shell.eval("echo before; exit 4; echo skipped");
+    assert_eq!(result.stdout, b"before\n");
+    assert_eq!(result.status, 4);
+    let result = shell.eval("echo a; echo b; echo c");
+    assert_eq!(result.stdout, b"a\nb\nc\n");
+    assert_eq!(result.status, 0);
+}
+
+#[test]
+fn test_shell_eval_forks_while_capturing() {
+    let mut shell = Shell::new();
+    let result = shell.eval("(echo subshell); { echo stage; } | tr a-z A-Z; echo job >&2 & wait");
+    assert_eq!(result.stdout, b"subshell\nSTAGE\n");
+    assert_eq!(result.stderr, b"job\n");
+    assert_eq!(result.status, 0);
+}
+
+#[test]
+fn test_shell_eval_process_substitution() {
+    let mut shell = Shell::new();
+    let result = shell.eval("cat <(echo in); echo out > >(tr a-z A-Z); wait $!");
+    assert_eq!(result.stdout, b"in\nOUT\n");
+    assert_eq!(result.status, 0);
+}
+
+#[test]
+fn test_shell_eval() {
+    let mut shell = Shell::new();
+    let result = shell.eval("echo hello; cat nonexistent_file");
+    assert_eq!(result.stdout, b"hello\n");
+    assert!(String::from_utf8_lossy(&result.stderr).contains("nonexistent_file"));
+    assert_eq!(result.status, 1);
+}
+
+#[test]
+fn test_shell_state_is_separate() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = canonicalize_path(temp_dir.path().to_str().unwrap());
+    let mut first = Shell::new();
+    let mut second = Shell::new();
+
+    first.eval(&format!("cd {} && export AISH_TEST_VAR=first", dir));
+    let result = first.eval("pwd; echo $AISH_TEST_VAR; sh -c 'echo $AISH_TEST_VAR'");
+    assert_eq!(
+        result.stdout,
+        format!("{}\nfirst\nfirst\n", dir).into_bytes()
+    );
+
+    let result = second.eval("echo $AISH_TEST_VAR");
+    assert_eq!(result.stdout, b"\n");
+    assert_ne!(second.cwd(), PathBuf::from(&dir));
+}
+
+#[test]
+fn test_shell_custom_builtin() {
+    let mut shell = Shell::new();
+    shell.register_builtin("greet", |_shell, args, io| {
+        writeln!(io.stdout, "hello {}", args[0].to_string_lossy())?;
+        Ok(String::new())
+    });
+    let result = shell.eval("greet world | tr a-z A-Z; greet again > /dev/null");
+    assert_eq!(result.stdout, b"HELLO WORLD\n");
+    assert_eq!(result.status, 0);
+}
+
+#[test]
+fn test_shell_exit() {
+    let mut shell = Shell::new();
+    let result = shell.eval("echo before; if true; then exit; fi; echo after");
+    assert_eq!(result.stdout, b"before\n");
+    assert_eq!(shell.exit_status(), Some(0));
+}
+
+#[test]
+fn test_alias() {
+    let mut shell = Shell::new();
+    let result =
+        shell.eval("alias greet='echo hello'\ngreet world\nalias g='echo one; echo two'; g");
+    assert_eq!(result.stdout, b"hello world\none\ntwo\n");
+}
+
+#[test]
+fn test_alias_recursion() {
+    let mut shell = Shell::new();
+    let result = shell.eval("alias echo='echo [' a=b b='echo chained'\necho x\na");
+    assert_eq!(result.stdout, b"[ x\n[ chained\n");
+}
+
+#[test]
+fn test_alias_trailing_space() {
+    let mut shell = Shell::new();
+    let result = shell.eval("alias say='echo ' word=expanded say2=echo\nsay word\nsay2 word");
+    assert_eq!(result.stdout, b"expanded\nword\n");
+}
+
+#[test]
+fn test_alias_listing_and_unalias() {
+    let mut shell = Shell::new();
+    let result = shell.eval("alias b='echo \"it'\\''s\"' a=pwd\nalias\nunalias a\nalias a");
+    assert_eq!(
+        result.stdout,
+        b"alias a='pwd'\nalias b='echo \"it'\\''s\"'\n".to_vec()
+    );
+    assert_eq!(result.stderr, b"alias: a: not found\n");
+    assert_eq!(result.status, 1);
+
+    // The listing can be run to define the same aliases again
+    let listing = shell.eval("alias").stdout;
+    let mut other = Shell::new();
+    other.eval(std::str::from_utf8(&listing).unwrap());
+    assert_eq!(other.eval("b").stdout, b"it's\n");
+}
+
+#[test]
+fn test_source() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(
+        temp_dir.path().join("lib.sh"),
+        "echo args $# $1\nexport LIB_VAR=set\nalias hi='echo hi'\nf() { echo in f; }\nreturn 4\necho skipped\n",
+    )
+    .unwrap();
+
+    let mut shell = Shell::new();
+    shell.set_cwd(temp_dir.path().to_path_buf());
+    let result = shell.eval("source lib.sh a b\necho $LIB_VAR\nhi\nf\n. ./lib.sh c");
+    assert_eq!(
+        result.stdout,
+        b"args 2 a\nset\nhi\nin f\nargs 1 c\n".to_vec()
+    );
+    assert_eq!(result.status, 4);
+}
+
+#[test]
+fn test_source_searches_path() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::create_dir(temp_dir.path().join("bin")).unwrap();
+    std::fs::write(temp_dir.path().join("bin/found.sh"), "echo found\n").unwrap();
+
+    let mut shell = Shell::new();
+    shell.set_cwd(temp_dir.path().to_path_buf());
+    shell.variables.set("PATH", temp_dir.path().join("bin"));
+    let result = shell.eval("source found.sh; source missing.sh");
+    assert_eq!(result.stdout, b"found\n");
+    assert!(String::from_utf8_lossy(&result.stderr).contains("missing.sh: file not found"));
+}
+
+#[test]
+fn test_source_syntax_error_location() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(temp_dir.path().join("bad.sh"), "echo ok\necho 'open\n").unwrap();
+
+    let mut shell = Shell::new();
+    shell.set_cwd(temp_dir.path().to_path_buf());
+    let result = shell.eval("source bad.sh");
+    assert_eq!(result.stdout, b"ok\n");
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--> bad.sh:2:6"));
+}
+
+#[test]
+fn test_return() {
+    let (stdout, stderr, _) =
+        run_shell_command("f() { echo one; return 2; echo two; }\nf || echo failed\nreturn");
+    assert_eq!(stdout, "one\nfailed");
+    assert!(stderr.contains("can only return from a function or sourced file"));
+}
+
+#[test]
+fn test_type() {
+    let mut shell = Shell::new();
+    let result = shell.eval(
+        "alias ll='ls -l'\nf() { :; }\ntype ll if f cd\ntype -t ll if f cd ls\ntype '\"a prompt\"'",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "ll is aliased to `ls -l'\nif is a shell keyword\nf is a function\ncd is a shell builtin\n\
+         alias\nkeyword\nfunction\nbuiltin\nfile\n\"a prompt\" is an LLM prompt\n"
+    );
+
+    let result = shell.eval("type nosuchcommand");
+    assert_eq!(result.stderr, b"type: nosuchcommand: not found\n");
+    assert_eq!(result.status, 1);
+}
+
+#[test]
+fn test_command_and_builtin() {
+    let mut shell = Shell::new();
+    let result = shell.eval(
+        "echo() { builtin echo wrapped \"$@\"; }\necho hi\ncommand echo plain\ncommand -v echo cd",
+    );
+    assert_eq!(result.stdout, b"wrapped hi\nplain\necho\ncd\n");
+
+    let result = shell.eval("builtin ls");
+    assert!(String::from_utf8_lossy(&result.stderr).contains("ls: not a shell builtin"));
+}
+
+#[test]
+fn test_command_not_found() {
+    let mut shell = Shell::new();
+    let result = shell.eval("nosuchcommand || echo failed");
+    assert_eq!(result.stdout, b"failed\n");
+    let result = shell.eval("nosuchcommand");
+    assert!(String::from_utf8_lossy(&result.stderr).contains("nosuchcommand: command not found"));
+    assert_eq!(result.status, 127);
+}
+
+#[test]
+fn test_missing_program_path() {
+    let (stdout, stderr, _) = run_shell_command("./nope; echo $?");
+    assert_eq!(stdout, "127");
+    assert_eq!(
+        stderr,
+        "Error in command: ./nope: No such file or directory"
+    );
+}
+
+#[test]
+fn test_program_without_permission() {
+    let (stdout, stderr, _) = run_shell_command("touch file; ./file; echo $?");
+    assert_eq!(stdout, "126");
+    assert_eq!(stderr, "Error in command: ./file: Permission denied");
+}
+
+#[test]
+fn test_directory_as_program() {
+    let (stdout, stderr, _) = run_shell_command("mkdir dir; ./dir; echo $?");
+    assert_eq!(stdout, "126");
+    assert_eq!(stderr, "Error in command: ./dir: Is a directory");
+}
+
+#[test]
+fn test_script_without_shebang() {
+    let (stdout, stderr, _) = run_shell_command(
+        "echo 'echo ran \"$@\"' > script; chmod +x script\n\
+         ./script a b; echo $?; ./script piped | cat",
+    );
+    assert_eq!(stdout, "ran a b\n0\nran piped");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_hash_follows_path() {
+    let temp_dir = TempDir::new().unwrap();
+    for dir in ["a", "b"] {
+        let bin = temp_dir.path().join(dir);
+        std::fs::create_dir(&bin).unwrap();
+        let script = bin.join("prog");
+        std::fs::write(&script, format!("#!/bin/sh\necho from {}\n", dir)).unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+    }
+
+    let mut shell = Shell::new();
+    shell.set_cwd(temp_dir.path().to_path_buf());
+    shell.variables.set("PATH", temp_dir.path().join("a"));
+    let result = shell.eval("prog\nprog\nhash\nwhich prog");
+    let dir = temp_dir.path().display();
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        format!("from a\nfrom a\nhits\tcommand\n   2\t{dir}/a/prog\n{dir}/a/prog\n")
+    );
+
+    // Changing PATH empties the cache, so the program is found again
+    shell.variables.set("PATH", temp_dir.path().join("b"));
+    let result = shell.eval("prog\nhash -r\nhash");
+    assert_eq!(result.stdout, b"from b\nhash: hash table empty\n");
+}
+
+#[test]
+fn test_history_expansion() {
+    let mut history = aish::history::History::new();
+    history.add("echo one 'two three' four");
+    history.add("ls -l /tmp");
+    let expand = |line: &str| {
+        aish::parsing::expand_history(line, &history)
+            .map(|expanded| expanded.unwrap_or_else(|| line.to_string()))
+            .map_err(|e| e.to_string())
+    };
+
+    assert_eq!(expand("!!"), Ok("ls -l /tmp".to_string()));
+    assert_eq!(expand("!1"), Ok("echo one 'two three' four".to_string()));
+    assert_eq!(expand("!-2:2"), Ok("'two three'".to_string()));
+    assert_eq!(expand("!ec:1-2"), Ok("one 'two three'".to_string()));
+    assert_eq!(expand("cat !$ !^"), Ok("cat /tmp -l".to_string()));
+    assert_eq!(expand("echo !*"), Ok("echo -l /tmp".to_string()));
+    assert_eq!(
+        expand("!?two?"),
+        Ok("echo one 'two three' four".to_string())
+    );
+    assert_eq!(expand("^tmp^var"), Ok("ls -l /var".to_string()));
+    assert_eq!(
+        expand("echo '!!' \\!! ! !="),
+        Ok("echo '!!' \\!! ! !=".to_string())
+    );
+    assert_eq!(expand("kill $!;"), Ok("kill $!;".to_string()));
+    assert_eq!(
+        expand("echo ${!arr[@]} \"$!\""),
+        Ok("echo ${!arr[@]} \"$!\"".to_string())
+    );
+    assert_eq!(
+        expand("echo $'it\\'s !!'"),
+        Ok("echo $'it\\'s !!'".to_string())
+    );
+
+    assert_eq!(expand("!nope"), Err("!nope: event not found".to_string()));
+    assert_eq!(expand("!!:5"), Err("!!:5: bad word specifier".to_string()));
+    assert_eq!(expand("^x^y"), Err("^x^y: substitution failed".to_string()));
+}
+
+#[test]
+fn test_history_builtin() {
+    let mut shell = Shell::new();
+    for line in ["echo a", "ls", "echo b", "pwd"] {
+        shell.history.add(line);
+    }
+    let result = shell.eval("history 2\nhistory echo");
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "    3  echo b\n    4  pwd\n    1  echo a\n    3  echo b\n"
+    );
+
+    let result = shell.eval("history -d 2\nhistory -d -1\nhistory");
+    assert_eq!(result.stdout, b"    1  echo a\n    2  echo b\n");
+    let result = shell.eval("history -d 9");
+    assert!(String::from_utf8_lossy(&result.stderr).contains("history position out of range"));
+
+    shell.eval("history -c");
+    assert!(shell.history.entries().is_empty());
+}
+
+#[test]
+fn test_read_loop() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(temp_dir.path().join("list"), "alice  1 2\nbob\n  carol \n").unwrap();
+
+    let mut shell = Shell::new();
+    shell.set_cwd(temp_dir.path().to_path_buf());
+    let result = shell.eval(
+        "cat list | while read -r name rest; do echo \"<$name> <$rest>\"; done\n\
+         while read line; do echo \"<$line>\"; done < list",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "<alice> <1 2>\n<bob> <>\n<carol> <>\n<alice  1 2>\n<bob>\n<carol>\n"
+    );
+}
+
+#[test]
+fn test_read_splitting() {
+    let mut shell = Shell::new();
+    let result = shell.eval(
+        "echo one:two::three | { export IFS=:; read a b; unset IFS; }\necho $a; echo $b\n\
+         printf 'a\\\\ b c\\\\\\nd e\\n' | read x y; echo $x; echo $y\n\
+         printf 'a\\\\ b c\\n' | read -r x y; echo $x; echo $y\n\
+         printf '  kept  \\n' | read; echo \"<$REPLY>\"",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "one\ntwo::three\na b\ncd e\na\\\nb c\n<  kept  >\n"
+    );
+}
+
+#[test]
+fn test_read_options() {
+    let mut shell = Shell::new();
+    let result = shell.eval(
+        "printf 'héllo' | read -n 2 x; echo $x\n\
+         printf 'ab;cd' | read -d ';' x; echo $x\n\
+         printf 'a\\nbc' | read -N 3 x; echo \"<$x>\"\n\
+         printf 'no newline' | read x || echo eof $x\n\
+         echo a b c | read -a words",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "hé\nab\n<a\nb>\neof no newline\n"
+    );
+    let words = ["a", "b", "c"].map(std::ffi::OsString::from);
+    assert_eq!(
+        shell.variables.value("words"),
+        Some(&aish::variables::Value::Indexed(
+            words.into_iter().enumerate().collect()
+        ))
+    );
+
+    // With nothing to read, the timeout ends the read
+    let result = shell.eval("sleep 1 | read -t 0.1 x");
+    assert_eq!(result.status, 142);
+}
+
+#[test]
+fn test_test_builtin() {
+    let (stdout, stderr, _) = run_shell_command(
+        "echo data > file; mkdir dir; touch empty\n\
+         test -f file && echo file\n\
+         [ -d dir ] && [ ! -f dir ] && echo dir\n\
+         [ -s empty ] || echo empty\n\
+         [ -e missing ] || echo missing\n\
+         [ 3 -lt 10 ] && [ abc != abd ] && echo compare\n\
+         [ -z \"\" -a -n x ] && echo and\n\
+         [ \\( a = b \\) -o \\( c = c \\) ] && echo parens\n\
+         test || echo no-args\n\
+         [ a -eq 1 ] || echo bad-integer\n\
+         [ x = x || echo unclosed",
+    );
+    assert_eq!(
+        stdout,
+        "file\ndir\nempty\nmissing\ncompare\nand\nparens\nno-args\nbad-integer\nunclosed"
+    );
+    assert_eq!(stderr, "[: a: integer expression expected\n[: missing `]'");
+}
+
+#[test]
+fn test_conditional_command() {
+    let (stdout, stderr, _) = run_shell_command(
+        "touch 'a b'\n\
+         [[ -f a\\ b && ! -d a\\ b ]] && echo file\n\
+         for w in 'a b*' abc; do [[ $w == a\" \"* ]] && echo \"pattern $w\"; done\n\
+         [[ * == \\* ]] && echo no-glob\n\
+         [[ b < a || ( 1 -eq 1 && x ) ]] && echo grouped\n\
+         [[ -z \"\" ]] && echo empty",
+    );
+    assert_eq!(stdout, "file\npattern a b*\nno-glob\ngrouped\nempty");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_conditional_arithmetic_operands() {
+    let (stdout, stderr, _) = run_shell_command(
+        "[[ x -eq 0 ]] && echo unset\n\
+         [[ 1 -gt abc- ]] && echo not-a-number\n\
+         n=' 5 ' m=n; [[ m -eq 5 && $n -ge n ]] && echo variables\n\
+         [[ '' -lt 1 ]] && echo empty",
+    );
+    assert_eq!(stdout, "unset\nnot-a-number\nvariables\nempty");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_conditional_regex() {
+    let mut shell = Shell::new();
+    let result = shell.eval("[[ foo123 =~ ^([a-z]+)-?([0-9]+)$ ]] && echo match");
+    assert_eq!(String::from_utf8_lossy(&result.stdout), "match\n");
+    let groups = ["foo123", "foo", "123"].map(std::ffi::OsString::from);
+    assert_eq!(
+        shell.variables.value("BASH_REMATCH"),
+        Some(&aish::variables::Value::Indexed(
+            groups.into_iter().enumerate().collect()
+        ))
+    );
+
+    // Quoted parts of the expression match literally
+    let result = shell.eval("for w in a.c abc; do [[ $w =~ ^a\".\"c ]] && echo $w; done");
+    assert_eq!(String::from_utf8_lossy(&result.stdout), "a.c\n");
+
+    let result = shell.eval("[[ a =~ ( ]]");
+    assert_eq!(result.status, 2);
+}
+
+#[test]
+fn test_echo_flags() {
+    let (stdout, _, _) = run_shell_command(
+        "echo -n one; echo ' two'\n\
+         echo -e 'a\\tb\\x41\\0101'\n\
+         echo -E 'a\\tb'\n\
+         echo -e 'cut\\cgone'; echo\n\
+         echo -nx",
+    );
+    assert_eq!(stdout, "one two\na\tbAA\na\\tb\ncut\n-nx");
+}
+
+#[test]
+fn test_printf() {
+    let mut shell = Shell::new();
+    let result = shell.eval(
+        "printf '%s=%s\\n' a 1 b 2 c\n\
+         printf '[%5s][%-5s][%.2s]\\n' ab cd efgh\n\
+         printf '%d %05d %+d %.3d %x %#X %o\\n' -7 42 6 8 255 255 8\n\
+         printf '%.2f %8.3f %e %g %g\\n' 2.5 1.5 12345.678 100000 0.0001\n\
+         printf '%c %q %q %b\\n' hello 'a b' \"it's\" 'x\\ty'\n\
+         printf '%*d|%-*d|\\101\\n' 5 1 4 2\n\
+         printf -v line '%s-%d' name 3; echo $line\n\
+         printf 'no newline'",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "a=1\nb=2\nc=\n\
+         [   ab][cd   ][ef]\n\
+         -7 00042 +6 008 ff 0XFF 10\n\
+         2.50    1.500 1.234568e+04 100000 0.0001\n\
+         h a\\ b it\\'s x\ty\n\
+         \x20   1|2   |A\n\
+         name-3\n\
+         no newline"
+    );
+
+    let result = shell.eval("printf '%d\\n' 12abc");
+    assert_eq!(result.status, 1);
+    assert_eq!(String::from_utf8_lossy(&result.stdout), "12\n");
+    assert_eq!(
+        String::from_utf8_lossy(&result.stderr),
+        "printf: 12abc: invalid number\n"
+    );
+
+    let result = shell.eval("printf '%d %d\\n' 99999999999999999999 -99999999999999999999");
+    assert_eq!(result.status, 1);
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "9223372036854775807 -9223372036854775808\n"
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stderr),
+        "printf: 99999999999999999999: Numerical result out of range\n\
+         printf: -99999999999999999999: Numerical result out of range\n"
+    );
+
+    let result = shell.eval("printf '%d\\n' -9223372036854775808");
+    assert_eq!(result.status, 0);
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "-9223372036854775808\n"
+    );
+
+    let result = shell.eval("printf 'x%z'");
+    assert_eq!(result.status, 1);
+    assert_eq!(String::from_utf8_lossy(&result.stdout), "x");
+    assert_eq!(
+        String::from_utf8_lossy(&result.stderr),
+        "printf: `%z': missing format character\n"
+    );
+}
+
+#[test]
+fn test_double_quoted_backslashes() {
+    let mut shell = Shell::new();
+    let result = shell.eval(
+        "echo -e \"b\\tc\"\n\
+         printf \"%s\\n\" x\n\
+         echo \"a\\$b \\\"q\\\" back\\\\slash \\x \\`\"\n\
+         echo \"one\\\ntwo\"",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "b\tc\nx\na$b \"q\" back\\slash \\x `\nonetwo\n"
+    );
+}
+
+#[test]
+fn test_cd_logical_paths() {
+    let (stdout, stderr, root) = run_shell_command(
+        "mkdir -p real/sub; ln -s real link\n\
+         cd link/sub; pwd; pwd -P; echo $PWD\n\
+         cd ..; pwd; cd -P ..; pwd\n\
+         cd link; cd -; echo $OLDPWD\n\
+         cd nowhere",
+    );
+    assert_eq!(
+        stdout,
+        format!(
+            "{root}/link/sub\n{root}/real/sub\n{root}/link/sub\n\
+             {root}/link\n{root}\n{root}\n{root}/link"
+        )
+    );
+    assert_eq!(
+        stderr,
+        "Error in command: cd: nowhere: No such file or directory"
+    );
+}
+
+#[test]
+fn test_cdpath() {
+    // The empty entry is the working directory, which is not printed
+    let (stdout, _, root) = run_shell_command(
+        "mkdir -p projects/aish projects/other other\n\
+         export CDPATH=:projects\n\
+         cd aish; cd ..; cd other; pwd",
+    );
+    assert_eq!(
+        stdout,
+        format!("{root}/projects/aish\n{root}/projects/other")
+    );
+}
+
+#[test]
+fn test_directory_stack() {
+    let (stdout, stderr, root) = run_shell_command(
+        "mkdir -p a/b\n\
+         pushd a; pushd b\n\
+         dirs -v\n\
+         pushd; popd\n\
+         pushd +1; dirs +0\n\
+         popd; popd",
+    );
+    assert_eq!(
+        stdout,
+        format!(
+            "{root}/a {root}\n\
+             {root}/a/b {root}/a {root}\n \
+             0  {root}/a/b\n 1  {root}/a\n 2  {root}\n\
+             {root}/a {root}/a/b {root}\n\
+             {root}/a/b {root}\n\
+             {root} {root}/a/b\n{root}\n\
+             {root}/a/b"
+        )
+    );
+    assert_eq!(stderr, "Error in command: popd: directory stack empty");
+}
+
+#[test]
+fn test_exit_runs_trap() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aish"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b"trap 'echo bye' EXIT\ntrap\nfalse\nexit\necho after\n")?;
+            drop(stdin);
+            child.wait_with_output()
+        })
+        .expect("Failed to run aish");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "trap -- 'echo bye' EXIT\nbye\n"
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_exit_too_many_arguments() {
+    let (stdout, stderr, _) = run_shell_command("exit 1 2; echo still running");
+    assert_eq!(stdout, "still running");
+    assert_eq!(stderr, "Error in command: exit: too many arguments");
+}
+
+#[test]
+fn test_exit_non_numeric_argument() {
+    let (stdout, stderr, _) = run_shell_command("exit abc; echo not reached");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "exit: abc: numeric argument required");
+}
+
+#[test]
+fn test_background_job() {
+    let (stdout, stderr, _) = run_shell_command("sleep 0.1 & echo started");
+    assert_eq!(stdout, "started");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_wait_for_job_status() {
+    let (stdout, stderr, _) = run_shell_command("(exit 3) &\nwait %1; echo $?");
+    assert_eq!(stdout, "3");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_wait_for_all_jobs() {
+    let (stdout, stderr, _) = run_shell_command("sleep 0.1 & sleep 0.1 &\nwait; echo done");
+    assert_eq!(stdout, "done");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_wait_not_a_child() {
+    let (stdout, stderr, _) = run_shell_command("wait 1; echo $?");
+    assert_eq!(stdout, "127");
+    assert_eq!(stderr, "wait: pid 1 is not a child of this shell");
+}
+
+#[test]
+fn test_kill_job() {
+    let (stdout, stderr, _) = run_shell_command("sleep 5 & kill %1\nwait %1; echo $?");
+    assert_eq!(stdout, "143");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_kill_list_signals() {
+    let (stdout, stderr, _) = run_shell_command("kill -l 143; kill -l INT");
+    assert_eq!(stdout, "TERM\n2");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_kill_job_reaches_its_commands() {
+    // The `sleep` the job started would otherwise keep stdout open
+    let start = std::time::Instant::now();
+    let (stdout, stderr, _) =
+        run_shell_command("{ sleep 5; echo late; } & sleep 0.2; kill %1; wait %1; echo $?");
+    assert_eq!(stdout, "143");
+    assert_eq!(stderr, "");
+    assert!(start.elapsed().as_secs() < 4);
+}
+
+#[test]
+fn test_exec_redirects_shell() {
+    let (stdout, stderr, _) = run_shell_command(
+        "exec 3>out\n\
+         echo one >&3; echo two >&3\n\
+         exec 3>&-\n\
+         cat out",
+    );
+    assert_eq!(stdout, "one\ntwo");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_exec_closes_fd() {
+    let (stdout, stderr, _) = run_shell_command("exec 3>out; exec 3>&-\necho one >&3");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "Error in command: 3: bad file descriptor");
+}
+
+#[test]
+fn test_exec_replaces_shell() {
+    let (stdout, stderr, _) = run_shell_command("echo one > out; exec cat out\necho not reached");
+    assert_eq!(stdout, "one");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_exec_not_found() {
+    let (stdout, stderr, _) = run_shell_command("echo before; exec nosuchcmd; echo after");
+    assert_eq!(stdout, "before");
+    assert_eq!(stderr, "exec: nosuchcmd: not found");
+}
+
+#[test]
+fn test_exec_not_found_status() {
+    let mut shell = Shell::new();
+    let result = shell.eval("exec nosuchcmd; echo after");
+    assert_eq!(result.stdout, b"");
+    assert_eq!(result.status, 127);
+}
+
+#[test]
+fn test_exec_not_executable_status() {
+    let mut shell = Shell::new();
+    let result = shell.eval("exec /etc/passwd; echo after");
+    assert_eq!(result.stdout, b"");
+    assert_eq!(result.status, 126);
+}
+
+#[test]
+fn test_exec_failure_at_prompt() {
+    // At the prompt the shell carries on
+    let mut shell = Shell::new();
+    shell.options.interactive = true;
+    let result = shell.eval("exec nosuchcmd; echo $?");
+    assert_eq!(result.stdout, b"127\n");
+}
+
+#[test]
+fn test_umask() {
+    let (stdout, stderr, _) = run_shell_command("umask 027; umask; umask -S");
+    assert_eq!(stdout, "0027\nu=rwx,g=rx,o=");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_umask_symbolic() {
+    let (stdout, stderr, _) = run_shell_command("umask 027; umask g+w,o=r; umask");
+    assert_eq!(stdout, "0003");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_umask_applies_to_new_files() {
+    let (stdout, stderr, _) = run_shell_command("umask 003; touch file; ls -l file | cut -c1-10");
+    assert_eq!(stdout, "-rw-rw-r--");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_umask_out_of_range() {
+    let (stdout, stderr, _) = run_shell_command("umask 8; echo $?");
+    assert_eq!(stdout, "1");
+    assert_eq!(
+        stderr,
+        "Error in command: umask: 8: octal number out of range"
+    );
+}
+
+#[test]
+fn test_ulimit() {
+    let (stdout, stderr, _) = run_shell_command("ulimit -Sn 64; ulimit -Sn");
+    assert_eq!(stdout, "64");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_time() {
+    let (stdout, stderr, _) = run_shell_command(
+        "time -p echo hi | cat\n\
+         export TIMEFORMAT='%0R %% %x'\n\
+         time sleep 0.1; time\n\
+         export TIMEFORMAT=\n\
+         time false || echo failed",
+    );
+    assert_eq!(stdout, "hi\nfailed");
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("real 0."));
+    assert!(lines[1].starts_with("user 0."));
+    assert!(lines[2].starts_with("sys 0."));
+    assert_eq!(lines[3..], ["0 % %x", "0 % %x"]);
+
+    let (_, stderr, _) = run_shell_command("time sleep 0.2");
+    assert!(stderr.starts_with("real\t0m0.2"));
+    assert!(stderr.contains("\nuser\t0m0."));
+}
+
+#[test]
+fn test_time_counts_pipeline_stages() {
+    let (stdout, stderr, _) = run_shell_command(
+        "TIMEFORMAT=%3U\n\
+         time sh -c 'i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done; echo done' | cat",
+    );
+    assert_eq!(stdout, "done");
+    let user: f64 = stderr.parse().unwrap();
+    assert!(user > 0.05, "user time {user}");
+}
+
+#[test]
+fn test_special_parameters() {
+    let (stdout, _, root) = run_shell_command(
+        "echo $$ > pid; sh -c 'echo $PPID' > ppid; (echo $$) > subshell\n\
+         cmp pid ppid && cmp pid subshell && echo same\n\
+         false; echo $?; echo $?\n\
+         sleep 0.1 & echo $! > bg; wait $!; [ -s bg ] && echo bg\n\
+         echo \"line $LINENO\"\n\
+         echo $SECONDS $-\n\
+         echo $PWD/file a$#b $%",
+    );
+    assert_eq!(
+        stdout,
+        format!("same\n1\n0\nbg\nline 5\n0 h\n{root}/file a0b $%")
+    );
+
+    let mut shell = Shell::new();
+    let result = shell.eval("echo $RANDOM $RANDOM; echo $SHLVL");
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    let random: Vec<u32> = lines[0].split(' ').map(|n| n.parse().unwrap()).collect();
+    assert!(random.iter().all(|n| *n < 32768));
+    assert_ne!(random[0], random[1]);
+    let level: i64 = env::var("SHLVL").map_or(0, |level| level.parse().unwrap_or(0));
+    assert_eq!(lines[1], (level + 1).to_string());
+}
+
+#[test]
+fn test_set_errexit() {
+    let (stdout, stderr, _) = run_shell_command("set -e\necho $-\nfalse\necho unreachable");
+    assert_eq!(stdout, "he");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_errexit_ignores_tested_commands() {
+    let (stdout, stderr, _) = run_shell_command(
+        "set -e\n\
+         if false; then echo then; fi\n\
+         false || echo or\n\
+         false && echo and\n\
+         echo reached",
+    );
+    assert_eq!(stdout, "or\nreached");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_errexit_in_function() {
+    let (stdout, stderr, _) =
+        run_shell_command("set -e\nf() { false && true; }\nf\necho unreachable");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_errexit_in_loop() {
+    let mut shell = Shell::new();
+    let result = shell.eval("set -e; for i in 1 2; do echo $i; false; done; echo no");
+    assert_eq!(result.stdout, b"1\n");
+    assert_eq!(result.status, 1);
+}
+
+#[test]
+fn test_set_pipefail() {
+    let (stdout, stderr, _) =
+        run_shell_command("false | true; echo $?\nset -o pipefail\nfalse | true; echo $?");
+    assert_eq!(stdout, "0\n1");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_nounset() {
+    let (stdout, stderr, _) = run_shell_command("echo $unset\nset -u\necho $unset\necho after");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "Error in command: unset: unbound variable");
+}
+
+#[test]
+fn test_set_xtrace() {
+    let (stdout, stderr, _) = run_shell_command("set -x\necho 'a b' c\nset +x\necho untraced");
+    assert_eq!(stdout, "a b c\nuntraced");
+    assert_eq!(stderr, "+ echo 'a b' c\n+ set +x");
+}
+
+#[test]
+fn test_set_positional_parameters() {
+    let (stdout, stderr, _) = run_shell_command("set -- one 'two three'; echo $# $2");
+    assert_eq!(stdout, "2 two three");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_invalid_option() {
+    let (stdout, stderr, _) = run_shell_command("set -z; echo $?\nset -o bogus; echo $?");
+    assert_eq!(stdout, "2\n2");
+    assert_eq!(
+        stderr,
+        "set: -z: invalid option\nset: bogus: invalid option name"
+    );
+}
+
+#[test]
+fn test_set_list_options() {
+    let (stdout, stderr, _) =
+        run_shell_command("set -o noclobber; set -o | grep clobber; set +o | grep clobber");
+    assert_eq!(stdout, "noclobber      \ton\nset -o noclobber");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_noclobber() {
+    let (stdout, stderr, _) = run_shell_command("set -C; echo one > f; echo two > f; cat f");
+    assert_eq!(stdout, "one");
+    assert_eq!(
+        stderr,
+        "Error in command: f: cannot overwrite existing file"
+    );
+}
+
+#[test]
+fn test_set_noclobber_override() {
+    let (stdout, stderr, _) = run_shell_command("set -C; echo one > f; echo two >| f; cat f");
+    assert_eq!(stdout, "two");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_noclobber_device() {
+    let (stdout, stderr, _) = run_shell_command("set -C; echo ok > /dev/null && echo null");
+    assert_eq!(stdout, "null");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shopt_nullglob() {
+    let (stdout, stderr, _) =
+        run_shell_command("shopt -s nullglob; echo *.none end; shopt -u nullglob; echo *.none end");
+    assert_eq!(stdout, "end\n*.none end");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shopt_failglob() {
+    let (stdout, stderr, _) = run_shell_command("shopt -s failglob; echo *.none end");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "Error in command: no match: *.none");
+}
+
+#[test]
+fn test_shopt_nocaseglob() {
+    let (stdout, stderr, _) = run_shell_command("touch a.txt B.txt; shopt -s nocaseglob; echo b*");
+    assert_eq!(stdout, "B.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shopt_dotglob() {
+    let (stdout, stderr, _) =
+        run_shell_command("touch a.txt B.txt .hidden; shopt -s dotglob; echo *");
+    assert_eq!(stdout, ".hidden B.txt a.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_set_noglob() {
+    let (stdout, stderr, _) = run_shell_command("touch a.txt; set -f; echo *; set +f; echo *");
+    assert_eq!(stdout, "*\na.txt");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shopt_print() {
+    let (stdout, stderr, _) = run_shell_command("shopt -p dotglob; shopt nullglob; echo $?");
+    assert_eq!(stdout, "shopt -u dotglob\nnullglob       \toff\n1");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shopt_invalid_option() {
+    let (stdout, stderr, _) = run_shell_command("shopt -q bogus; echo $?");
+    assert_eq!(stdout, "1");
+    assert_eq!(stderr, "shopt: bogus: invalid shell option name");
+}
+
+#[test]
+fn test_shopt_list() {
+    let (stdout, stderr, _) = run_shell_command("shopt | grep nullglob");
+    assert_eq!(stdout, "nullglob       \toff");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shopt_list_status() {
+    let (stdout, stderr, _) =
+        run_shell_command("shopt > /dev/null; echo $?; shopt -o > /dev/null; echo $?");
+    assert_eq!(stdout, "0\n0");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_shopt_query_status() {
+    let (stdout, stderr, _) = run_shell_command(
+        "shopt -q; echo $?\n\
+         shopt nullglob dotglob > /dev/null; echo $?\n\
+         shopt -s nullglob dotglob; shopt -q nullglob dotglob; echo $?",
+    );
+    assert_eq!(stdout, "0\n1\n0");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_field_splitting() {
+    let (stdout, _, _) = run_shell_command(
+        "export FLAGS='-a -b'\n\
+         printf '[%s]' $FLAGS \"$FLAGS\"; echo\n\
+         export IFS=:; export path='a::b:'\n\
+         printf '<%s>' $path $path'c'; echo\n\
+         unset IFS; export empty=\n\
+         printf '<%s>' $empty \"\" a$empty; echo\n\
+         \techo\ttabs\t  and spaces",
+    );
+    assert_eq!(
+        stdout,
+        "[-a][-b][-a -b]\n<a><><b><a><><b><c>\n<><a>\ntabs and spaces"
+    );
+}
+
+#[test]
+fn test_indexed_array() {
+    let (stdout, stderr, _) = run_shell_command(
+        "arr=(a 'b c' d)\n\
+         arr+=(e)\n\
+         echo ${arr[1]} ${#arr[@]} ${!arr[@]} ${arr[-1]} $arr",
+    );
+    assert_eq!(stdout, "b c 4 0 1 2 3 e a");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_indexed_array_all_elements() {
+    let (stdout, stderr, _) =
+        run_shell_command("arr=(a 'b c' d); printf '[%s]' \"${arr[@]}\" \"${arr[*]}\"");
+    assert_eq!(stdout, "[a][b c][d][a b c d]");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_indexed_array_element_assignment() {
+    let (stdout, stderr, _) = run_shell_command(
+        "arr=(a b c d)\n\
+         unset arr[1]; i=2; arr[i]+=x\n\
+         echo ${!arr[@]} ${arr[$i]} ${#arr[0]}",
+    );
+    assert_eq!(stdout, "0 2 3 cx 1");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_empty_array() {
+    let (stdout, stderr, _) = run_shell_command("empty=()\nprintf '[%s]' \"${empty[@]}\" end");
+    assert_eq!(stdout, "[end]");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_array_over_several_lines() {
+    let (stdout, stderr, _) =
+        run_shell_command("list=(\n  1\n  2 # two\n  [5]=6 7\n)\ndeclare -p list");
+    assert_eq!(
+        stdout,
+        "declare -a list=([0]=\"1\" [1]=\"2\" [5]=\"6\" [6]=\"7\")"
+    );
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_associative_array() {
+    let (stdout, stderr, _) = run_shell_command(
+        "declare -A m=([one]=1 ['two words']=2)\n\
+         m[three]=3\n\
+         echo ${m[one]} \"${m[two words]}\" ${#m[@]}",
+    );
+    assert_eq!(stdout, "1 2 3");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_associative_array_unset() {
+    let (stdout, stderr, _) = run_shell_command(
+        "declare -A m=([one]=1 ['two words']=2 [three]=3)\n\
+         unset 'm[one]'\n\
+         printf '<%s>' \"${!m[@]}\"; echo\n\
+         declare -p m",
+    );
+    assert_eq!(
+        stdout,
+        "<three><two words>\ndeclare -A m=([three]=\"3\" [\"two words\"]=\"2\")"
+    );
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_associative_array_needs_subscript() {
+    let (_, stderr, _) = run_shell_command("declare -A m; m+=(four)");
+    assert!(stderr.contains("m: must use subscript when assigning associative array"));
+}
+
+#[test]
+fn test_subscript_with_blanks() {
+    let (stdout, stderr, _) =
+        run_shell_command("declare -A m; m[y z]=2; m[y z]+=3; echo \"${m[y z]}\"");
+    assert_eq!(stdout, "23");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_subscript_with_blanks_in_argument() {
+    let (stdout, stderr, _) = run_shell_command("echo a[1 2]=3");
+    assert_eq!(stdout, "a[1 2]=3");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_prefix_assignment_after_expansion() {
+    let (stdout, stderr, _) = run_shell_command("x=5 echo \"[$x]\"");
+    assert_eq!(stdout, "[]");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_prefix_assignment_restored() {
+    let (stdout, stderr, _) = run_shell_command("x=1; x=2 sh -c 'echo $x' \"$x\"; echo $x");
+    assert_eq!(stdout, "2\n1");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_append_assignment() {
+    let (stdout, stderr, _) = run_shell_command("x=1; x+=2; y=\"$x  z\"\necho \"$x $y\"");
+    assert_eq!(stdout, "12 12  z");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_prefix_assignment_exported() {
+    let (stdout, stderr, _) = run_shell_command("V=temp sh -c 'echo $V'\necho \"[$V]\"");
+    assert_eq!(stdout, "temp\n[]");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_prefix_assignment_for_function() {
+    let (stdout, stderr, _) = run_shell_command("f() { echo \"in f: $V\"; }\nV=call f");
+    assert_eq!(stdout, "in f: call");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_unset_array_element_nounset() {
+    let (_, stderr, _) = run_shell_command("set -u; a=(1); echo ${a[3]}");
+    assert!(stderr.contains("a[3]: unbound variable"));
+}
+
+#[test]
+fn test_ansi_c_quoting() {
+    let mut shell = Shell::new();
+    let result = shell.eval("printf '[%s]' $'a\\tb' $'it\\'s' x$'\\n'y \"$'no'\" $'*' $'\\e[1m'");
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "[a\tb][it's][x\ny][$'no'][*][\x1b[1m]"
+    );
+}
+
+#[test]
+fn test_ansi_c_numeric_escapes() {
+    let mut shell = Shell::new();
+    let result =
+        shell.eval("printf '[%s]' $'\\x41\\101\\u00e9\\U0001F600' $'\\cA' $'a\\0b' $'\\\"\\?\\\\'");
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "[AAé😀][\x01][a][\"?\\]"
+    );
+}
+
+#[test]
+fn test_process_substitution() {
+    let (stdout, stderr, _) = run_shell_command(
+        "printf 'b\\na\\n' > one; printf 'a\\nc\\n' > two\n\
+         diff <(sort one) <(sort two) > /dev/null || echo differ\n\
+         cat <(echo first) <(echo second)\n\
+         while read -r line; do echo \"got $line\"; done < <(printf 'x\\ny\\n')\n\
+         f() { cat \"$1\"; }; f <(echo in function)\n\
+         source <(echo 'echo sourced')\n\
+         echo loud > >(tr a-z A-Z > upper); wait $!; cat upper\n\
+         echo <(true) | grep -c '^/dev/fd/'\n\
+         echo piped | tee >(tr a-z A-Z > teed) > /dev/null; wait $!; cat teed",
+    );
+    assert_eq!(
+        stdout,
+        "differ\nfirst\nsecond\ngot x\ngot y\nin function\nsourced\nLOUD\n1\nPIPED"
+    );
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_pipeline_negation() {
+    let (stdout, stderr, _) = run_shell_command(
+        "! true; echo $?\n\
+         ! false; echo $?\n\
+         ! ! false; echo $?\n\
+         if ! grep -q foo /dev/null; then echo no foo; fi\n\
+         ! echo hi | grep -q hi; echo $?\n\
+         set -e; ! true; echo still running",
+    );
+    assert_eq!(stdout, "1\n0\n1\nno foo\n1\nstill running");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_pipe_stderr() {
+    let (stdout, stderr, _) = run_shell_command(
+        "sh -c 'echo out; echo err >&2' |& sort\n\
+         { echo group >&2; } |& tr a-z A-Z\n\
+         f() { echo function >&2; }; f |& sed 's/^/got /'\n\
+         type nosuchcommand |& wc -l\n\
+         sh -c 'echo only >&2' | cat",
+    );
+    assert_eq!(stdout, "err\nout\nGROUP\ngot function\n1");
+    assert_eq!(stderr, "only");
+}